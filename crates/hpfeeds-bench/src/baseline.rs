@@ -0,0 +1,114 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+
+/// A run's results as written by `--save-baseline` and read back by `--compare`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BenchResult {
+    /// Clients, messages and payload sizes of the run; results from different setups are not
+    /// comparable
+    pub setup: String,
+    pub received: u64,
+    pub elapsed_secs: f64,
+    /// Messages received per second, across all subscribers
+    pub msgs_per_sec: f64,
+    /// Payload bytes received per second, across all subscribers
+    pub bytes_per_sec: f64,
+}
+
+impl BenchResult {
+    pub fn read(path: &Path) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("reading baseline {}", path.display()))?;
+        serde_json::from_str(&data).with_context(|| format!("parsing baseline {}", path.display()))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let data = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, data + "\n").with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+/// Throughput of a run against a baseline's.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Comparison {
+    pub baseline: f64,
+    pub current: f64,
+}
+
+impl Comparison {
+    pub fn new(current: &BenchResult, baseline: &BenchResult) -> Self {
+        Self {
+            baseline: baseline.msgs_per_sec,
+            current: current.msgs_per_sec,
+        }
+    }
+
+    /// Percentage change from the baseline; negative when slower.
+    pub fn change_pct(&self) -> f64 {
+        if self.baseline == 0.0 {
+            return 0.0;
+        }
+        (self.current - self.baseline) / self.baseline * 100.0
+    }
+
+    /// True if throughput fell by more than `max_regression_pct` percent.
+    pub fn regressed(&self, max_regression_pct: f64) -> bool {
+        -self.change_pct() > max_regression_pct
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Throughput {:.2} msg/s against baseline {:.2} msg/s ({:+.1}%)",
+            self.current,
+            self.baseline,
+            self.change_pct()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(msgs_per_sec: f64) -> BenchResult {
+        BenchResult {
+            setup: "10 subs, 1 pubs".into(),
+            received: 10_000,
+            elapsed_secs: 10_000.0 / msgs_per_sec,
+            msgs_per_sec,
+            bytes_per_sec: msgs_per_sec * 1024.0,
+        }
+    }
+
+    #[test]
+    fn regression_beyond_tolerance_fails() {
+        let baseline = result(10_000.0);
+
+        let within = Comparison::new(&result(9_500.0), &baseline);
+        assert!(!within.regressed(10.0));
+        let faster = Comparison::new(&result(12_000.0), &baseline);
+        assert!(!faster.regressed(10.0));
+
+        let beyond = Comparison::new(&result(8_500.0), &baseline);
+        assert!(beyond.regressed(10.0));
+        assert_eq!(
+            beyond.to_string(),
+            "Throughput 8500.00 msg/s against baseline 10000.00 msg/s (-15.0%)"
+        );
+    }
+
+    #[test]
+    fn baseline_file_roundtrip() {
+        let path = std::env::temp_dir().join(format!("bench-baseline-{}.json", std::process::id()));
+        let saved = result(10_000.0);
+        saved.write(&path).unwrap();
+        let read = BenchResult::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read, saved);
+    }
+}
@@ -1,12 +1,20 @@
-use anyhow::Result;
+use anyhow::{Result, bail};
+use baseline::{BenchResult, Comparison};
 use clap::Parser;
 use futures::{SinkExt, StreamExt};
 use hpfeeds_client::connect_and_auth;
 use hpfeeds_core::Frame;
-use std::sync::Arc;
+use sizes::{SizeDist, SizeStats};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tokio::sync::Barrier;
+use verify::{Verifier, VerifyReport, sequenced_payload};
+
+mod baseline;
+mod sizes;
+mod verify;
 
 #[derive(Parser, Debug)]
 #[clap(name = "hpfeeds-bench", about = "Benchmarking tool for hpfeeds")]
@@ -39,6 +47,10 @@ struct Args {
     #[clap(long, default_value_t = 1024)]
     payload_size: usize,
 
+    /// How payload sizes vary: fixed (--payload-size), uniform:MIN-MAX or lognormal:MEDIAN,SIGMA
+    #[clap(long, default_value = "fixed")]
+    payload_size_dist: SizeDist,
+
     /// Identity prefix
     #[clap(long, default_value = "bench")]
     ident: String,
@@ -54,6 +66,78 @@ struct Args {
     /// Path to server SQLite DB to seed users (optional)
     #[clap(long)]
     db: Option<String>,
+
+    /// Embed per-publisher sequence numbers and report lost or reordered messages
+    #[clap(long)]
+    verify: bool,
+
+    /// Write the results to this JSON file, for later runs to --compare against
+    #[clap(long)]
+    save_baseline: Option<PathBuf>,
+
+    /// Compare throughput with a file from --save-baseline, exiting non-zero on a regression
+    #[clap(long)]
+    compare: Option<PathBuf>,
+
+    /// Largest drop in throughput, in percent, that --compare tolerates
+    #[clap(long, default_value_t = 10.0)]
+    max_regression_pct: f64,
+}
+
+/// What a benchmark run observed.
+struct Summary {
+    received: u64,
+    received_bytes: u64,
+    /// Sizes of the payloads published
+    sizes: SizeStats,
+    elapsed: Duration,
+    verify: Option<VerifyReport>,
+    outcomes: Outcomes,
+}
+
+/// Outcome counts shared by the publisher and subscriber tasks.
+#[derive(Default)]
+struct Counters {
+    published: AtomicU64,
+    publish_errors: AtomicU64,
+    pubs_failed: AtomicU64,
+    subs_connected: AtomicU64,
+    subs_failed: AtomicU64,
+    subs_dropped: AtomicU64,
+}
+
+/// A snapshot of [`Counters`] taken when the run ends.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Outcomes {
+    /// Publishes written to the broker
+    published: u64,
+    /// Publishers whose connection failed mid-run
+    publish_errors: u64,
+    /// Publishers that never connected
+    pubs_failed: u64,
+    subs_connected: u64,
+    /// Subscribers that failed to connect or subscribe
+    subs_failed: u64,
+    /// Subscribers whose connection ended before the run did
+    subs_dropped: u64,
+}
+
+impl Counters {
+    fn snapshot(&self) -> Outcomes {
+        Outcomes {
+            published: self.published.load(Ordering::Relaxed),
+            publish_errors: self.publish_errors.load(Ordering::Relaxed),
+            pubs_failed: self.pubs_failed.load(Ordering::Relaxed),
+            subs_connected: self.subs_connected.load(Ordering::Relaxed),
+            subs_failed: self.subs_failed.load(Ordering::Relaxed),
+            subs_dropped: self.subs_dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Subscribers still connected.
+    fn subs_live(&self) -> u64 {
+        self.subs_connected.load(Ordering::Relaxed) - self.subs_dropped.load(Ordering::Relaxed)
+    }
 }
 
 #[tokio::main]
@@ -105,29 +189,128 @@ async fn main() -> Result<()> {
         println!("Database seeded.");
     }
 
+    let summary = run(&args, &addr).await?;
+
+    println!("--- Benchmark Results ---");
+    println!("Total Messages Received: {}", summary.received);
+    println!("Total Time: {:.2?}", summary.elapsed);
+    println!(
+        "Throughput: {:.2} msg/s",
+        summary.received as f64 / summary.elapsed.as_secs_f64()
+    );
+    println!(
+        "Data Rate: {:.2} MB/s",
+        summary.received_bytes as f64 / (1024.0 * 1024.0 * summary.elapsed.as_secs_f64())
+    );
+    if summary.sizes.count > 0 {
+        println!(
+            "Payload sizes ({}): min {}, mean {:.0}, max {} bytes",
+            args.payload_size_dist,
+            summary.sizes.min,
+            summary.sizes.mean(),
+            summary.sizes.max
+        );
+    }
+    let f = &summary.outcomes;
     println!(
-        "Starting benchmark with {} subs, {} pubs, {} msgs/pub, payload {} bytes",
-        args.subs, args.pubs, args.msgs, args.payload_size
+        "Publishes: {} sent, {} publishers failed to connect, {} publish errors",
+        f.published, f.pubs_failed, f.publish_errors
     );
+    println!(
+        "Subscribers: {} connected, {} failed, {} dropped",
+        f.subs_connected, f.subs_failed, f.subs_dropped
+    );
+    if let Some(report) = summary.verify {
+        println!("Lost: {}", report.lost);
+        println!("Out of order: {}", report.out_of_order);
+        if report.malformed > 0 {
+            println!("Malformed: {}", report.malformed);
+        }
+    }
+
+    let result = BenchResult {
+        setup: setup(&args),
+        received: summary.received,
+        elapsed_secs: summary.elapsed.as_secs_f64(),
+        msgs_per_sec: summary.received as f64 / summary.elapsed.as_secs_f64(),
+        bytes_per_sec: summary.received_bytes as f64 / summary.elapsed.as_secs_f64(),
+    };
+    if let Some(path) = &args.save_baseline {
+        result.write(path)?;
+        println!("Results saved to {}", path.display());
+    }
+    if let Some(path) = &args.compare {
+        let baseline = BenchResult::read(path)?;
+        if baseline.setup != result.setup {
+            eprintln!(
+                "warning: baseline is from a different setup: {}",
+                baseline.setup
+            );
+        }
+        let comparison = Comparison::new(&result, &baseline);
+        println!("{}", comparison);
+        if comparison.regressed(args.max_regression_pct) {
+            bail!(
+                "throughput regressed by more than {}%",
+                args.max_regression_pct
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// The clients, messages and payload sizes of a run, e.g. `10 subs, 1 pubs, 1000 msgs/pub,
+/// payload 1024 bytes`.
+fn setup(args: &Args) -> String {
+    let messages = match args.duration {
+        Some(secs) => format!("{}s", secs),
+        None => format!("{} msgs/pub", args.msgs),
+    };
+    let payload = match args.payload_size_dist {
+        SizeDist::Fixed => format!("{} bytes", args.payload_size),
+        dist => dist.to_string(),
+    };
+    format!(
+        "{} subs, {} pubs, {}, payload {}",
+        args.subs, args.pubs, messages, payload
+    )
+}
+
+async fn run(args: &Args, addr: &str) -> Result<Summary> {
+    println!("Starting benchmark with {}", setup(args));
 
     let total_expected = (args.pubs * args.msgs * args.subs) as u64;
     let received_count = Arc::new(AtomicU64::new(0));
+    let received_bytes = Arc::new(AtomicU64::new(0));
     let start_barrier = Arc::new(Barrier::new(args.subs + args.pubs + 1));
+    let verifiers: Vec<_> = (0..args.subs)
+        .map(|_| Arc::new(Mutex::new(Verifier::default())))
+        .collect();
+    let sent: Arc<Vec<AtomicU64>> = Arc::new((0..args.pubs).map(|_| AtomicU64::new(0)).collect());
+    let sent_sizes: Vec<_> = (0..args.pubs)
+        .map(|_| Arc::new(Mutex::new(SizeStats::default())))
+        .collect();
+    let counters = Arc::new(Counters::default());
 
     // Spawn subscribers
-    for i in 0..args.subs {
-        let addr = addr.clone();
+    for (i, verifier) in verifiers.iter().enumerate() {
+        let addr = addr.to_string();
         let ident = args.ident.clone(); // Use same ident
         let secret = args.secret.clone();
         let channel = args.channel.clone();
         let counter = received_count.clone();
+        let bytes = received_bytes.clone();
         let barrier = start_barrier.clone();
+        let verifier = args.verify.then(|| verifier.clone());
+        let counters = counters.clone();
 
         tokio::spawn(async move {
             let mut client = match connect_and_auth(&addr, &ident, &secret).await {
                 Ok(c) => c,
                 Err(e) => {
                     eprintln!("Sub {} connect failed: {}", i, e);
+                    counters.subs_failed.fetch_add(1, Ordering::Relaxed);
                     barrier.wait().await;
                     return;
                 }
@@ -140,17 +323,24 @@ async fn main() -> Result<()> {
                 .await
             {
                 eprintln!("Sub {} subscribe failed: {}", i, e);
+                counters.subs_failed.fetch_add(1, Ordering::Relaxed);
                 barrier.wait().await;
                 return;
             }
+            counters.subs_connected.fetch_add(1, Ordering::Relaxed);
 
             barrier.wait().await;
 
             while let Some(msg) = client.next().await {
-                if let Ok(Frame::Publish { .. }) = msg {
+                if let Ok(Frame::Publish { payload, .. }) = msg {
+                    if let Some(v) = &verifier {
+                        v.lock().unwrap().record(&payload);
+                    }
                     counter.fetch_add(1, Ordering::Relaxed);
+                    bytes.fetch_add(payload.len() as u64, Ordering::Relaxed);
                 }
             }
+            counters.subs_dropped.fetch_add(1, Ordering::Relaxed);
         });
     }
 
@@ -158,24 +348,29 @@ async fn main() -> Result<()> {
     tokio::time::sleep(Duration::from_millis(500)).await;
 
     // Spawn publishers
-    let payload = vec![0u8; args.payload_size];
+    // Unverified payloads are zeros, sliced to each drawn size
+    let payload = vec![0u8; args.payload_size_dist.max(args.payload_size)];
     let payload = bytes::Bytes::from(payload);
     let run_duration = args.duration.map(Duration::from_secs);
 
-    for i in 0..args.pubs {
-        let addr = addr.clone();
+    for (i, sizes) in sent_sizes.iter().enumerate() {
+        let addr = addr.to_string();
         let ident = args.ident.clone(); // Use same ident
         let secret = args.secret.clone();
         let channel = args.channel.clone();
         let msgs = args.msgs;
         let barrier = start_barrier.clone();
         let p = payload.clone();
+        let (verify, payload_size, sent) = (args.verify, args.payload_size, sent.clone());
+        let (dist, sizes) = (args.payload_size_dist, sizes.clone());
+        let counters = counters.clone();
 
         tokio::spawn(async move {
             let mut client = match connect_and_auth(&addr, &ident, &secret).await {
                 Ok(c) => c,
                 Err(e) => {
                     eprintln!("Pub {} connect failed: {}", i, e);
+                    counters.pubs_failed.fetch_add(1, Ordering::Relaxed);
                     barrier.wait().await;
                     return;
                 }
@@ -195,19 +390,30 @@ async fn main() -> Result<()> {
                     break;
                 }
 
+                let size = dist.sample(payload_size, &mut rand::rng());
+                let payload = if verify {
+                    sequenced_payload(i as u32, count as u64, size)
+                } else {
+                    p.slice(..size)
+                };
+                sizes.lock().unwrap().record(payload.len());
+
                 // Tight loop for publishing
                 if let Err(e) = client
                     .send(Frame::Publish {
                         ident: ident.clone().into(),
                         channel: channel.clone().into(),
-                        payload: p.clone(),
+                        payload,
                     })
                     .await
                 {
                     eprintln!("Pub {} failed: {}", i, e);
+                    counters.publish_errors.fetch_add(1, Ordering::Relaxed);
                     break;
                 }
                 count += 1;
+                counters.published.fetch_add(1, Ordering::Relaxed);
+                sent[i].store(count as u64, Ordering::Relaxed);
             }
         });
     }
@@ -245,23 +451,136 @@ async fn main() -> Result<()> {
             println!("Benchmark finished after msg count.");
             break;
         }
+
+        if counters.subs_live() == 0 {
+            println!("Benchmark stopped: no subscribers left.");
+            break;
+        }
     }
 
-    let total_elapsed = start_time.elapsed();
-    let final_count = received_count.load(Ordering::Relaxed);
+    let elapsed = start_time.elapsed();
+    if args.verify && run_duration.is_some() {
+        // Let deliveries still in flight when publishing stopped arrive
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
 
-    println!("--- Benchmark Results ---");
-    println!("Total Messages Received: {}", final_count);
-    println!("Total Time: {:.2?}", total_elapsed);
-    println!(
-        "Throughput: {:.2} msg/s",
-        final_count as f64 / total_elapsed.as_secs_f64()
-    );
-    println!(
-        "Data Rate: {:.2} MB/s",
-        (final_count * args.payload_size as u64) as f64
-            / (1024.0 * 1024.0 * total_elapsed.as_secs_f64())
-    );
+    let verify = args.verify.then(|| {
+        let sent: Vec<u64> = sent.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+        let mut total = VerifyReport::default();
+        for v in &verifiers {
+            total += v.lock().unwrap().finish(&sent);
+        }
+        total
+    });
 
-    Ok(())
+    let mut sizes = SizeStats::default();
+    for s in &sent_sizes {
+        sizes.merge(&s.lock().unwrap());
+    }
+
+    Ok(Summary {
+        received: received_count.load(Ordering::Relaxed),
+        received_bytes: received_bytes.load(Ordering::Relaxed),
+        sizes,
+        elapsed,
+        verify,
+        outcomes: counters.snapshot(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hpfeeds_server::auth::MemoryAuthenticator;
+    use hpfeeds_server::metrics::Metrics;
+    use hpfeeds_server::server::{Broker, run_server};
+
+    #[tokio::test]
+    async fn verify_reports_no_loss_on_loopback() {
+        let auth = MemoryAuthenticator::new();
+        auth.add("bench", "benchsecret").await;
+        let broker = Arc::new(Broker::new(Arc::new(auth), Arc::new(Metrics::new())));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(run_server(listener, broker, None));
+
+        let args = Args::parse_from([
+            "hpfeeds-bench",
+            "--subs",
+            "2",
+            "--pubs",
+            "2",
+            "--msgs",
+            "500",
+            "--payload-size",
+            "64",
+            "--verify",
+        ]);
+        let summary = tokio::time::timeout(Duration::from_secs(30), run(&args, &addr))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(summary.received, 2 * 2 * 500);
+        assert_eq!(summary.verify, Some(VerifyReport::default()));
+        assert_eq!(summary.outcomes.published, 2 * 500);
+        assert_eq!(summary.outcomes.subs_connected, 2);
+    }
+
+    #[tokio::test]
+    async fn uniform_payload_sizes_vary_within_the_range() {
+        let auth = MemoryAuthenticator::new();
+        auth.add("bench", "benchsecret").await;
+        let broker = Arc::new(Broker::new(Arc::new(auth), Arc::new(Metrics::new())));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(run_server(listener, broker, None));
+
+        let args = Args::parse_from([
+            "hpfeeds-bench",
+            "--subs",
+            "1",
+            "--msgs",
+            "500",
+            "--payload-size-dist",
+            "uniform:100-2000",
+            "--verify",
+        ]);
+        let summary = tokio::time::timeout(Duration::from_secs(30), run(&args, &addr))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(summary.verify, Some(VerifyReport::default()));
+        let sizes = summary.sizes;
+        assert_eq!(sizes.count, 500);
+        assert!(sizes.min >= 100 && sizes.max <= 2000, "{:?}", sizes);
+        assert!(sizes.max - sizes.min > 1000, "{:?}", sizes);
+        assert_eq!(summary.received_bytes, sizes.total);
+    }
+
+    #[tokio::test]
+    async fn summary_counts_connect_failures() {
+        // nothing listens on a port we just released
+        let addr = {
+            let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            l.local_addr().unwrap().to_string()
+        };
+
+        let args = Args::parse_from(["hpfeeds-bench", "--subs", "2", "--pubs", "3"]);
+        let summary = tokio::time::timeout(Duration::from_secs(30), run(&args, &addr))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(summary.received, 0);
+        assert_eq!(
+            summary.outcomes,
+            Outcomes {
+                pubs_failed: 3,
+                subs_failed: 2,
+                ..Default::default()
+            }
+        );
+    }
 }
@@ -0,0 +1,191 @@
+use anyhow::{Context, bail};
+use rand::{Rng, RngExt};
+use std::fmt;
+use std::str::FromStr;
+
+/// Largest payload a distribution draws, well inside the broker's `MAXBUF` frame limit.
+pub const MAX_SAMPLED_SIZE: usize = hpfeeds_core::MAXBUF / 2;
+
+/// How publishers pick each payload's size, from `--payload-size-dist`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SizeDist {
+    /// Always `--payload-size`
+    Fixed,
+    /// Evenly spread between `min` and `max` bytes, inclusive
+    Uniform { min: usize, max: usize },
+    /// Log-normal around `median` bytes; `sigma` widens the tail of large payloads
+    LogNormal { median: f64, sigma: f64 },
+}
+
+impl FromStr for SizeDist {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.split_once(':') {
+            None if s == "fixed" => Ok(SizeDist::Fixed),
+            Some(("uniform", range)) => {
+                let (min, max) = range
+                    .split_once('-')
+                    .context("uniform needs a range, e.g. uniform:64-4096")?;
+                let (min, max) = (min.parse()?, max.parse()?);
+                if min > max || max > MAX_SAMPLED_SIZE {
+                    bail!(
+                        "uniform range must be ascending and at most {} bytes",
+                        MAX_SAMPLED_SIZE
+                    );
+                }
+                Ok(SizeDist::Uniform { min, max })
+            }
+            Some(("lognormal", params)) => {
+                let (median, sigma) = params
+                    .split_once(',')
+                    .context("lognormal needs a median and sigma, e.g. lognormal:512,1.0")?;
+                let (median, sigma): (f64, f64) = (median.parse()?, sigma.parse()?);
+                if !(median >= 1.0 && sigma >= 0.0 && sigma.is_finite()) {
+                    bail!("lognormal median must be at least 1 and sigma not negative");
+                }
+                Ok(SizeDist::LogNormal { median, sigma })
+            }
+            _ => bail!(
+                "unknown size distribution {:?}; expected fixed, uniform:MIN-MAX or lognormal:MEDIAN,SIGMA",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for SizeDist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SizeDist::Fixed => f.write_str("fixed"),
+            SizeDist::Uniform { min, max } => write!(f, "uniform {}-{} bytes", min, max),
+            SizeDist::LogNormal { median, sigma } => {
+                write!(f, "lognormal, median {} bytes, sigma {}", median, sigma)
+            }
+        }
+    }
+}
+
+impl SizeDist {
+    /// Draws a payload size; `fixed` is `--payload-size`.
+    pub fn sample(&self, fixed: usize, rng: &mut impl Rng) -> usize {
+        match *self {
+            SizeDist::Fixed => fixed,
+            SizeDist::Uniform { min, max } => rng.random_range(min..=max),
+            SizeDist::LogNormal { median, sigma } => {
+                // Box-Muller: a standard normal from two uniforms
+                let u1: f64 = 1.0 - rng.random::<f64>();
+                let u2: f64 = rng.random();
+                let z = (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos();
+                ((median * (sigma * z).exp()) as usize).min(MAX_SAMPLED_SIZE)
+            }
+        }
+    }
+
+    /// The largest size `sample` can return.
+    pub fn max(&self, fixed: usize) -> usize {
+        match *self {
+            SizeDist::Fixed => fixed,
+            SizeDist::Uniform { max, .. } => max,
+            SizeDist::LogNormal { .. } => MAX_SAMPLED_SIZE,
+        }
+    }
+}
+
+/// Smallest, largest and total payload sizes sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeStats {
+    pub min: usize,
+    pub max: usize,
+    pub total: u64,
+    pub count: u64,
+}
+
+impl Default for SizeStats {
+    fn default() -> Self {
+        Self {
+            min: usize::MAX,
+            max: 0,
+            total: 0,
+            count: 0,
+        }
+    }
+}
+
+impl SizeStats {
+    pub fn record(&mut self, size: usize) {
+        self.min = self.min.min(size);
+        self.max = self.max.max(size);
+        self.total += size as u64;
+        self.count += 1;
+    }
+
+    pub fn merge(&mut self, other: &SizeStats) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.total += other.total;
+        self.count += other.count;
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.total as f64 / self.count as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_distributions() {
+        assert_eq!("fixed".parse::<SizeDist>().unwrap(), SizeDist::Fixed);
+        assert_eq!(
+            "uniform:64-4096".parse::<SizeDist>().unwrap(),
+            SizeDist::Uniform { min: 64, max: 4096 }
+        );
+        assert_eq!(
+            "lognormal:512,1.5".parse::<SizeDist>().unwrap(),
+            SizeDist::LogNormal {
+                median: 512.0,
+                sigma: 1.5
+            }
+        );
+        for bad in [
+            "uniform:10",
+            "uniform:9-3",
+            "lognormal:512",
+            "lognormal:0,1",
+            "normal:5",
+        ] {
+            assert!(bad.parse::<SizeDist>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn uniform_sizes_vary_within_the_range() {
+        let dist: SizeDist = "uniform:100-200".parse().unwrap();
+        let mut rng = rand::rng();
+        let mut stats = SizeStats::default();
+        for _ in 0..1000 {
+            stats.record(dist.sample(1024, &mut rng));
+        }
+        assert!(stats.min >= 100 && stats.max <= 200, "{:?}", stats);
+        // 1000 draws from 101 values all but surely reach near both ends
+        assert!(stats.min < 120 && stats.max > 180, "{:?}", stats);
+    }
+
+    #[test]
+    fn lognormal_sizes_centre_on_the_median() {
+        let dist: SizeDist = "lognormal:1000,0.5".parse().unwrap();
+        let mut rng = rand::rng();
+        let mut sizes: Vec<usize> = (0..2001).map(|_| dist.sample(0, &mut rng)).collect();
+        sizes.sort_unstable();
+        let median = sizes[1000];
+        assert!((800..1250).contains(&median), "{}", median);
+        assert!(sizes.iter().all(|&s| s <= MAX_SAMPLED_SIZE));
+    }
+}
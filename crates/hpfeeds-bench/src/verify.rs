@@ -0,0 +1,91 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::HashMap;
+
+/// Bytes at the start of each payload taken up by the publisher id and sequence number.
+pub const HEADER_LEN: usize = 12;
+
+/// Builds a payload of at least `size` bytes carrying `publisher` and `seq`.
+pub fn sequenced_payload(publisher: u32, seq: u64, size: usize) -> Bytes {
+    let mut buf = BytesMut::with_capacity(size.max(HEADER_LEN));
+    buf.put_u32(publisher);
+    buf.put_u64(seq);
+    buf.resize(size.max(HEADER_LEN), 0);
+    buf.freeze()
+}
+
+/// Loss and ordering totals from a `--verify` run.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub lost: u64,
+    pub out_of_order: u64,
+    pub malformed: u64,
+}
+
+impl std::ops::AddAssign for VerifyReport {
+    fn add_assign(&mut self, other: Self) {
+        self.lost += other.lost;
+        self.out_of_order += other.out_of_order;
+        self.malformed += other.malformed;
+    }
+}
+
+/// Tracks the sequence numbers one subscriber has seen from each publisher.
+#[derive(Debug, Default)]
+pub struct Verifier {
+    next: HashMap<u32, u64>,
+    report: VerifyReport,
+}
+
+impl Verifier {
+    pub fn record(&mut self, payload: &[u8]) {
+        if payload.len() < HEADER_LEN {
+            self.report.malformed += 1;
+            return;
+        }
+        let publisher = u32::from_be_bytes(payload[..4].try_into().unwrap());
+        let seq = u64::from_be_bytes(payload[4..HEADER_LEN].try_into().unwrap());
+        let next = self.next.entry(publisher).or_default();
+        if seq >= *next {
+            // Anything skipped over counts as lost unless it turns up late
+            self.report.lost += seq - *next;
+            *next = seq + 1;
+        } else {
+            self.report.out_of_order += 1;
+            self.report.lost = self.report.lost.saturating_sub(1);
+        }
+    }
+
+    /// Totals so far, counting anything `sent` by a publisher but never seen as lost.
+    pub fn finish(&self, sent: &[u64]) -> VerifyReport {
+        let mut report = self.report;
+        for (publisher, &count) in sent.iter().enumerate() {
+            let seen = self.next.get(&(publisher as u32)).copied().unwrap_or(0);
+            report.lost += count.saturating_sub(seen);
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_gaps_and_reordering() {
+        let mut v = Verifier::default();
+        for seq in [0, 1, 3, 2, 5] {
+            v.record(&sequenced_payload(0, seq, 32));
+        }
+        v.record(&sequenced_payload(1, 0, 4));
+        v.record(b"short");
+        // 4 was never seen, and publisher 0 sent one more after 5
+        assert_eq!(
+            v.finish(&[7, 1]),
+            VerifyReport {
+                lost: 2,
+                out_of_order: 1,
+                malformed: 1
+            }
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use anyhow::{Context, Result, bail};
+use bytes::BytesMut;
+use clap::ValueEnum;
+use hpfeeds_core::{Frame, HpfeedsCodec};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Longest ident or channel a str8 field can carry.
+const STR8_MAX: usize = 255;
+
+/// Frames `encode` can build.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Op {
+    Publish,
+    Subscribe,
+    Unsubscribe,
+    Error,
+}
+
+/// Parses a hex string, ignoring whitespace, `:` separators and a leading `0x`.
+pub fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    let digits: Vec<u8> = s
+        .trim()
+        .trim_start_matches("0x")
+        .bytes()
+        .filter(|b| !b.is_ascii_whitespace() && *b != b':')
+        .collect();
+    if !digits.len().is_multiple_of(2) {
+        bail!("hex input has an odd number of digits");
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).unwrap_or("");
+            u8::from_str_radix(pair, 16).with_context(|| format!("invalid hex byte {:?}", pair))
+        })
+        .collect()
+}
+
+/// Builds a frame of kind `op`. Error frames carry `payload` as their message; subscribes and
+/// unsubscribes ignore it.
+pub fn build_frame(op: Op, ident: &str, channel: &str, payload: &[u8]) -> Result<Frame> {
+    for (field, value) in [("ident", ident), ("channel", channel)] {
+        if value.len() > STR8_MAX {
+            bail!(
+                "{} is {} bytes; the limit is {}",
+                field,
+                value.len(),
+                STR8_MAX
+            );
+        }
+    }
+    let (ident, channel) = (ident.to_string().into(), channel.to_string().into());
+    Ok(match op {
+        Op::Publish => Frame::Publish {
+            ident,
+            channel,
+            payload: payload.to_vec().into(),
+        },
+        Op::Subscribe => Frame::Subscribe { ident, channel },
+        Op::Unsubscribe => Frame::Unsubscribe { ident, channel },
+        Op::Error => Frame::Error(payload.to_vec().into()),
+    })
+}
+
+/// Encodes `frame` and returns the bytes as lowercase hex.
+pub fn encode_hex(frame: Frame) -> Result<String> {
+    let mut buf = BytesMut::new();
+    HpfeedsCodec::new().encode(frame, &mut buf)?;
+    Ok(buf.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Decodes every complete frame in `data`. Returns the frames and the number of trailing bytes
+/// that did not make up a whole frame.
+pub fn decode_frames(data: &[u8]) -> Result<(Vec<Frame>, usize)> {
+    let mut codec = HpfeedsCodec::new();
+    let mut buf = BytesMut::from(data);
+    let mut frames = Vec::new();
+    while let Some(frame) = codec
+        .decode(&mut buf)
+        .with_context(|| format!("frame {} is invalid", frames.len() + 1))?
+    {
+        frames.push(frame);
+    }
+    Ok((frames, buf.len()))
+}
+
+/// Prints each decoded frame on its own line, noting any incomplete tail.
+pub fn print_decoded(data: &[u8]) -> Result<()> {
+    let (frames, leftover) = decode_frames(data)?;
+    for frame in &frames {
+        println!("{}", frame);
+    }
+    if leftover > 0 {
+        eprintln!("{} trailing bytes do not form a complete frame", leftover);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_known_publish() {
+        // len=17, op=publish, ident "sensor", channel "ch", payload "hi"
+        let data = parse_hex("00000011 03 06 73656e736f72 02 6368 6869").unwrap();
+        let (frames, leftover) = decode_frames(&data).unwrap();
+        assert_eq!(leftover, 0);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(
+            frames[0].to_string(),
+            r#"publish ident="sensor" channel="ch" payload="hi""#
+        );
+    }
+
+    #[test]
+    fn encodes_like_the_codec() {
+        let frame = build_frame(Op::Publish, "sensor", "ch", b"hi").unwrap();
+        let mut expected = BytesMut::new();
+        HpfeedsCodec::new()
+            .encode(frame.clone(), &mut expected)
+            .unwrap();
+
+        let hex = encode_hex(frame.clone()).unwrap();
+        assert_eq!(parse_hex(&hex).unwrap(), expected.to_vec());
+        assert_eq!(hex, "00000011030673656e736f720263686869");
+        assert_eq!(decode_frames(&expected).unwrap().0, vec![frame]);
+    }
+
+    #[test]
+    fn rejects_over_long_str8_fields() {
+        let long = "x".repeat(256);
+        let err = build_frame(Op::Subscribe, &long, "ch", b"").unwrap_err();
+        assert_eq!(err.to_string(), "ident is 256 bytes; the limit is 255");
+        assert!(build_frame(Op::Publish, "sensor", &long, b"").is_err());
+        assert!(build_frame(Op::Publish, "sensor", &long[1..], b"").is_ok());
+    }
+
+    #[test]
+    fn reports_partial_and_invalid_input() {
+        let data = parse_hex("0x00:00:00:11:03:06").unwrap();
+        assert_eq!(decode_frames(&data).unwrap(), (Vec::new(), 6));
+        assert!(parse_hex("abc").is_err());
+        assert!(parse_hex("zz").is_err());
+        // length below the 5-byte minimum
+        assert!(decode_frames(&[0, 0, 0, 1, 3]).is_err());
+    }
+}
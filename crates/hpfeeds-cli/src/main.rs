@@ -1,11 +1,16 @@
-use anyhow::Result;
+use anyhow::{Result, bail};
 use clap::{Parser, Subcommand};
-use futures::{SinkExt, StreamExt};
-use hpfeeds_client::connect_and_auth;
-use hpfeeds_core::Frame;
+use futures::{SinkExt, Stream, StreamExt};
+use hpfeeds_client::{Transport, connect_and_auth_verbose};
+use hpfeeds_core::{Frame, MOTD_CHANNEL, SecretPolicy};
 use tokio::io::{self, AsyncReadExt};
+use tokio::net::TcpStream;
+use tokio::time::Duration;
 use tokio_rusqlite::{Connection, rusqlite};
 
+mod inspect;
+mod users;
+
 #[derive(Parser, Debug)]
 #[clap(name = "hpfeeds-cli", about = "CLI tool for hpfeeds")]
 struct Cli {
@@ -25,17 +30,28 @@ struct Cli {
     #[clap(long, short = 's', default_value = "")]
     secret: String,
 
+    /// Print the broker's name, rand length and capabilities from the handshake
+    #[clap(long, short = 'v')]
+    verbose: bool,
+
     #[clap(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
-    /// Subscribe to channels
+    /// Subscribe to channels. Exits non-zero if the connection ends before --count or
+    /// --timeout-secs is reached.
     Sub {
         /// Channels to subscribe to (space separated)
         #[clap(required = true)]
         channels: Vec<String>,
+        /// Exit after receiving this many messages
+        #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+        count: Option<u64>,
+        /// Exit after this many seconds
+        #[clap(long)]
+        timeout_secs: Option<u64>,
     },
     /// Publish data to a channel
     Pub {
@@ -46,6 +62,32 @@ enum Commands {
         /// Payload (string). If not provided, reads from stdin.
         #[clap(long, short = 'p')]
         payload: Option<String>,
+
+        /// Wait a second for the broker to reject the publish, and exit non-zero if it does
+        #[clap(long)]
+        confirm: bool,
+    },
+    /// Decode raw hpfeeds frames and print them, one per line
+    Decode {
+        /// Frame bytes as hex
+        #[clap(long, required_unless_present = "file", conflicts_with = "file")]
+        hex: Option<String>,
+        /// File of raw captured frame bytes
+        #[clap(long)]
+        file: Option<String>,
+    },
+    /// Build a frame and print it as hex
+    Encode {
+        #[clap(long, value_enum)]
+        op: inspect::Op,
+        /// Ident (defaults to --ident)
+        #[clap(long)]
+        ident: Option<String>,
+        #[clap(long, short = 'c', default_value = "")]
+        channel: String,
+        /// Payload for publish, or the message for error
+        #[clap(long, short = 'p', default_value = "")]
+        payload: String,
     },
     /// Admin commands (Direct DB access)
     Admin {
@@ -71,10 +113,136 @@ enum AdminCommands {
         #[clap(long)]
         sub_allowed: bool,
     },
-    /// List users
-    ListUsers,
+    /// List users and their permissions, sorted by ident and channel
+    ListUsers {
+        /// Print JSON in the `--config` format, secrets included, for `import`
+        #[clap(long)]
+        json: bool,
+    },
+    /// Add or replace the users in a JSON file from `list-users --json` or a broker `--config`
+    Import { file: String },
     /// Remove a user (and their permissions)
     RemoveUser { ident: String },
+    /// Report ACL entries whose ident has no user; exits non-zero if any are found
+    Validate,
+    /// Report users whose secrets are below the given strength; exits non-zero if any are found
+    CheckSecrets {
+        /// Shortest acceptable secret, in characters
+        #[clap(long, default_value_t = 16)]
+        min_len: usize,
+        /// Least acceptable estimated entropy, in bits
+        #[clap(long, default_value_t = 0.0)]
+        min_entropy: f64,
+    },
+}
+
+/// Describes the broker's OP_INFO as received during the handshake.
+fn describe_handshake<T>(conn: &hpfeeds_client::Connection<T>) -> String {
+    let caps = if conn.capabilities.is_empty() {
+        "(none)".to_string()
+    } else {
+        conn.capabilities.to_string()
+    };
+    format!(
+        "Broker: {}\nRand: {} bytes\nCapabilities: {}",
+        conn.broker_name,
+        conn.rand.len(),
+        caps
+    )
+}
+
+/// How a `sub` session ended.
+#[derive(Debug)]
+enum SubEnd {
+    /// `--count` messages arrived or `--timeout-secs` passed
+    Done,
+    /// The broker closed the connection
+    Closed,
+    Failed(std::io::Error),
+}
+
+/// Prints frames from `client` until `count` publishes have arrived, `timeout` has passed, or
+/// the connection ends.
+async fn receive<S>(client: &mut S, count: Option<u64>, timeout: Option<Duration>) -> SubEnd
+where
+    S: Stream<Item = std::io::Result<Frame>> + Unpin,
+{
+    let session = async {
+        let mut received = 0;
+        while count.is_none_or(|n| received < n) {
+            match client.next().await {
+                Some(Ok(Frame::Publish {
+                    channel, payload, ..
+                })) if channel == MOTD_CHANNEL => {
+                    // stderr, to keep it out of piped output
+                    eprintln!("Message of the day: {}", String::from_utf8_lossy(&payload));
+                }
+                Some(Ok(Frame::Publish {
+                    ident,
+                    channel,
+                    payload,
+                })) => {
+                    let data = String::from_utf8_lossy(&payload);
+                    let ident_str = String::from_utf8_lossy(&ident);
+                    let chan_str = String::from_utf8_lossy(&channel);
+                    println!("[{}] {}: {}", chan_str, ident_str, data);
+                    received += 1;
+                }
+                Some(Ok(Frame::Error(e))) => {
+                    eprintln!("Error from server: {}", String::from_utf8_lossy(&e));
+                }
+                Some(Ok(other)) => {
+                    println!("Received frame: {:?}", other);
+                }
+                Some(Err(e)) => return SubEnd::Failed(e),
+                None => return SubEnd::Closed,
+            }
+        }
+        SubEnd::Done
+    };
+    match timeout {
+        Some(t) => tokio::time::timeout(t, session)
+            .await
+            .unwrap_or(SubEnd::Done),
+        None => session.await,
+    }
+}
+
+/// How long `pub --confirm` waits for an OP_ERROR before taking the publish as accepted.
+const CONFIRM_WINDOW: Duration = Duration::from_secs(1);
+
+/// Fails if `client` answers with OP_ERROR, or the connection ends, within `window`.
+async fn confirm<S>(client: &mut S, window: Duration) -> Result<()>
+where
+    S: Stream<Item = std::io::Result<Frame>> + Unpin,
+{
+    let answer = async {
+        loop {
+            match client.next().await {
+                Some(Ok(Frame::Error(e))) => {
+                    bail!("publish rejected: {}", String::from_utf8_lossy(&e))
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => bail!("connection error: {}", e),
+                None => bail!("broker closed the connection"),
+            }
+        }
+    };
+    tokio::time::timeout(window, answer).await.unwrap_or(Ok(()))
+}
+
+async fn open(
+    addr: &str,
+    ident: &str,
+    secret: &str,
+    verbose: bool,
+) -> Result<Transport<TcpStream>> {
+    let conn = connect_and_auth_verbose(addr, ident, secret).await?;
+    if verbose {
+        println!("{}", describe_handshake(&conn));
+    }
+    println!("Connected and authenticated as {}", ident);
+    Ok(conn.transport)
 }
 
 #[tokio::main]
@@ -82,10 +250,13 @@ async fn main() -> Result<()> {
     let args = Cli::parse();
 
     match args.command {
-        Commands::Sub { channels } => {
+        Commands::Sub {
+            channels,
+            count,
+            timeout_secs,
+        } => {
             let addr = format!("{}:{}", args.host, args.port);
-            let mut client = connect_and_auth(&addr, &args.ident, &args.secret).await?;
-            println!("Connected and authenticated as {}", args.ident);
+            let mut client = open(&addr, &args.ident, &args.secret, args.verbose).await?;
             for c in channels {
                 println!("Subscribing to {}", c);
                 client
@@ -97,35 +268,19 @@ async fn main() -> Result<()> {
             }
 
             println!("Waiting for messages...");
-            while let Some(msg) = client.next().await {
-                match msg {
-                    Ok(Frame::Publish {
-                        ident,
-                        channel,
-                        payload,
-                    }) => {
-                        let data = String::from_utf8_lossy(&payload);
-                        let ident_str = String::from_utf8_lossy(&ident);
-                        let chan_str = String::from_utf8_lossy(&channel);
-                        println!("[{}] {}: {}", chan_str, ident_str, data);
-                    }
-                    Ok(Frame::Error(e)) => {
-                        eprintln!("Error from server: {}", String::from_utf8_lossy(&e));
-                    }
-                    Ok(other) => {
-                        println!("Received frame: {:?}", other);
-                    }
-                    Err(e) => {
-                        eprintln!("Connection error: {}", e);
-                        break;
-                    }
-                }
+            match receive(&mut client, count, timeout_secs.map(Duration::from_secs)).await {
+                SubEnd::Done => {}
+                SubEnd::Closed => anyhow::bail!("broker closed the connection"),
+                SubEnd::Failed(e) => anyhow::bail!("connection error: {}", e),
             }
         }
-        Commands::Pub { channel, payload } => {
+        Commands::Pub {
+            channel,
+            payload,
+            confirm: wait,
+        } => {
             let addr = format!("{}:{}", args.host, args.port);
-            let mut client = connect_and_auth(&addr, &args.ident, &args.secret).await?;
-            println!("Connected and authenticated as {}", args.ident);
+            let mut client = open(&addr, &args.ident, &args.secret, args.verbose).await?;
             let data = match payload {
                 Some(p) => p.into_bytes(),
                 None => {
@@ -143,8 +298,29 @@ async fn main() -> Result<()> {
                     payload: data.into(),
                 })
                 .await?;
+            if wait {
+                confirm(&mut client, CONFIRM_WINDOW).await?;
+            }
             println!("Done.");
         }
+        Commands::Decode { hex, file } => {
+            let data = match (hex, file) {
+                (Some(hex), _) => inspect::parse_hex(&hex)?,
+                (None, Some(path)) => tokio::fs::read(&path).await?,
+                (None, None) => unreachable!("clap requires --hex or --file"),
+            };
+            inspect::print_decoded(&data)?;
+        }
+        Commands::Encode {
+            op,
+            ident,
+            channel,
+            payload,
+        } => {
+            let ident = ident.unwrap_or(args.ident);
+            let frame = inspect::build_frame(op, &ident, &channel, payload.as_bytes())?;
+            println!("{}", inspect::encode_hex(frame)?);
+        }
         Commands::Admin { db, cmd } => {
             if !std::path::Path::new(&db).exists() {
                 anyhow::bail!(
@@ -191,37 +367,18 @@ async fn main() -> Result<()> {
                         ident_display, channel_display, pub_allowed, sub_allowed
                     );
                 }
-                AdminCommands::ListUsers => {
-                    let users = conn
-                        .call(|conn| {
-                            let mut stmt = conn.prepare("SELECT ident, secret FROM users")?;
-                            let rows = stmt.query_map([], |row| {
-                                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
-                            })?;
-                            rows.collect::<Result<Vec<_>, _>>()
-                        })
-                        .await?;
-
-                    println!("{:<20}", "IDENT");
-                    println!("{:-<20}", "");
-                    for (ident, _secret) in users {
-                        println!("{:<20}", ident);
-
-                        // fetch permissions
-                        let ident_clone = ident.clone();
-                        let perms = conn.call(move |conn| {
-                                let mut stmt = conn.prepare("SELECT channel, can_pub, can_sub FROM permissions WHERE ident = ?")?;
-                                let rows = stmt.query_map([&ident_clone], |row| {
-                                    Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?, row.get::<_, bool>(2)?))
-                                })?;
-                                rows.collect::<Result<Vec<_>, _>>()
-                            }).await?;
-
-                        for (chan, can_pub, can_sub) in perms {
-                            println!("  -> ACL: {:<15} pub={} sub={}", chan, can_pub, can_sub);
-                        }
+                AdminCommands::ListUsers { json } => {
+                    let users = users::list(&conn).await?;
+                    if json {
+                        println!("{}", serde_json::to_string_pretty(&users::to_file(users))?);
+                    } else {
+                        users::print_table(&users);
                     }
                 }
+                AdminCommands::Import { file } => {
+                    let count = users::import(&conn, users::read_file(&file)?).await?;
+                    println!("Imported {} users from {}.", count, file);
+                }
                 AdminCommands::RemoveUser { ident } => {
                     let ident_display = ident.clone();
                     let ident = ident.clone();
@@ -242,9 +399,151 @@ async fn main() -> Result<()> {
                         println!("User {} not found.", ident_display);
                     }
                 }
+                AdminCommands::Validate => {
+                    let dangling = conn
+                        .call(|conn| {
+                            let mut stmt = conn.prepare(
+                                "SELECT p.ident, p.channel FROM permissions p \
+                                 LEFT JOIN users u ON p.ident = u.ident \
+                                 WHERE u.ident IS NULL ORDER BY p.id",
+                            )?;
+                            let rows = stmt.query_map([], |row| {
+                                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                            })?;
+                            rows.collect::<Result<Vec<_>, _>>()
+                        })
+                        .await?;
+
+                    if dangling.is_empty() {
+                        println!("All ACL entries reference existing users.");
+                    } else {
+                        for (ident, channel) in &dangling {
+                            println!("ACL on {} references unknown ident {}", channel, ident);
+                        }
+                        anyhow::bail!("{} dangling ACL entries", dangling.len());
+                    }
+                }
+                AdminCommands::CheckSecrets {
+                    min_len,
+                    min_entropy,
+                } => {
+                    let policy = SecretPolicy {
+                        min_len,
+                        min_entropy_bits: min_entropy,
+                    };
+                    let users = conn
+                        .call(|conn| {
+                            let mut stmt =
+                                conn.prepare("SELECT ident, secret FROM users ORDER BY ident")?;
+                            let rows = stmt.query_map([], |row| {
+                                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                            })?;
+                            rows.collect::<Result<Vec<_>, _>>()
+                        })
+                        .await?;
+
+                    let mut weak = 0;
+                    for (ident, secret) in &users {
+                        if let Some(reason) = policy.check(secret) {
+                            println!("{}: {}", ident, reason);
+                            weak += 1;
+                        }
+                    }
+                    if weak == 0 {
+                        println!("All {} secrets meet the policy.", users.len());
+                    } else {
+                        anyhow::bail!("{} of {} secrets are too weak", weak, users.len());
+                    }
+                }
             }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hpfeeds_core::HpfeedsCodec;
+    use tokio_util::codec::Framed;
+
+    fn publish(payload: &'static str) -> std::io::Result<Frame> {
+        Ok(Frame::Publish {
+            ident: "sensor".into(),
+            channel: "ch".into(),
+            payload: payload.into(),
+        })
+    }
+
+    #[tokio::test]
+    async fn confirm_fails_on_rejection_or_close() {
+        let window = Duration::from_millis(10);
+        let mut rejected = futures::stream::iter([Ok(Frame::Error("denied".into()))]);
+        let err = confirm(&mut rejected, window).await.unwrap_err();
+        assert_eq!(err.to_string(), "publish rejected: denied");
+        let mut closed = futures::stream::iter([publish("a")]);
+        assert!(confirm(&mut closed, window).await.is_err());
+        let mut quiet = futures::stream::pending();
+        assert!(confirm(&mut quiet, window).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn sub_ends_cleanly_at_count() {
+        let mut frames = futures::stream::iter([publish("a"), publish("b"), publish("c")]);
+        assert!(matches!(
+            receive(&mut frames, Some(2), None).await,
+            SubEnd::Done
+        ));
+        assert!(frames.next().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn sub_reports_how_the_connection_ended() {
+        let mut closed = futures::stream::iter([publish("a")]);
+        assert!(matches!(
+            receive(&mut closed, Some(2), None).await,
+            SubEnd::Closed
+        ));
+        let mut failed = futures::stream::iter([
+            publish("a"),
+            Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset)),
+        ]);
+        assert!(matches!(
+            receive(&mut failed, None, None).await,
+            SubEnd::Failed(_)
+        ));
+        let mut quiet = futures::stream::pending();
+        assert!(matches!(
+            receive(&mut quiet, None, Some(Duration::from_millis(10))).await,
+            SubEnd::Done
+        ));
+    }
+
+    #[tokio::test]
+    async fn verbose_handshake_shows_broker_info() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut framed = Framed::new(socket, HpfeedsCodec::new());
+            framed
+                .send(Frame::Info {
+                    name: "test-broker caps=backlog,select".into(),
+                    rand: vec![7u8; 8].into(),
+                })
+                .await
+                .unwrap();
+            while framed.next().await.is_some() {}
+        });
+
+        let conn = connect_and_auth_verbose(&addr, "client", "secret")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            describe_handshake(&conn),
+            "Broker: test-broker\nRand: 8 bytes\nCapabilities: backlog,select"
+        );
+    }
+}
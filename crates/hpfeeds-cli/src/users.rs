@@ -0,0 +1,206 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use tokio_rusqlite::{Connection, rusqlite};
+
+/// The users table in the same shape as the broker's `--config` file, so either can be imported.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct UsersFile {
+    pub users: Vec<UserEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct UserEntry {
+    pub ident: String,
+    pub secret: String,
+    pub pub_channels: Vec<String>,
+    pub sub_channels: Vec<String>,
+}
+
+/// One user as stored, with permission rows sorted by channel.
+#[derive(Debug, PartialEq)]
+pub struct UserRecord {
+    pub ident: String,
+    pub secret: String,
+    pub acls: Vec<Acl>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Acl {
+    pub channel: String,
+    pub can_pub: bool,
+    pub can_sub: bool,
+}
+
+/// Reads every user and their permissions, ordered by ident and then channel.
+pub async fn list(conn: &Connection) -> Result<Vec<UserRecord>> {
+    let rows = conn
+        .call(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT u.ident, u.secret, p.channel, p.can_pub, p.can_sub FROM users u \
+                 LEFT JOIN permissions p ON p.ident = u.ident \
+                 ORDER BY u.ident, p.channel, p.id",
+            )?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Option<bool>>(3)?,
+                    row.get::<_, Option<bool>>(4)?,
+                ))
+            })?;
+            rows.collect::<Result<Vec<_>, _>>()
+        })
+        .await?;
+
+    let mut users: Vec<UserRecord> = Vec::new();
+    for (ident, secret, channel, can_pub, can_sub) in rows {
+        if users.last().is_none_or(|u| u.ident != ident) {
+            users.push(UserRecord {
+                ident,
+                secret,
+                acls: Vec::new(),
+            });
+        }
+        if let Some(channel) = channel {
+            users.last_mut().unwrap().acls.push(Acl {
+                channel,
+                can_pub: can_pub.unwrap_or(false),
+                can_sub: can_sub.unwrap_or(false),
+            });
+        }
+    }
+    Ok(users)
+}
+
+/// Prints `users` as the `list-users` table.
+pub fn print_table(users: &[UserRecord]) {
+    println!("{:<20}", "IDENT");
+    println!("{:-<20}", "");
+    for user in users {
+        println!("{:<20}", user.ident);
+        for acl in &user.acls {
+            println!(
+                "  -> ACL: {:<15} pub={} sub={}",
+                acl.channel, acl.can_pub, acl.can_sub
+            );
+        }
+    }
+}
+
+/// Converts `users` to the importable file form. Permission rows granting neither publish nor
+/// subscribe have no equivalent and are left out.
+pub fn to_file(users: Vec<UserRecord>) -> UsersFile {
+    let users = users
+        .into_iter()
+        .map(|u| {
+            let channels = |f: fn(&Acl) -> bool| -> Vec<String> {
+                let set: BTreeSet<&str> = u
+                    .acls
+                    .iter()
+                    .filter(|a| f(a))
+                    .map(|a| a.channel.as_str())
+                    .collect();
+                set.into_iter().map(String::from).collect()
+            };
+            UserEntry {
+                pub_channels: channels(|a| a.can_pub),
+                sub_channels: channels(|a| a.can_sub),
+                ident: u.ident,
+                secret: u.secret,
+            }
+        })
+        .collect();
+    UsersFile { users }
+}
+
+/// Parses a users file, as written by `list-users --json` or used for the broker's `--config`.
+pub fn read_file(path: &str) -> Result<UsersFile> {
+    let data = std::fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+    serde_json::from_str(&data).with_context(|| format!("invalid users file {}", path))
+}
+
+/// Adds or replaces each user in `file`, along with all of their permissions, in one
+/// transaction. Returns the number of users written.
+pub async fn import(conn: &Connection, file: UsersFile) -> Result<usize> {
+    let count = conn
+        .call(move |conn| {
+            let tx = conn.transaction()?;
+            for user in &file.users {
+                tx.execute(
+                    "INSERT OR REPLACE INTO users (ident, secret) VALUES (?, ?)",
+                    [&user.ident, &user.secret],
+                )?;
+                tx.execute("DELETE FROM permissions WHERE ident = ?", [&user.ident])?;
+                let channels: BTreeSet<&String> =
+                    user.pub_channels.iter().chain(&user.sub_channels).collect();
+                for channel in channels {
+                    tx.execute(
+                        "INSERT INTO permissions (ident, channel, can_pub, can_sub) VALUES (?, ?, ?, ?)",
+                        rusqlite::params![
+                            &user.ident,
+                            channel,
+                            user.pub_channels.contains(channel),
+                            user.sub_channels.contains(channel)
+                        ],
+                    )?;
+                }
+            }
+            tx.commit()?;
+            Ok::<usize, rusqlite::Error>(file.users.len())
+        })
+        .await?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hpfeeds_server::db::SqliteAuthenticator;
+
+    async fn open_db(name: &str) -> (Connection, std::path::PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("hpfeeds-cli-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // the broker creates the schema
+        SqliteAuthenticator::new(path.to_str().unwrap())
+            .await
+            .unwrap();
+        (Connection::open(&path).await.unwrap(), path)
+    }
+
+    #[tokio::test]
+    async fn lists_sorted_and_round_trips_through_import() {
+        let (conn, path) = open_db("list").await;
+        conn.call(|conn| {
+            conn.execute_batch(
+                "INSERT INTO users VALUES ('zeta', 's1'), ('alpha', 's2'), ('mid', 's3');
+                 INSERT INTO permissions (ident, channel, can_pub, can_sub) VALUES
+                    ('zeta', 'c.two', 1, 0), ('alpha', 'b', 0, 1), ('zeta', 'c.one', 1, 1),
+                    ('alpha', 'a', 1, 0);",
+            )?;
+            Ok::<(), rusqlite::Error>(())
+        })
+        .await
+        .unwrap();
+
+        let users = list(&conn).await.unwrap();
+        let idents: Vec<&str> = users.iter().map(|u| u.ident.as_str()).collect();
+        assert_eq!(idents, ["alpha", "mid", "zeta"]);
+        let zeta: Vec<&str> = users[2].acls.iter().map(|a| a.channel.as_str()).collect();
+        assert_eq!(zeta, ["c.one", "c.two"]);
+        assert!(users[1].acls.is_empty());
+
+        let json = serde_json::to_string_pretty(&to_file(users)).unwrap();
+        let (copy, copy_path) = open_db("import").await;
+        let imported = import(&copy, serde_json::from_str(&json).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(imported, 3);
+        assert_eq!(list(&copy).await.unwrap(), list(&conn).await.unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&copy_path).unwrap();
+    }
+}
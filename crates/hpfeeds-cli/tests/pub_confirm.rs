@@ -0,0 +1,55 @@
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::process::{Command, Output};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+// A broker reporting denied publishes, where `sensor` may publish only to `allowed`.
+async fn broker() -> String {
+    let auth = MemoryAuthenticator::new();
+    auth.add_user("sensor", "s3cret", vec!["allowed".into()], vec![])
+        .await;
+    let broker = Broker::with_options(
+        Arc::new(auth),
+        Arc::new(Metrics::new()),
+        BrokerOptions {
+            report_denied: true,
+            ..Default::default()
+        },
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port().to_string();
+    tokio::spawn(run_server(listener, Arc::new(broker), None));
+    port
+}
+
+async fn publish(port: String, channel: &'static str) -> Output {
+    tokio::task::spawn_blocking(move || {
+        Command::new(env!("CARGO_BIN_EXE_hpfeeds-cli"))
+            .args(["--port", &port, "-i", "sensor", "-s", "s3cret"])
+            .args(["pub", "-c", channel, "-p", "hello", "--confirm"])
+            .output()
+            .unwrap()
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn confirmed_publish_to_denied_channel_fails() {
+    let port = broker().await;
+
+    let out = publish(port.clone(), "forbidden").await;
+    assert!(!out.status.success(), "{:?}", out);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains("publish rejected: not authorized to publish to forbidden"),
+        "{}",
+        stderr
+    );
+
+    let out = publish(port, "allowed").await;
+    assert!(out.status.success(), "{:?}", out);
+    assert!(String::from_utf8_lossy(&out.stdout).contains("Done."));
+}
@@ -0,0 +1,72 @@
+use futures::{SinkExt, StreamExt};
+use hpfeeds_core::{Frame, HpfeedsCodec};
+use std::process::{Command, Output};
+use tokio::net::TcpListener;
+use tokio_util::codec::Framed;
+
+// A broker that greets one client, waits for its OP_AUTH and `subscribes` subscribes, sends
+// `publishes`, then hangs up.
+async fn broker(subscribes: usize, publishes: usize) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut framed = Framed::new(socket, HpfeedsCodec::new());
+        framed
+            .send(Frame::Info {
+                name: "fake".into(),
+                rand: vec![0u8; 4].into(),
+            })
+            .await
+            .unwrap();
+        for _ in 0..=subscribes {
+            framed.next().await.unwrap().unwrap();
+        }
+        for i in 0..publishes {
+            framed
+                .send(Frame::Publish {
+                    ident: "sensor".into(),
+                    channel: "ch".into(),
+                    payload: i.to_string().into(),
+                })
+                .await
+                .unwrap();
+        }
+    });
+    addr.port().to_string()
+}
+
+async fn sub(port: String, extra: &'static [&'static str]) -> Output {
+    tokio::task::spawn_blocking(move || {
+        Command::new(env!("CARGO_BIN_EXE_hpfeeds-cli"))
+            .args(["--port", &port, "sub", "ch"])
+            .args(extra)
+            .output()
+            .unwrap()
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn abrupt_disconnect_exits_non_zero() {
+    let out = sub(broker(1, 1).await, &[]).await;
+    assert!(!out.status.success(), "{:?}", out);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains("broker closed the connection"),
+        "{}",
+        stderr
+    );
+
+    // also when it hangs up before --count is reached
+    let out = sub(broker(1, 1).await, &["--count", "2"]).await;
+    assert!(!out.status.success(), "{:?}", out);
+}
+
+#[tokio::test]
+async fn reaching_count_exits_zero() {
+    let out = sub(broker(1, 3).await, &["--count", "3"]).await;
+    assert!(out.status.success(), "{:?}", out);
+    assert!(String::from_utf8_lossy(&out.stdout).contains("[ch] sensor: 2"));
+}
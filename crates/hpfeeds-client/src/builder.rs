@@ -0,0 +1,237 @@
+use crate::{ClientError, HANDSHAKE_TIMEOUT, ReconnectingClient, Result, Transport, handshake};
+use hpfeeds_core::{HpfeedsCodec, hashsecret};
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, RootCertStore};
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+use tokio_util::codec::Framed;
+
+// What a TLS connection verifies the broker's certificate against.
+#[derive(Clone)]
+enum Trust {
+    /// A single DER certificate, the broker's own or its CA's
+    Cert(Vec<u8>),
+    /// The Mozilla root set bundled from `webpki-roots`
+    WebPki,
+}
+
+/// Everything needed to connect to a broker, set in one place. Plaintext with the default
+/// handshake timeout unless configured otherwise:
+///
+/// ```no_run
+/// # async fn example(ca: Vec<u8>) -> hpfeeds_client::Result<()> {
+/// use hpfeeds_client::ClientBuilder;
+/// use std::time::Duration;
+///
+/// let transport = ClientBuilder::new("10.0.0.5:10000", "ident", "secret")
+///     .tls(ca)
+///     .server_name("hpfeeds.example.org")
+///     .handshake_timeout(Duration::from_secs(5))
+///     .connect()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ClientBuilder {
+    addr: String,
+    ident: String,
+    secret: String,
+    trust: Option<Trust>,
+    server_name: Option<String>,
+    handshake_timeout: Duration,
+}
+
+impl ClientBuilder {
+    /// Connects to `addr` and authenticates as `ident` with `secret`.
+    pub fn new(addr: &str, ident: &str, secret: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+            ident: ident.to_string(),
+            secret: secret.to_string(),
+            trust: None,
+            server_name: None,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    /// Connects over TLS, trusting `root_cert`, the DER certificate of the broker or its CA.
+    pub fn tls(mut self, root_cert: impl Into<Vec<u8>>) -> Self {
+        self.trust = Some(Trust::Cert(root_cert.into()));
+        self
+    }
+
+    /// Connects over TLS, trusting the Mozilla root set bundled from `webpki-roots`, for
+    /// brokers with publicly issued certificates.
+    pub fn tls_webpki(mut self) -> Self {
+        self.trust = Some(Trust::WebPki);
+        self
+    }
+
+    /// The name the broker's TLS certificate must be valid for, also sent as SNI. Defaults to
+    /// the host part of `addr`.
+    pub fn server_name(mut self, server_name: &str) -> Self {
+        self.server_name = Some(server_name.to_string());
+        self
+    }
+
+    /// How long the broker has to send OP_INFO once connected. Defaults to
+    /// [`HANDSHAKE_TIMEOUT`].
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Makes `connect` return a [`ReconnectingClient`], which waits `base` after its first
+    /// failed attempt to reconnect and doubles that up to `max`. Set everything else first.
+    pub fn reconnect(self, base: Duration, max: Duration) -> ReconnectBuilder {
+        ReconnectBuilder {
+            builder: self,
+            base,
+            max: max.max(base),
+        }
+    }
+
+    /// Connects, over TLS if configured, and performs the handshake.
+    pub async fn connect(&self) -> Result<Transport<MaybeTlsStream>> {
+        let stream = match self.trust {
+            None => MaybeTlsStream::Plain(self.open().await?),
+            Some(_) => MaybeTlsStream::Tls(Box::new(self.open_tls().await?)),
+        };
+        self.authenticate(stream).await
+    }
+
+    pub(crate) fn ident(&self) -> &str {
+        &self.ident
+    }
+
+    // Opens the TCP connection.
+    pub(crate) async fn open(&self) -> Result<TcpStream> {
+        TcpStream::connect(&self.addr)
+            .await
+            .map_err(ClientError::Connect)
+    }
+
+    // The configured server name, or else the host `addr` names, without its port or an IPv6
+    // address's brackets.
+    pub(crate) fn host(&self) -> &str {
+        self.server_name.as_deref().unwrap_or_else(|| {
+            self.addr
+                .rsplit_once(':')
+                .map_or(self.addr.as_str(), |(host, _)| host)
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+        })
+    }
+
+    // Opens the TCP connection and verifies the broker as `host` over it.
+    pub(crate) async fn open_tls(&self) -> Result<TlsStream<TcpStream>> {
+        let roots = match &self.trust {
+            Some(Trust::Cert(der)) => {
+                let mut roots = RootCertStore::empty();
+                roots
+                    .add(CertificateDer::from(der.clone()))
+                    .map_err(|e| ClientError::Tls(format!("invalid root cert: {}", e)))?;
+                roots
+            }
+            Some(Trust::WebPki) => RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            },
+            None => return Err(ClientError::Tls("no root certificate configured".into())),
+        };
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+        let server_name = ServerName::try_from(self.host())
+            .map_err(|e| ClientError::Tls(e.to_string()))?
+            .to_owned();
+        connector
+            .connect(server_name, self.open().await?)
+            .await
+            .map_err(|e| ClientError::Tls(e.to_string()))
+    }
+
+    // Performs the handshake over an already open `stream`.
+    pub(crate) async fn authenticate<T>(&self, stream: T) -> Result<Transport<T>>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let framed = Framed::new(stream, HpfeedsCodec::new());
+        let auth_hash = |rand: &[u8]| hashsecret(rand, &self.secret);
+        Ok(
+            handshake(framed, &self.ident, auth_hash, self.handshake_timeout)
+                .await?
+                .transport,
+        )
+    }
+}
+
+/// A [`ClientBuilder`] set to reconnect, from [`ClientBuilder::reconnect`].
+#[derive(Clone)]
+pub struct ReconnectBuilder {
+    builder: ClientBuilder,
+    base: Duration,
+    max: Duration,
+}
+
+impl ReconnectBuilder {
+    /// Connects and authenticates once; errors here are returned rather than retried.
+    pub async fn connect(&self) -> Result<ReconnectingClient> {
+        ReconnectingClient::from_builder(self.builder.clone(), self.base, self.max).await
+    }
+}
+
+/// The connection under a [`ClientBuilder`]'s transport, with or without TLS.
+#[derive(Debug)]
+pub enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
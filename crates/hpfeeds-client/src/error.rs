@@ -0,0 +1,43 @@
+use hpfeeds_core::Frame;
+use std::io;
+use std::time::Duration;
+
+/// How long the broker has to send OP_INFO once connected.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Frames other than OP_INFO, such as a banner, skipped at the start of a session before
+/// giving up with [`ClientError::UnexpectedFrame`].
+pub const MAX_BANNER_FRAMES: usize = 3;
+
+/// Why a client operation failed. Converts into `anyhow::Error` for binaries.
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    /// The TCP connection could not be opened
+    #[error("connecting to broker: {0}")]
+    Connect(#[source] io::Error),
+    /// The TLS configuration was invalid or the TLS handshake failed
+    #[error("TLS: {0}")]
+    Tls(String),
+    /// The broker did not send OP_INFO within [`HANDSHAKE_TIMEOUT`], or the timeout set with
+    /// [`ClientBuilder::handshake_timeout`](crate::ClientBuilder::handshake_timeout)
+    #[error("timed out waiting for OP_INFO")]
+    HandshakeTimeout,
+    /// The broker sent a frame that does not fit the protocol at this point, such as more than
+    /// [`MAX_BANNER_FRAMES`] frames before OP_INFO
+    #[error("unexpected frame: {0}")]
+    UnexpectedFrame(Box<Frame>),
+    /// The broker sent OP_ERROR before OP_INFO, e.g. refusing the connection
+    #[error("broker sent OP_ERROR during the handshake: {0}")]
+    Auth(String),
+    /// The broker sent OP_ERROR on an established session, e.g. for an invalid channel
+    #[error("broker error: {0}")]
+    Broker(String),
+    /// A publish or subscribe named an empty channel, and was not sent
+    #[error("channel name is empty")]
+    EmptyChannel,
+    /// Reading or writing frames failed, or the broker closed the connection mid-handshake
+    #[error("protocol: {0}")]
+    Protocol(#[from] io::Error),
+}
+
+pub type Result<T, E = ClientError> = std::result::Result<T, E>;
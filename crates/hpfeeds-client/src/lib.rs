@@ -1,83 +1,719 @@
-use anyhow::{Result, anyhow};
+use bytes::Bytes;
 use futures::SinkExt;
-use futures::StreamExt;
-use hpfeeds_core::{Frame, HpfeedsCodec, hashsecret};
+use futures::{Stream, StreamExt};
+use hpfeeds_core::{
+    CAP_BACKLOG, CAP_PAUSE, CAP_SELECT, CAP_SHARE, CAP_SUBACK, Capabilities, Frame, HpfeedsCodec,
+    SUBACK_CHANNEL, SubscriptionControl, hashsecret, with_control, with_share_group,
+};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 use tokio_util::codec::Framed;
 
-use rustls::pki_types::{CertificateDer, ServerName};
-use rustls::{ClientConfig, RootCertStore};
-use std::sync::Arc;
-use tokio_rustls::TlsConnector;
+mod builder;
+pub use builder::{ClientBuilder, MaybeTlsStream, ReconnectBuilder};
+mod error;
+pub use error::{ClientError, HANDSHAKE_TIMEOUT, MAX_BANNER_FRAMES, Result};
+mod reconnect;
+pub use reconnect::{DEFAULT_RECONNECT_BASE, DEFAULT_RECONNECT_MAX, ReconnectingClient};
 
 pub type Transport<T> = Framed<T, HpfeedsCodec>;
 
+/// Name sent in this client's capability-selection OP_INFO.
+pub const CLIENT_NAME: &str = "hpfeeds-rs-client";
+
+/// Capabilities implemented by this client: selecting them with
+/// [`connect_and_auth_selecting`], and the subscription features [`Subscriber`] drives.
+pub fn client_capabilities() -> Capabilities {
+    Capabilities::new([CAP_SELECT, CAP_BACKLOG, CAP_PAUSE, CAP_SHARE, CAP_SUBACK])
+}
+
+/// Parses a broker's OP_INFO name and returns the capabilities both sides support.
+pub fn negotiate_capabilities(info_name: &[u8]) -> Capabilities {
+    let (_, broker_caps) = Capabilities::parse_info_name(info_name);
+    broker_caps.negotiate(&client_capabilities())
+}
+
 /// Connects to `addr` and returns a framed transport using the hpfeeds codec.
 pub async fn connect(addr: &str) -> Result<Transport<TcpStream>> {
-    let stream = TcpStream::connect(addr).await?;
+    let stream = TcpStream::connect(addr)
+        .await
+        .map_err(ClientError::Connect)?;
     let framed = Framed::new(stream, HpfeedsCodec::new());
     Ok(framed)
 }
 
+// Waits up to `timeout` for the broker's OP_INFO and returns its name and rand. Up to
+// MAX_BANNER_FRAMES other frames ahead of it are skipped, as some brokers send a banner; an
+// OP_ERROR is never skipped.
+async fn read_info<T>(framed: &mut Transport<T>, timeout: Duration) -> Result<(Bytes, Bytes)>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut skipped = 0;
+    loop {
+        match tokio::time::timeout_at(deadline, framed.next()).await {
+            Err(_) => return Err(ClientError::HandshakeTimeout),
+            Ok(Some(Ok(Frame::Info { name, rand }))) => return Ok((name, rand)),
+            Ok(Some(Ok(Frame::Error(msg)))) => {
+                return Err(ClientError::Auth(
+                    String::from_utf8_lossy(&msg).into_owned(),
+                ));
+            }
+            Ok(Some(Ok(_))) if skipped < MAX_BANNER_FRAMES => skipped += 1,
+            Ok(Some(Ok(frame))) => return Err(ClientError::UnexpectedFrame(Box::new(frame))),
+            Ok(Some(Err(e))) => return Err(e.into()),
+            Ok(None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "broker closed the connection before OP_INFO",
+                )
+                .into());
+            }
+        }
+    }
+}
+
 /// Connects and performs the hpfeeds handshake: reads OP_INFO and sends OP_AUTH.
 pub async fn connect_and_auth(
     addr: &str,
     ident: &str,
     secret: &str,
 ) -> Result<Transport<TcpStream>> {
+    let builder = ClientBuilder::new(addr, ident, secret);
+    builder.authenticate(builder.open().await?).await
+}
+
+/// Performs the handshake over `stream`, already connected by the caller, e.g. through a TLS
+/// setup of its own.
+pub async fn auth_stream<T>(stream: T, ident: &str, secret: &str) -> Result<Transport<T>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let framed = Framed::new(stream, HpfeedsCodec::new());
+    let auth_hash = |rand: &[u8]| hashsecret(rand, secret);
+    Ok(handshake(framed, ident, auth_hash, HANDSHAKE_TIMEOUT)
+        .await?
+        .transport)
+}
+
+/// Like `connect_and_auth`, but `auth_hash` computes the OP_AUTH hash from the broker's rand,
+/// so the secret can stay in an HSM or keyring. It must return `SHA1(rand || secret)`.
+pub async fn connect_and_auth_with<F>(
+    addr: &str,
+    ident: &str,
+    auth_hash: F,
+) -> Result<Transport<TcpStream>>
+where
+    F: Fn(&[u8]) -> Vec<u8>,
+{
+    Ok(
+        handshake(connect(addr).await?, ident, auth_hash, HANDSHAKE_TIMEOUT)
+            .await?
+            .transport,
+    )
+}
+
+/// An authenticated transport along with what the broker announced in its OP_INFO.
+pub struct Connection<T> {
+    pub transport: Transport<T>,
+    /// Broker name, without any capability token
+    pub broker_name: String,
+    /// The nonce the secret was hashed with
+    pub rand: Bytes,
+    /// Capabilities the broker advertised, whether or not this client supports them
+    pub capabilities: Capabilities,
+}
+
+/// Like `connect_and_auth`, but also returns the broker's OP_INFO, e.g. for troubleshooting
+/// capability negotiation.
+pub async fn connect_and_auth_verbose(
+    addr: &str,
+    ident: &str,
+    secret: &str,
+) -> Result<Connection<TcpStream>> {
+    let auth_hash = |rand: &[u8]| hashsecret(rand, secret);
+    handshake(connect(addr).await?, ident, auth_hash, HANDSHAKE_TIMEOUT).await
+}
+
+// Reads OP_INFO, allowing it `timeout`, and answers with OP_AUTH.
+async fn handshake<T, F>(
+    mut framed: Transport<T>,
+    ident: &str,
+    auth_hash: F,
+    timeout: Duration,
+) -> Result<Connection<T>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    F: Fn(&[u8]) -> Vec<u8>,
+{
+    let (name, rand) = read_info(&mut framed, timeout).await?;
+    framed
+        .send(Frame::Auth {
+            ident: ident.to_string().into(),
+            secret_hash: auth_hash(&rand).into(),
+        })
+        .await?;
+    let (broker_name, capabilities) = Capabilities::parse_info_name(&name);
+    Ok(Connection {
+        transport: framed,
+        broker_name,
+        rand,
+        capabilities,
+    })
+}
+
+/// Connects and authenticates like `connect_and_auth`, first telling the broker which of
+/// `wanted` to enable if it accepts a selection. Returns the transport and the capabilities
+/// both sides support.
+pub async fn connect_and_auth_selecting(
+    addr: &str,
+    ident: &str,
+    secret: &str,
+    wanted: &Capabilities,
+) -> Result<(Transport<TcpStream>, Capabilities)> {
     let mut framed = connect(addr).await?;
 
-    // read OP_INFO
-    if let Some(Ok(Frame::Info { name: _, rand })) = framed.next().await {
-        let sh = hashsecret(&rand, secret);
+    let (name, rand) = read_info(&mut framed, HANDSHAKE_TIMEOUT).await?;
+    let (_, broker_caps) = Capabilities::parse_info_name(&name);
+    let agreed = broker_caps.negotiate(wanted);
+    if broker_caps.contains(CAP_SELECT) {
         framed
-            .send(Frame::Auth {
-                ident: ident.to_string().into(),
-                secret_hash: sh.into(),
+            .send(Frame::Info {
+                name: agreed.info_name(CLIENT_NAME).into(),
+                rand: Default::default(),
+            })
+            .await?;
+    }
+    framed
+        .send(Frame::Auth {
+            ident: ident.to_string().into(),
+            secret_hash: hashsecret(&rand, secret).into(),
+        })
+        .await?;
+    Ok((framed, agreed))
+}
+
+// Refuses an empty channel name before anything is sent; brokers reject them anyway.
+pub(crate) fn check_channel(channel: &str) -> Result<()> {
+    if channel.is_empty() {
+        return Err(ClientError::EmptyChannel);
+    }
+    Ok(())
+}
+
+/// A publish delivered to a subscriber.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublishMessage {
+    pub ident: Bytes,
+    pub channel: Bytes,
+    pub payload: Bytes,
+}
+
+/// Subscribes an authenticated transport to channels and yields what is published on them.
+///
+/// An alias map lets a channel be presented under a local name: the broker is sent the
+/// upstream name, and deliveries on it report the alias as their channel, so downstream code
+/// keeps working when the broker side is renamed.
+pub struct Subscriber<T> {
+    transport: Transport<T>,
+    ident: String,
+    aliases: HashMap<Bytes, Bytes>,
+    // Publishes read by `wait_for_error`, yielded before anything new
+    pending: VecDeque<PublishMessage>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Subscriber<T> {
+    pub fn new(transport: Transport<T>, ident: &str) -> Self {
+        Self {
+            transport,
+            ident: ident.to_string(),
+            aliases: HashMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Reports deliveries on each upstream channel (the key) under its alias (the value).
+    pub fn with_aliases<K, V>(mut self, aliases: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.aliases.extend(
+            aliases
+                .into_iter()
+                .map(|(k, v)| (Bytes::from(k.into()), Bytes::from(v.into()))),
+        );
+        self
+    }
+
+    /// Subscribes to the upstream channel `channel`.
+    pub async fn subscribe(&mut self, channel: &str) -> Result<()> {
+        check_channel(channel)?;
+        self.transport
+            .send(Frame::Subscribe {
+                ident: self.ident.clone().into(),
+                channel: channel.to_string().into(),
             })
             .await?;
-        Ok(framed)
-    } else {
-        Err(anyhow!("Expected OP_INFO from server"))
+        Ok(())
+    }
+
+    /// Joins share group `group` on the upstream channel `channel`, so that each publish on it
+    /// is delivered to only one of the group's subscribers, in turn. The connection must have
+    /// selected `CAP_SHARE`; otherwise the broker takes it as a subscribe to a channel literally
+    /// named `channel;share=group`.
+    pub async fn subscribe_shared(&mut self, channel: &str, group: &str) -> Result<()> {
+        check_channel(channel)?;
+        self.subscribe(&with_share_group(channel, group)).await
+    }
+
+    /// Unsubscribes from the upstream channel `channel`. Our broker also ends a share-group
+    /// membership on `channel` this way.
+    pub async fn unsubscribe(&mut self, channel: &str) -> Result<()> {
+        check_channel(channel)?;
+        self.transport
+            .send(Frame::Unsubscribe {
+                ident: self.ident.clone().into(),
+                channel: channel.to_string().into(),
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Leaves share group `group` on the upstream channel `channel`, joined with
+    /// [`subscribe_shared`](Self::subscribe_shared).
+    pub async fn unsubscribe_shared(&mut self, channel: &str, group: &str) -> Result<()> {
+        check_channel(channel)?;
+        self.unsubscribe(&with_share_group(channel, group)).await
+    }
+
+    /// Subscribes to the upstream channel `channel` and waits up to `timeout` for the broker's
+    /// answer. Returns true once the broker confirms the subscribe, and false if `timeout`
+    /// passes without an answer, which is all brokers that don't confirm subscribes give. A
+    /// denied subscribe is [`ClientError::Broker`]. Publishes arriving in the meantime are kept
+    /// and yielded by the stream as usual.
+    ///
+    /// Our broker confirms subscribes, and reports denied ones, on connections that selected
+    /// `CAP_SUBACK`, e.g. with `connect_and_auth_selecting`. Otherwise a denied subscribe is
+    /// ignored, and looks just like an idle channel.
+    pub async fn subscribe_confirmed(&mut self, channel: &str, timeout: Duration) -> Result<bool> {
+        self.subscribe(channel).await?;
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            match self.next_reply(deadline).await {
+                None => return Ok(false),
+                Some(Err(e)) => return Err(e),
+                Some(Ok(Frame::Publish { payload, .. })) if payload == channel => return Ok(true),
+                Some(Ok(Frame::Error(msg))) => {
+                    return Err(ClientError::Broker(
+                        String::from_utf8_lossy(&msg).into_owned(),
+                    ));
+                }
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+
+    /// Waits up to `window` for the broker to answer with OP_ERROR, e.g. after a subscribe to a
+    /// channel name it may refuse. Publishes arriving in the meantime are kept and yielded by the
+    /// stream as usual. Returns None if the window passes without an error; a failed or closed
+    /// connection is returned as an error.
+    ///
+    /// Brokers that ignore denied operations silently, as ours does for ACLs unless started with
+    /// `--report-denied`, also return None.
+    pub async fn wait_for_error(&mut self, window: Duration) -> Option<ClientError> {
+        let deadline = tokio::time::Instant::now() + window;
+        loop {
+            match self.next_reply(deadline).await? {
+                Err(e) => return Some(e),
+                Ok(Frame::Error(msg)) => {
+                    return Some(ClientError::Broker(
+                        String::from_utf8_lossy(&msg).into_owned(),
+                    ));
+                }
+                Ok(_) => {}
+            }
+        }
+    }
+
+    // Reads frames until `deadline`, keeping publishes for the stream, and returns the first
+    // other frame, counting subscribe confirmations as other frames. None once the deadline
+    // passes; a failed or closed connection is an error.
+    async fn next_reply(&mut self, deadline: tokio::time::Instant) -> Option<Result<Frame>> {
+        loop {
+            let frame = match tokio::time::timeout_at(deadline, self.transport.next()).await {
+                Err(_) => return None,
+                Ok(Some(Ok(frame))) => frame,
+                Ok(Some(Err(e))) => return Some(Err(e.into())),
+                Ok(None) => {
+                    return Some(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "broker closed the connection",
+                    )
+                    .into()));
+                }
+            };
+            match frame {
+                Frame::Publish {
+                    ident,
+                    channel,
+                    payload,
+                } if channel != SUBACK_CHANNEL => {
+                    let msg = self.delivery(ident, channel, payload);
+                    self.pending.push_back(msg);
+                }
+                frame => return Some(Ok(frame)),
+            }
+        }
+    }
+
+    // A publish as reported to the caller, under its channel's alias if it has one.
+    fn delivery(&self, ident: Bytes, channel: Bytes, payload: Bytes) -> PublishMessage {
+        let channel = self.aliases.get(&channel).cloned().unwrap_or(channel);
+        PublishMessage {
+            ident,
+            channel,
+            payload,
+        }
+    }
+
+    /// Asks the broker to hold deliveries on the upstream channel `channel` until
+    /// [`resume`](Self::resume). The connection must have selected `CAP_PAUSE`, e.g. with
+    /// `connect_and_auth_selecting`; otherwise the broker takes it as a subscribe to a channel
+    /// literally named `channel;pause`. To stop reading only briefly, simply stop polling.
+    pub async fn pause(&mut self, channel: &str) -> Result<()> {
+        check_channel(channel)?;
+        self.subscribe(&with_control(channel, SubscriptionControl::Pause))
+            .await
+    }
+
+    /// Releases deliveries held by [`pause`](Self::pause), oldest first.
+    pub async fn resume(&mut self, channel: &str) -> Result<()> {
+        check_channel(channel)?;
+        self.subscribe(&with_control(channel, SubscriptionControl::Resume))
+            .await
+    }
+
+    /// Returns the transport, e.g. to publish on the same connection.
+    pub fn into_inner(self) -> Transport<T> {
+        self.transport
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Stream for Subscriber<T> {
+    type Item = Result<PublishMessage>;
+
+    /// Yields publishes and broker errors; other frames, and subscribe confirmations, are skipped.
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(msg) = self.pending.pop_front() {
+            return Poll::Ready(Some(Ok(msg)));
+        }
+        loop {
+            let frame = match self.transport.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(frame))) => frame,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e.into()))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            match frame {
+                Frame::Publish {
+                    ident,
+                    channel,
+                    payload,
+                } if channel != SUBACK_CHANNEL => {
+                    return Poll::Ready(Some(Ok(self.delivery(ident, channel, payload))));
+                }
+                Frame::Error(msg) => {
+                    return Poll::Ready(Some(Err(ClientError::Broker(
+                        String::from_utf8_lossy(&msg).into_owned(),
+                    ))));
+                }
+                _ => continue,
+            }
+        }
     }
 }
 
 /// Connects using TLS to `addr` and performs the handshake. `root_cert` should be DER-formatted certificate bytes of the CA/server to trust.
+/// The broker's certificate must be valid for "localhost", whatever `addr` is; see
+/// `connect_tls_and_auth_named` for any other broker.
 pub async fn connect_tls_and_auth(
     addr: &str,
     ident: &str,
     secret: &str,
     root_cert: &[u8],
 ) -> Result<Transport<tokio_rustls::client::TlsStream<TcpStream>>> {
-    // Build rustls client config with provided root
-    let mut roots = RootCertStore::empty();
-    let cert = CertificateDer::from(root_cert.to_vec());
-    roots.add(cert).map_err(|_| anyhow!("invalid root cert"))?;
-    let config = ClientConfig::builder()
-        .with_root_certificates(roots)
-        .with_no_client_auth();
-    let connector = TlsConnector::from(Arc::new(config));
-
-    let stream = TcpStream::connect(addr).await?;
-    // For tests, we expect the server name to be "localhost"; parse into ServerName
-    let server_name = ServerName::try_from("localhost")
-        .map_err(|_| anyhow!("invalid dnsname"))?
-        .to_owned();
-    let tls_stream = connector.connect(server_name, stream).await?;
-
-    let mut framed = Framed::new(tls_stream, HpfeedsCodec::new());
-
-    // read OP_INFO
-    if let Some(Ok(Frame::Info { name: _, rand })) = framed.next().await {
-        let sh = hashsecret(&rand, secret);
-        framed
-            .send(Frame::Auth {
-                ident: ident.to_string().into(),
-                secret_hash: sh.into(),
-            })
-            .await?;
-        Ok(framed)
-    } else {
-        Err(anyhow!("Expected OP_INFO from server"))
+    connect_tls_and_auth_named(addr, "localhost", ident, secret, root_cert).await
+}
+
+/// Like `connect_tls_and_auth`, but the broker's certificate must be valid for `server_name`,
+/// which is also sent as SNI, e.g. `hpfeeds.example.org` when connecting by IP address.
+pub async fn connect_tls_and_auth_named(
+    addr: &str,
+    server_name: &str,
+    ident: &str,
+    secret: &str,
+    root_cert: &[u8],
+) -> Result<Transport<tokio_rustls::client::TlsStream<TcpStream>>> {
+    let builder = ClientBuilder::new(addr, ident, secret)
+        .tls(root_cert)
+        .server_name(server_name);
+    builder.authenticate(builder.open_tls().await?).await
+}
+
+/// Like `connect_tls_and_auth_named`, but trusts the Mozilla root set bundled from
+/// `webpki-roots` instead of a given certificate, for brokers with publicly issued certificates.
+pub async fn connect_tls_and_auth_webpki(
+    addr: &str,
+    server_name: &str,
+    ident: &str,
+    secret: &str,
+) -> Result<Transport<tokio_rustls::client::TlsStream<TcpStream>>> {
+    let builder = ClientBuilder::new(addr, ident, secret)
+        .tls_webpki()
+        .server_name(server_name);
+    builder.authenticate(builder.open_tls().await?).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    // A broker that sends `frames` and then holds the connection open.
+    async fn broker(frames: Vec<Frame>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut framed = Framed::new(socket, HpfeedsCodec::new());
+            for frame in frames {
+                framed.send(frame).await.unwrap();
+            }
+            while framed.next().await.is_some() {}
+        });
+        addr
+    }
+
+    #[test]
+    fn negotiates_what_both_sides_support() {
+        let agreed = negotiate_capabilities(b"hpfeeds-rs/0.3 caps=seq,select,pause,share,suback");
+        assert_eq!(agreed.to_string(), "pause,select,share,suback");
+        assert!(negotiate_capabilities(b"hpfeeds").is_empty());
+    }
+
+    #[tokio::test]
+    async fn refused_connection_is_connect_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let err = connect_and_auth(&addr, "i", "s").await.unwrap_err();
+        assert!(matches!(err, ClientError::Connect(_)), "{:?}", err);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_broker_is_handshake_timeout() {
+        let addr = broker(vec![]).await;
+
+        let err = connect_and_auth(&addr, "i", "s").await.unwrap_err();
+        assert!(matches!(err, ClientError::HandshakeTimeout), "{:?}", err);
+    }
+
+    fn banner(n: usize) -> Frame {
+        Frame::Publish {
+            ident: "b".into(),
+            channel: "banner".into(),
+            payload: n.to_string().into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn builder_connects_in_plaintext() {
+        let addr = broker(vec![Frame::Info {
+            name: "b".into(),
+            rand: "1234".into(),
+        }])
+        .await;
+
+        let transport = ClientBuilder::new(&addr, "i", "s").connect().await.unwrap();
+        assert!(matches!(transport.get_ref(), MaybeTlsStream::Plain(_)));
+    }
+
+    #[test]
+    fn builder_server_name_defaults_to_the_host() {
+        let builder = |addr| ClientBuilder::new(addr, "i", "s").tls_webpki();
+        assert_eq!(
+            builder("hpfeeds.example.org:10000").host(),
+            "hpfeeds.example.org"
+        );
+        assert_eq!(builder("[::1]:10000").host(), "::1");
+        assert_eq!(
+            builder("10.0.0.5:10000")
+                .server_name("hpfeeds.example.org")
+                .host(),
+            "hpfeeds.example.org"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn builder_handshake_timeout_is_applied() {
+        let addr = broker(vec![]).await;
+        let started = tokio::time::Instant::now();
+
+        let err = ClientBuilder::new(&addr, "i", "s")
+            .handshake_timeout(Duration::from_secs(2))
+            .connect()
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ClientError::HandshakeTimeout), "{:?}", err);
+        assert_eq!(started.elapsed().as_secs(), 2);
+    }
+
+    #[tokio::test]
+    async fn empty_channels_are_refused_before_sending() {
+        let addr = broker(vec![Frame::Info {
+            name: "b".into(),
+            rand: "1234".into(),
+        }])
+        .await;
+
+        let transport = connect_and_auth(&addr, "i", "s").await.unwrap();
+        let mut subscriber = Subscriber::new(transport, "i");
+        let err = subscriber.subscribe("").await.unwrap_err();
+        assert!(matches!(err, ClientError::EmptyChannel), "{:?}", err);
+        assert!(matches!(
+            subscriber.subscribe_shared("", "g").await,
+            Err(ClientError::EmptyChannel)
+        ));
+        assert!(subscriber.subscribe("ch").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn banner_frames_before_info_are_skipped() {
+        let mut frames: Vec<Frame> = (0..MAX_BANNER_FRAMES).map(banner).collect();
+        frames.push(Frame::Info {
+            name: "b".into(),
+            rand: "1234".into(),
+        });
+        let addr = broker(frames).await;
+
+        assert!(connect_and_auth(&addr, "i", "s").await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_subscribe_is_unconfirmed() {
+        let addr = broker(vec![
+            Frame::Info {
+                name: "b".into(),
+                rand: "1234".into(),
+            },
+            banner(0),
+        ])
+        .await;
+
+        let transport = connect_and_auth(&addr, "i", "s").await.unwrap();
+        let mut subscriber = Subscriber::new(transport, "i");
+        let confirmed = subscriber
+            .subscribe_confirmed("ch", Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert!(!confirmed);
+        // the publish read while waiting is still delivered
+        assert_eq!(subscriber.next().await.unwrap().unwrap().channel, "banner");
+    }
+
+    #[tokio::test]
+    async fn too_many_frames_before_info_are_unexpected() {
+        let addr = broker((0..=MAX_BANNER_FRAMES).map(banner).collect()).await;
+
+        let err = connect_and_auth(&addr, "i", "s").await.unwrap_err();
+        assert!(
+            matches!(&err, ClientError::UnexpectedFrame(f) if **f == banner(MAX_BANNER_FRAMES)),
+            "{:?}",
+            err
+        );
+        assert!(
+            err.to_string().starts_with("unexpected frame: publish"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn error_in_place_of_info_is_auth_error() {
+        let addr = broker(vec![banner(0), Frame::Error("access denied".into())]).await;
+
+        let err = connect_and_auth(&addr, "i", "s").await.unwrap_err();
+        assert!(
+            matches!(&err, ClientError::Auth(msg) if msg == "access denied"),
+            "{:?}",
+            err
+        );
+        assert_eq!(
+            err.to_string(),
+            "broker sent OP_ERROR during the handshake: access denied"
+        );
+    }
+
+    #[tokio::test]
+    async fn undecodable_bytes_are_protocol_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            // a 6-byte frame with unknown opcode 99
+            socket.write_all(&[0, 0, 0, 6, 99, 0]).await.unwrap();
+            let _ = socket.shutdown().await;
+        });
+
+        let err = connect_and_auth(&addr, "i", "s").await.unwrap_err();
+        assert!(matches!(err, ClientError::Protocol(_)), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn bad_root_cert_is_tls_error() {
+        let err = connect_tls_and_auth("127.0.0.1:1", "i", "s", b"not a certificate")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ClientError::Tls(_)), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn broker_error_on_session_is_broker_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut framed = Framed::new(socket, HpfeedsCodec::new());
+            framed
+                .send(Frame::Info {
+                    name: "b".into(),
+                    rand: "1234".into(),
+                })
+                .await
+                .unwrap();
+            framed.next().await;
+            framed
+                .send(Frame::Error("invalid channel name: x".into()))
+                .await
+                .unwrap();
+            while framed.next().await.is_some() {}
+        });
+
+        let transport = connect_and_auth(&addr, "i", "s").await.unwrap();
+        let err = Subscriber::new(transport, "i")
+            .next()
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert!(matches!(err, ClientError::Broker(_)), "{:?}", err);
     }
 }
@@ -0,0 +1,293 @@
+use crate::{ClientBuilder, MaybeTlsStream, Result, Transport, check_channel};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::{FutureExt, SinkExt, Stream, StreamExt};
+use hpfeeds_core::Frame;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// First delay between reconnection attempts.
+pub const DEFAULT_RECONNECT_BASE: Duration = Duration::from_secs(1);
+/// Longest delay between reconnection attempts.
+pub const DEFAULT_RECONNECT_MAX: Duration = Duration::from_secs(30);
+
+/// A connection that survives broker restarts.
+///
+/// When the connection fails or the broker closes it, the client connects and authenticates
+/// again, waiting `base` after the first failed attempt and doubling up to `max`, then re-sends
+/// every subscription made so far. The delay keeps growing across connections the broker
+/// closes straight after OP_AUTH, such as on bad credentials, and only starts over once a
+/// connection has delivered a frame other than OP_ERROR or stayed up for `base`. As a
+/// [`Stream`] it yields each frame the broker sends and never ends; frames in flight while the
+/// connection was down are lost.
+pub struct ReconnectingClient {
+    builder: ClientBuilder,
+    base: Duration,
+    max: Duration,
+    subscriptions: Vec<String>,
+    transport: Option<Transport<MaybeTlsStream>>,
+    reconnecting: Option<BoxFuture<'static, (Transport<MaybeTlsStream>, u32)>>,
+    reconnects: u64,
+    // Reconnection attempts since a connection last proved healthy, for the backoff
+    attempt: u32,
+    connected_at: Instant,
+}
+
+impl ReconnectingClient {
+    /// Connects once; errors here are returned rather than retried, so a bad address or a
+    /// broker that never sends OP_INFO shows up straight away. The broker does not confirm
+    /// OP_AUTH, so bad credentials only show as the connection being closed, and are retried
+    /// with backoff like any other failure. See [`ClientBuilder::reconnect`] for TLS and other
+    /// settings.
+    pub async fn connect(addr: &str, ident: &str, secret: &str) -> Result<Self> {
+        ClientBuilder::new(addr, ident, secret)
+            .reconnect(DEFAULT_RECONNECT_BASE, DEFAULT_RECONNECT_MAX)
+            .connect()
+            .await
+    }
+
+    pub(crate) async fn from_builder(
+        builder: ClientBuilder,
+        base: Duration,
+        max: Duration,
+    ) -> Result<Self> {
+        let transport = builder.connect().await?;
+        Ok(Self {
+            builder,
+            base,
+            max,
+            subscriptions: Vec::new(),
+            transport: Some(transport),
+            reconnecting: None,
+            reconnects: 0,
+            attempt: 0,
+            connected_at: Instant::now(),
+        })
+    }
+
+    /// Sets the delay after the first failed reconnection attempt and the most it doubles to.
+    pub fn with_backoff(mut self, base: Duration, max: Duration) -> Self {
+        self.base = base;
+        self.max = max.max(base);
+        self
+    }
+
+    /// Subscribes to `channel`, now and after every reconnect.
+    pub async fn subscribe(&mut self, channel: &str) -> Result<()> {
+        check_channel(channel)?;
+        if !self.subscriptions.iter().any(|c| c == channel) {
+            self.subscriptions.push(channel.to_string());
+        }
+        let frame = subscribe_frame(self.builder.ident(), channel);
+        // a failed send is made good by the re-subscribe after reconnecting
+        self.send_or_reconnect(frame).await;
+        Ok(())
+    }
+
+    /// Publishes `payload` on `channel`, first waiting for the connection to come back if it
+    /// is down.
+    pub async fn publish(&mut self, channel: &str, payload: impl Into<Bytes>) -> Result<()> {
+        check_channel(channel)?;
+        let frame = Frame::Publish {
+            ident: self.builder.ident().to_string().into(),
+            channel: channel.to_string().into(),
+            payload: payload.into(),
+        };
+        loop {
+            if self.send_or_reconnect(frame.clone()).await {
+                return Ok(());
+            }
+        }
+    }
+
+    /// How many times the connection has been re-established.
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+
+    // Sends `frame` once connected. On failure the connection is dropped, a reconnect is
+    // started, and false is returned.
+    async fn send_or_reconnect(&mut self, frame: Frame) -> bool {
+        if let Some(pending) = self.reconnecting.take() {
+            let (transport, attempt) = pending.await;
+            self.connected(transport, attempt);
+        }
+        let Some(transport) = &mut self.transport else {
+            self.start_reconnect();
+            return false;
+        };
+        if transport.send(frame).await.is_ok() {
+            return true;
+        }
+        self.start_reconnect();
+        false
+    }
+
+    fn connected(&mut self, transport: Transport<MaybeTlsStream>, attempt: u32) {
+        self.transport = Some(transport);
+        self.reconnects += 1;
+        self.attempt = attempt;
+        self.connected_at = Instant::now();
+    }
+
+    fn start_reconnect(&mut self) {
+        self.transport = None;
+        if self.connected_at.elapsed() >= self.base {
+            self.attempt = 0;
+        }
+        self.reconnecting = Some(
+            reconnect(
+                self.builder.clone(),
+                self.subscriptions.clone(),
+                self.base,
+                self.max,
+                self.attempt,
+            )
+            .boxed(),
+        );
+    }
+}
+
+impl Stream for ReconnectingClient {
+    type Item = Frame;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Frame>> {
+        loop {
+            if let Some(pending) = &mut self.reconnecting {
+                let (transport, attempt) = std::task::ready!(pending.poll_unpin(cx));
+                self.reconnecting = None;
+                self.connected(transport, attempt);
+            }
+            let Some(transport) = &mut self.transport else {
+                self.start_reconnect();
+                continue;
+            };
+            match std::task::ready!(transport.poll_next_unpin(cx)) {
+                Some(Ok(frame)) => {
+                    if !matches!(frame, Frame::Error(_)) {
+                        self.attempt = 0;
+                    }
+                    return Poll::Ready(Some(frame));
+                }
+                Some(Err(_)) | None => self.start_reconnect(),
+            }
+        }
+    }
+}
+
+fn subscribe_frame(ident: &str, channel: &str) -> Frame {
+    Frame::Subscribe {
+        ident: ident.to_string().into(),
+        channel: channel.to_string().into(),
+    }
+}
+
+/// The delay before reconnection attempt `attempt` (0 for the first): none, then `base`,
+/// doubling up to `max`.
+pub(crate) fn backoff_delay(attempt: u32, base: Duration, max: Duration) -> Duration {
+    match attempt {
+        0 => Duration::ZERO,
+        n => base.saturating_mul(1 << (n - 1).min(16)).min(max),
+    }
+}
+
+// Connects and authenticates until it works, then re-sends `subscriptions`. Backs off from
+// `attempt` onwards and returns the attempt count reached alongside the transport.
+async fn reconnect(
+    builder: ClientBuilder,
+    subscriptions: Vec<String>,
+    base: Duration,
+    max: Duration,
+    mut attempt: u32,
+) -> (Transport<MaybeTlsStream>, u32) {
+    loop {
+        tokio::time::sleep(backoff_delay(attempt, base, max)).await;
+        attempt += 1;
+        let Ok(mut transport) = builder.connect().await else {
+            continue;
+        };
+        let mut resubscribed = true;
+        for channel in &subscriptions {
+            if transport
+                .send(subscribe_frame(builder.ident(), channel))
+                .await
+                .is_err()
+            {
+                resubscribed = false;
+                break;
+            }
+        }
+        if resubscribed {
+            return (transport, attempt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hpfeeds_core::HpfeedsCodec;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+    use tokio_util::codec::Framed;
+
+    // A broker that turns every client away with OP_ERROR right after OP_AUTH, as one does
+    // for an ident over its connection limit. Returns its address and a connection count.
+    async fn refusing_broker() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let count = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                count.fetch_add(1, Ordering::SeqCst);
+                let mut framed = Framed::new(socket, HpfeedsCodec::new());
+                let info = Frame::Info {
+                    name: "refusing".into(),
+                    rand: vec![0; 4].into(),
+                };
+                if framed.send(info).await.is_err() {
+                    continue;
+                }
+                if let Some(Ok(Frame::Auth { .. })) = framed.next().await {
+                    let _ = framed
+                        .send(Frame::Error("too many connections".into()))
+                        .await;
+                }
+            }
+        });
+        (addr, accepted)
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let (base, max) = (Duration::from_millis(100), Duration::from_millis(350));
+        let delays: Vec<_> = (0..5)
+            .map(|n| backoff_delay(n, base, max).as_millis())
+            .collect();
+        assert_eq!(delays, vec![0, 100, 200, 350, 350]);
+        assert_eq!(backoff_delay(200, base, Duration::MAX), base * (1 << 16));
+    }
+
+    #[tokio::test]
+    async fn backoff_keeps_growing_when_the_broker_closes_after_auth() {
+        let (addr, accepted) = refusing_broker().await;
+        let mut client = ReconnectingClient::connect(&addr, "i", "s")
+            .await
+            .unwrap()
+            .with_backoff(Duration::from_millis(50), Duration::from_millis(400));
+
+        // 0 + 50 + 100 + 200 + 400 ms: about six connections in a second, not hundreds
+        let _ = tokio::time::timeout(Duration::from_secs(1), async {
+            while client.next().await.is_some() {}
+        })
+        .await;
+        let connections = accepted.load(Ordering::SeqCst);
+        assert!(connections <= 8, "{} connections in a second", connections);
+        assert!(client.reconnects() >= 3, "{}", client.reconnects());
+    }
+}
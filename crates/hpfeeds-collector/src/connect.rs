@@ -0,0 +1,289 @@
+use crate::Args;
+use anyhow::Context;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::{Transport, auth_stream};
+use hpfeeds_core::Frame;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+/// Exit status when the broker keeps rejecting the credentials (`EX_NOPERM` in sysexits.h), so
+/// supervisors can tell it apart from a crash and stop restarting.
+pub const EXIT_AUTH_FAILED: i32 = 77;
+
+/// How long a new session is watched for the broker hanging up on OP_AUTH. The broker closes
+/// the connection without a reply when authentication fails, and sends nothing when it
+/// succeeds, so a close this early is taken as a rejection.
+pub const AUTH_CHECK_WINDOW: Duration = Duration::from_millis(500);
+
+/// Longest wait between reconnection attempts.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Why no session could be established.
+#[derive(Debug)]
+pub enum ConnectError {
+    /// The broker rejected the credentials this many times in a row
+    AuthRejected { attempts: u32 },
+    /// Bad arguments; retrying would not help
+    Fatal(anyhow::Error),
+}
+
+/// The connection to the broker, plain or TLS.
+pub trait BrokerIo: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> BrokerIo for T {}
+
+/// An authenticated, subscribed session, with the first frame if one arrived while it was
+/// being checked.
+pub struct Session {
+    pub transport: Transport<Box<dyn BrokerIo>>,
+    pub first: Option<Frame>,
+}
+
+/// Connects, authenticates and subscribes to `--channels`. Network errors are retried forever
+/// with exponential backoff from `--reconnect-delay-ms`; rejected credentials are retried until
+/// `--max-auth-failures` rejections in a row.
+pub async fn establish(args: &Args) -> Result<Session, ConnectError> {
+    let ident = args
+        .ident
+        .as_deref()
+        .context("--ident required")
+        .map_err(ConnectError::Fatal)?;
+    let secret = args
+        .secret
+        .as_deref()
+        .context("--secret required")
+        .map_err(ConnectError::Fatal)?;
+    let addr = format!("{}:{}", args.host, args.port);
+    let mut delay = Duration::from_millis(args.reconnect_delay_ms);
+    let mut rejections = 0;
+
+    loop {
+        match attempt(&addr, ident, secret, args).await {
+            Ok(Some(session)) => return Ok(session),
+            Ok(None) => {
+                rejections += 1;
+                eprintln!(
+                    "Broker at {} rejected credentials for {} ({}/{})",
+                    addr, ident, rejections, args.max_auth_failures
+                );
+                if rejections >= args.max_auth_failures {
+                    return Err(ConnectError::AuthRejected {
+                        attempts: rejections,
+                    });
+                }
+            }
+            Err(e) => {
+                // a network error says nothing about the credentials
+                rejections = 0;
+                eprintln!(
+                    "Connecting to {} failed: {:#}; retrying in {:?}",
+                    addr, e, delay
+                );
+            }
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+// Opens the connection to the broker, over TLS with `--tls`.
+async fn open(addr: &str, args: &Args) -> anyhow::Result<Box<dyn BrokerIo>> {
+    if !args.tls {
+        return Ok(Box::new(TcpStream::connect(addr).await?));
+    }
+    #[cfg(feature = "tls")]
+    return Ok(Box::new(
+        crate::tls::connect(
+            addr,
+            args.ca_cert.as_deref(),
+            Some(args.tls_server_name.as_deref().unwrap_or(&args.host)),
+        )
+        .await?,
+    ));
+    #[cfg(not(feature = "tls"))]
+    anyhow::bail!(
+        "TLS to {} requested, but the collector was built without the tls feature",
+        addr
+    )
+}
+
+// One connection attempt. Ok(None) means the broker hung up right after OP_AUTH.
+async fn attempt(
+    addr: &str,
+    ident: &str,
+    secret: &str,
+    args: &Args,
+) -> anyhow::Result<Option<Session>> {
+    let mut transport = auth_stream(open(addr, args).await?, ident, secret).await?;
+    for channel in args.channels.split(',') {
+        // the broker may already have closed the socket; that shows up below
+        if transport
+            .send(Frame::Subscribe {
+                ident: ident.to_string().into(),
+                channel: channel.trim().to_string().into(),
+            })
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+    match tokio::time::timeout(AUTH_CHECK_WINDOW, transport.next()).await {
+        Err(_) => Ok(Some(Session {
+            transport,
+            first: None,
+        })),
+        Ok(Some(Ok(frame))) => Ok(Some(Session {
+            transport,
+            first: Some(frame),
+        })),
+        Ok(None) => Ok(None),
+        // a reset while the broker tears down a rejected session, or over TLS a close without
+        // close_notify
+        Ok(Some(Err(e)))
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::UnexpectedEof
+            ) =>
+        {
+            Ok(None)
+        }
+        Ok(Some(Err(e))) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use hpfeeds_core::{HpfeedsCodec, hashsecret};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    // Accepts `secret` for every ident, closing the connection on anything else like the real
+    // broker.
+    async fn serve(socket: impl BrokerIo, secret: &str) {
+        let mut framed = Transport::new(socket, HpfeedsCodec::new());
+        let rand = bytes::Bytes::from_static(b"1234");
+        framed
+            .send(Frame::Info {
+                name: "test".into(),
+                rand: rand.clone(),
+            })
+            .await
+            .unwrap();
+        if let Some(Ok(Frame::Auth { secret_hash, .. })) = framed.next().await
+            && secret_hash[..] == hashsecret(&rand, secret)[..]
+        {
+            // hold the session open
+            while framed.next().await.is_some() {}
+        }
+    }
+
+    // A broker serving sessions as `serve` does. Returns its port and a count of the sessions
+    // it has accepted.
+    async fn broker(secret: &'static str) -> (u16, Arc<AtomicU32>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sessions = Arc::new(AtomicU32::new(0));
+        let count = sessions.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                count.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(serve(socket, secret));
+            }
+        });
+        (port, sessions)
+    }
+
+    fn args(port: u16, secret: &str) -> Args {
+        Args::parse_from([
+            "hpfeeds-collector",
+            "--ident=i",
+            &format!("--secret={}", secret),
+            &format!("--port={}", port),
+            "--max-auth-failures=3",
+            "--reconnect-delay-ms=10",
+        ])
+    }
+
+    #[tokio::test]
+    async fn wrong_secret_gives_up_after_max_failures() {
+        let (port, sessions) = broker("right").await;
+
+        let result = tokio::time::timeout(Duration::from_secs(10), establish(&args(port, "wrong")))
+            .await
+            .expect("gave up rather than retrying forever");
+
+        assert!(matches!(
+            result,
+            Err(ConnectError::AuthRejected { attempts: 3 })
+        ));
+        assert_eq!(sessions.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn right_secret_connects() {
+        let (port, sessions) = broker("right").await;
+
+        let session = establish(&args(port, "right")).await.unwrap();
+
+        assert!(session.first.is_none());
+        assert_eq!(sessions.load(Ordering::SeqCst), 1);
+    }
+
+    // A TLS broker serving sessions as `serve` does, with a certificate for `name` only.
+    // Returns its port and the path of a CA file trusting it.
+    #[cfg(feature = "tls")]
+    async fn tls_broker(name: &str, secret: &'static str) -> (u16, std::path::PathBuf) {
+        let (acceptor, ca) = crate::tls::test_support::acceptor(name, "broker-ca");
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(socket).await {
+                        serve(stream, secret).await;
+                    }
+                });
+            }
+        });
+        (port, ca)
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn connects_over_tls_to_the_named_broker() {
+        let (port, ca) = tls_broker("broker.example", "right").await;
+        let args = |secret: &str, server_name: Option<&str>| {
+            let mut argv = vec![
+                "hpfeeds-collector".to_string(),
+                "--ident=i".into(),
+                format!("--secret={}", secret),
+                format!("--port={}", port),
+                "--max-auth-failures=1".into(),
+                "--tls".into(),
+                format!("--ca-cert={}", ca.display()),
+            ];
+            argv.extend(server_name.map(|n| format!("--tls-server-name={}", n)));
+            Args::parse_from(argv)
+        };
+
+        // the certificate is not valid for --host, 127.0.0.1
+        let addr = format!("127.0.0.1:{}", port);
+        assert!(open(&addr, &args("right", None)).await.is_err());
+
+        let session = establish(&args("right", Some("broker.example")))
+            .await
+            .unwrap();
+        assert!(session.first.is_none());
+        let rejected = establish(&args("wrong", Some("broker.example"))).await;
+        std::fs::remove_file(&ca).unwrap();
+        assert!(matches!(
+            rejected,
+            Err(ConnectError::AuthRejected { attempts: 1 })
+        ));
+    }
+}
@@ -0,0 +1,168 @@
+use chrono::Utc;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Digest used for `--hash-payload`.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlg {
+    Sha256,
+    Blake3,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Event {
+    pub timestamp: chrono::DateTime<Utc>,
+    pub channel: String,
+    pub source: String,
+    #[serde(with = "serde_bytes")]
+    pub payload: Vec<u8>,
+    /// The exact payload bytes as lowercase hex, when `--include-hex` is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_hex: Option<String>,
+    /// Lowercase hex digest of the payload bytes, when `--hash-payload` is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_hash: Option<String>,
+}
+
+impl Event {
+    /// An event received now.
+    pub fn new(channel: String, source: String, payload: Vec<u8>) -> Self {
+        Event {
+            timestamp: Utc::now(),
+            channel,
+            source,
+            payload,
+            payload_hex: None,
+            payload_hash: None,
+        }
+    }
+
+    /// Adds `payload_hex` alongside the decoded payload.
+    pub fn with_hex(mut self) -> Self {
+        self.payload_hex = Some(to_hex(&self.payload));
+        self
+    }
+
+    /// Adds `payload_hash`, the `alg` digest of the payload.
+    pub fn with_hash(mut self, alg: HashAlg) -> Self {
+        self.payload_hash = Some(match alg {
+            HashAlg::Sha256 => to_hex(&Sha256::digest(&self.payload)),
+            HashAlg::Blake3 => blake3::hash(&self.payload).to_hex().to_string(),
+        });
+        self
+    }
+
+    /// Lowercase hex SHA-256 over the timestamp, channel, source and payload, each prefixed
+    /// with its length. Retries and `--replay` send an event with the same id, so sinks that
+    /// key documents on it store each event once.
+    pub fn id(&self) -> String {
+        let mut hasher = Sha256::new();
+        let timestamp = self.timestamp.to_rfc3339();
+        for field in [
+            timestamp.as_bytes(),
+            self.channel.as_bytes(),
+            self.source.as_bytes(),
+            &self.payload,
+        ] {
+            hasher.update((field.len() as u64).to_be_bytes());
+            hasher.update(field);
+        }
+        to_hex(&hasher.finalize())
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+mod serde_bytes {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use serde::{Deserialize, Serializer};
+
+    pub fn serialize<S>(v: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match std::str::from_utf8(v) {
+            Ok(s) => serializer.serialize_str(s),
+            Err(_) => serializer.serialize_str(&STANDARD.encode(v)),
+        }
+    }
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Ok(STANDARD.decode(&s).unwrap_or_else(|_| s.into_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_matches_payload() {
+        let payload = "caf\u{e9} \u{430}".as_bytes().to_vec();
+        let event = Event::new("ch".into(), "src".into(), payload.clone()).with_hex();
+        let doc = serde_json::to_value(&event).unwrap();
+
+        assert_eq!(doc["payload"], "caf\u{e9} \u{430}");
+        let hex = doc["payload_hex"].as_str().unwrap();
+        assert_eq!(hex, "636166c3a920d0b0");
+        let decoded: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        assert_eq!(decoded, payload);
+
+        let plain = serde_json::to_value(Event::new("ch".into(), "src".into(), payload)).unwrap();
+        assert!(plain.get("payload_hex").is_none());
+    }
+
+    #[test]
+    fn hash_matches_reference_digests() {
+        // FIPS 180-2 and BLAKE3 reference vectors for "abc"
+        let event = Event::new("ch".into(), "src".into(), b"abc".to_vec());
+        let sha = event.clone().with_hash(HashAlg::Sha256);
+        assert_eq!(
+            sha.payload_hash.as_deref(),
+            Some("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        let b3 = event.clone().with_hash(HashAlg::Blake3);
+        assert_eq!(
+            b3.payload_hash.as_deref(),
+            Some("6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85")
+        );
+
+        let doc = serde_json::to_value(&sha).unwrap();
+        assert_eq!(doc["payload_hash"], sha.payload_hash.unwrap());
+        assert!(
+            serde_json::to_value(&event)
+                .unwrap()
+                .get("payload_hash")
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn id_is_stable_and_covers_every_field() {
+        let event = Event::new("ch".into(), "src".into(), b"abc".to_vec());
+        let id = event.id();
+        assert_eq!(id.len(), 64);
+        let replayed: Event =
+            serde_json::from_str(&serde_json::to_string(&event).unwrap()).unwrap();
+        assert_eq!(replayed.id(), id);
+        assert_eq!(event.clone().with_hex().id(), id);
+
+        let mut moved = event.clone();
+        moved.channel = "chs".into();
+        moved.source = "rc".into();
+        assert_ne!(moved.id(), id);
+        let mut later = event.clone();
+        later.timestamp += chrono::Duration::nanoseconds(1);
+        assert_ne!(later.id(), id);
+    }
+}
@@ -0,0 +1,82 @@
+use serde_json::{Map, Value};
+
+/// Flattens a JSON object payload for `--es-flatten`: nested keys are joined with dots down to
+/// `depth` segments, e.g. `http.request.method`, and anything nested deeper is stored as its
+/// JSON text. Arrays holding objects or arrays are stored as text too, so the fields a document
+/// can map stay bounded. Returns None for payloads that are not a JSON object.
+pub fn flatten_payload(payload: &[u8], depth: usize) -> Option<Map<String, Value>> {
+    let Ok(Value::Object(doc)) = serde_json::from_slice(payload) else {
+        return None;
+    };
+    let mut fields = Map::new();
+    flatten_into(&mut fields, String::new(), doc, depth.max(1));
+    Some(fields)
+}
+
+fn flatten_into(
+    fields: &mut Map<String, Value>,
+    prefix: String,
+    obj: Map<String, Value>,
+    depth: usize,
+) {
+    for (key, value) in obj {
+        let key = if prefix.is_empty() {
+            key
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value {
+            Value::Object(inner) if depth > 1 => flatten_into(fields, key, inner, depth - 1),
+            Value::Object(_) => {
+                fields.insert(key, value.to_string().into());
+            }
+            Value::Array(ref items) if items.iter().any(|v| v.is_object() || v.is_array()) => {
+                fields.insert(key, value.to_string().into());
+            }
+            leaf => {
+                fields.insert(key, leaf);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn nested_keys_are_dotted_down_to_depth() {
+        let payload = json!({
+            "src_ip": "192.0.2.1",
+            "http": {"request": {"method": "GET", "headers": {"host": "example.org"}}},
+            "ports": [22, 23],
+            "logins": [{"user": "root"}]
+        })
+        .to_string();
+
+        let fields = flatten_payload(payload.as_bytes(), 3).unwrap();
+        assert_eq!(
+            Value::Object(fields),
+            json!({
+                "src_ip": "192.0.2.1",
+                "http.request.method": "GET",
+                "http.request.headers": r#"{"host":"example.org"}"#,
+                "ports": [22, 23],
+                "logins": r#"[{"user":"root"}]"#
+            })
+        );
+
+        let shallow = flatten_payload(payload.as_bytes(), 1).unwrap();
+        assert_eq!(
+            shallow["http"],
+            r#"{"request":{"method":"GET","headers":{"host":"example.org"}}}"#
+        );
+    }
+
+    #[test]
+    fn non_objects_are_left_alone() {
+        assert!(flatten_payload(b"not json", 2).is_none());
+        assert!(flatten_payload(b"[1,2]", 2).is_none());
+    }
+}
@@ -1,25 +1,32 @@
-use anyhow::{Context, Result};
-use base64::Engine;
-use base64::engine::general_purpose::STANDARD;
-use chrono::Utc;
+use anyhow::{Result, bail};
 use clap::Parser;
-use elasticsearch::{BulkIndexOperation, BulkOperations, BulkParts, Elasticsearch};
-use futures::{SinkExt, StreamExt};
-use hpfeeds_client::connect_and_auth;
-use hpfeeds_core::Frame;
-use mongodb::{Client as MongoClient, options::ClientOptions as MongoOptions};
-use rskafka::client::{
-    ClientBuilder as KafkaClientBuilder,
-    partition::{Compression, UnknownTopicHandling},
-};
-use rskafka::record::Record;
-use serde::{Deserialize, Serialize};
+use futures::{Stream, StreamExt};
+use hpfeeds_core::{Frame, MOTD_CHANNEL};
+use std::future::Future;
 use std::time::{Duration, Instant};
-use tokio::io::AsyncWriteExt;
-use tokio_postgres::NoTls;
-use uuid::Uuid;
 
-#[derive(Parser, Debug)]
+mod connect;
+mod event;
+mod flatten;
+mod replay;
+mod retry;
+mod routing;
+mod sinks;
+mod stix;
+mod syslog;
+#[cfg(feature = "tls")]
+mod tls;
+mod transform;
+
+use connect::{ConnectError, EXIT_AUTH_FAILED};
+use event::{Event, HashAlg};
+use replay::Replay;
+use routing::Router;
+use stix::StixMode;
+use syslog::{SyslogEncoding, SyslogTransport};
+use transform::Transform;
+
+#[derive(Parser, Debug, Clone)]
 #[clap(
     name = "hpfeeds-collector",
     about = "Universal batteries-included collector for hpfeeds"
@@ -29,19 +36,42 @@ struct Args {
     host: String,
     #[clap(long, default_value_t = 10000)]
     port: u16,
-    #[clap(long, short = 'i', required = true)]
-    ident: String,
-    #[clap(long, short = 's', required = true)]
-    secret: String,
+    #[clap(long, short = 'i', required_unless_present = "replay")]
+    ident: Option<String>,
+    #[clap(long, short = 's', required_unless_present = "replay")]
+    secret: Option<String>,
     #[clap(long, default_value = "bench")]
     channels: String,
+    /// Connect to the broker over TLS
+    #[clap(long)]
+    tls: bool,
+    /// PEM file of CA certificates to verify the broker against, instead of the public roots
+    #[clap(long, requires = "tls")]
+    ca_cert: Option<String>,
+    /// Name the broker's certificate must be valid for, if not --host
+    #[clap(long, requires = "tls")]
+    tls_server_name: Option<String>,
 
-    /// Output mode: file, console, redis, postgres, mongo, elastic, splunk-hec, stix, kafka, syslog, tcp
+    /// Output mode: file, console, redis, postgres, mongo, elastic, opensearch,
+    /// splunk-hec, stix, kafka, syslog, tcp
     #[clap(long, default_value = "console")]
     output: String,
+    /// Also print every event to stdout, as `--output console` would
+    #[clap(long)]
+    tee_console: bool,
+    /// JSON file routing channel patterns to their own sinks; unmatched events go to --output
+    #[clap(long)]
+    routes: Option<String>,
 
     #[clap(long)]
     file_path: Option<String>,
+    /// Write a STIX bundle per flush, or one STIX object per line
+    #[clap(long, value_enum, default_value_t = StixMode::Bundle)]
+    stix_mode: StixMode,
+    /// JSON file shaping the STIX object written per event, in place of observed-data and
+    /// sighting
+    #[clap(long)]
+    stix_mapping: Option<String>,
     #[clap(long, default_value = "redis://127.0.0.1/")]
     redis_url: String,
     #[clap(long, default_value = "hpfeeds.events")]
@@ -52,18 +82,75 @@ struct Args {
     mongo_url: String,
     #[clap(long, default_value = "http://localhost:9200")]
     elastic_url: String,
+    /// Also index JSON-object payloads as a `payload_fields` object of dotted keys, e.g.
+    /// `http.request.method`, up to this many levels deep; deeper values are kept as JSON text
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    es_flatten: Option<u32>,
+    #[clap(long, default_value = "http://localhost:9200")]
+    opensearch_url: String,
+    /// Data stream the opensearch sink writes to; needs a matching index template with
+    /// `data_stream` enabled
+    #[clap(long, default_value = "hpfeeds-events")]
+    opensearch_data_stream: String,
     #[clap(long, default_value = "http://localhost:8088/services/collector")]
     splunk_url: String,
     #[clap(long)]
     splunk_token: Option<String>,
+    /// PEM file of extra CA certificates to trust for the HEC endpoint
+    #[clap(long)]
+    splunk_ca: Option<String>,
+    /// Skip certificate verification for the HEC endpoint
+    #[clap(long)]
+    splunk_insecure: bool,
     #[clap(long, default_value = "localhost:9092")]
     kafka_url: String,
     #[clap(long, default_value = "hpfeeds.events")]
     kafka_topic: String,
     #[clap(long, default_value = "127.0.0.1:514")]
     syslog_addr: String,
+    #[clap(long, value_enum, default_value_t = SyslogTransport::Udp)]
+    syslog_transport: SyslogTransport,
+    /// Render events in syslog messages as JSON or flat key="value" pairs
+    #[clap(long, value_enum, default_value_t = SyslogEncoding::Json)]
+    syslog_encoding: SyslogEncoding,
+    /// Truncate syslog messages longer than this many bytes, ending them with a marker
+    #[clap(long)]
+    syslog_max_len: Option<usize>,
     #[clap(long, default_value = "127.0.0.1:9999")]
     tcp_addr: String,
+    /// Connect the tcp sink over TLS
+    #[clap(long)]
+    tcp_tls: bool,
+    /// PEM file of CA certificates to verify TLS tcp and syslog endpoints against, instead of
+    /// the public roots
+    #[clap(long)]
+    tls_ca: Option<String>,
+
+    /// Decode payloads before storing them, e.g. `gunzip,json-field:data`; steps run in order
+    #[clap(long, value_delimiter = ',')]
+    payload_transform: Vec<Transform>,
+    /// Drop events whose payload a transform fails on, instead of storing them untouched
+    #[clap(long)]
+    strict_transforms: bool,
+
+    /// Add a `payload_hex` field with the exact payload bytes to every event
+    #[clap(long)]
+    include_hex: bool,
+    /// Add a `payload_hash` field with this digest of the payload to every event
+    #[clap(long, value_enum)]
+    hash_payload: Option<HashAlg>,
+
+    /// Give up with exit status 77 after the broker rejects the credentials this many times in
+    /// a row; network errors are retried indefinitely
+    #[clap(long, default_value_t = 3)]
+    max_auth_failures: u32,
+    /// Delay before the first reconnection attempt, doubling up to 30s
+    #[clap(long, default_value_t = 1000)]
+    reconnect_delay_ms: u64,
+
+    /// Flush and exit after collecting this many events
+    #[clap(long)]
+    max_events: Option<usize>,
 
     /// Batch size for flushes
     #[clap(long, default_value_t = 1000)]
@@ -71,298 +158,421 @@ struct Args {
     /// Max time to wait before flushing (seconds)
     #[clap(long, default_value_t = 5)]
     flush_interval: u64,
-}
-
-#[derive(Serialize, Deserialize, Clone)]
-struct Event {
-    timestamp: chrono::DateTime<Utc>,
-    channel: String,
-    source: String,
-    #[serde(with = "serde_bytes")]
-    payload: Vec<u8>,
-}
-
-mod serde_bytes {
-    use base64::Engine;
-    use base64::engine::general_purpose::STANDARD;
-    use serde::{Deserialize, Serializer};
-
-    pub fn serialize<S>(v: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        match std::str::from_utf8(v) {
-            Ok(s) => serializer.serialize_str(s),
-            Err(_) => serializer.serialize_str(&STANDARD.encode(v)),
-        }
-    }
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let s = String::deserialize(deserializer)?;
-        Ok(STANDARD.decode(&s).unwrap_or_else(|_| s.into_bytes()))
-    }
-}
+    /// Retries of a failed sink write before its events go to --dead-letter-file
+    #[clap(long, default_value_t = 5)]
+    max_retries: u32,
+    /// Delay before the first retry of a failed sink write, doubling for each one after
+    #[clap(long, default_value_t = 500)]
+    retry_base_ms: u64,
+    /// NDJSON file events are appended to when a sink keeps failing; replay it with --replay
+    #[clap(long, default_value = "hpfeeds-dead-letter.ndjson")]
+    dead_letter_file: String,
 
-fn to_stix_bundle(events: &[Event]) -> serde_json::Value {
-    let bundle_id = format!("bundle--{}", Uuid::new_v4());
-    let mut objects = Vec::new();
-    for event in events {
-        let observed_data_id = format!("observed-data--{}", Uuid::new_v4());
-        objects.push(serde_json::json!({
-            "type": "observed-data", "id": observed_data_id, "spec_version": "2.1",
-            "first_observed": event.timestamp.to_rfc3339(), "last_observed": event.timestamp.to_rfc3339(),
-            "number_observed": 1, "external_references": [{"source_name": "hpfeeds", "external_id": event.source}],
-            "x_hpfeeds_channel": event.channel, "x_hpfeeds_payload": STANDARD.encode(&event.payload)
-        }));
-        objects.push(serde_json::json!({
-            "type": "sighting", "id": format!("sighting--{}", Uuid::new_v4()), "spec_version": "2.1",
-            "sighting_of_ref": observed_data_id, "last_seen": event.timestamp.to_rfc3339(), "count": 1
-        }));
-    }
-    serde_json::json!({"type": "bundle", "id": bundle_id, "objects": objects})
+    /// Replay events from an NDJSON file (as written by `--output file`) instead of
+    /// connecting to a broker
+    #[clap(long)]
+    replay: Option<String>,
+    /// Longest replay record accepted, in bytes; longer lines are skipped
+    #[clap(long, default_value_t = replay::DEFAULT_MAX_RECORD_LEN)]
+    max_record_len: usize,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
-    let addr = format!("{}:{}", args.host, args.port);
-
-    let mut client = connect_and_auth(&addr, &args.ident, &args.secret).await?;
-    println!("Collector connected to broker at {}", addr);
 
-    for channel in args.channels.split(',') {
-        client
-            .send(Frame::Subscribe {
-                ident: args.ident.clone().into(),
-                channel: channel.trim().to_string().into(),
-            })
-            .await?;
+    // batches dead-lettered mid-replay would be read back again by the same replay
+    if let Some(path) = &args.replay
+        && same_file(path, &args.dead_letter_file)
+    {
+        bail!(
+            "--replay {} is also the --dead-letter-file; move it aside or pick another --dead-letter-file",
+            path
+        );
     }
 
-    // Initialize Sinks
-    let mut file_sink = if args.output == "file" || args.output == "stix" {
-        let p = args.file_path.as_ref().context("--file-path required")?;
-        Some(
-            tokio::fs::OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(p)
-                .await?,
-        )
-    } else {
-        None
-    };
-
-    let mut redis_conn = if args.output == "redis" {
-        Some(
-            redis::Client::open(args.redis_url.clone())?
-                .get_multiplexed_async_connection()
-                .await?,
-        )
-    } else {
-        None
-    };
+    let mut sink = Router::open(&args).await?;
 
-    let pg_client = if args.output == "postgres" {
-        let (client, connection) = tokio_postgres::connect(&args.postgres_url, NoTls).await?;
-        tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                eprintln!("Postgres connection error: {}", e);
+    if let Some(path) = &args.replay {
+        let file = tokio::fs::File::open(path).await?;
+        let mut replay = Replay::new(tokio::io::BufReader::new(file), args.max_record_len);
+        let mut buffer: Vec<Event> = Vec::with_capacity(args.batch_size);
+        let mut total = 0usize;
+        while let Some(event) = replay.next_event().await? {
+            if args
+                .max_events
+                .is_some_and(|max| total + buffer.len() >= max)
+            {
+                break;
             }
-        });
-        client.execute("CREATE TABLE IF NOT EXISTS events (id SERIAL PRIMARY KEY, ts TIMESTAMPTZ, channel TEXT, source TEXT, payload BYTEA)", &[]).await?;
-        Some(client)
-    } else {
-        None
-    };
+            buffer.push(annotate(event, &args));
+            if buffer.len() >= args.batch_size {
+                sink.write(&buffer).await?;
+                total += buffer.len();
+                buffer.clear();
+            }
+        }
+        if !buffer.is_empty() {
+            sink.write(&buffer).await?;
+            total += buffer.len();
+        }
+        println!(
+            "Replayed {} events from {} ({} skipped)",
+            total,
+            path,
+            replay.skipped()
+        );
+        sink.close().await?;
+        return Ok(());
+    }
 
-    let mongo_coll = if args.output == "mongo" {
-        let c = MongoClient::with_options(MongoOptions::parse(&args.mongo_url).await?)?;
-        Some(c.database("hpfeeds").collection::<Event>("events"))
-    } else {
-        None
+    let session = match connect::establish(&args).await {
+        Ok(session) => session,
+        Err(ConnectError::AuthRejected { attempts }) => {
+            eprintln!(
+                "Authentication failed {} times in a row; check --ident and --secret",
+                attempts
+            );
+            std::process::exit(EXIT_AUTH_FAILED);
+        }
+        Err(ConnectError::Fatal(e)) => return Err(e),
     };
+    println!(
+        "Collector connected to broker at {}:{}",
+        args.host, args.port
+    );
+    let frames = futures::stream::iter(session.first.map(Ok)).chain(session.transport);
 
-    let es_client = if args.output == "elastic" {
-        Some(Elasticsearch::new(
-            elasticsearch::http::transport::Transport::single_node(&args.elastic_url)?,
-        ))
-    } else {
-        None
-    };
+    println!(
+        "Starting collection loop using output mode: {}",
+        args.output
+    );
+    let collected = collect(frames, &mut sink, &args, shutdown_signal()).await?;
+    sink.close().await?;
+    if collected.interrupted {
+        println!("Shutting down after writing {} events", collected.confirmed);
+    } else if args.max_events.is_some_and(|max| collected.received >= max) {
+        println!("Collected {} events, exiting", collected.received);
+    }
+    Ok(())
+}
 
-    let kafka_producer = if args.output == "kafka" {
-        let client = KafkaClientBuilder::new(vec![args.kafka_url.clone()])
-            .build()
-            .await?;
-        let partition_client = client
-            .partition_client(args.kafka_topic.clone(), 0, UnknownTopicHandling::Retry)
-            .await?;
-        Some(partition_client)
-    } else {
-        None
-    };
+// True if `a` and `b` name the same existing file.
+fn same_file(a: &str, b: &str) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
 
-    let syslog_socket = if args.output == "syslog" {
-        Some(tokio::net::UdpSocket::bind("0.0.0.0:0").await?)
-    } else {
-        None
-    };
+/// Resolves on Ctrl-C, or SIGTERM where there is one.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("installing SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = term.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
 
-    let mut tcp_stream = if args.output == "tcp" {
-        Some(tokio::net::TcpStream::connect(&args.tcp_addr).await?)
-    } else {
-        None
-    };
+/// Adds the optional fields requested on the command line.
+fn annotate(mut event: Event, args: &Args) -> Event {
+    if args.include_hex {
+        event = event.with_hex();
+    }
+    if let Some(alg) = args.hash_payload {
+        event = event.with_hash(alg);
+    }
+    event
+}
 
-    let http_client = reqwest::Client::new();
+/// Applies `--payload-transform`. A failing transform leaves the payload as received, or with
+/// `--strict-transforms` drops the event.
+fn transform_payload(channel: &[u8], payload: &[u8], args: &Args) -> Option<Vec<u8>> {
+    match transform::apply_all(&args.payload_transform, payload) {
+        Ok(p) => Some(p),
+        Err(e) if args.strict_transforms => {
+            eprintln!(
+                "Dropping event on {}: {:#}",
+                String::from_utf8_lossy(channel),
+                e
+            );
+            None
+        }
+        Err(_) => Some(payload.to_vec()),
+    }
+}
+
+/// What a collection run did.
+#[derive(Debug, PartialEq, Eq)]
+struct Collected {
+    /// Events taken from the broker
+    received: usize,
+    /// Events every sink has accepted, or that were dead-lettered after a sink kept failing;
+    /// only these are safe to acknowledge upstream
+    confirmed: usize,
+    /// Whether the run stopped on the shutdown signal
+    interrupted: bool,
+}
+
+/// Batches publishes from `frames` into `sink` until the stream ends, `--max-events` have been
+/// collected or `shutdown` resolves, then flushes whatever is left over.
+async fn collect<S, E>(
+    mut frames: S,
+    sink: &mut Router,
+    args: &Args,
+    shutdown: impl Future<Output = ()>,
+) -> Result<Collected>
+where
+    S: Stream<Item = Result<Frame, E>> + Unpin,
+{
     let mut buffer: Vec<Event> = Vec::with_capacity(args.batch_size);
     let mut last_flush = Instant::now();
+    let mut total = 0usize;
+    let mut confirmed = 0usize;
+    let mut interrupted = false;
+    tokio::pin!(shutdown);
 
-    println!(
-        "Starting collection loop using output mode: {}",
-        args.output
-    );
-    while let Some(msg) = client.next().await {
+    loop {
+        let msg = tokio::select! {
+            // frames already received are taken before the signal is noticed
+            biased;
+            msg = frames.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            _ = &mut shutdown => {
+                interrupted = true;
+                break;
+            }
+        };
         if let Ok(Frame::Publish {
+            channel, payload, ..
+        }) = &msg
+            && channel == MOTD_CHANNEL
+        {
+            // the broker's own announcement, not an event
+            eprintln!("Message of the day: {}", String::from_utf8_lossy(payload));
+        } else if let Ok(Frame::Publish {
             ident,
             channel,
             payload,
         }) = msg
+            && let Some(payload) = transform_payload(&channel, &payload, args)
         {
-            buffer.push(Event {
-                timestamp: Utc::now(),
-                channel: String::from_utf8_lossy(&channel).to_string(),
-                source: String::from_utf8_lossy(&ident).to_string(),
-                payload: payload.to_vec(),
-            });
+            let event = Event::new(
+                String::from_utf8_lossy(&channel).to_string(),
+                String::from_utf8_lossy(&ident).to_string(),
+                payload,
+            );
+            buffer.push(annotate(event, args));
+            total += 1;
+            if args.max_events.is_some_and(|max| total >= max) {
+                break;
+            }
         }
 
         if buffer.len() >= args.batch_size
             || (last_flush.elapsed() >= Duration::from_secs(args.flush_interval)
                 && !buffer.is_empty())
         {
-            match args.output.as_str() {
-                "console" => {
-                    for e in &buffer {
-                        println!("{}", serde_json::to_string(e)?);
-                    }
-                }
-                "file" => {
-                    if let Some(f) = file_sink.as_mut() {
-                        let mut d = String::new();
-                        for e in &buffer {
-                            d.push_str(&serde_json::to_string(e)?);
-                            d.push('\n');
-                        }
-                        f.write_all(d.as_bytes()).await?;
-                    }
-                }
-                "stix" => {
-                    if let Some(f) = file_sink.as_mut() {
-                        let bundle = to_stix_bundle(&buffer);
-                        f.write_all(serde_json::to_string_pretty(&bundle)?.as_bytes())
-                            .await?;
-                        f.write_all(b"\n").await?;
-                    }
-                }
-                "redis" => {
-                    if let Some(conn) = redis_conn.as_mut() {
-                        for e in &buffer {
-                            let _: () = redis::AsyncCommands::publish(
-                                conn,
-                                &args.redis_channel,
-                                serde_json::to_string(e)?,
-                            )
-                            .await?;
-                        }
-                    }
-                }
-                "postgres" => {
-                    if let Some(client) = &pg_client {
-                        for e in &buffer {
-                            client.execute("INSERT INTO events (ts, channel, source, payload) VALUES ($1, $2, $3, $4)", &[&e.timestamp, &e.channel, &e.source, &e.payload]).await?;
-                        }
-                    }
-                }
-                "mongo" => {
-                    if let Some(coll) = &mongo_coll {
-                        coll.insert_many(&buffer).await?;
-                    }
-                }
-                "elastic" => {
-                    if let Some(es) = &es_client {
-                        let mut ops = BulkOperations::new();
-                        for e in &buffer {
-                            ops.push(BulkIndexOperation::new(e.clone())).unwrap();
-                        }
-                        es.bulk(BulkParts::Index("hpfeeds-events"))
-                            .body(vec![ops])
-                            .send()
-                            .await?;
-                    }
-                }
-                "kafka" => {
-                    if let Some(p) = &kafka_producer {
-                        let records: Vec<Record> = buffer
-                            .iter()
-                            .map(|e| Record {
-                                key: Some(e.channel.as_bytes().to_vec()),
-                                value: Some(serde_json::to_vec(e).unwrap()),
-                                timestamp: rskafka::chrono::Utc::now(),
-                                headers: Default::default(),
-                            })
-                            .collect();
-                        p.produce(records, Compression::NoCompression).await?;
-                    }
-                }
-                "syslog" => {
-                    if let Some(s) = &syslog_socket {
-                        for e in &buffer {
-                            let msg = format!(
-                                "<134>1 {} {} hpfeeds - - - {}",
-                                e.timestamp.to_rfc3339(),
-                                e.source,
-                                serde_json::to_string(e)?
-                            );
-                            s.send_to(msg.as_bytes(), &args.syslog_addr).await?;
-                        }
-                    }
-                }
-                "tcp" => {
-                    if let Some(s) = tcp_stream.as_mut() {
-                        let mut d = String::new();
-                        for e in &buffer {
-                            d.push_str(&serde_json::to_string(e)?);
-                            d.push('\n');
-                        }
-                        s.write_all(d.as_bytes()).await?;
-                    }
-                }
-                "splunk-hec" => {
-                    let token = args
-                        .splunk_token
-                        .as_ref()
-                        .context("--splunk-token required")?;
-                    let mut b = String::new();
-                    for e in &buffer {
-                        b.push_str(&serde_json::json!({"time": e.timestamp.timestamp(), "event": e, "sourcetype": "_json"}).to_string());
-                        b.push('\n');
-                    }
-                    http_client
-                        .post(&args.splunk_url)
-                        .header("Authorization", format!("Splunk {}", token))
-                        .body(b)
-                        .send()
-                        .await?;
-                }
-                _ => {}
-            }
+            sink.write(&buffer).await?;
+            confirmed += buffer.len();
             buffer.clear();
             last_flush = Instant::now();
         }
     }
-    Ok(())
+    if !buffer.is_empty() {
+        sink.write(&buffer).await?;
+        confirmed += buffer.len();
+    }
+    Ok(Collected {
+        received: total,
+        confirmed,
+        interrupted,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn same_file_resolves_paths() {
+        let dir = std::env::temp_dir().join(format!("hpfeeds-same-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dead-letter.ndjson");
+        std::fs::write(&path, "").unwrap();
+        let dotted = dir.join(".").join("dead-letter.ndjson");
+
+        assert!(same_file(path.to_str().unwrap(), dotted.to_str().unwrap()));
+        assert!(!same_file(
+            path.to_str().unwrap(),
+            dir.join("missing.ndjson").to_str().unwrap()
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stops_after_max_events() {
+        let path = std::env::temp_dir().join(format!("hpfeeds-max-events-{}", std::process::id()));
+        let args = Args::parse_from([
+            "hpfeeds-collector",
+            "--ident",
+            "i",
+            "--secret",
+            "s",
+            "--output",
+            "file",
+            "--file-path",
+            path.to_str().unwrap(),
+            "--batch-size",
+            "4",
+            "--max-events",
+            "10",
+        ]);
+        // the broker's message of the day is not counted as an event
+        let motd = Ok(Frame::Publish {
+            ident: Bytes::from_static(b"hpfeeds-rs"),
+            channel: Bytes::from_static(MOTD_CHANNEL.as_bytes()),
+            payload: Bytes::from_static(b"welcome"),
+        });
+        let frames = std::iter::once(motd).chain((0..15).map(|i| {
+            Ok::<_, std::io::Error>(Frame::Publish {
+                ident: Bytes::from_static(b"sensor"),
+                channel: Bytes::from_static(b"ch"),
+                payload: Bytes::from(i.to_string()),
+            })
+        }));
+        let mut sink = Router::open(&args).await.unwrap();
+
+        let collected = collect(
+            futures::stream::iter(frames),
+            &mut sink,
+            &args,
+            std::future::pending(),
+        )
+        .await
+        .unwrap();
+
+        let written: Vec<Vec<u8>> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<Event>(l).unwrap().payload)
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(collected.received, 10);
+        assert_eq!(collected.confirmed, 10);
+        let expected: Vec<Vec<u8>> = (0..10).map(|i| i.to_string().into_bytes()).collect();
+        assert_eq!(written, expected);
+    }
+
+    #[tokio::test]
+    async fn pending_batch_is_flushed_on_shutdown() {
+        let path = std::env::temp_dir().join(format!("hpfeeds-shutdown-{}", std::process::id()));
+        let args = Args::parse_from([
+            "hpfeeds-collector",
+            "--ident",
+            "i",
+            "--secret",
+            "s",
+            "--output",
+            "file",
+            "--file-path",
+            path.to_str().unwrap(),
+            "--batch-size",
+            "100",
+            "--flush-interval",
+            "3600",
+        ]);
+        // three events, then a broker that goes quiet without closing the connection
+        let frames = futures::stream::iter((0..3).map(|i| {
+            Ok::<_, std::io::Error>(Frame::Publish {
+                ident: Bytes::from_static(b"sensor"),
+                channel: Bytes::from_static(b"ch"),
+                payload: Bytes::from(i.to_string()),
+            })
+        }))
+        .chain(futures::stream::pending());
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let mut sink = Router::open(&args).await.unwrap();
+
+        stop.send(()).unwrap();
+        let collected = collect(frames, &mut sink, &args, async {
+            stopped.await.ok();
+        })
+        .await
+        .unwrap();
+        sink.close().await.unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap().lines().count();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            collected,
+            Collected {
+                received: 3,
+                confirmed: 3,
+                interrupted: true,
+            }
+        );
+        assert_eq!(written, 3);
+    }
+
+    #[tokio::test]
+    async fn transforms_unwrap_gzipped_envelopes() {
+        use flate2::{Compression, write::GzEncoder};
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join(format!("hpfeeds-transform-{}", std::process::id()));
+        let args = Args::parse_from([
+            "hpfeeds-collector",
+            "--ident=i",
+            "--secret=s",
+            "--output=file",
+            &format!("--file-path={}", path.display()),
+            "--payload-transform=gunzip,json-field:data",
+        ]);
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(br#"{"sensor":"s1","data":"{\"src_ip\":\"10.0.0.1\"}"}"#)
+            .unwrap();
+        let payloads = [gz.finish().unwrap(), b"plain, not gzip".to_vec()];
+        let frames = payloads.into_iter().map(|p| {
+            Ok::<_, std::io::Error>(Frame::Publish {
+                ident: Bytes::from_static(b"sensor"),
+                channel: Bytes::from_static(b"ch"),
+                payload: Bytes::from(p),
+            })
+        });
+        let mut sink = Router::open(&args).await.unwrap();
+
+        collect(
+            futures::stream::iter(frames),
+            &mut sink,
+            &args,
+            std::future::pending(),
+        )
+        .await
+        .unwrap();
+
+        let stored: Vec<Vec<u8>> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<Event>(l).unwrap().payload)
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        // the second payload fails to gunzip and is kept as received
+        assert_eq!(
+            stored,
+            [
+                br#"{"src_ip":"10.0.0.1"}"#.to_vec(),
+                b"plain, not gzip".to_vec()
+            ]
+        );
+    }
 }
@@ -0,0 +1,127 @@
+use crate::event::Event;
+use anyhow::Result;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// Default cap on a single NDJSON record: a full 1MB payload, base64-encoded, plus envelope.
+pub const DEFAULT_MAX_RECORD_LEN: usize = 2 * 1024 * 1024;
+
+/// Reads events from NDJSON as written by the `file` output.
+///
+/// Lines longer than `max_len` are discarded as they are read, without being buffered, so an
+/// over-long or unterminated record cannot exhaust memory.
+pub struct Replay<R> {
+    reader: R,
+    max_len: usize,
+    line: Vec<u8>,
+    line_no: usize,
+    skipped: usize,
+}
+
+impl<R: AsyncBufRead + Unpin> Replay<R> {
+    pub fn new(reader: R, max_len: usize) -> Self {
+        Self {
+            reader,
+            max_len,
+            line: Vec::new(),
+            line_no: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Number of records skipped because they were too long or not valid events.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Returns the next valid event, or `None` at end of input.
+    pub async fn next_event(&mut self) -> Result<Option<Event>> {
+        loop {
+            let fits = match self.next_line().await? {
+                Some(fits) => fits,
+                None => return Ok(None),
+            };
+            if !fits {
+                eprintln!(
+                    "Skipping line {}: record exceeds {} bytes",
+                    self.line_no, self.max_len
+                );
+                self.skipped += 1;
+                continue;
+            }
+            let line = self.line.trim_ascii();
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_slice(line) {
+                Ok(event) => return Ok(Some(event)),
+                Err(e) => {
+                    eprintln!("Skipping line {}: {}", self.line_no, e);
+                    self.skipped += 1;
+                }
+            }
+        }
+    }
+
+    // Reads the next line into `self.line`. Returns `Some(false)` if the line was longer than
+    // `max_len` (and was discarded), `None` at end of input.
+    async fn next_line(&mut self) -> Result<Option<bool>> {
+        self.line.clear();
+        let mut too_long = false;
+        let mut read_any = false;
+        loop {
+            let buf = self.reader.fill_buf().await?;
+            if buf.is_empty() {
+                if !read_any {
+                    return Ok(None);
+                }
+                break;
+            }
+            read_any = true;
+            let (chunk, used, done) = match buf.iter().position(|&b| b == b'\n') {
+                Some(i) => (&buf[..i], i + 1, true),
+                None => (buf, buf.len(), false),
+            };
+            if !too_long {
+                if self.line.len() + chunk.len() > self.max_len {
+                    too_long = true;
+                    self.line.clear();
+                } else {
+                    self.line.extend_from_slice(chunk);
+                }
+            }
+            self.reader.consume(used);
+            if done {
+                break;
+            }
+        }
+        self.line_no += 1;
+        Ok(Some(!too_long))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn skips_over_long_lines() {
+        let long = format!(
+            r#"{{"timestamp":"2024-01-01T00:00:00Z","channel":"big","source":"s","payload":"{}"}}"#,
+            "x".repeat(500)
+        );
+        let input = format!(
+            "{}\n{}\n{}\n",
+            r#"{"timestamp":"2024-01-01T00:00:00Z","channel":"a","source":"s","payload":"one"}"#,
+            long,
+            r#"{"timestamp":"2024-01-01T00:00:00Z","channel":"b","source":"s","payload":"two"}"#,
+        );
+        let mut replay = Replay::new(input.as_bytes(), 200);
+
+        let first = replay.next_event().await.unwrap().unwrap();
+        assert_eq!(first.channel, "a");
+        let second = replay.next_event().await.unwrap().unwrap();
+        assert_eq!(second.channel, "b");
+        assert!(replay.next_event().await.unwrap().is_none());
+        assert_eq!(replay.skipped(), 1);
+    }
+}
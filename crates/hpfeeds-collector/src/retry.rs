@@ -0,0 +1,340 @@
+use crate::event::Event;
+use crate::sinks::ReopenableSink;
+use anyhow::{Context, Result, anyhow};
+use std::borrow::Cow;
+use std::fmt;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+/// How failed sink writes are retried, from `--max-retries`, `--retry-base-ms` and
+/// `--dead-letter-file`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts after the first before the batch is dead-lettered
+    pub max_retries: u32,
+    /// Delay before the first retry, doubling for each one after
+    pub base: Duration,
+    /// NDJSON file batches go to once every attempt has failed
+    pub dead_letter: String,
+}
+
+impl RetryPolicy {
+    /// The delay before retry `retry`, counting from 0.
+    fn delay(&self, retry: u32) -> Duration {
+        self.base.saturating_mul(1 << retry.min(16))
+    }
+}
+
+/// A write that stored only part of its batch. `rejected` holds events the sink refused as
+/// malformed, which would fail again and go straight to the dead-letter file; `retry` holds
+/// the ones that failed for reasons that may pass, such as the cluster being overloaded.
+#[derive(Debug)]
+pub(crate) struct PartialWrite {
+    pub rejected: Vec<Event>,
+    pub retry: Vec<Event>,
+}
+
+impl fmt::Display for PartialWrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} events rejected, {} to retry",
+            self.rejected.len(),
+            self.retry.len()
+        )
+    }
+}
+
+impl std::error::Error for PartialWrite {}
+
+/// Something a batch of events can be written to as a whole.
+pub(crate) trait BatchSink {
+    async fn write_batch(&mut self, batch: &[Event]) -> Result<()>;
+
+    /// Replaces a connection a failed write may have left broken.
+    async fn reopen(&mut self) -> Result<()>;
+}
+
+impl BatchSink for ReopenableSink {
+    async fn write_batch(&mut self, batch: &[Event]) -> Result<()> {
+        self.write(batch).await
+    }
+
+    async fn reopen(&mut self) -> Result<()> {
+        ReopenableSink::reopen(self).await
+    }
+}
+
+/// Writes `batch` to `sink`, retrying with exponential backoff. The sink is reopened before
+/// each retry, since the failure may have been its connection dropping. After a
+/// [`PartialWrite`] its rejected events are dead-lettered at once and only the rest are
+/// retried. Once `policy.max_retries` have failed too, what is left of the batch is appended
+/// to the dead-letter file instead, where `--replay` can pick it up later. Only failing to
+/// write the dead-letter file is an error.
+pub(crate) async fn write_with_retry<S: BatchSink>(
+    sink: &mut S,
+    batch: &[Event],
+    policy: &RetryPolicy,
+) -> Result<()> {
+    let mut batch = Cow::Borrowed(batch);
+    let mut retry = 0;
+    loop {
+        let attempt = async {
+            if retry > 0 {
+                sink.reopen().await.context("reopening sink")?;
+            }
+            sink.write_batch(&batch).await
+        };
+        let err = match attempt.await {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        let err = match err.downcast::<PartialWrite>() {
+            Ok(partial) => {
+                if !partial.rejected.is_empty() {
+                    eprintln!(
+                        "{} events were rejected, saving them to {}",
+                        partial.rejected.len(),
+                        policy.dead_letter
+                    );
+                    dead_letter(&partial.rejected, &policy.dead_letter).await?;
+                }
+                if partial.retry.is_empty() {
+                    return Ok(());
+                }
+                let err = anyhow!("{} of {} events failed", partial.retry.len(), batch.len());
+                batch = Cow::Owned(partial.retry);
+                err
+            }
+            Err(e) => e,
+        };
+        if retry == policy.max_retries {
+            eprintln!(
+                "Writing {} events failed after {} retries, saving them to {}: {:#}",
+                batch.len(),
+                retry,
+                policy.dead_letter,
+                err
+            );
+            return dead_letter(&batch, &policy.dead_letter).await;
+        }
+        let delay = policy.delay(retry);
+        eprintln!(
+            "Writing {} events failed, retrying in {:?}: {:#}",
+            batch.len(),
+            delay,
+            err
+        );
+        tokio::time::sleep(delay).await;
+        retry += 1;
+    }
+}
+
+// Appends `batch` to `path` in the `--output file` format.
+async fn dead_letter(batch: &[Event], path: &str) -> Result<()> {
+    let mut d = String::new();
+    for e in batch {
+        d.push_str(&serde_json::to_string(e)?);
+        d.push('\n');
+    }
+    let mut f = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("opening dead-letter file {}", path))?;
+    f.write_all(d.as_bytes()).await?;
+    f.sync_all().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+
+    // Fails the first `failures` writes, then keeps every batch it is given.
+    struct FlakySink {
+        failures: u32,
+        attempts: u32,
+        written: Vec<Vec<u8>>,
+    }
+
+    impl BatchSink for FlakySink {
+        async fn write_batch(&mut self, batch: &[Event]) -> Result<()> {
+            self.attempts += 1;
+            if self.failures > 0 {
+                self.failures -= 1;
+                bail!("connection reset");
+            }
+            self.written.extend(batch.iter().map(|e| e.payload.clone()));
+            Ok(())
+        }
+
+        async fn reopen(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    // Loses its connection partway through the first batch, after which every write fails
+    // until it is reopened. Like the Postgres sink, a batch is kept only if all of it lands.
+    struct DroppingSink {
+        connected: bool,
+        drop_at: Option<usize>,
+        reopens: u32,
+        written: Vec<Vec<u8>>,
+    }
+
+    impl BatchSink for DroppingSink {
+        async fn write_batch(&mut self, batch: &[Event]) -> Result<()> {
+            let mut tx = Vec::new();
+            for (i, e) in batch.iter().enumerate() {
+                if self.drop_at.take_if(|at| *at == i).is_some() {
+                    self.connected = false;
+                }
+                if !self.connected {
+                    bail!("connection closed");
+                }
+                tx.push(e.payload.clone());
+            }
+            self.written.extend(tx);
+            Ok(())
+        }
+
+        async fn reopen(&mut self) -> Result<()> {
+            self.reopens += 1;
+            self.connected = true;
+            Ok(())
+        }
+    }
+
+    // Rejects payload "1" outright and is too busy for payload "2" the first time round, as a
+    // bulk API can be; everything else is stored.
+    struct BulkSink {
+        busy: bool,
+        written: Vec<Vec<u8>>,
+    }
+
+    impl BatchSink for BulkSink {
+        async fn write_batch(&mut self, batch: &[Event]) -> Result<()> {
+            let mut partial = PartialWrite {
+                rejected: Vec::new(),
+                retry: Vec::new(),
+            };
+            for e in batch {
+                match &e.payload[..] {
+                    b"1" => partial.rejected.push(e.clone()),
+                    b"2" if std::mem::take(&mut self.busy) => partial.retry.push(e.clone()),
+                    _ => self.written.push(e.payload.clone()),
+                }
+            }
+            if partial.rejected.is_empty() && partial.retry.is_empty() {
+                return Ok(());
+            }
+            Err(partial.into())
+        }
+
+        async fn reopen(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn policy(max_retries: u32, dead_letter: &std::path::Path) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            base: Duration::from_millis(100),
+            dead_letter: dead_letter.to_str().unwrap().to_string(),
+        }
+    }
+
+    fn events() -> Vec<Event> {
+        (0..3)
+            .map(|i| Event::new("ch".into(), "s".into(), i.to_string().into_bytes()))
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transient_failures_are_retried_until_the_batch_lands() {
+        let path = std::env::temp_dir().join(format!("hpfeeds-dlq-retry-{}", std::process::id()));
+        let mut sink = FlakySink {
+            failures: 2,
+            attempts: 0,
+            written: Vec::new(),
+        };
+        let started = tokio::time::Instant::now();
+
+        write_with_retry(&mut sink, &events(), &policy(3, &path))
+            .await
+            .unwrap();
+
+        assert_eq!(sink.attempts, 3);
+        assert_eq!(sink.written, [b"0".to_vec(), b"1".to_vec(), b"2".to_vec()]);
+        // 100ms, then 200ms
+        assert_eq!(started.elapsed(), Duration::from_millis(300));
+        assert!(!path.exists());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_connection_lost_mid_batch_is_reopened_and_the_batch_written_once() {
+        let path = std::env::temp_dir().join(format!("hpfeeds-dlq-drop-{}", std::process::id()));
+        let mut sink = DroppingSink {
+            connected: true,
+            drop_at: Some(1),
+            reopens: 0,
+            written: Vec::new(),
+        };
+
+        write_with_retry(&mut sink, &events(), &policy(3, &path))
+            .await
+            .unwrap();
+
+        assert_eq!(sink.reopens, 1);
+        assert_eq!(sink.written, [b"0".to_vec(), b"1".to_vec(), b"2".to_vec()]);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batches_failing_every_retry_are_dead_lettered() {
+        let path = std::env::temp_dir().join(format!("hpfeeds-dlq-{}", std::process::id()));
+        let mut sink = FlakySink {
+            failures: u32::MAX,
+            attempts: 0,
+            written: Vec::new(),
+        };
+
+        write_with_retry(&mut sink, &events(), &policy(2, &path))
+            .await
+            .unwrap();
+
+        let saved: Vec<Vec<u8>> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<Event>(l).unwrap().payload)
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(sink.attempts, 3);
+        assert_eq!(saved, [b"0".to_vec(), b"1".to_vec(), b"2".to_vec()]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_failed_items_are_retried_and_rejected_ones_dead_lettered() {
+        let path = std::env::temp_dir().join(format!("hpfeeds-dlq-bulk-{}", std::process::id()));
+        let mut sink = BulkSink {
+            busy: true,
+            written: Vec::new(),
+        };
+
+        write_with_retry(&mut sink, &events(), &policy(3, &path))
+            .await
+            .unwrap();
+
+        let saved: Vec<Vec<u8>> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<Event>(l).unwrap().payload)
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(sink.written, [b"0".to_vec(), b"2".to_vec()]);
+        assert_eq!(saved, [b"1".to_vec()]);
+    }
+}
@@ -0,0 +1,263 @@
+use crate::Args;
+use crate::event::Event;
+use crate::retry::{RetryPolicy, write_with_retry};
+use crate::sinks::ReopenableSink;
+use anyhow::{Context, Result, anyhow, bail};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Routing file given with `--routes`.
+#[derive(Debug, Deserialize)]
+pub struct RoutesConfig {
+    pub routes: Vec<RouteConfig>,
+}
+
+/// Sends events on any of `channels` to a sink of its own.
+#[derive(Debug, Deserialize)]
+pub struct RouteConfig {
+    /// Exact channel names, or patterns ending in `*` to match a prefix
+    pub channels: Vec<String>,
+    /// Output mode, as for `--output`
+    pub output: String,
+    /// Overrides for the connection flags, keyed by flag name (e.g. `file_path`, `kafka_topic`)
+    #[serde(default)]
+    pub settings: HashMap<String, String>,
+}
+
+pub fn load_routes(path: &str) -> Result<RoutesConfig> {
+    let content = std::fs::read_to_string(path)?;
+    serde_json::from_str(&content).with_context(|| format!("invalid routes file {}", path))
+}
+
+/// True if `channel` is matched by `pattern`: equal to it, or starting with whatever precedes a
+/// trailing `*`.
+pub fn pattern_matches(pattern: &str, channel: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => channel.starts_with(prefix),
+        None => pattern == channel,
+    }
+}
+
+struct Route {
+    channels: Vec<String>,
+    sink: ReopenableSink,
+}
+
+/// Dispatches each event to every sink whose route matches its channel, and to the fallback
+/// sink if none do. Each sink's share of a batch is retried on its own, so a sink that already
+/// took its events is not sent them again.
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: ReopenableSink,
+    /// Console sink every event is also printed to, with `--tee-console`
+    tee: Option<ReopenableSink>,
+    retry: RetryPolicy,
+}
+
+impl Router {
+    /// Opens the fallback `--output` sink and, when `--routes` is given, a sink per route.
+    pub async fn open(args: &Args) -> Result<Router> {
+        let config = match &args.routes {
+            Some(path) => load_routes(path)?,
+            None => RoutesConfig { routes: Vec::new() },
+        };
+        Self::from_config(config, args).await
+    }
+
+    pub async fn from_config(config: RoutesConfig, args: &Args) -> Result<Router> {
+        let mut routes = Vec::with_capacity(config.routes.len());
+        for route in config.routes {
+            let route_args = with_settings(args, &route.settings)?;
+            routes.push(Route {
+                sink: ReopenableSink::open(&route.output, &route_args).await?,
+                channels: route.channels,
+            });
+        }
+        // a console output already prints everything
+        let tee = if args.tee_console && args.output != "console" {
+            Some(ReopenableSink::open("console", args).await?)
+        } else {
+            None
+        };
+        Ok(Router {
+            routes,
+            fallback: ReopenableSink::open(&args.output, args).await?,
+            tee,
+            retry: RetryPolicy {
+                max_retries: args.max_retries,
+                base: Duration::from_millis(args.retry_base_ms),
+                dead_letter: args.dead_letter_file.clone(),
+            },
+        })
+    }
+
+    /// Writes one batch, split per sink. A sink's events are dead-lettered rather than
+    /// failing the batch once its retries run out, so an error means they could not be saved
+    /// even there.
+    pub async fn write(&mut self, buffer: &[Event]) -> Result<()> {
+        if let Some(tee) = &mut self.tee {
+            write_with_retry(tee, buffer, &self.retry).await?;
+        }
+        let mut unrouted = Vec::new();
+        let mut routed: Vec<Vec<Event>> = vec![Vec::new(); self.routes.len()];
+        for event in buffer {
+            let mut matched = false;
+            for (route, events) in self.routes.iter().zip(routed.iter_mut()) {
+                if route
+                    .channels
+                    .iter()
+                    .any(|p| pattern_matches(p, &event.channel))
+                {
+                    events.push(event.clone());
+                    matched = true;
+                }
+            }
+            if !matched {
+                unrouted.push(event.clone());
+            }
+        }
+        for (route, events) in self.routes.iter_mut().zip(routed) {
+            if !events.is_empty() {
+                write_with_retry(&mut route.sink, &events, &self.retry).await?;
+            }
+        }
+        if !unrouted.is_empty() {
+            write_with_retry(&mut self.fallback, &unrouted, &self.retry).await?;
+        }
+        Ok(())
+    }
+
+    /// Closes every sink, reporting the first failure.
+    pub async fn close(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for route in &mut self.routes {
+            result = result.and(route.sink.close().await);
+        }
+        result.and(self.fallback.close().await)
+    }
+}
+
+// A copy of `args` with the route's connection settings applied.
+fn with_settings(args: &Args, settings: &HashMap<String, String>) -> Result<Args> {
+    let mut a = args.clone();
+    for (key, value) in settings {
+        let value = value.clone();
+        match key.as_str() {
+            "file_path" => a.file_path = Some(value),
+            "stix_mapping" => a.stix_mapping = Some(value),
+            "stix_mode" => {
+                a.stix_mode =
+                    ValueEnum::from_str(&value, true).map_err(|e| anyhow!("stix_mode: {}", e))?
+            }
+            "redis_url" => a.redis_url = value,
+            "redis_channel" => a.redis_channel = value,
+            "postgres_url" => a.postgres_url = value,
+            "mongo_url" => a.mongo_url = value,
+            "elastic_url" => a.elastic_url = value,
+            "es_flatten" => a.es_flatten = Some(value.parse()?),
+            "opensearch_url" => a.opensearch_url = value,
+            "opensearch_data_stream" => a.opensearch_data_stream = value,
+            "splunk_url" => a.splunk_url = value,
+            "splunk_token" => a.splunk_token = Some(value),
+            "kafka_url" => a.kafka_url = value,
+            "kafka_topic" => a.kafka_topic = value,
+            "syslog_addr" => a.syslog_addr = value,
+            "syslog_transport" => {
+                a.syslog_transport = ValueEnum::from_str(&value, true)
+                    .map_err(|e| anyhow!("syslog_transport: {}", e))?
+            }
+            "syslog_encoding" => {
+                a.syslog_encoding = ValueEnum::from_str(&value, true)
+                    .map_err(|e| anyhow!("syslog_encoding: {}", e))?
+            }
+            "syslog_max_len" => a.syslog_max_len = Some(value.parse()?),
+            "tcp_addr" => a.tcp_addr = value,
+            "tcp_tls" => a.tcp_tls = value.parse()?,
+            "tls_ca" => a.tls_ca = Some(value),
+            "splunk_ca" => a.splunk_ca = Some(value),
+            "splunk_insecure" => a.splunk_insecure = value.parse()?,
+            other => bail!("unknown route setting: {}", other),
+        }
+    }
+    Ok(a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn event(channel: &str, payload: &str) -> Event {
+        Event::new(channel.into(), "sensor".into(), payload.as_bytes().to_vec())
+    }
+
+    fn channels_in(path: &std::path::Path) -> Vec<String> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<Event>(l).unwrap().channel)
+            .collect()
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(pattern_matches("cowrie.*", "cowrie.sessions"));
+        assert!(!pattern_matches("cowrie.*", "dionaea.capture"));
+        assert!(pattern_matches("*", "anything"));
+        assert!(pattern_matches("pcap", "pcap"));
+        assert!(!pattern_matches("pcap", "pcap.raw"));
+    }
+
+    #[tokio::test]
+    async fn routes_channels_to_their_sinks() {
+        let dir = std::env::temp_dir().join(format!("hpfeeds-routes-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cowrie, pcap, rest) = (
+            dir.join("cowrie.ndjson"),
+            dir.join("pcap.ndjson"),
+            dir.join("rest.ndjson"),
+        );
+        let config: RoutesConfig = serde_json::from_value(serde_json::json!({
+            "routes": [
+                {"channels": ["cowrie.*"], "output": "file",
+                 "settings": {"file_path": cowrie.to_str().unwrap()}},
+                {"channels": ["pcap.*"], "output": "file",
+                 "settings": {"file_path": pcap.to_str().unwrap()}},
+            ]
+        }))
+        .unwrap();
+        let args = Args::parse_from([
+            "hpfeeds-collector",
+            "--ident",
+            "i",
+            "--secret",
+            "s",
+            "--output",
+            "file",
+            "--file-path",
+            rest.to_str().unwrap(),
+        ]);
+
+        let mut router = Router::from_config(config, &args).await.unwrap();
+        router
+            .write(&[
+                event("cowrie.sessions", "a"),
+                event("pcap.raw", "b"),
+                event("cowrie.sessions", "c"),
+                event("other", "d"),
+            ])
+            .await
+            .unwrap();
+        drop(router);
+
+        assert_eq!(
+            channels_in(&cowrie),
+            vec!["cowrie.sessions", "cowrie.sessions"]
+        );
+        assert_eq!(channels_in(&pcap), vec!["pcap.raw"]);
+        assert_eq!(channels_in(&rest), vec!["other"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
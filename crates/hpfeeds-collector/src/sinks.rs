@@ -0,0 +1,668 @@
+use crate::Args;
+use crate::event::Event;
+use crate::flatten::flatten_payload;
+use crate::retry::PartialWrite;
+use crate::stix::{self, StixMapping, StixMode};
+use crate::syslog::{SyslogEncoding, SyslogTransport, format_message, octet_counted};
+use anyhow::{Context, Result, bail};
+use elasticsearch::{BulkIndexOperation, BulkOperations, BulkParts, Elasticsearch};
+use mongodb::{Client as MongoClient, Collection, options::ClientOptions as MongoOptions};
+use rskafka::client::{
+    ClientBuilder as KafkaClientBuilder,
+    partition::{Compression, PartitionClient, UnknownTopicHandling},
+};
+use rskafka::record::Record;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio_postgres::NoTls;
+
+/// A configured output that batches of events are written to.
+pub enum Sink {
+    Console,
+    File(tokio::fs::File),
+    Stix {
+        file: tokio::fs::File,
+        mode: StixMode,
+        mapping: Option<StixMapping>,
+    },
+    Redis {
+        conn: redis::aio::MultiplexedConnection,
+        channel: String,
+    },
+    Postgres(tokio_postgres::Client),
+    Mongo(Collection<Event>),
+    Elastic {
+        client: Elasticsearch,
+        /// `--es-flatten` depth
+        flatten: Option<usize>,
+    },
+    /// Bulk `create` requests against a data stream
+    OpenSearch {
+        client: reqwest::Client,
+        /// The data stream's `_bulk` endpoint
+        url: String,
+    },
+    Kafka(PartitionClient),
+    Syslog {
+        conn: SyslogConn,
+        encoding: SyslogEncoding,
+        max_len: Option<usize>,
+    },
+    Tcp(ByteStream),
+    SplunkHec {
+        client: reqwest::Client,
+        url: String,
+        token: String,
+    },
+}
+
+/// A sink together with the output mode and settings it was opened from, so that a connection
+/// broken by a failed write can be replaced by a fresh one.
+pub struct ReopenableSink {
+    output: String,
+    args: Args,
+    sink: Sink,
+}
+
+impl ReopenableSink {
+    /// Opens the sink for `output`, as [`Sink::open`] does.
+    pub async fn open(output: &str, args: &Args) -> Result<ReopenableSink> {
+        Ok(ReopenableSink {
+            output: output.to_string(),
+            args: args.clone(),
+            sink: Sink::open(output, args).await?,
+        })
+    }
+
+    /// Drops the current sink and opens a new one in its place.
+    pub async fn reopen(&mut self) -> Result<()> {
+        self.sink = Sink::open(&self.output, &self.args).await?;
+        Ok(())
+    }
+
+    pub async fn write(&mut self, buffer: &[Event]) -> Result<()> {
+        self.sink.write(buffer).await
+    }
+
+    pub async fn close(&mut self) -> Result<()> {
+        self.sink.close().await
+    }
+}
+
+/// A plain or TLS connection that sinks write a byte stream to.
+pub type ByteStream = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// The connection to a syslog server.
+pub enum SyslogConn {
+    Udp(tokio::net::UdpSocket),
+    /// TCP or TLS, with octet-counted framing
+    Stream(ByteStream),
+}
+
+/// Connects to `addr`, over TLS if `tls` is set.
+async fn connect_stream(addr: &str, tls: bool, args: &Args) -> Result<ByteStream> {
+    if !tls {
+        return Ok(Box::new(tokio::net::TcpStream::connect(addr).await?));
+    }
+    #[cfg(feature = "tls")]
+    return Ok(Box::new(
+        crate::tls::connect(addr, args.tls_ca.as_deref(), None).await?,
+    ));
+    #[cfg(not(feature = "tls"))]
+    {
+        let _ = args;
+        bail!(
+            "TLS to {} requested, but the collector was built without the tls feature",
+            addr
+        )
+    }
+}
+
+impl Sink {
+    /// Opens the sink for `output` using the connection settings in `args`.
+    pub async fn open(output: &str, args: &Args) -> Result<Sink> {
+        let sink = match output {
+            "console" => Sink::Console,
+            "file" | "stix" => {
+                let p = args.file_path.as_ref().context("--file-path required")?;
+                let f = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(p)
+                    .await?;
+                if output == "file" {
+                    Sink::File(f)
+                } else {
+                    Sink::Stix {
+                        file: f,
+                        mode: args.stix_mode,
+                        mapping: args
+                            .stix_mapping
+                            .as_deref()
+                            .map(StixMapping::load)
+                            .transpose()?,
+                    }
+                }
+            }
+            "redis" => Sink::Redis {
+                conn: redis::Client::open(args.redis_url.clone())?
+                    .get_multiplexed_async_connection()
+                    .await?,
+                channel: args.redis_channel.clone(),
+            },
+            "postgres" => {
+                let (client, connection) =
+                    tokio_postgres::connect(&args.postgres_url, NoTls).await?;
+                tokio::spawn(async move {
+                    if let Err(e) = connection.await {
+                        eprintln!("Postgres connection error: {}", e);
+                    }
+                });
+                client.execute("CREATE TABLE IF NOT EXISTS events (id SERIAL PRIMARY KEY, ts TIMESTAMPTZ, channel TEXT, source TEXT, payload BYTEA)", &[]).await?;
+                Sink::Postgres(client)
+            }
+            "mongo" => {
+                let c = MongoClient::with_options(MongoOptions::parse(&args.mongo_url).await?)?;
+                Sink::Mongo(c.database("hpfeeds").collection::<Event>("events"))
+            }
+            "elastic" => Sink::Elastic {
+                client: Elasticsearch::new(elasticsearch::http::transport::Transport::single_node(
+                    &args.elastic_url,
+                )?),
+                flatten: args.es_flatten.map(|d| d as usize),
+            },
+            "opensearch" => Sink::OpenSearch {
+                client: reqwest::Client::new(),
+                url: format!(
+                    "{}/{}/_bulk",
+                    args.opensearch_url.trim_end_matches('/'),
+                    args.opensearch_data_stream
+                ),
+            },
+            "kafka" => {
+                let client = KafkaClientBuilder::new(vec![args.kafka_url.clone()])
+                    .build()
+                    .await?;
+                Sink::Kafka(
+                    client
+                        .partition_client(args.kafka_topic.clone(), 0, UnknownTopicHandling::Retry)
+                        .await?,
+                )
+            }
+            "syslog" => Sink::Syslog {
+                conn: match args.syslog_transport {
+                    SyslogTransport::Udp => {
+                        let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+                        socket.connect(&args.syslog_addr).await?;
+                        SyslogConn::Udp(socket)
+                    }
+                    transport => SyslogConn::Stream(
+                        connect_stream(&args.syslog_addr, transport == SyslogTransport::Tls, args)
+                            .await?,
+                    ),
+                },
+                encoding: args.syslog_encoding,
+                max_len: args.syslog_max_len,
+            },
+            "tcp" => Sink::Tcp(connect_stream(&args.tcp_addr, args.tcp_tls, args).await?),
+            "splunk-hec" => Sink::SplunkHec {
+                client: splunk_client(args)?,
+                url: args.splunk_url.clone(),
+                token: args
+                    .splunk_token
+                    .clone()
+                    .context("--splunk-token required")?,
+            },
+            other => bail!("unknown output mode: {}", other),
+        };
+        Ok(sink)
+    }
+
+    /// Writes one batch of events.
+    pub async fn write(&mut self, buffer: &[Event]) -> Result<()> {
+        match self {
+            Sink::Console => {
+                for e in buffer {
+                    println!("{}", serde_json::to_string(e)?);
+                }
+            }
+            Sink::File(f) => {
+                let mut d = String::new();
+                for e in buffer {
+                    d.push_str(&serde_json::to_string(e)?);
+                    d.push('\n');
+                }
+                f.write_all(d.as_bytes()).await?;
+                f.flush().await?;
+            }
+            Sink::Stix {
+                file,
+                mode,
+                mapping,
+            } => {
+                let mapping = mapping.as_ref();
+                let mut d = match mode {
+                    StixMode::Bundle => {
+                        serde_json::to_string_pretty(&stix::bundle(buffer, mapping))?
+                    }
+                    StixMode::Ndjson => stix::objects(buffer, mapping)
+                        .iter()
+                        .map(serde_json::Value::to_string)
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                };
+                d.push('\n');
+                file.write_all(d.as_bytes()).await?;
+                file.flush().await?;
+            }
+            Sink::Redis { conn, channel } => {
+                // pub/sub cannot tell a repeat apart, so a retry after a failure partway through
+                // publishes the events before it again
+                for e in buffer {
+                    let _: () =
+                        redis::AsyncCommands::publish(conn, &*channel, serde_json::to_string(e)?)
+                            .await?;
+                }
+            }
+            Sink::Postgres(client) => {
+                // all or nothing, so a retry after a failure partway through adds no duplicates
+                let tx = client.transaction().await?;
+                let insert = tx
+                    .prepare(
+                        "INSERT INTO events (ts, channel, source, payload) VALUES ($1, $2, $3, $4)",
+                    )
+                    .await?;
+                for e in buffer {
+                    tx.execute(&insert, &[&e.timestamp, &e.channel, &e.source, &e.payload])
+                        .await?;
+                }
+                tx.commit().await?;
+            }
+            Sink::Mongo(coll) => {
+                coll.insert_many(buffer).await?;
+            }
+            Sink::Elastic { client, flatten } => {
+                let mut ops = BulkOperations::new();
+                for e in buffer {
+                    ops.push(BulkIndexOperation::new(elastic_doc(e, *flatten)?).id(e.id()))
+                        .unwrap();
+                }
+                let response = client
+                    .bulk(BulkParts::Index("hpfeeds-events"))
+                    .body(vec![ops])
+                    .send()
+                    .await?
+                    .error_for_status_code()?;
+                // a bulk request succeeds as a whole even when some documents are rejected
+                let body: serde_json::Value = response.json().await?;
+                check_bulk_items(&body, "index", buffer)?;
+            }
+            Sink::OpenSearch { client, url } => {
+                let response = client
+                    .post(&*url)
+                    .header("Content-Type", "application/x-ndjson")
+                    .body(opensearch_bulk_body(buffer)?)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                let body: serde_json::Value = response.json().await?;
+                check_bulk_items(&body, "create", buffer)?;
+            }
+            Sink::Kafka(p) => {
+                let records: Vec<Record> = buffer
+                    .iter()
+                    .map(|e| Record {
+                        key: Some(e.channel.as_bytes().to_vec()),
+                        value: Some(serde_json::to_vec(e).unwrap()),
+                        timestamp: rskafka::chrono::Utc::now(),
+                        headers: Default::default(),
+                    })
+                    .collect();
+                let offsets = p.produce(records, Compression::NoCompression).await?;
+                if offsets.len() != buffer.len() {
+                    bail!(
+                        "kafka acknowledged {} of {} events",
+                        offsets.len(),
+                        buffer.len()
+                    );
+                }
+            }
+            Sink::Syslog {
+                conn,
+                encoding,
+                max_len,
+            } => {
+                // as with tcp, a retry re-sends the whole batch, so a receiver sees whatever
+                // reached it before the connection dropped twice
+                for e in buffer {
+                    let msg = format_message(e, *encoding, *max_len)?;
+                    match conn {
+                        SyslogConn::Udp(socket) => {
+                            socket.send(msg.as_bytes()).await?;
+                        }
+                        SyslogConn::Stream(stream) => {
+                            stream.write_all(octet_counted(&msg).as_bytes()).await?;
+                        }
+                    }
+                }
+                if let SyslogConn::Stream(stream) = conn {
+                    stream.flush().await?;
+                }
+            }
+            Sink::Tcp(s) => {
+                // a retry re-sends the whole batch, including any of it that got through
+                let mut d = String::new();
+                for e in buffer {
+                    d.push_str(&serde_json::to_string(e)?);
+                    d.push('\n');
+                }
+                s.write_all(d.as_bytes()).await?;
+                s.flush().await?;
+            }
+            Sink::SplunkHec { client, url, token } => {
+                let mut b = String::new();
+                for e in buffer {
+                    b.push_str(&serde_json::json!({"time": e.timestamp.timestamp(), "event": e, "sourcetype": "_json"}).to_string());
+                    b.push('\n');
+                }
+                client
+                    .post(&*url)
+                    .header("Authorization", format!("Splunk {}", token))
+                    .body(b)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finishes any open stream before exit, so TLS peers see a clean close rather than a reset.
+    pub async fn close(&mut self) -> Result<()> {
+        match self {
+            Sink::File(f) | Sink::Stix { file: f, .. } => f.sync_all().await?,
+            Sink::Tcp(s)
+            | Sink::Syslog {
+                conn: SyslogConn::Stream(s),
+                ..
+            } => s.shutdown().await?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// HTTP client for the HEC endpoint. Certificates are verified against the system's public
+/// roots plus `--splunk-ca`, unless `--splunk-insecure` turns verification off.
+fn splunk_client(args: &Args) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(path) = &args.splunk_ca {
+        let pem = std::fs::read(path).with_context(|| format!("reading {}", path))?;
+        for cert in reqwest::Certificate::from_pem_bundle(&pem)? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    if args.splunk_insecure {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    Ok(builder.build()?)
+}
+
+// The document indexed for `e`, with the payload's fields flattened alongside it when
+// `--es-flatten` is set and the payload is a JSON object.
+fn elastic_doc(e: &Event, flatten: Option<usize>) -> Result<serde_json::Value> {
+    let mut doc = serde_json::to_value(e)?;
+    if let Some(fields) = flatten.and_then(|depth| flatten_payload(&e.payload, depth)) {
+        doc["payload_fields"] = fields.into();
+    }
+    Ok(doc)
+}
+
+// Bulk request body creating one document per event. Data streams only accept the `create`
+// op, and need an `@timestamp` field on every document. The event id as `_id` makes a
+// document already stored by an earlier attempt a conflict rather than a second copy.
+fn opensearch_bulk_body(events: &[Event]) -> Result<String> {
+    let mut body = String::new();
+    for e in events {
+        let mut doc = serde_json::to_value(e)?;
+        doc["@timestamp"] = e.timestamp.to_rfc3339().into();
+        body.push_str(&serde_json::json!({"create": {"_id": e.id()}}).to_string());
+        body.push('\n');
+        body.push_str(&doc.to_string());
+        body.push('\n');
+    }
+    Ok(body)
+}
+
+// Checks the `op` items of a bulk response for `events`, in the same order. The request as a
+// whole succeeds even when some documents fail, so a failure comes back as a [`PartialWrite`]:
+// 429 and 5xx items are worth retrying, other 4xx ones are rejections that would only fail
+// again. A 409 is a document an earlier attempt already stored.
+fn check_bulk_items(response: &serde_json::Value, op: &str, events: &[Event]) -> Result<()> {
+    if response["errors"].as_bool() != Some(true) {
+        return Ok(());
+    }
+    let items = response["items"]
+        .as_array()
+        .filter(|items| items.len() == events.len())
+        .context("bulk response items do not match the request")?;
+    let mut partial = PartialWrite {
+        rejected: Vec::new(),
+        retry: Vec::new(),
+    };
+    for (item, e) in items.iter().zip(events) {
+        let item = &item[op];
+        if item["error"].is_null() {
+            continue;
+        }
+        match item["status"].as_u64().unwrap_or(500) {
+            409 => {}
+            429 | 500.. => partial.retry.push(e.clone()),
+            _ => partial.rejected.push(e.clone()),
+        }
+    }
+    if partial.rejected.is_empty() && partial.retry.is_empty() {
+        return Ok(());
+    }
+    Err(partial.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opensearch_bulk_body_creates_timestamped_documents() {
+        let events = vec![
+            Event::new("a".into(), "s".into(), b"one".to_vec()),
+            Event::new("b".into(), "s".into(), b"two".to_vec()),
+        ];
+        let body = opensearch_bulk_body(&events).unwrap();
+        let lines: Vec<serde_json::Value> = body
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        for (pair, event) in lines.chunks(2).zip(&events) {
+            assert_eq!(pair[0], serde_json::json!({"create": {"_id": event.id()}}));
+            assert_eq!(pair[1]["channel"], event.channel);
+            assert_eq!(pair[1]["@timestamp"], event.timestamp.to_rfc3339());
+        }
+        assert!(body.ends_with('\n'));
+    }
+
+    #[test]
+    fn elastic_doc_flattens_json_payloads() {
+        let nested = Event::new(
+            "cowrie.sessions".into(),
+            "s".into(),
+            br#"{"session":{"peer":{"ip":"192.0.2.1"}}}"#.to_vec(),
+        );
+        let doc = elastic_doc(&nested, Some(2)).unwrap();
+        assert_eq!(
+            doc["payload_fields"],
+            serde_json::json!({"session.peer": r#"{"ip":"192.0.2.1"}"#})
+        );
+        assert!(doc["payload"].is_string());
+
+        assert!(
+            elastic_doc(&nested, None)
+                .unwrap()
+                .get("payload_fields")
+                .is_none()
+        );
+        let text = Event::new("ch".into(), "s".into(), b"plain".to_vec());
+        assert!(
+            elastic_doc(&text, Some(2))
+                .unwrap()
+                .get("payload_fields")
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn stix_ndjson_writes_one_object_per_line() {
+        use clap::Parser;
+        let path = std::env::temp_dir().join(format!("hpfeeds-stix-{}", std::process::id()));
+        let args = Args::parse_from([
+            "hpfeeds-collector",
+            "--ident",
+            "i",
+            "--secret",
+            "s",
+            "--output",
+            "stix",
+            "--stix-mode",
+            "ndjson",
+            "--file-path",
+            path.to_str().unwrap(),
+        ]);
+        let mut sink = Sink::open("stix", &args).await.unwrap();
+        let events: Vec<Event> = (0..3)
+            .map(|i| Event::new("cowrie".into(), "s".into(), vec![i]))
+            .collect();
+        sink.write(&events).await.unwrap();
+        sink.write(&events[..1]).await.unwrap();
+        sink.close().await.unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let objects: Vec<serde_json::Value> = written
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(objects.len(), 8);
+        for pair in objects.chunks(2) {
+            assert_eq!(pair[0]["type"], "observed-data");
+            assert_eq!(pair[1]["type"], "sighting");
+            assert_eq!(pair[1]["sighting_of_ref"], pair[0]["id"]);
+            for o in pair {
+                assert_eq!(o["spec_version"], "2.1");
+                let id = o["id"].as_str().unwrap();
+                assert!(id.starts_with(&format!("{}--", o["type"].as_str().unwrap())));
+            }
+        }
+    }
+
+    #[test]
+    fn bulk_items_split_into_rejections_and_retries() {
+        let events: Vec<Event> = (0..5)
+            .map(|i| Event::new("ch".into(), "s".into(), vec![i]))
+            .collect();
+        let ok = serde_json::json!({"errors": false, "items": [{"create": {"status": 201}}]});
+        assert!(check_bulk_items(&ok, "create", &events).is_ok());
+
+        let failed = |status: u16| serde_json::json!({"status": status, "error": {"type": "x"}});
+        let partial = serde_json::json!({"errors": true, "items": [
+            {"create": {"status": 201}},
+            {"create": failed(400)},
+            {"create": failed(409)},
+            {"create": failed(429)},
+            {"create": failed(503)},
+        ]});
+        let err = check_bulk_items(&partial, "create", &events).unwrap_err();
+        let partial = err.downcast::<PartialWrite>().unwrap();
+        let payloads = |events: &[Event]| events.iter().map(|e| e.payload[0]).collect::<Vec<_>>();
+        assert_eq!(payloads(&partial.rejected), [1]);
+        assert_eq!(payloads(&partial.retry), [3, 4]);
+
+        let short = serde_json::json!({"errors": true, "items": [{"create": failed(400)}]});
+        let err = check_bulk_items(&short, "create", &events).unwrap_err();
+        assert!(err.downcast_ref::<PartialWrite>().is_none());
+    }
+}
+
+// Needs a running cluster: `OPENSEARCH_URL=http://localhost:9200 cargo test -p
+// hpfeeds-collector --features opensearch-tests`.
+#[cfg(all(test, feature = "opensearch-tests"))]
+mod opensearch_tests {
+    use super::*;
+    use clap::Parser;
+
+    #[tokio::test]
+    async fn documents_land_in_the_data_stream() {
+        let url =
+            std::env::var("OPENSEARCH_URL").unwrap_or_else(|_| "http://localhost:9200".into());
+        let stream = format!("hpfeeds-test-{}", uuid::Uuid::new_v4().simple());
+        let http = reqwest::Client::new();
+        http.put(format!("{}/_index_template/{}", url, stream))
+            .json(&serde_json::json!({"index_patterns": [&stream], "data_stream": {}}))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+
+        let args = Args::parse_from([
+            "hpfeeds-collector",
+            "--ident",
+            "i",
+            "--secret",
+            "s",
+            "--output",
+            "opensearch",
+            "--opensearch-url",
+            &url,
+            "--opensearch-data-stream",
+            &stream,
+        ]);
+        let mut sink = Sink::open("opensearch", &args).await.unwrap();
+        let events: Vec<Event> = (0..3)
+            .map(|i| Event::new("cowrie".into(), "s".into(), i.to_string().into_bytes()))
+            .collect();
+        sink.write(&events).await.unwrap();
+
+        http.post(format!("{}/{}/_refresh", url, stream))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+        let info: serde_json::Value = http
+            .get(format!("{}/_data_stream/{}", url, stream))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(info["data_streams"][0]["name"], stream);
+        let count: serde_json::Value = http
+            .get(format!("{}/{}/_count", url, stream))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(count["count"], 3);
+
+        http.delete(format!("{}/_data_stream/{}", url, stream))
+            .send()
+            .await
+            .unwrap();
+        http.delete(format!("{}/_index_template/{}", url, stream))
+            .send()
+            .await
+            .unwrap();
+    }
+}
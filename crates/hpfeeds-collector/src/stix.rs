@@ -0,0 +1,281 @@
+use crate::event::Event;
+use anyhow::{Context, Result, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use clap::ValueEnum;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+/// How the stix sink lays out each flush.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StixMode {
+    /// One pretty-printed bundle per flush
+    Bundle,
+    /// One STIX object per line, for streaming ingestion
+    Ndjson,
+}
+
+/// A custom object shape, loaded from `--stix-mapping`, written instead of the default
+/// observed-data and sighting.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StixMapping {
+    /// STIX type of the single object written per event, e.g. `indicator`
+    #[serde(rename = "type")]
+    pub object_type: String,
+    /// The object's properties besides `type`, `id` and `spec_version`. String values are
+    /// templates in which `{field}` is replaced by a field of the event: `channel`, `source`,
+    /// `timestamp`, `payload` (base64), `payload_text`, `payload_hex`, `payload_hash`, or
+    /// `payload.<dotted path>` into a JSON payload. A string that is a single placeholder takes
+    /// the field's JSON value as is. A property whose fields are missing is left out. Other
+    /// values are copied unchanged.
+    #[serde(default)]
+    pub properties: Map<String, Value>,
+}
+
+impl StixMapping {
+    pub fn load(path: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+        let mapping: StixMapping = serde_json::from_str(&content)
+            .with_context(|| format!("invalid STIX mapping {}", path))?;
+        mapping.check()?;
+        Ok(mapping)
+    }
+
+    // Rejects templates that are malformed or name an unknown field, rather than leaving the
+    // property out of every object.
+    fn check(&self) -> Result<()> {
+        for (name, value) in &self.properties {
+            let Value::String(template) = value else {
+                continue;
+            };
+            for field in
+                placeholders(template).with_context(|| format!("STIX mapping property {}", name))?
+            {
+                if !KNOWN_FIELDS.contains(&field) && !field.starts_with("payload.") {
+                    bail!(
+                        "STIX mapping property {}: unknown field {{{}}}",
+                        name,
+                        field
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The object for `event`.
+    pub fn render(&self, event: &Event) -> Value {
+        let fields = Fields::new(event);
+        let mut object = Map::new();
+        object.insert("type".into(), self.object_type.clone().into());
+        object.insert(
+            "id".into(),
+            format!("{}--{}", self.object_type, Uuid::new_v4()).into(),
+        );
+        object.insert("spec_version".into(), "2.1".into());
+        for (name, value) in &self.properties {
+            let rendered = match value {
+                Value::String(template) => fill(template, &fields),
+                other => Some(other.clone()),
+            };
+            if let Some(v) = rendered {
+                object.insert(name.clone(), v);
+            }
+        }
+        Value::Object(object)
+    }
+}
+
+const KNOWN_FIELDS: [&str; 7] = [
+    "channel",
+    "source",
+    "timestamp",
+    "payload",
+    "payload_text",
+    "payload_hex",
+    "payload_hash",
+];
+
+// The field names in `template`, in order.
+fn placeholders(template: &str) -> Result<Vec<&str>> {
+    let mut fields = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            bail!("unclosed {{ in {:?}", template);
+        };
+        fields.push(&rest[start + 1..start + len]);
+        rest = &rest[start + len + 1..];
+    }
+    Ok(fields)
+}
+
+// An event's values by template field name.
+struct Fields<'a> {
+    event: &'a Event,
+    doc: Option<Value>,
+}
+
+impl<'a> Fields<'a> {
+    fn new(event: &'a Event) -> Self {
+        Self {
+            event,
+            doc: serde_json::from_slice(&event.payload).ok(),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<Value> {
+        let e = self.event;
+        match name {
+            "channel" => Some(e.channel.clone().into()),
+            "source" => Some(e.source.clone().into()),
+            "timestamp" => Some(e.timestamp.to_rfc3339().into()),
+            "payload" => Some(STANDARD.encode(&e.payload).into()),
+            "payload_text" => Some(String::from_utf8_lossy(&e.payload).into_owned().into()),
+            "payload_hex" => e.payload_hex.clone().map(Value::from),
+            "payload_hash" => e.payload_hash.clone().map(Value::from),
+            _ => {
+                let path = name.strip_prefix("payload.")?;
+                path.split('.')
+                    .try_fold(self.doc.as_ref()?, |v, key| v.get(key))
+                    .filter(|v| !v.is_null())
+                    .cloned()
+            }
+        }
+    }
+}
+
+// Fills in `template`, or None if any field it names is missing.
+fn fill(template: &str, fields: &Fields) -> Option<Value> {
+    let names = placeholders(template).ok()?;
+    if let [name] = names[..]
+        && template.len() == name.len() + 2
+    {
+        return fields.get(name);
+    }
+    let mut out = String::new();
+    let mut rest = template;
+    for name in names {
+        let start = rest.find('{')?;
+        out.push_str(&rest[..start]);
+        match fields.get(name)? {
+            Value::String(s) => out.push_str(&s),
+            other => out.push_str(&other.to_string()),
+        }
+        rest = &rest[start + name.len() + 2..];
+    }
+    out.push_str(rest);
+    Some(out.into())
+}
+
+/// The STIX objects for `events`: by default an observed-data and a sighting of it per event,
+/// or one object per event shaped by `mapping`.
+pub fn objects(events: &[Event], mapping: Option<&StixMapping>) -> Vec<Value> {
+    if let Some(mapping) = mapping {
+        return events.iter().map(|e| mapping.render(e)).collect();
+    }
+    let mut objects = Vec::new();
+    for event in events {
+        let observed_data_id = format!("observed-data--{}", Uuid::new_v4());
+        let mut observed = serde_json::json!({
+            "type": "observed-data", "id": observed_data_id, "spec_version": "2.1",
+            "first_observed": event.timestamp.to_rfc3339(), "last_observed": event.timestamp.to_rfc3339(),
+            "number_observed": 1, "external_references": [{"source_name": "hpfeeds", "external_id": event.source}],
+            "x_hpfeeds_channel": event.channel, "x_hpfeeds_payload": STANDARD.encode(&event.payload)
+        });
+        if let Some(hex) = &event.payload_hex {
+            observed["x_hpfeeds_payload_hex"] = hex.clone().into();
+        }
+        if let Some(hash) = &event.payload_hash {
+            observed["x_hpfeeds_payload_hash"] = hash.clone().into();
+        }
+        objects.push(observed);
+        objects.push(serde_json::json!({
+            "type": "sighting", "id": format!("sighting--{}", Uuid::new_v4()), "spec_version": "2.1",
+            "sighting_of_ref": observed_data_id, "last_seen": event.timestamp.to_rfc3339(), "count": 1
+        }));
+    }
+    objects
+}
+
+/// `objects(events, mapping)` wrapped in a bundle.
+pub fn bundle(events: &[Event], mapping: Option<&StixMapping>) -> Value {
+    let bundle_id = format!("bundle--{}", Uuid::new_v4());
+    serde_json::json!({"type": "bundle", "id": bundle_id, "objects": objects(events, mapping)})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(json: Value) -> StixMapping {
+        let mapping: StixMapping = serde_json::from_value(json).unwrap();
+        mapping.check().unwrap();
+        mapping
+    }
+
+    #[test]
+    fn custom_mapping_renders_an_indicator() {
+        let mapping = mapping(serde_json::json!({
+            "type": "indicator",
+            "properties": {
+                "name": "{channel} attacker",
+                "pattern": "[ipv4-addr:value = '{payload.peer.ip}']",
+                "pattern_type": "stix",
+                "valid_from": "{timestamp}",
+                "x_port": "{payload.peer.port}",
+                "x_user": "{payload.username}",
+                "labels": ["honeypot"],
+            }
+        }));
+        let event = Event::new(
+            "cowrie.sessions".into(),
+            "sensor1".into(),
+            br#"{"peer": {"ip": "203.0.113.7", "port": 2222}}"#.to_vec(),
+        );
+
+        let objects = objects(std::slice::from_ref(&event), Some(&mapping));
+        assert_eq!(objects.len(), 1);
+        let o = &objects[0];
+        assert_eq!(o["type"], "indicator");
+        assert!(o["id"].as_str().unwrap().starts_with("indicator--"));
+        assert_eq!(o["spec_version"], "2.1");
+        assert_eq!(o["name"], "cowrie.sessions attacker");
+        assert_eq!(o["pattern"], "[ipv4-addr:value = '203.0.113.7']");
+        assert_eq!(o["pattern_type"], "stix");
+        assert_eq!(o["valid_from"], event.timestamp.to_rfc3339());
+        // a lone placeholder keeps the JSON type
+        assert_eq!(o["x_port"], 2222);
+        assert_eq!(o["labels"], serde_json::json!(["honeypot"]));
+        // no such field in the payload
+        assert!(o.get("x_user").is_none());
+    }
+
+    #[test]
+    fn mapping_fields_outside_json_payloads() {
+        let mapping = mapping(serde_json::json!({
+            "type": "network-traffic",
+            "properties": {"x_raw": "{payload}", "x_text": "{payload_text}", "x_ip": "{payload.ip}"}
+        }));
+        let o = mapping.render(&Event::new("c".into(), "s".into(), b"not json".to_vec()));
+        assert_eq!(o["type"], "network-traffic");
+        assert_eq!(o["x_raw"], STANDARD.encode(b"not json"));
+        assert_eq!(o["x_text"], "not json");
+        assert!(o.get("x_ip").is_none());
+    }
+
+    #[test]
+    fn mapping_rejects_bad_templates() {
+        for properties in [
+            serde_json::json!({"name": "{chanel}"}),
+            serde_json::json!({"name": "{channel"}),
+        ] {
+            let mapping: StixMapping = serde_json::from_value(
+                serde_json::json!({"type": "indicator", "properties": properties}),
+            )
+            .unwrap();
+            assert!(mapping.check().is_err(), "{:?}", mapping);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use crate::event::Event;
+use anyhow::Result;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use clap::ValueEnum;
+use std::fmt::Write;
+
+/// Appended to messages cut short by `--syslog-max-len`.
+pub const TRUNCATION_MARKER: &str = "...[truncated]";
+
+/// How an event is rendered in the message body.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyslogEncoding {
+    /// The event as JSON, as written by the file sink
+    Json,
+    /// Flat `key="value"` pairs, which SIEM parsers handle without a JSON stage
+    Keyvalue,
+}
+
+/// How messages reach the syslog server.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyslogTransport {
+    /// One datagram per message
+    Udp,
+    /// A stream of octet-counted messages (RFC 6587)
+    Tcp,
+    /// The same stream over TLS (RFC 5425), verified against `--tls-ca` if given
+    Tls,
+}
+
+/// Renders `e` as an RFC 5424 message, cut to at most `max_len` bytes if given.
+pub fn format_message(
+    e: &Event,
+    encoding: SyslogEncoding,
+    max_len: Option<usize>,
+) -> Result<String> {
+    let body = match encoding {
+        SyslogEncoding::Json => serde_json::to_string(e)?,
+        SyslogEncoding::Keyvalue => key_values(e),
+    };
+    let mut msg = format!(
+        "<134>1 {} {} hpfeeds - - - {}",
+        e.timestamp.to_rfc3339(),
+        e.source,
+        body
+    );
+    if let Some(max) = max_len {
+        truncate(&mut msg, max);
+    }
+    Ok(msg)
+}
+
+/// Frames `msg` for a stream transport: its length in bytes, a space, then the message.
+pub fn octet_counted(msg: &str) -> String {
+    format!("{} {}", msg.len(), msg)
+}
+
+fn key_values(e: &Event) -> String {
+    let mut out = String::new();
+    let mut pair = |key: &str, value: &str| {
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        let _ = write!(out, "{}=\"{}\"", key, escape(value));
+    };
+    pair("channel", &e.channel);
+    pair("source", &e.source);
+    match std::str::from_utf8(&e.payload) {
+        Ok(s) => pair("payload", s),
+        Err(_) => pair("payload_b64", &STANDARD.encode(&e.payload)),
+    }
+    if let Some(hex) = &e.payload_hex {
+        pair("payload_hex", hex);
+    }
+    if let Some(hash) = &e.payload_hash {
+        pair("payload_hash", hash);
+    }
+    out
+}
+
+// Escapes what would end the quoted value or the line.
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// Cuts `msg` to at most `max` bytes, ending with the marker, on a character boundary.
+fn truncate(msg: &mut String, max: usize) {
+    if msg.len() <= max {
+        return;
+    }
+    let mut end = max.saturating_sub(TRUNCATION_MARKER.len());
+    while !msg.is_char_boundary(end) {
+        end -= 1;
+    }
+    msg.truncate(end);
+    msg.push_str(TRUNCATION_MARKER);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn event(payload: &str) -> Event {
+        let mut e = Event::new(
+            "cowrie".into(),
+            "sensor-1".into(),
+            payload.as_bytes().to_vec(),
+        );
+        e.timestamp = chrono::Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        e
+    }
+
+    #[test]
+    fn long_messages_are_truncated_with_a_marker() {
+        let e = event(&"\u{e9}".repeat(2000));
+        let msg = format_message(&e, SyslogEncoding::Json, Some(1024)).unwrap();
+        assert!(msg.len() <= 1024);
+        assert!(msg.len() > 1024 - TRUNCATION_MARKER.len() - 2);
+        assert!(msg.ends_with(TRUNCATION_MARKER));
+
+        let short = format_message(&event("hi"), SyslogEncoding::Json, Some(1024)).unwrap();
+        assert!(!short.contains(TRUNCATION_MARKER));
+    }
+
+    #[test]
+    fn keyvalue_is_flat_and_escaped() {
+        let e = event("user=\"root\"\nlogin ok");
+        let msg = format_message(&e, SyslogEncoding::Keyvalue, None).unwrap();
+        assert_eq!(
+            msg,
+            "<134>1 2024-05-01T12:00:00+00:00 sensor-1 hpfeeds - - - \
+             channel=\"cowrie\" source=\"sensor-1\" payload=\"user=\\\"root\\\"\\nlogin ok\""
+        );
+        assert_eq!(octet_counted("<134>1 x"), "8 <134>1 x");
+    }
+}
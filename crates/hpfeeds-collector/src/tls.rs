@@ -0,0 +1,176 @@
+use anyhow::{Context, Result, anyhow};
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, RootCertStore};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+
+/// Connects to `addr` and completes a TLS handshake, verifying the server against the PEM
+/// certificates in `ca` if given, otherwise the Mozilla root set. The certificate must be valid
+/// for `server_name`, or else for the host part of `addr`.
+pub async fn connect(
+    addr: &str,
+    ca: Option<&str>,
+    server_name: Option<&str>,
+) -> Result<TlsStream<TcpStream>> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(path) => {
+            let data =
+                std::fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+            for p in pem::parse_many(&data)?
+                .into_iter()
+                .filter(|p| p.tag() == "CERTIFICATE")
+            {
+                roots.add(CertificateDer::from(p.contents().to_vec()))?;
+            }
+            if roots.is_empty() {
+                return Err(anyhow!("no certificates found in {}", path));
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+    let host = server_name.unwrap_or_else(|| {
+        addr.rsplit_once(':')
+            .map_or(addr, |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+    });
+    let server_name =
+        ServerName::try_from(host.to_string()).map_err(|_| anyhow!("invalid TLS host {}", host))?;
+    let stream = TcpStream::connect(addr).await?;
+    Ok(TlsConnector::from(Arc::new(config))
+        .connect(server_name, stream)
+        .await?)
+}
+
+/// Test fixtures shared by the modules that talk TLS.
+#[cfg(test)]
+pub(crate) mod test_support {
+    use rustls::ServerConfig;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio_rustls::TlsAcceptor;
+
+    /// An acceptor presenting a self-signed certificate for `name`, and the path of a CA file
+    /// trusting it, named after `file`. Callers remove the file when done.
+    pub(crate) fn acceptor(name: &str, file: &str) -> (TlsAcceptor, PathBuf) {
+        let cert = rcgen::generate_simple_self_signed(vec![name.into()]).unwrap();
+        let ca = std::env::temp_dir().join(format!("hpfeeds-{}-{}.pem", file, std::process::id()));
+        std::fs::write(&ca, cert.cert.pem()).unwrap();
+        let config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(
+                    vec![CertificateDer::from(cert.cert.der().to_vec())],
+                    PrivateKeyDer::try_from(cert.signing_key.serialize_der()).unwrap(),
+                )
+                .unwrap();
+        (TlsAcceptor::from(Arc::new(config)), ca)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_support::acceptor;
+    use crate::Args;
+    use crate::event::Event;
+    use crate::sinks::Sink;
+    use clap::Parser;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+
+    // Starts a TLS endpoint for "localhost" and returns its address, the path of a CA file
+    // trusting it, and a task yielding everything the first client sends.
+    async fn endpoint(
+        name: &str,
+    ) -> (String, std::path::PathBuf, tokio::task::JoinHandle<Vec<u8>>) {
+        let (acceptor, ca) = acceptor("localhost", name);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("localhost:{}", listener.local_addr().unwrap().port());
+        let received = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(socket).await.unwrap();
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).await.ok();
+            data
+        });
+        (addr, ca, received)
+    }
+
+    fn events() -> Vec<Event> {
+        ["one", "two"]
+            .iter()
+            .map(|p| Event::new("ch".into(), "sensor".into(), p.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn tcp_sink_delivers_over_tls() {
+        let (addr, ca, received) = endpoint("tcp-ca").await;
+        let args = Args::parse_from([
+            "hpfeeds-collector",
+            "--ident=i",
+            "--secret=s",
+            "--tcp-tls",
+            &format!("--tcp-addr={}", addr),
+            &format!("--tls-ca={}", ca.display()),
+        ]);
+
+        let mut sink = Sink::open("tcp", &args).await.unwrap();
+        sink.write(&events()).await.unwrap();
+        drop(sink);
+
+        let data = received.await.unwrap();
+        std::fs::remove_file(&ca).unwrap();
+        let mut lines = BufReader::new(&data[..]).lines();
+        let mut payloads = Vec::new();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            payloads.push(serde_json::from_str::<Event>(&line).unwrap().payload);
+        }
+        assert_eq!(payloads, [b"one".to_vec(), b"two".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn syslog_sink_delivers_over_tls() {
+        let (addr, ca, received) = endpoint("syslog-ca").await;
+        let args = Args::parse_from([
+            "hpfeeds-collector",
+            "--ident=i",
+            "--secret=s",
+            "--syslog-transport=tls",
+            &format!("--syslog-addr={}", addr),
+            &format!("--tls-ca={}", ca.display()),
+        ]);
+
+        let mut sink = Sink::open("syslog", &args).await.unwrap();
+        sink.write(&events()).await.unwrap();
+        drop(sink);
+
+        let data = String::from_utf8(received.await.unwrap()).unwrap();
+        std::fs::remove_file(&ca).unwrap();
+        // RFC 5425 frames each message with its length
+        let (len, rest) = data.split_once(' ').unwrap();
+        let first = &rest[..len.parse::<usize>().unwrap()];
+        assert!(first.starts_with("<134>1 "));
+        assert!(first.contains(r#""payload":"one""#));
+        assert!(rest[first.len()..].contains(r#""payload":"two""#));
+    }
+
+    #[tokio::test]
+    async fn untrusted_certificates_are_rejected() {
+        let (addr, ca, _received) = endpoint("untrusted-ca").await;
+        std::fs::remove_file(&ca).unwrap();
+        // no --tls-ca, so the self-signed certificate is checked against the public roots
+        assert!(super::connect(&addr, None, None).await.is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::{Context, Result, anyhow, bail};
+use flate2::read::GzDecoder;
+use std::io::Read;
+use std::str::FromStr;
+
+/// Largest payload `gunzip` will inflate to, so a small hostile payload cannot exhaust memory.
+pub const MAX_INFLATED_LEN: u64 = 16 * 1024 * 1024;
+
+/// One step of `--payload-transform`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transform {
+    /// Decompresses a gzip payload
+    Gunzip,
+    /// Replaces a JSON object payload with one of its fields, given as a dotted path. String
+    /// values are stored as their text, anything else as JSON.
+    JsonField(String),
+}
+
+impl FromStr for Transform {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "gunzip" => Ok(Transform::Gunzip),
+            Some(("json-field", path)) if !path.is_empty() => {
+                Ok(Transform::JsonField(path.to_string()))
+            }
+            _ => bail!(
+                "unknown payload transform {:?}; expected gunzip or json-field:<path>",
+                s
+            ),
+        }
+    }
+}
+
+impl Transform {
+    fn apply(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Transform::Gunzip => {
+                let mut out = Vec::new();
+                GzDecoder::new(payload)
+                    .take(MAX_INFLATED_LEN + 1)
+                    .read_to_end(&mut out)
+                    .context("gunzip")?;
+                if out.len() as u64 > MAX_INFLATED_LEN {
+                    bail!("gunzip: inflates past {} bytes", MAX_INFLATED_LEN);
+                }
+                Ok(out)
+            }
+            Transform::JsonField(path) => {
+                let doc: serde_json::Value =
+                    serde_json::from_slice(payload).context("json-field: payload is not JSON")?;
+                let value = path
+                    .split('.')
+                    .try_fold(&doc, |v, key| v.get(key))
+                    .ok_or_else(|| anyhow!("json-field: no field {}", path))?;
+                Ok(match value {
+                    serde_json::Value::String(s) => s.clone().into_bytes(),
+                    other => serde_json::to_vec(other)?,
+                })
+            }
+        }
+    }
+}
+
+/// Runs `payload` through each transform in turn.
+pub fn apply_all(transforms: &[Transform], payload: &[u8]) -> Result<Vec<u8>> {
+    let mut current = payload.to_vec();
+    for t in transforms {
+        current = t.apply(&current)?;
+    }
+    Ok(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_chains_and_falls_through_on_bad_input() {
+        let chain: Vec<Transform> = "gunzip,json-field:a.b"
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            chain,
+            [Transform::Gunzip, Transform::JsonField("a.b".into())]
+        );
+        assert!("json-field:".parse::<Transform>().is_err());
+        assert!("rot13".parse::<Transform>().is_err());
+
+        let nested = [Transform::JsonField("a.b".into())];
+        assert_eq!(
+            apply_all(&nested, br#"{"a":{"b":[1,2]}}"#).unwrap(),
+            b"[1,2]"
+        );
+        assert!(apply_all(&nested, br#"{"a":1}"#).is_err());
+        assert!(apply_all(&[Transform::Gunzip], b"not gzip").is_err());
+    }
+}
@@ -0,0 +1,43 @@
+use std::process::Command;
+
+#[test]
+fn tee_console_prints_events_written_to_the_file_sink() {
+    let dir = std::env::temp_dir();
+    let input = dir.join(format!("hpfeeds-tee-in-{}", std::process::id()));
+    let output = dir.join(format!("hpfeeds-tee-out-{}", std::process::id()));
+    let events: Vec<String> = (0..3)
+        .map(|i| {
+            serde_json::json!({
+                "timestamp": "2026-01-01T00:00:00Z",
+                "channel": "cowrie.sessions",
+                "source": "sensor1",
+                "payload": format!("event {}", i),
+            })
+            .to_string()
+        })
+        .collect();
+    std::fs::write(&input, events.join("\n") + "\n").unwrap();
+
+    let run = Command::new(env!("CARGO_BIN_EXE_hpfeeds-collector"))
+        .args(["--replay", input.to_str().unwrap()])
+        .args(["--output", "file", "--file-path", output.to_str().unwrap()])
+        .arg("--tee-console")
+        .output()
+        .unwrap();
+    let written = std::fs::read_to_string(&output).unwrap();
+    std::fs::remove_file(&input).unwrap();
+    std::fs::remove_file(&output).unwrap();
+    assert!(run.status.success(), "{:?}", run);
+
+    let parse = |text: &str| -> Vec<serde_json::Value> {
+        text.lines()
+            .filter_map(|l| serde_json::from_str(l).ok())
+            .collect()
+    };
+    let stdout = String::from_utf8(run.stdout).unwrap();
+    let printed = parse(&stdout);
+    let stored = parse(&written);
+    assert_eq!(stored.len(), 3);
+    assert_eq!(printed, stored);
+    assert_eq!(stored[2]["payload"], "event 2");
+}
@@ -0,0 +1,272 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// Prefix of the capability token appended to the OP_INFO broker name.
+pub const CAPS_PREFIX: &str = "caps=";
+
+/// Capability meaning the broker accepts an OP_INFO from the client, before OP_AUTH, whose
+/// name carries the capabilities the client selects.
+pub const CAP_SELECT: &str = "select";
+
+/// Capability letting a subscribe ask for the last K retained messages by naming the channel
+/// `name;backlog=K`. Only parsed on connections that selected it.
+pub const CAP_BACKLOG: &str = "backlog";
+
+const BACKLOG_PARAM: &str = ";backlog=";
+
+/// Channel name to subscribe to `channel` with a backlog of `k` under [`CAP_BACKLOG`].
+pub fn with_backlog(channel: &str, k: usize) -> String {
+    format!("{}{}{}", channel, BACKLOG_PARAM, k)
+}
+
+/// Splits a [`CAP_BACKLOG`] subscribe channel into the channel and the requested backlog.
+/// Channels without a valid parameter are returned whole.
+pub fn split_backlog(channel: &[u8]) -> (&[u8], Option<usize>) {
+    let param = BACKLOG_PARAM.as_bytes();
+    let Some(at) = channel.windows(param.len()).rposition(|w| w == param) else {
+        return (channel, None);
+    };
+    let k = std::str::from_utf8(&channel[at + param.len()..])
+        .ok()
+        .and_then(|k| k.parse().ok());
+    match k {
+        Some(k) => (&channel[..at], Some(k)),
+        None => (channel, None),
+    }
+}
+
+/// Capability letting a subscriber hold deliveries on a channel without unsubscribing, by
+/// subscribing to `name;pause`, and release them again with `name;resume`. Only parsed on
+/// connections that selected it.
+pub const CAP_PAUSE: &str = "pause";
+
+const PAUSE_SUFFIX: &str = ";pause";
+const RESUME_SUFFIX: &str = ";resume";
+
+/// A [`CAP_PAUSE`] control sent in place of a subscribe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionControl {
+    Pause,
+    Resume,
+}
+
+/// Channel name to subscribe to in order to apply `control` to `channel` under [`CAP_PAUSE`].
+pub fn with_control(channel: &str, control: SubscriptionControl) -> String {
+    let suffix = match control {
+        SubscriptionControl::Pause => PAUSE_SUFFIX,
+        SubscriptionControl::Resume => RESUME_SUFFIX,
+    };
+    format!("{}{}", channel, suffix)
+}
+
+/// Splits a [`CAP_PAUSE`] subscribe channel into the channel and the control it carries.
+/// Ordinary channels are returned whole.
+pub fn split_control(channel: &[u8]) -> (&[u8], Option<SubscriptionControl>) {
+    if let Some(name) = channel.strip_suffix(PAUSE_SUFFIX.as_bytes()) {
+        (name, Some(SubscriptionControl::Pause))
+    } else if let Some(name) = channel.strip_suffix(RESUME_SUFFIX.as_bytes()) {
+        (name, Some(SubscriptionControl::Resume))
+    } else {
+        (channel, None)
+    }
+}
+
+/// Capability letting subscribers share the work on a channel: subscribing to `name;share=G`
+/// joins group `G`, and each publish on `name` goes to just one member of the group, in turn.
+/// Only parsed on connections that selected it.
+pub const CAP_SHARE: &str = "share";
+
+const SHARE_PARAM: &str = ";share=";
+
+/// Channel name to subscribe to in order to join `group` on `channel` under [`CAP_SHARE`].
+pub fn with_share_group(channel: &str, group: &str) -> String {
+    format!("{}{}{}", channel, SHARE_PARAM, group)
+}
+
+/// Splits a [`CAP_SHARE`] subscribe channel into the channel and the group it joins.
+/// Channels without a non-empty group are returned whole.
+pub fn split_share_group(channel: &[u8]) -> (&[u8], Option<&[u8]>) {
+    let param = SHARE_PARAM.as_bytes();
+    match channel.windows(param.len()).rposition(|w| w == param) {
+        Some(at) if at + param.len() < channel.len() => {
+            (&channel[..at], Some(&channel[at + param.len()..]))
+        }
+        _ => (channel, None),
+    }
+}
+
+/// Capability asking the broker to confirm each subscribe it accepts with a publish on
+/// [`SUBACK_CHANNEL`], whose payload is the channel exactly as subscribed, and to answer each one
+/// it denies with OP_ERROR. Only honoured on connections that selected it.
+pub const CAP_SUBACK: &str = "suback";
+
+/// Channel a broker confirms subscribes on under [`CAP_SUBACK`], from its own ident.
+pub const SUBACK_CHANNEL: &str = "__subscribed__";
+
+/// Optional protocol behaviours advertised by a broker in its OP_INFO name,
+/// e.g. `hpfeeds-rs/0.3 caps=seq,zstd,sha256`.
+///
+/// Legacy peers treat the whole name as opaque, so the suffix is invisible to them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    caps: BTreeSet<String>,
+}
+
+impl Capabilities {
+    pub fn new<I, S>(caps: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            caps: caps
+                .into_iter()
+                .map(Into::into)
+                .filter(|c| !c.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Splits an OP_INFO name into the broker name and its advertised capabilities.
+    /// Names without a `caps=` token yield an empty set.
+    pub fn parse_info_name(name: &[u8]) -> (String, Capabilities) {
+        let name = String::from_utf8_lossy(name);
+        match name.rsplit_once(' ') {
+            Some((broker, token)) if token.starts_with(CAPS_PREFIX) => (
+                broker.trim_end().to_string(),
+                Capabilities::new(token[CAPS_PREFIX.len()..].split(',')),
+            ),
+            _ => (name.into_owned(), Capabilities::default()),
+        }
+    }
+
+    /// Builds the OP_INFO name advertising these capabilities. With no capabilities
+    /// the broker name is returned unchanged.
+    pub fn info_name(&self, broker: &str) -> String {
+        if self.caps.is_empty() {
+            broker.to_string()
+        } else {
+            format!("{} {}{}", broker, CAPS_PREFIX, self)
+        }
+    }
+
+    /// Returns the capabilities supported by both sides.
+    pub fn negotiate(&self, other: &Capabilities) -> Capabilities {
+        Capabilities {
+            caps: self.caps.intersection(&other.caps).cloned().collect(),
+        }
+    }
+
+    pub fn contains(&self, cap: &str) -> bool {
+        self.caps.contains(cap)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.caps.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.caps.iter().map(String::as_str)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for cap in &self.caps {
+            if !first {
+                f.write_str(",")?;
+            }
+            f.write_str(cap)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_negotiates_caps() {
+        let (broker, caps) = Capabilities::parse_info_name(b"hpfeeds-rs/0.3 caps=seq,zstd,sha256");
+        assert_eq!(broker, "hpfeeds-rs/0.3");
+        assert!(caps.contains("seq") && caps.contains("zstd") && caps.contains("sha256"));
+
+        let ours = Capabilities::new(["sha256", "seq", "confirm"]);
+        let agreed = caps.negotiate(&ours);
+        assert_eq!(agreed, Capabilities::new(["seq", "sha256"]));
+        assert_eq!(agreed.to_string(), "seq,sha256");
+    }
+
+    #[test]
+    fn backlog_parameter_roundtrip() {
+        let channel = with_backlog("cowrie.sessions", 2);
+        assert_eq!(channel, "cowrie.sessions;backlog=2");
+        assert_eq!(
+            split_backlog(channel.as_bytes()),
+            (&b"cowrie.sessions"[..], Some(2))
+        );
+        assert_eq!(split_backlog(b"plain"), (&b"plain"[..], None));
+        assert_eq!(split_backlog(b"ch;backlog=x"), (&b"ch;backlog=x"[..], None));
+    }
+
+    #[test]
+    fn control_suffix_roundtrip() {
+        let pause = with_control("cowrie.sessions", SubscriptionControl::Pause);
+        assert_eq!(pause, "cowrie.sessions;pause");
+        assert_eq!(
+            split_control(pause.as_bytes()),
+            (&b"cowrie.sessions"[..], Some(SubscriptionControl::Pause))
+        );
+        assert_eq!(
+            split_control(b"ch;resume"),
+            (&b"ch"[..], Some(SubscriptionControl::Resume))
+        );
+        assert_eq!(split_control(b"ch;paused"), (&b"ch;paused"[..], None));
+    }
+
+    #[test]
+    fn share_group_roundtrip() {
+        let channel = with_share_group("cowrie.sessions", "workers");
+        assert_eq!(channel, "cowrie.sessions;share=workers");
+        assert_eq!(
+            split_share_group(channel.as_bytes()),
+            (&b"cowrie.sessions"[..], Some(&b"workers"[..]))
+        );
+        assert_eq!(split_share_group(b"ch;share="), (&b"ch;share="[..], None));
+        assert_eq!(split_share_group(b"plain"), (&b"plain"[..], None));
+        // the last parameter counts, as for backlogs
+        assert_eq!(
+            split_share_group(b"a;share=x;share=g"),
+            (&b"a;share=x"[..], Some(&b"g"[..]))
+        );
+    }
+
+    #[test]
+    fn legacy_name_has_no_caps() {
+        let (broker, caps) = Capabilities::parse_info_name(b"hpfeeds");
+        assert_eq!(broker, "hpfeeds");
+        assert!(caps.is_empty());
+
+        let (broker, caps) = Capabilities::parse_info_name(b"some broker");
+        assert_eq!(broker, "some broker");
+        assert!(caps.is_empty());
+    }
+
+    #[test]
+    fn info_name_roundtrip() {
+        let caps = Capabilities::new(["seq", "zstd"]);
+        let name = caps.info_name("hpfeeds-rs");
+        assert_eq!(name, "hpfeeds-rs caps=seq,zstd");
+        assert_eq!(
+            Capabilities::parse_info_name(name.as_bytes()),
+            ("hpfeeds-rs".to_string(), caps)
+        );
+        assert_eq!(
+            Capabilities::default().info_name("hpfeeds-rs"),
+            "hpfeeds-rs"
+        );
+    }
+}
@@ -1,8 +1,18 @@
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use sha1::{Digest, Sha1};
+use std::fmt;
 use std::io;
 use tokio_util::codec::{Decoder, Encoder};
 
+mod capabilities;
+pub use capabilities::{
+    CAP_BACKLOG, CAP_PAUSE, CAP_SELECT, CAP_SHARE, CAP_SUBACK, CAPS_PREFIX, Capabilities,
+    SUBACK_CHANNEL, SubscriptionControl, split_backlog, split_control, split_share_group,
+    with_backlog, with_control, with_share_group,
+};
+mod secrets;
+pub use secrets::{SecretPolicy, entropy_bits};
+
 pub const OP_ERROR: u8 = 0;
 pub const OP_INFO: u8 = 1;
 pub const OP_AUTH: u8 = 2;
@@ -10,10 +20,24 @@ pub const OP_PUBLISH: u8 = 3;
 pub const OP_SUBSCRIBE: u8 = 4;
 pub const OP_UNSUBSCRIBE: u8 = 5;
 
+/// Channel a broker publishes its message of the day on, from the broker's own ident, to each
+/// client right after OP_INFO. Clients that know it can show the payload as text.
+pub const MOTD_CHANNEL: &str = "__motd__";
+
 // Max buffer size (1MB) to match original implementation limits (MAXBUF)
 pub const MAXBUF: usize = 1024 * 1024;
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+// Longest OP_INFO rand accepted; brokers usually send 16 bytes
+pub const MAX_RAND_LEN: usize = 32;
+
+/// With the `serde` feature, frames serialize tagged by opcode, e.g.
+/// `{"op":"publish","fields":{"ident":[115],"channel":[99],"payload":[104,105]}}`.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "op", content = "fields", rename_all = "lowercase")
+)]
 pub enum Frame {
     Error(Bytes),
     Info {
@@ -39,6 +63,67 @@ pub enum Frame {
     },
 }
 
+impl Frame {
+    /// `(channel, payload)` for a publish, ignoring the ident, so repeats of the same message from
+    /// different publishers share a key. `None` for every other frame.
+    pub fn content_key(&self) -> Option<(&[u8], &[u8])> {
+        match self {
+            Frame::Publish {
+                channel, payload, ..
+            } => Some((channel, payload)),
+            _ => None,
+        }
+    }
+}
+
+/// One line per frame, e.g. `publish ident="sensor" channel="ch" payload="hi"`. Byte strings are
+/// shown with non-printable bytes escaped, rand and hashes as hex.
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Frame::Error(msg) => write!(f, "error \"{}\"", msg.escape_ascii()),
+            Frame::Info { name, rand } => {
+                write!(f, "info name=\"{}\" rand=", name.escape_ascii())?;
+                write_hex(f, rand)
+            }
+            Frame::Auth { ident, secret_hash } => {
+                write!(f, "auth ident=\"{}\" secret_hash=", ident.escape_ascii())?;
+                write_hex(f, secret_hash)
+            }
+            Frame::Publish {
+                ident,
+                channel,
+                payload,
+            } => write!(
+                f,
+                "publish ident=\"{}\" channel=\"{}\" payload=\"{}\"",
+                ident.escape_ascii(),
+                channel.escape_ascii(),
+                payload.escape_ascii()
+            ),
+            Frame::Subscribe { ident, channel } => write!(
+                f,
+                "subscribe ident=\"{}\" channel=\"{}\"",
+                ident.escape_ascii(),
+                channel.escape_ascii()
+            ),
+            Frame::Unsubscribe { ident, channel } => write!(
+                f,
+                "unsubscribe ident=\"{}\" channel=\"{}\"",
+                ident.escape_ascii(),
+                channel.escape_ascii()
+            ),
+        }
+    }
+}
+
+fn write_hex(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    for b in bytes {
+        write!(f, "{:02x}", b)?;
+    }
+    Ok(())
+}
+
 pub fn strpack8(s: &str) -> Result<Vec<u8>, io::Error> {
     let b = s.as_bytes();
     if b.len() > 255 {
@@ -109,17 +194,58 @@ pub fn hashsecret(rand: &[u8], secret: &str) -> Vec<u8> {
     hasher.finalize().to_vec()
 }
 
-pub struct HpfeedsCodec;
+#[derive(Debug, Clone)]
+pub struct HpfeedsCodec {
+    resync: bool,
+    skipped: u64,
+    /// Longest frame accepted, length prefix included
+    max_frame_len: usize,
+}
 
 impl Default for HpfeedsCodec {
     fn default() -> Self {
-        Self::new()
+        Self {
+            resync: false,
+            skipped: 0,
+            max_frame_len: MAXBUF,
+        }
     }
 }
 
 impl HpfeedsCodec {
     pub fn new() -> Self {
-        Self
+        Self::default()
+    }
+
+    /// Creates a codec that rejects frames longer than `len` bytes, length prefix included,
+    /// instead of `MAXBUF`. Raise it for feeds that carry large samples, or lower it to bound
+    /// the memory a peer can make a connection buffer.
+    pub fn with_max_len(len: usize) -> Self {
+        Self {
+            max_frame_len: len,
+            ..Self::default()
+        }
+    }
+
+    /// Longest frame this codec accepts, length prefix included.
+    pub fn max_len(&self) -> usize {
+        self.max_frame_len
+    }
+
+    /// Creates a codec that skips a corrupt frame (unknown opcode, malformed body or a length
+    /// over the opcode limit) instead of failing the stream, as long as its declared length is
+    /// plausible. Only meant for lossy/bridged transports; on raw TCP a corrupt length prefix
+    /// means the stream is already out of sync.
+    pub fn resync() -> Self {
+        Self {
+            resync: true,
+            ..Self::default()
+        }
+    }
+
+    /// Number of frames skipped in resync mode.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
     }
 
     pub fn encode_to_bytes(&mut self, item: Frame) -> Result<Bytes, io::Error> {
@@ -127,105 +253,151 @@ impl HpfeedsCodec {
         self.encode(item, &mut dst)?;
         Ok(dst.freeze())
     }
+
+    /// Decodes every complete frame buffered in `src`, in order, leaving any trailing partial
+    /// frame in place for the next read. Fails like [`Decoder::decode`]; frames decoded before
+    /// the failing one have been consumed from `src` and are lost with it.
+    pub fn decode_all(&mut self, src: &mut BytesMut) -> Result<Vec<Frame>, io::Error> {
+        let mut frames = Vec::new();
+        while let Some(frame) = self.decode(src)? {
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
 }
 
-impl Decoder for HpfeedsCodec {
-    type Item = Frame;
-    type Error = io::Error;
+// Validates the length prefix of the frame at the front of `src` against `max_len` and the
+// opcode limits.
+fn check_frame_len(src: &BytesMut, len: usize, max_len: usize) -> Result<(), io::Error> {
+    if len > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message too large",
+        ));
+    }
+    if len < 5 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid message length",
+        ));
+    }
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, io::Error> {
-        // Need at least 4 bytes for length
-        if src.len() < 4 {
-            return Ok(None);
-        }
-        let len = (&src[..4]).get_u32() as usize;
+    // Peek opcode if we have enough bytes (4 len + 1 opcode)
+    if src.len() >= 5 {
+        let op = src[4];
+        let max_op_len = match op {
+            OP_INFO => 1 + 256 + MAX_RAND_LEN, // name(256) + rand(usually 16)
+            OP_AUTH => 1 + 256 + 20,           // ident(256) + hash(20)
+            OP_PUBLISH => max_len,
+            OP_SUBSCRIBE => 1 + 256 + 256 * 2, // ident + channel (generous limit)
+            OP_UNSUBSCRIBE => 1 + 256 + 256 * 2,
+            OP_ERROR => 1 + 256, // error msg
+            _ => {
+                // Invalid opcode, we will catch it later, but for now enforce the overall limit
+                max_len
+            }
+        };
 
-        if len > MAXBUF {
+        let limit = 5 + max_op_len;
+        if len > limit {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
-                "message too large",
+                format!("message too large for opcode {}", op),
             ));
         }
+    }
+    Ok(())
+}
 
-        // Peek opcode if we have enough bytes (4 len + 1 opcode)
-        if src.len() >= 5 {
-            let op = src[4];
-            let max_op_len = match op {
-                OP_INFO => 1 + 256 + 20, // name(256) + rand(20, usually 16)
-                OP_AUTH => 1 + 256 + 20, // ident(256) + hash(20)
-                OP_PUBLISH => MAXBUF,
-                OP_SUBSCRIBE => 1 + 256 + 256 * 2, // ident + channel (generous limit)
-                OP_UNSUBSCRIBE => 1 + 256 + 256 * 2,
-                OP_ERROR => 1 + 256, // error msg
-                _ => {
-                    // Invalid opcode, we will catch it later, but for now enforce MAXBUF
-                    MAXBUF
-                }
-            };
-
-            let limit = 5 + max_op_len;
-            if len > limit {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("message too large for opcode {}", op),
-                ));
-            }
-        }
+// Parses a frame body (opcode followed by its fields).
+fn parse_frame(mut msg: Bytes) -> Result<Frame, io::Error> {
+    // First byte is opcode
+    if msg.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "empty message"));
+    }
+    let op = msg.split_to(1)[0];
 
-        if src.len() < len {
-            return Ok(None);
+    match op {
+        OP_ERROR => Ok(Frame::Error(msg)),
+        OP_INFO => {
+            let name = read_str8_bytes(&mut msg)?;
+            Ok(Frame::Info { name, rand: msg })
         }
-        // We have a full message in the buffer
-        // Remove length bytes
-        src.advance(4);
-        let mut msg = src.split_to(len - 4).freeze(); // Convert to Bytes for zero-copy slicing
-
-        // First byte is opcode
-        if msg.is_empty() {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty message"));
+        OP_AUTH => {
+            let ident = read_str8_bytes(&mut msg)?;
+            Ok(Frame::Auth {
+                ident,
+                secret_hash: msg,
+            })
+        }
+        OP_PUBLISH => {
+            let ident = read_str8_bytes(&mut msg)?;
+            let channel = read_str8_bytes(&mut msg)?;
+            Ok(Frame::Publish {
+                ident,
+                channel,
+                payload: msg,
+            })
+        }
+        OP_SUBSCRIBE => {
+            let ident = read_str8_bytes(&mut msg)?;
+            Ok(Frame::Subscribe {
+                ident,
+                channel: msg,
+            })
+        }
+        OP_UNSUBSCRIBE => {
+            let ident = read_str8_bytes(&mut msg)?;
+            Ok(Frame::Unsubscribe {
+                ident,
+                channel: msg,
+            })
         }
-        let op = msg.split_to(1)[0];
+        other => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown opcode: {}", other),
+        )),
+    }
+}
 
-        match op {
-            OP_ERROR => Ok(Some(Frame::Error(msg))),
-            OP_INFO => {
-                let name = read_str8_bytes(&mut msg)?;
-                Ok(Some(Frame::Info { name, rand: msg }))
-            }
-            OP_AUTH => {
-                let ident = read_str8_bytes(&mut msg)?;
-                Ok(Some(Frame::Auth {
-                    ident,
-                    secret_hash: msg,
-                }))
+impl Decoder for HpfeedsCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, io::Error> {
+        loop {
+            // Need at least 4 bytes for length
+            if src.len() < 4 {
+                return Ok(None);
             }
-            OP_PUBLISH => {
-                let ident = read_str8_bytes(&mut msg)?;
-                let channel = read_str8_bytes(&mut msg)?;
-                Ok(Some(Frame::Publish {
-                    ident,
-                    channel,
-                    payload: msg,
-                }))
+            let len = (&src[..4]).get_u32() as usize;
+
+            if let Err(e) = check_frame_len(src, len, self.max_frame_len) {
+                // A length we cannot step over leaves nothing to resync on
+                if !self.resync || !(4..=self.max_frame_len).contains(&len) {
+                    return Err(e);
+                }
+                if src.len() < len {
+                    return Ok(None);
+                }
+                src.advance(len);
+                self.skipped += 1;
+                continue;
             }
-            OP_SUBSCRIBE => {
-                let ident = read_str8_bytes(&mut msg)?;
-                Ok(Some(Frame::Subscribe {
-                    ident,
-                    channel: msg,
-                }))
+
+            if src.len() < len {
+                return Ok(None);
             }
-            OP_UNSUBSCRIBE => {
-                let ident = read_str8_bytes(&mut msg)?;
-                Ok(Some(Frame::Unsubscribe {
-                    ident,
-                    channel: msg,
-                }))
+            // We have a full message in the buffer
+            // Remove length bytes
+            src.advance(4);
+            let msg = src.split_to(len - 4).freeze(); // Convert to Bytes for zero-copy slicing
+
+            match parse_frame(msg) {
+                Ok(frame) => return Ok(Some(frame)),
+                Err(_) if self.resync => self.skipped += 1,
+                Err(e) => return Err(e),
             }
-            other => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("unknown opcode: {}", other),
-            )),
         }
     }
 }
@@ -284,6 +456,27 @@ mod tests {
     use super::*;
     use bytes::BytesMut;
 
+    #[cfg(feature = "serde")]
+    #[test]
+    fn publish_json_roundtrip() {
+        let frame = Frame::Publish {
+            ident: Bytes::from_static(b"sensor"),
+            channel: Bytes::from_static(b"cowrie.sessions"),
+            payload: Bytes::from_static(b"\x00\xff{}"),
+        };
+        let json = serde_json::to_value(&frame).unwrap();
+        assert_eq!(json["op"], "publish");
+        assert_eq!(
+            json["fields"]["payload"],
+            serde_json::json!([0, 255, 123, 125])
+        );
+        assert_eq!(serde_json::from_value::<Frame>(json).unwrap(), frame);
+
+        let error = Frame::Error(Bytes::from_static(b"denied"));
+        let text = serde_json::to_string(&error).unwrap();
+        assert_eq!(serde_json::from_str::<Frame>(&text).unwrap(), error);
+    }
+
     #[test]
     fn strpack_unpack_roundtrip() {
         let s = "identity";
@@ -300,6 +493,74 @@ mod tests {
         assert!(res.is_err());
     }
 
+    fn publish_of_len(frame_len: usize) -> BytesMut {
+        // 4 length + 1 opcode + 2 one-byte str8 fields
+        let payload = vec![0u8; frame_len - 9];
+        let mut buf = BytesMut::new();
+        HpfeedsCodec::new()
+            .encode(
+                Frame::Publish {
+                    ident: Bytes::from_static(b"i"),
+                    channel: Bytes::from_static(b"c"),
+                    payload: payload.into(),
+                },
+                &mut buf,
+            )
+            .unwrap();
+        assert_eq!(buf.len(), frame_len);
+        buf
+    }
+
+    #[test]
+    fn small_max_len_rejects_larger_publish() {
+        let mut codec = HpfeedsCodec::with_max_len(1024);
+        assert_eq!(codec.max_len(), 1024);
+        assert!(codec.decode(&mut publish_of_len(1024)).unwrap().is_some());
+        let err = codec.decode(&mut publish_of_len(1025)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn default_max_len_allows_up_to_maxbuf() {
+        let mut codec = HpfeedsCodec::new();
+        assert_eq!(codec.max_len(), MAXBUF);
+        assert!(codec.decode(&mut publish_of_len(MAXBUF)).unwrap().is_some());
+        assert!(codec.decode(&mut publish_of_len(MAXBUF + 1)).is_err());
+
+        // and a larger limit lets big samples through
+        let mut big = HpfeedsCodec::with_max_len(4 * MAXBUF);
+        assert!(
+            big.decode(&mut publish_of_len(2 * MAXBUF))
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn decode_all_returns_buffered_frames_in_order() {
+        let mut codec = HpfeedsCodec::new();
+        let first = Frame::Publish {
+            ident: Bytes::from_static(b"sensor"),
+            channel: Bytes::from_static(b"ch"),
+            payload: Bytes::from_static(b"one"),
+        };
+        let second = Frame::Subscribe {
+            ident: Bytes::from_static(b"sensor"),
+            channel: Bytes::from_static(b"other"),
+        };
+        let mut buf = BytesMut::new();
+        codec.encode(first.clone(), &mut buf).unwrap();
+        codec.encode(second.clone(), &mut buf).unwrap();
+        let mut partial = BytesMut::new();
+        codec.encode(first.clone(), &mut partial).unwrap();
+        buf.extend_from_slice(&partial[..partial.len() - 1]);
+
+        assert_eq!(codec.decode_all(&mut buf).unwrap(), [first, second]);
+        // the incomplete third frame waits for more bytes
+        assert_eq!(buf.len(), partial.len() - 1);
+        assert!(codec.decode_all(&mut buf).unwrap().is_empty());
+    }
+
     #[test]
     fn info_roundtrip() {
         let mut codec = HpfeedsCodec::new();
@@ -313,6 +574,58 @@ mod tests {
         assert_eq!(decoded, frame);
     }
 
+    #[test]
+    fn info_accepts_max_rand_len() {
+        let mut codec = HpfeedsCodec::new();
+        let frame = Frame::Info {
+            name: Bytes::from(vec![b'n'; 255]),
+            rand: Bytes::from(vec![7; MAX_RAND_LEN]),
+        };
+        let mut buf = BytesMut::new();
+        codec.encode(frame.clone(), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), frame);
+    }
+
+    #[test]
+    fn content_key_ignores_ident() {
+        use std::collections::HashSet;
+        let publish = |ident: &'static [u8]| Frame::Publish {
+            ident: Bytes::from_static(ident),
+            channel: Bytes::from_static(b"ch"),
+            payload: Bytes::from_static(b"data"),
+        };
+        let (a, b) = (publish(b"sensor-a"), publish(b"sensor-b"));
+        assert_ne!(a, b);
+        assert_eq!(a.content_key(), Some((&b"ch"[..], &b"data"[..])));
+        assert_eq!(a.content_key(), b.content_key());
+
+        let keys: HashSet<_> = [&a, &b].iter().filter_map(|f| f.content_key()).collect();
+        assert_eq!(keys.len(), 1);
+        let subscribe = Frame::Subscribe {
+            ident: Bytes::from_static(b"sensor-a"),
+            channel: Bytes::from_static(b"ch"),
+        };
+        assert_eq!(subscribe.content_key(), None);
+    }
+
+    #[test]
+    fn display_escapes_and_hexes() {
+        let publish = Frame::Publish {
+            ident: Bytes::from_static(b"sensor"),
+            channel: Bytes::from_static(b"ch"),
+            payload: Bytes::from_static(b"a\"b\x00"),
+        };
+        assert_eq!(
+            publish.to_string(),
+            r#"publish ident="sensor" channel="ch" payload="a\"b\x00""#
+        );
+        let info = Frame::Info {
+            name: Bytes::from_static(b"hpfeeds"),
+            rand: Bytes::from_static(&[0xde, 0xad]),
+        };
+        assert_eq!(info.to_string(), r#"info name="hpfeeds" rand=dead"#);
+    }
+
     #[test]
     fn publish_roundtrip() {
         let mut codec = HpfeedsCodec::new();
@@ -361,4 +674,63 @@ mod tests {
         // compute directly using sha1 to verify length
         assert_eq!(expected.len(), 20);
     }
+
+    #[test]
+    fn resync_skips_corrupt_frame() {
+        let mut codec = HpfeedsCodec::resync();
+        let first = Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch1"),
+            payload: Bytes::from_static(b"one"),
+        };
+        let second = Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch1"),
+            payload: Bytes::from_static(b"two"),
+        };
+        let mut buf = BytesMut::new();
+        codec.encode(first.clone(), &mut buf).unwrap();
+        // unknown opcode with a plausible length
+        buf.put_u32(8);
+        buf.put_u8(99);
+        buf.extend_from_slice(b"bad");
+        codec.encode(second.clone(), &mut buf).unwrap();
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(first));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(second));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(codec.skipped(), 1);
+    }
+
+    #[test]
+    fn resync_skips_over_limit_frame() {
+        let mut codec = HpfeedsCodec::resync();
+        let frame = Frame::Subscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch1"),
+        };
+        let mut buf = BytesMut::new();
+        // an OP_AUTH far larger than its opcode limit
+        buf.put_u32(1000);
+        buf.put_u8(OP_AUTH);
+        buf.extend_from_slice(&[0u8; 995]);
+        codec.encode(frame.clone(), &mut buf).unwrap();
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(frame));
+        assert_eq!(codec.skipped(), 1);
+    }
+
+    #[test]
+    fn strict_mode_rejects_corrupt_frame() {
+        let mut codec = HpfeedsCodec::new();
+        let mut buf = BytesMut::new();
+        buf.put_u32(8);
+        buf.put_u8(99);
+        buf.extend_from_slice(b"bad");
+        assert!(codec.decode(&mut buf).is_err());
+
+        let mut buf = BytesMut::new();
+        buf.put_u32(2);
+        assert!(codec.decode(&mut buf).is_err());
+    }
 }
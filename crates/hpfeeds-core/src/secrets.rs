@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+
+/// Minimum strength required of user secrets. Entropy is estimated from the character
+/// frequencies of the secret itself, so repeated or patterned secrets score low even when long.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SecretPolicy {
+    /// Shortest secret accepted, in characters
+    pub min_len: usize,
+    /// Least estimated entropy accepted, in bits
+    pub min_entropy_bits: f64,
+}
+
+impl SecretPolicy {
+    /// Describes why `secret` falls short of the policy, or returns `None` if it passes.
+    pub fn check(&self, secret: &str) -> Option<String> {
+        let len = secret.chars().count();
+        if len < self.min_len {
+            return Some(format!(
+                "secret is {} characters, below the minimum of {}",
+                len, self.min_len
+            ));
+        }
+        let bits = entropy_bits(secret);
+        if bits < self.min_entropy_bits {
+            return Some(format!(
+                "secret has about {:.0} bits of entropy, below the minimum of {}",
+                bits, self.min_entropy_bits
+            ));
+        }
+        None
+    }
+}
+
+/// Shannon entropy of the characters of `s`, times its length.
+pub fn entropy_bits(s: &str) -> f64 {
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    for c in s.chars() {
+        *counts.entry(c).or_default() += 1;
+    }
+    let len: usize = counts.values().sum();
+    let per_char: f64 = counts
+        .values()
+        .map(|&n| {
+            let p = n as f64 / len as f64;
+            -p * p.log2()
+        })
+        .sum();
+    per_char * len as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_length_then_entropy() {
+        let policy = SecretPolicy {
+            min_len: 12,
+            min_entropy_bits: 40.0,
+        };
+        assert!(policy.check("short").unwrap().contains("5 characters"));
+        assert!(
+            policy
+                .check("aaaaaaaaaaaaaaaa")
+                .unwrap()
+                .contains("entropy")
+        );
+        assert_eq!(policy.check("k3Vq9-zR!p2Lw7xM"), None);
+        assert_eq!(SecretPolicy::default().check(""), None);
+
+        assert_eq!(entropy_bits("aaaa"), 0.0);
+        assert_eq!(entropy_bits("abcd"), 8.0);
+    }
+}
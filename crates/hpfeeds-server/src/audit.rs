@@ -0,0 +1,107 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::io::{LineWriter, Write};
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Records queued for the writer before further ones are dropped.
+pub const AUDIT_QUEUE: usize = 65536;
+
+/// Where the audit trail goes.
+#[derive(Debug, Clone)]
+pub enum AuditTarget {
+    /// Appends one JSON line per publish
+    File(String),
+    /// Sends one RFC 5424 datagram per publish to this address
+    Syslog(String),
+}
+
+/// One accepted publish.
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    pub ts_ms: u64,
+    pub ident: String,
+    pub channel: String,
+    pub size: usize,
+    /// The start of the payload, when previews are on and the channel is not redacted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview: Option<String>,
+}
+
+/// Writes a record of every accepted publish from a background thread, so connections never
+/// wait on the disk or network.
+pub struct AuditLog {
+    tx: SyncSender<AuditRecord>,
+    dropped: AtomicU64,
+}
+
+impl AuditLog {
+    /// Opens `target` and starts the writer.
+    pub fn open(target: &AuditTarget) -> Result<Self> {
+        let (tx, rx) = sync_channel(AUDIT_QUEUE);
+        match target {
+            AuditTarget::File(path) => {
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?;
+                let mut out = LineWriter::new(file);
+                spawn_writer(rx, move |line| writeln!(out, "{}", line));
+            }
+            AuditTarget::Syslog(addr) => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(addr)?;
+                spawn_writer(rx, move |line| {
+                    let msg = format!("<134>1 - - hpfeeds-server - audit - {}", line);
+                    socket.send(msg.as_bytes()).map(|_| ())
+                });
+            }
+        }
+        Ok(Self {
+            tx,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Queues a record of a publish of `size` bytes by `ident` on `channel`, beginning with
+    /// `preview` if given.
+    pub fn record(&self, ident: &str, channel: &[u8], size: usize, preview: Option<String>) {
+        let ts_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let record = AuditRecord {
+            ts_ms,
+            ident: ident.to_string(),
+            channel: String::from_utf8_lossy(channel).into_owned(),
+            size,
+            preview,
+        };
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(record)
+            && self.dropped.fetch_add(1, Ordering::Relaxed) == 0
+        {
+            warn!("audit writer is behind; dropping records");
+        }
+    }
+
+    /// Records dropped because the writer fell behind.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+fn spawn_writer<W>(rx: Receiver<AuditRecord>, mut write: W)
+where
+    W: FnMut(&str) -> std::io::Result<()> + Send + 'static,
+{
+    std::thread::spawn(move || {
+        for record in rx {
+            let line = serde_json::to_string(&record).expect("audit records serialize");
+            if let Err(e) = write(&line) {
+                warn!("audit write failed: {}", e);
+            }
+        }
+    });
+}
@@ -1,9 +1,23 @@
 use async_trait::async_trait;
+use bytes::Bytes;
 use hpfeeds_core::hashsecret;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+/// True if the ACL entry `pattern` covers `channel`: `*` covers every channel, an entry ending
+/// in `.*` covers every channel under that prefix (`dionaea.*` covers `dionaea.capture` but not
+/// `dionaea` or `dionaeaX`), and anything else only the channel of that name.
+pub fn channel_matches(pattern: &str, channel: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    match pattern.strip_suffix('*') {
+        Some(prefix) if prefix.ends_with('.') => channel.starts_with(prefix),
+        _ => pattern == channel,
+    }
+}
+
 /// Permissions for an authenticated user
 #[derive(Debug, Clone, PartialEq)]
 pub struct AccessContext {
@@ -14,11 +28,117 @@ pub struct AccessContext {
 
 impl AccessContext {
     pub fn can_publish(&self, channel: &str) -> bool {
-        self.pub_channels.iter().any(|c| c == channel || c == "*")
+        self.pub_channels
+            .iter()
+            .any(|p| channel_matches(p, channel))
     }
 
     pub fn can_subscribe(&self, channel: &str) -> bool {
-        self.sub_channels.iter().any(|c| c == channel || c == "*")
+        self.sub_channels
+            .iter()
+            .any(|p| channel_matches(p, channel))
+    }
+
+    /// Like `can_publish`, for a raw channel name off the wire. Non-UTF-8 channels are denied.
+    pub fn can_publish_bytes(&self, channel: &[u8]) -> bool {
+        std::str::from_utf8(channel).is_ok_and(|c| self.can_publish(c))
+    }
+
+    /// Like `can_subscribe`, for a raw channel name off the wire. Non-UTF-8 channels are denied.
+    pub fn can_subscribe_bytes(&self, channel: &[u8]) -> bool {
+        std::str::from_utf8(channel).is_ok_and(|c| self.can_subscribe(c))
+    }
+}
+
+/// Most channels per direction whose decision a [`CachedAccess`] remembers, so a client cycling
+/// through channel names can't grow it without bound. Later channels are checked every time.
+pub const ACL_CACHE_LIMIT: usize = 1024;
+
+/// An [`AccessContext`] that remembers its allow/deny decision for each channel after the first
+/// check. The context doesn't change once a connection has authenticated, so neither do the
+/// decisions.
+#[derive(Debug)]
+pub struct CachedAccess {
+    ctx: AccessContext,
+    publish: HashMap<Bytes, bool>,
+    subscribe: HashMap<Bytes, bool>,
+    scans: u64,
+}
+
+impl CachedAccess {
+    pub fn new(ctx: AccessContext) -> Self {
+        Self {
+            ctx,
+            publish: HashMap::new(),
+            subscribe: HashMap::new(),
+            scans: 0,
+        }
+    }
+
+    pub fn context(&self) -> &AccessContext {
+        &self.ctx
+    }
+
+    /// Cached [`AccessContext::can_publish_bytes`].
+    pub fn can_publish(&mut self, channel: &[u8]) -> bool {
+        if let Some(&allowed) = self.publish.get(channel) {
+            return allowed;
+        }
+        self.scans += 1;
+        let allowed = self.ctx.can_publish_bytes(channel);
+        if self.publish.len() < ACL_CACHE_LIMIT {
+            self.publish
+                .insert(Bytes::copy_from_slice(channel), allowed);
+        }
+        allowed
+    }
+
+    /// Cached [`AccessContext::can_subscribe_bytes`].
+    pub fn can_subscribe(&mut self, channel: &[u8]) -> bool {
+        if let Some(&allowed) = self.subscribe.get(channel) {
+            return allowed;
+        }
+        self.scans += 1;
+        let allowed = self.ctx.can_subscribe_bytes(channel);
+        if self.subscribe.len() < ACL_CACHE_LIMIT {
+            self.subscribe
+                .insert(Bytes::copy_from_slice(channel), allowed);
+        }
+        allowed
+    }
+
+    /// How many checks have had to scan the channel lists.
+    pub fn scans(&self) -> u64 {
+        self.scans
+    }
+}
+
+/// What a client asks an [`Authorizer`] to be allowed to do with a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Publish,
+    Subscribe,
+}
+
+/// Decides whether an authenticated client may publish or subscribe to a channel. Unlike the
+/// ACL fixed in [`AccessContext`] at authentication, it is asked on every publish and
+/// subscribe, so decisions can change while the client stays connected, e.g. by time of day or
+/// by consulting an external policy engine. Keep it quick: it runs on the connection's task.
+pub trait Authorizer: Send + Sync {
+    fn authorize(&self, ctx: &AccessContext, op: Operation, channel: &str) -> bool;
+}
+
+/// What the broker does without an [`Authorizer`]: the client's ACL decides. Custom
+/// authorizers that only add restrictions can defer to it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AclAuthorizer;
+
+impl Authorizer for AclAuthorizer {
+    fn authorize(&self, ctx: &AccessContext, op: Operation, channel: &str) -> bool {
+        match op {
+            Operation::Publish => ctx.can_publish(channel),
+            Operation::Subscribe => ctx.can_subscribe(channel),
+        }
     }
 }
 
@@ -31,6 +151,13 @@ pub trait Authenticator: Send + Sync {
         secret_hash: &[u8],
         rand: &[u8],
     ) -> Option<AccessContext>;
+
+    /// Looks up `ident`'s access without checking a secret, for a client the TLS layer has
+    /// already identified by its certificate. The default knows no one, so such clients are
+    /// turned away.
+    async fn access(&self, _ident: &str) -> Option<AccessContext> {
+        None
+    }
 }
 
 struct UserData {
@@ -45,6 +172,12 @@ pub struct MemoryAuthenticator {
     inner: Arc<RwLock<HashMap<String, UserData>>>,
 }
 
+impl Default for MemoryAuthenticator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MemoryAuthenticator {
     pub fn new() -> Self {
         Self {
@@ -98,6 +231,15 @@ impl Authenticator for MemoryAuthenticator {
         }
         None
     }
+
+    async fn access(&self, ident: &str) -> Option<AccessContext> {
+        let m = self.inner.read().await;
+        m.get(ident).map(|user| AccessContext {
+            ident: ident.to_string(),
+            pub_channels: user.pub_channels.clone(),
+            sub_channels: user.sub_channels.clone(),
+        })
+    }
 }
 
 #[cfg(test)]
@@ -135,4 +277,102 @@ mod tests {
         assert!(!ctx.can_publish("pub2"));
         assert!(ctx.can_subscribe("any")); // because of *
     }
+
+    #[test]
+    fn prefix_patterns() {
+        assert!(channel_matches("dionaea.*", "dionaea.capture"));
+        assert!(channel_matches("dionaea.*", "dionaea.capture.raw"));
+        assert!(!channel_matches("dionaea.*", "dionaeaX"));
+        assert!(!channel_matches("dionaea.*", "dionaea"));
+        assert!(!channel_matches("dionaea.*", "xdionaea.capture"));
+        assert!(channel_matches("cowrie.sessions.*", "cowrie.sessions.ssh"));
+        assert!(!channel_matches("cowrie.sessions.*", "cowrie.sessions"));
+        assert!(channel_matches("*", "anything"));
+        assert!(channel_matches("*", ""));
+        // only a trailing `.*` is a pattern
+        assert!(!channel_matches("dionaea*", "dionaea.capture"));
+        assert!(channel_matches("dionaea*", "dionaea*"));
+        assert!(!channel_matches("a.*.b", "a.x.b"));
+        assert!(channel_matches("exact", "exact"));
+        assert!(!channel_matches("exact", "exact2"));
+
+        let ctx = AccessContext {
+            ident: "u".into(),
+            pub_channels: vec!["dionaea.*".into()],
+            sub_channels: vec!["cowrie.sessions.*".into(), "hpfeeds.keepalive".into()],
+        };
+        assert!(ctx.can_publish("dionaea.capture"));
+        assert!(!ctx.can_publish("dionaeaX"));
+        assert!(ctx.can_subscribe("cowrie.sessions.telnet"));
+        assert!(ctx.can_subscribe("hpfeeds.keepalive"));
+        assert!(!ctx.can_subscribe("cowrie.events"));
+    }
+
+    #[tokio::test]
+    async fn memory_authenticator_applies_prefix_patterns() {
+        let auth = MemoryAuthenticator::new();
+        auth.add_user("u", "s", vec!["dionaea.*".into()], vec![])
+            .await;
+        let rand = b"0123";
+        let ctx = auth
+            .authenticate("u", &hashsecret(rand, "s"), rand)
+            .await
+            .unwrap();
+        assert!(ctx.can_publish("dionaea.capture"));
+        assert!(!ctx.can_publish("dionaeaX"));
+    }
+
+    #[test]
+    fn access_context_byte_checks() {
+        let ctx = AccessContext {
+            ident: "u".into(),
+            pub_channels: vec!["pub1".into()],
+            sub_channels: vec!["*".into()],
+        };
+        assert!(ctx.can_publish_bytes(b"pub1"));
+        assert!(!ctx.can_publish_bytes(b"pub2"));
+        assert!(ctx.can_subscribe_bytes(b"anything"));
+        // invalid UTF-8 is denied even where a wildcard would match
+        assert!(!ctx.can_subscribe_bytes(b"ch\xff"));
+        assert!(!ctx.can_publish_bytes(b"pub1\xff"));
+    }
+
+    #[test]
+    fn cached_access_matches_uncached_checks() {
+        let ctx = AccessContext {
+            ident: "u".into(),
+            pub_channels: (0..5000).map(|i| format!("pub{}", i)).collect(),
+            sub_channels: (0..5000).map(|i| format!("sub{}", i)).collect(),
+        };
+        let mut cached = CachedAccess::new(ctx.clone());
+        let channels: Vec<Vec<u8>> = (4990..5010)
+            .flat_map(|i| [format!("pub{}", i), format!("sub{}", i)])
+            .map(String::into_bytes)
+            .chain([b"pub4999\xff".to_vec(), b"*".to_vec()])
+            .collect();
+        for _ in 0..3 {
+            for ch in &channels {
+                assert_eq!(cached.can_publish(ch), ctx.can_publish_bytes(ch));
+                assert_eq!(cached.can_subscribe(ch), ctx.can_subscribe_bytes(ch));
+            }
+        }
+        // only the first pass scanned
+        assert_eq!(cached.scans(), 2 * channels.len() as u64);
+    }
+
+    #[test]
+    fn cached_access_stops_remembering_at_the_limit() {
+        let mut cached = CachedAccess::new(AccessContext {
+            ident: "u".into(),
+            pub_channels: vec!["*".into()],
+            sub_channels: vec![],
+        });
+        for i in 0..ACL_CACHE_LIMIT + 10 {
+            assert!(cached.can_publish(format!("ch{}", i).as_bytes()));
+        }
+        let scans = cached.scans();
+        assert!(cached.can_publish(b"ch0"));
+        assert!(cached.can_publish(format!("ch{}", ACL_CACHE_LIMIT + 5).as_bytes()));
+        assert_eq!(cached.scans(), scans + 1);
+    }
 }
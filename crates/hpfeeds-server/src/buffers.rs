@@ -0,0 +1,79 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::Notify;
+
+/// Tracks the bytes held in delivery buffers across every connection.
+///
+/// Taking a hold that leaves the total above the limit wakes everything waiting in
+/// [`shedding`](Self::shedding). Connections wait there while a flush is blocked on a slow peer,
+/// so those are the ones shed.
+#[derive(Debug, Default)]
+pub struct BufferAccountant {
+    in_flight: AtomicUsize,
+    limit: Option<usize>,
+    shed: Notify,
+}
+
+impl BufferAccountant {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            ..Default::default()
+        }
+    }
+
+    /// Bytes currently held.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Accounts for `n` bytes until the returned guard is dropped.
+    pub fn hold(&self, n: usize) -> Held<'_> {
+        let total = self.in_flight.fetch_add(n, Ordering::Relaxed) + n;
+        if self.limit.is_some_and(|limit| total > limit) {
+            self.shed.notify_waiters();
+        }
+        Held { acct: self, n }
+    }
+
+    /// Completes the next time a hold takes the total over the limit.
+    pub async fn shedding(&self) {
+        self.shed.notified().await
+    }
+}
+
+/// Bytes accounted for by a [`BufferAccountant`], released on drop.
+pub struct Held<'a> {
+    acct: &'a BufferAccountant,
+    n: usize,
+}
+
+impl Drop for Held<'_> {
+    fn drop(&mut self) {
+        self.acct.in_flight.fetch_sub(self.n, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    fn holds_are_released_and_over_limit_sheds_waiters() {
+        let acct = BufferAccountant::new(Some(100));
+        let shed = acct.shedding();
+        futures::pin_mut!(shed);
+        assert!(shed.as_mut().now_or_never().is_none());
+
+        let a = acct.hold(60);
+        assert_eq!(acct.in_flight(), 60);
+        assert!(shed.as_mut().now_or_never().is_none());
+
+        let b = acct.hold(60);
+        assert_eq!(acct.in_flight(), 120);
+        assert!(shed.now_or_never().is_some());
+
+        drop((a, b));
+        assert_eq!(acct.in_flight(), 0);
+    }
+}
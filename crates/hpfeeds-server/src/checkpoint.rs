@@ -0,0 +1,55 @@
+use crate::metrics::Metrics;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+use tracing::warn;
+
+/// Writes the current value of every counter in [`Metrics::counters`] to `path` as a JSON
+/// object keyed by metric name. The file is replaced atomically, so a crash mid-write leaves
+/// the previous checkpoint in place.
+pub fn save(metrics: &Metrics, path: &Path) -> Result<()> {
+    let values: BTreeMap<&str, u64> = metrics
+        .counters()
+        .into_iter()
+        .map(|(name, counter)| (name, counter.get()))
+        .collect();
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(&values)?)
+        .with_context(|| format!("writing {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("replacing {}", path.display()))
+}
+
+/// Adds the values saved in `path` to the counters. Call it once, before the broker starts
+/// counting, so each counter resumes from its saved total; counters only ever go up, so this
+/// is the one way to set them. A missing file is a fresh start. Names the broker no longer
+/// exports are ignored.
+pub fn restore(metrics: &Metrics, path: &Path) -> Result<()> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    let values: BTreeMap<String, u64> = serde_json::from_slice(&data)
+        .with_context(|| format!("invalid metrics checkpoint {}", path.display()))?;
+    for (name, counter) in metrics.counters() {
+        if let Some(&v) = values.get(name) {
+            counter.inc_by(v);
+        }
+    }
+    Ok(())
+}
+
+/// Saves a checkpoint to `path` every `interval`, forever. Failures are logged and retried at
+/// the next interval.
+pub async fn run(metrics: &Metrics, path: &Path, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        if let Err(e) = save(metrics, path) {
+            warn!("metrics checkpoint failed: {:#}", e);
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use async_trait::async_trait;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Source of time for the broker's time-based behaviour.
+#[async_trait]
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Completes once `now()` has reached `deadline`.
+    async fn sleep_until(&self, deadline: Instant);
+}
+
+/// Wall-clock time, via the tokio timer.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioClock;
+
+#[async_trait]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        tokio::time::sleep_until(deadline.into()).await
+    }
+}
+
+/// A clock that only moves when `advance` is called, for deterministic tests.
+pub struct TestClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+    advanced: Notify,
+}
+
+impl Default for TestClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TestClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+            advanced: Notify::new(),
+        }
+    }
+
+    /// Moves time forward, waking any sleepers whose deadline has passed.
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+        self.advanced.notify_waiters();
+    }
+}
+
+#[async_trait]
+impl Clock for TestClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        loop {
+            // Created before the check so an advance in between is not missed
+            let advanced = self.advanced.notified();
+            if self.now() >= deadline {
+                return;
+            }
+            advanced.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_clock_sleeps_until_advanced() {
+        let clock = std::sync::Arc::new(TestClock::new());
+        let deadline = clock.now() + Duration::from_secs(10);
+        let sleeper = tokio::spawn({
+            let clock = clock.clone();
+            async move { clock.sleep_until(deadline).await }
+        });
+
+        clock.advance(Duration::from_secs(5));
+        tokio::task::yield_now().await;
+        assert!(!sleeper.is_finished());
+
+        clock.advance(Duration::from_secs(5));
+        tokio::time::timeout(Duration::from_secs(1), sleeper)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
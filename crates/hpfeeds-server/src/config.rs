@@ -1,8 +1,11 @@
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
+use hpfeeds_core::SecretPolicy;
 use serde::Deserialize;
 use std::fs;
+use std::path::Path;
+use tracing::warn;
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct UserConfig {
     pub ident: String,
     pub secret: String,
@@ -10,13 +13,419 @@ pub struct UserConfig {
     pub sub_channels: Vec<String>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// Publish de-duplication for the listed channels.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct DedupConfig {
+    pub channels: Vec<String>,
+    #[serde(default = "default_dedup_window_ms")]
+    pub window_ms: u64,
+}
+
+fn default_dedup_window_ms() -> u64 {
+    1000
+}
+
+/// Certificate and key for the hpfeeds listener, as paths relative to the working directory.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+    /// Require client certificates signed by the CAs in this file
+    #[serde(default)]
+    pub client_ca: Option<String>,
+    /// Oldest protocol version accepted; TLS 1.2 when unset
+    #[serde(default)]
+    pub min_version: Option<TlsVersion>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    V1_2,
+    #[serde(rename = "1.3")]
+    V1_3,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct ServerConfig {
     pub users: Vec<UserConfig>,
+    #[serde(default)]
+    pub dedup: Option<DedupConfig>,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+impl ServerConfig {
+    /// Folds `later` into this config. A user defined in both is replaced by the later
+    /// definition, and a `dedup` or `tls` section in `later` replaces this one.
+    pub fn merge(&mut self, later: ServerConfig) {
+        for user in later.users {
+            match self.users.iter_mut().find(|u| u.ident == user.ident) {
+                Some(existing) => {
+                    warn!("ident {} redefined by a later config file", user.ident);
+                    *existing = user;
+                }
+                None => self.users.push(user),
+            }
+        }
+        if later.dedup.is_some() {
+            self.dedup = later.dedup;
+        }
+        if later.tls.is_some() {
+            self.tls = later.tls;
+        }
+    }
+
+    /// Describes users whose permissions can never take effect: an empty ident matches no
+    /// client, and only the last of several definitions of an ident is used.
+    pub fn dangling_permissions(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (i, user) in self.users.iter().enumerate() {
+            let has_acl = !user.pub_channels.is_empty() || !user.sub_channels.is_empty();
+            if !has_acl {
+                continue;
+            }
+            if user.ident.is_empty() {
+                problems.push(format!("user #{} has channels but no ident", i + 1));
+            } else if self.users[i + 1..].iter().any(|u| u.ident == user.ident) {
+                problems.push(format!(
+                    "user #{} ({}) is shadowed by a later entry",
+                    i + 1,
+                    user.ident
+                ));
+            }
+        }
+        problems
+    }
+
+    /// Applies [`check_secrets`] to every configured user.
+    pub fn check_secrets(&self, policy: &SecretPolicy, strict: bool) -> Result<()> {
+        check_secrets(
+            self.users
+                .iter()
+                .map(|u| (u.ident.as_str(), u.secret.as_str())),
+            policy,
+            strict,
+        )
+    }
+}
+
+/// Warns about each `(ident, secret)` pair that falls short of `policy`, and fails if any do and
+/// `strict` is set.
+pub fn check_secrets<'a>(
+    users: impl IntoIterator<Item = (&'a str, &'a str)>,
+    policy: &SecretPolicy,
+    strict: bool,
+) -> Result<()> {
+    let mut weak = Vec::new();
+    for (ident, secret) in users {
+        if let Some(reason) = policy.check(secret) {
+            warn!("weak secret for {}: {}", ident, reason);
+            weak.push(ident);
+        }
+    }
+    if strict && !weak.is_empty() {
+        bail!("weak secrets for {}", weak.join(", "));
+    }
+    Ok(())
+}
+
+/// Applies the `--tls-cert`, `--tls-key` and `--tls-client-ca` flags over a config file's
+/// `tls` section, each flag replacing just its own field. Without a `tls` section the cert and
+/// key flags must come together, and the client CA flag needs them.
+pub fn tls_with_flags(
+    file: Option<TlsConfig>,
+    cert: Option<&str>,
+    key: Option<&str>,
+    client_ca: Option<&str>,
+) -> Result<Option<TlsConfig>> {
+    let mut tls = match (file, cert, key) {
+        (Some(tls), _, _) => tls,
+        (None, Some(cert), Some(key)) => TlsConfig {
+            cert: cert.to_string(),
+            key: key.to_string(),
+            client_ca: None,
+            min_version: None,
+        },
+        (None, None, None) if client_ca.is_none() => return Ok(None),
+        (None, None, None) => {
+            bail!("--tls-client-ca needs --tls-cert and --tls-key, or a tls section in --config")
+        }
+        (None, _, _) => {
+            bail!("--tls-cert and --tls-key go together unless --config has a tls section")
+        }
+    };
+    if let Some(cert) = cert {
+        tls.cert = cert.to_string();
+    }
+    if let Some(key) = key {
+        tls.key = key.to_string();
+    }
+    if let Some(client_ca) = client_ca {
+        tls.client_ca = Some(client_ca.to_string());
+    }
+    Ok(Some(tls))
+}
+
+/// Environment variable holding `ident:secret` users, separated by commas or newlines.
+pub const AUTH_ENV: &str = "HPFEEDS_AUTH";
+/// Environment variable naming a file of `ident:secret` users, one per line.
+pub const AUTH_FILE_ENV: &str = "HPFEEDS_AUTH_FILE";
+
+/// Splits `text` into `ident:secret` entries at `separators`, skipping blank entries and lines
+/// starting with `#`. Malformed entries are reported by position, never by content, since they
+/// may hold a secret.
+pub fn parse_auth_entries(text: &str, separators: &[char]) -> Result<Vec<String>> {
+    let mut entries = Vec::new();
+    for (i, entry) in text.split(separators).map(str::trim).enumerate() {
+        if entry.is_empty() || entry.starts_with('#') {
+            continue;
+        }
+        if !entry.contains(':') {
+            bail!("entry {} is not ident:secret", i + 1);
+        }
+        entries.push(entry.to_string());
+    }
+    Ok(entries)
+}
+
+/// Users given in `AUTH_ENV` and in the file named by `AUTH_FILE_ENV`, in that order.
+pub fn auth_from_env() -> Result<Vec<String>> {
+    let mut entries = Vec::new();
+    if let Ok(text) = std::env::var(AUTH_ENV) {
+        entries.extend(parse_auth_entries(&text, &[',', '\n']).context(AUTH_ENV)?);
+    }
+    if let Ok(path) = std::env::var(AUTH_FILE_ENV) {
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("reading {} {}", AUTH_FILE_ENV, path))?;
+        entries.extend(parse_auth_entries(&text, &['\n']).with_context(|| path.clone())?);
+    }
+    Ok(entries)
 }
 
+/// Loads a config file in the format its extension names: `.toml` with the `toml` feature,
+/// `.yaml` or `.yml` with the `yaml` feature, and JSON otherwise.
 pub fn load_config(path: &str) -> Result<ServerConfig> {
     let content = fs::read_to_string(path)?;
-    let config: ServerConfig = serde_json::from_str(&content)?;
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+    let config = match ext.as_str() {
+        #[cfg(feature = "toml")]
+        "toml" => toml::from_str(&content)?,
+        #[cfg(feature = "yaml")]
+        "yaml" | "yml" => serde_yaml::from_str(&content)?,
+        #[cfg(not(feature = "toml"))]
+        "toml" => bail!("TOML configs need hpfeeds-server built with the toml feature"),
+        #[cfg(not(feature = "yaml"))]
+        "yaml" | "yml" => bail!("YAML configs need hpfeeds-server built with the yaml feature"),
+        _ => serde_json::from_str(&content)?,
+    };
     Ok(config)
 }
+
+/// Loads each config in order and merges them, later files winning on conflicts.
+/// Returns `None` when `paths` is empty.
+pub fn load_configs<P: AsRef<str>>(paths: &[P]) -> Result<Option<ServerConfig>> {
+    let mut merged: Option<ServerConfig> = None;
+    for path in paths {
+        let path = path.as_ref();
+        let cfg = load_config(path).with_context(|| format!("loading config {}", path))?;
+        match &mut merged {
+            Some(m) => m.merge(cfg),
+            None => merged = Some(cfg),
+        }
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tls_flags_override_the_config_field_by_field() {
+        let file = TlsConfig {
+            cert: "file.pem".into(),
+            key: "file.key".into(),
+            client_ca: None,
+            min_version: Some(TlsVersion::V1_3),
+        };
+        let tls = tls_with_flags(Some(file.clone()), None, None, Some("ca.pem"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(tls.client_ca.as_deref(), Some("ca.pem"));
+        assert_eq!(
+            (tls.cert.as_str(), tls.key.as_str()),
+            ("file.pem", "file.key")
+        );
+        assert_eq!(tls.min_version, Some(TlsVersion::V1_3));
+
+        let tls = tls_with_flags(Some(file), Some("flag.pem"), None, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (tls.cert.as_str(), tls.key.as_str()),
+            ("flag.pem", "file.key")
+        );
+
+        let tls = tls_with_flags(None, Some("c.pem"), Some("k.pem"), Some("ca.pem"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(tls.client_ca.as_deref(), Some("ca.pem"));
+        assert!(tls_with_flags(None, None, None, None).unwrap().is_none());
+        assert!(tls_with_flags(None, Some("c.pem"), None, None).is_err());
+        assert!(tls_with_flags(None, None, Some("k.pem"), None).is_err());
+        assert!(tls_with_flags(None, None, None, Some("ca.pem")).is_err());
+    }
+
+    #[test]
+    fn auth_entries_skip_blanks_and_comments() {
+        let entries = parse_auth_entries("a:x, b:y:z,\n# c:nope\n\n", &[',', '\n']).unwrap();
+        assert_eq!(entries, ["a:x", "b:y:z"]);
+        let err = parse_auth_entries("a:x\nsecretonly\n", &['\n']).unwrap_err();
+        assert_eq!(err.to_string(), "entry 2 is not ident:secret");
+    }
+
+    #[test]
+    fn later_files_override_earlier_users() {
+        let dir = std::env::temp_dir();
+        let first = dir.join(format!("hpfeeds-config-a-{}.json", std::process::id()));
+        let second = dir.join(format!("hpfeeds-config-b-{}.json", std::process::id()));
+        fs::write(
+            &first,
+            r#"{"users": [
+                {"ident": "team-a", "secret": "a", "pub_channels": ["a"], "sub_channels": []},
+                {"ident": "shared", "secret": "old", "pub_channels": [], "sub_channels": ["x"]}
+            ], "dedup": {"channels": ["a"]}}"#,
+        )
+        .unwrap();
+        fs::write(
+            &second,
+            r#"{"users": [
+                {"ident": "shared", "secret": "new", "pub_channels": ["y"], "sub_channels": []},
+                {"ident": "team-b", "secret": "b", "pub_channels": ["b"], "sub_channels": []}
+            ]}"#,
+        )
+        .unwrap();
+
+        let paths = [first.to_str().unwrap(), second.to_str().unwrap()];
+        let merged = load_configs(&paths).unwrap().unwrap();
+        fs::remove_file(&first).unwrap();
+        fs::remove_file(&second).unwrap();
+
+        let idents: Vec<&str> = merged.users.iter().map(|u| u.ident.as_str()).collect();
+        assert_eq!(idents, ["team-a", "shared", "team-b"]);
+        let shared = &merged.users[1];
+        assert_eq!(shared.secret, "new");
+        assert_eq!(shared.pub_channels, ["y"]);
+        assert!(shared.sub_channels.is_empty());
+        // the second file has no dedup section, so the first one's stands
+        assert_eq!(merged.dedup.unwrap().channels, ["a"]);
+
+        assert!(load_configs::<&str>(&[]).unwrap().is_none());
+    }
+
+    #[cfg(all(feature = "toml", feature = "yaml"))]
+    #[test]
+    fn json_toml_and_yaml_configs_are_equivalent() {
+        let json = r#"{
+            "users": [
+                {"ident": "sensor", "secret": "s", "pub_channels": ["a", "b"], "sub_channels": []}
+            ],
+            "dedup": {"channels": ["a"], "window_ms": 500},
+            "tls": {"cert": "cert.pem", "key": "key.pem", "min_version": "1.3"}
+        }"#;
+        let toml = r#"
+            [[users]]
+            ident = "sensor"
+            secret = "s"
+            pub_channels = ["a", "b"]
+            sub_channels = []
+
+            [dedup]
+            channels = ["a"]
+            window_ms = 500
+
+            [tls]
+            cert = "cert.pem"
+            key = "key.pem"
+            min_version = "1.3"
+        "#;
+        let yaml = r#"
+users:
+  - ident: sensor
+    secret: s
+    pub_channels: [a, b]
+    sub_channels: []
+dedup:
+  channels: [a]
+  window_ms: 500
+tls:
+  cert: cert.pem
+  key: key.pem
+  min_version: "1.3"
+"#;
+        let dir = std::env::temp_dir();
+        let configs: Vec<ServerConfig> = [("json", json), ("toml", toml), ("YML", yaml)]
+            .iter()
+            .map(|(ext, text)| {
+                let path = dir.join(format!("hpfeeds-format-{}.{}", std::process::id(), ext));
+                fs::write(&path, text).unwrap();
+                let config = load_config(path.to_str().unwrap()).unwrap();
+                fs::remove_file(&path).unwrap();
+                config
+            })
+            .collect();
+
+        assert_eq!(configs[0].users[0].pub_channels, ["a", "b"]);
+        assert_eq!(
+            configs[0].tls.as_ref().unwrap().min_version,
+            Some(TlsVersion::V1_3)
+        );
+        assert_eq!(configs[0], configs[1]);
+        assert_eq!(configs[0], configs[2]);
+    }
+
+    #[test]
+    fn reports_acls_that_cannot_apply() {
+        let cfg: ServerConfig = serde_json::from_str(
+            r#"{"users": [
+                {"ident": "", "secret": "x", "pub_channels": ["a"], "sub_channels": []},
+                {"ident": "dup", "secret": "1", "pub_channels": [], "sub_channels": ["b"]},
+                {"ident": "dup", "secret": "2", "pub_channels": [], "sub_channels": ["b"]},
+                {"ident": "ok", "secret": "3", "pub_channels": ["c"], "sub_channels": []}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            cfg.dangling_permissions(),
+            [
+                "user #1 has channels but no ident",
+                "user #2 (dup) is shadowed by a later entry"
+            ]
+        );
+    }
+
+    #[test]
+    fn short_secret_fails_in_strict_mode() {
+        let cfg: ServerConfig = serde_json::from_str(
+            r#"{"users": [
+                {"ident": "sensor", "secret": "honey", "pub_channels": ["a"], "sub_channels": []},
+                {"ident": "reader", "secret": "Zq8-r4Lm!x2Vt9Wk", "pub_channels": [], "sub_channels": ["a"]}
+            ]}"#,
+        )
+        .unwrap();
+        let policy = SecretPolicy {
+            min_len: 12,
+            min_entropy_bits: 0.0,
+        };
+
+        let err = cfg.check_secrets(&policy, true).unwrap_err();
+        assert_eq!(err.to_string(), "weak secrets for sensor");
+        // without strict mode the weak secret is only logged
+        cfg.check_secrets(&policy, false).unwrap();
+    }
+}
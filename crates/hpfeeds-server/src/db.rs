@@ -78,8 +78,26 @@ impl SqliteAuthenticator {
             .await?;
         Ok(())
     }
+
+    /// Permission rows whose ident has no user, as `(ident, channel)`. These can never take
+    /// effect and are usually a typo.
+    pub async fn dangling_permissions(&self) -> Result<Vec<(String, String)>> {
+        let rows = self
+            .conn
+            .call(|conn| {
+                let mut stmt = conn.prepare(DANGLING_PERMISSIONS)?;
+                let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+                rows.collect::<Result<Vec<_>, _>>()
+            })
+            .await?;
+        Ok(rows)
+    }
 }
 
+/// Selects `(ident, channel)` for every permission row without a matching user.
+pub const DANGLING_PERMISSIONS: &str = "SELECT p.ident, p.channel FROM permissions p \
+     LEFT JOIN users u ON p.ident = u.ident WHERE u.ident IS NULL ORDER BY p.id";
+
 #[async_trait]
 impl Authenticator for SqliteAuthenticator {
     async fn authenticate(
@@ -108,43 +126,111 @@ impl Authenticator for SqliteAuthenticator {
                     return Ok(None);
                 }
 
-                let mut stmt = match conn
-                    .prepare("SELECT channel, can_pub, can_sub FROM permissions WHERE ident = ?")
-                {
-                    Ok(s) => s,
-                    Err(_) => return Ok(None),
-                };
-
-                let perms: Vec<(String, bool, bool)> = match stmt
-                    .query_map([&ident], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
-                {
-                    Ok(rows) => match rows.collect::<Result<Vec<_>, _>>() {
-                        Ok(p) => p,
-                        Err(_) => return Ok(None),
-                    },
-                    Err(_) => return Ok(None),
-                };
-
-                let mut pub_channels = Vec::new();
-                let mut sub_channels = Vec::new();
-
-                for (channel, can_pub, can_sub) in perms {
-                    if can_pub {
-                        pub_channels.push(channel.clone());
-                    }
-                    if can_sub {
-                        sub_channels.push(channel);
-                    }
-                }
+                Ok(user_access(conn, &ident))
+            })
+            .await
+            .ok()
+            .flatten()
+    }
 
-                Ok(Some(AccessContext {
-                    ident: ident.clone(),
-                    pub_channels,
-                    sub_channels,
-                }))
+    async fn access(&self, ident: &str) -> Option<AccessContext> {
+        let ident = ident.to_string();
+        self.conn
+            .call(move |conn| {
+                let known = conn
+                    .query_row("SELECT 1 FROM users WHERE ident = ?", [&ident], |_| Ok(()))
+                    .is_ok();
+                Ok::<_, rusqlite::Error>(if known {
+                    user_access(conn, &ident)
+                } else {
+                    None
+                })
             })
             .await
             .ok()
             .flatten()
     }
 }
+
+// Builds `ident`'s access from its rows in `permissions`.
+fn user_access(conn: &rusqlite::Connection, ident: &str) -> Option<AccessContext> {
+    let mut stmt = conn
+        .prepare("SELECT channel, can_pub, can_sub FROM permissions WHERE ident = ?")
+        .ok()?;
+    let perms = stmt
+        .query_map([ident], |row| {
+            Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?))
+        })
+        .ok()?
+        .collect::<Result<Vec<(String, bool, bool)>, _>>()
+        .ok()?;
+
+    let mut pub_channels = Vec::new();
+    let mut sub_channels = Vec::new();
+
+    for (channel, can_pub, can_sub) in perms {
+        if can_pub {
+            pub_channels.push(channel.clone());
+        }
+        if can_sub {
+            sub_channels.push(channel);
+        }
+    }
+
+    Some(AccessContext {
+        ident: ident.to_string(),
+        pub_channels,
+        sub_channels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reports_permissions_without_a_user() {
+        let path = std::env::temp_dir().join(format!("hpfeeds-acl-{}.db", std::process::id()));
+        let db = SqliteAuthenticator::new(path.to_str().unwrap())
+            .await
+            .unwrap();
+        db.add_user("sensor", "s3cret").await.unwrap();
+        db.add_permission("sensor", "cowrie", true, false)
+            .await
+            .unwrap();
+        // databases edited by other tools may not have enforced the foreign key
+        db.conn
+            .call(|conn| conn.execute_batch("PRAGMA foreign_keys = OFF"))
+            .await
+            .unwrap();
+        db.add_permission("sensr", "dionaea", true, false)
+            .await
+            .unwrap();
+
+        let dangling = db.dangling_permissions().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(dangling, [("sensr".to_string(), "dionaea".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn prefix_permissions_cover_subchannels() {
+        let path = std::env::temp_dir().join(format!("hpfeeds-prefix-{}.db", std::process::id()));
+        let db = SqliteAuthenticator::new(path.to_str().unwrap())
+            .await
+            .unwrap();
+        db.add_user("sensor", "s3cret").await.unwrap();
+        db.add_permission("sensor", "dionaea.*", true, true)
+            .await
+            .unwrap();
+
+        let rand = b"0123";
+        let ctx = db
+            .authenticate("sensor", &hpfeeds_core::hashsecret(rand, "s3cret"), rand)
+            .await;
+        std::fs::remove_file(&path).unwrap();
+        let ctx = ctx.unwrap();
+        assert!(ctx.can_publish("dionaea.capture"));
+        assert!(ctx.can_subscribe("dionaea.capture"));
+        assert!(!ctx.can_publish("dionaeaX"));
+    }
+}
@@ -0,0 +1,91 @@
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Suppresses identical publishes (same channel and payload) seen again within a short window.
+pub struct Deduplicator {
+    channels: HashSet<String>,
+    window: Duration,
+    seen: Mutex<Seen>,
+}
+
+// Publishes are keyed on a SHA-256 digest rather than their bytes, so a window full of large
+// payloads costs a few dozen bytes per publish.
+#[derive(Default)]
+struct Seen {
+    last: HashMap<[u8; 32], Instant>,
+    order: VecDeque<(Instant, [u8; 32])>,
+}
+
+impl Deduplicator {
+    pub fn new(channels: impl IntoIterator<Item = String>, window: Duration) -> Self {
+        Self {
+            channels: channels.into_iter().collect(),
+            window,
+            seen: Mutex::new(Seen::default()),
+        }
+    }
+
+    pub fn applies_to(&self, channel: &str) -> bool {
+        self.channels.contains(channel)
+    }
+
+    /// Records the publish and returns true if an identical one was seen within the window.
+    pub fn is_duplicate(&self, channel: &[u8], payload: &[u8], now: Instant) -> bool {
+        let key = content_key(channel, payload);
+
+        let mut seen = self.seen.lock().unwrap();
+        // Evict everything that has left the window
+        while let Some((at, _)) = seen.order.front() {
+            if now.duration_since(*at) < self.window {
+                break;
+            }
+            let (at, k) = seen.order.pop_front().unwrap();
+            if seen.last.get(&k) == Some(&at) {
+                seen.last.remove(&k);
+            }
+        }
+        if seen.last.contains_key(&key) {
+            return true;
+        }
+        seen.last.insert(key, now);
+        seen.order.push_back((now, key));
+        false
+    }
+}
+
+// SHA-256 over the channel's length, the channel and the payload, so that no channel and
+// payload pair can be run together into another.
+fn content_key(channel: &[u8], payload: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update((channel.len() as u64).to_be_bytes());
+    hasher.update(channel);
+    hasher.update(payload);
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicates_expire_after_window() {
+        let dedup = Deduplicator::new(["ch".to_string()], Duration::from_millis(100));
+        let start = Instant::now();
+        assert!(!dedup.is_duplicate(b"ch", b"a", start));
+        assert!(dedup.is_duplicate(b"ch", b"a", start + Duration::from_millis(50)));
+        assert!(!dedup.is_duplicate(b"ch", b"b", start + Duration::from_millis(50)));
+        assert!(!dedup.is_duplicate(b"other", b"a", start + Duration::from_millis(50)));
+        assert!(!dedup.is_duplicate(b"ch", b"a", start + Duration::from_millis(150)));
+    }
+
+    #[test]
+    fn channel_and_payload_are_not_run_together() {
+        let dedup = Deduplicator::new(["ch".to_string()], Duration::from_millis(100));
+        let start = Instant::now();
+        assert!(!dedup.is_duplicate(b"ch", b"ab", start));
+        assert!(!dedup.is_duplicate(b"cha", b"b", start));
+        assert!(dedup.is_duplicate(b"ch", b"ab", start));
+    }
+}
@@ -0,0 +1,18 @@
+pub mod audit;
+pub mod auth;
+pub mod buffers;
+pub mod checkpoint;
+pub mod clock;
+pub mod config;
+pub mod db;
+pub mod dedup;
+pub mod listen;
+pub mod metrics;
+pub mod ratelimit;
+#[cfg(feature = "recording")]
+pub mod recording;
+pub mod retain;
+pub mod server;
+pub mod stats;
+pub mod tls;
+pub mod topchannels;
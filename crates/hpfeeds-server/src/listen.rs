@@ -0,0 +1,38 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+/// Pending connections queued by the kernel, as for `TcpListener::bind`.
+const BACKLOG: i32 = 1024;
+
+/// Socket options for the broker and metrics listeners.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ListenOptions {
+    /// Set `SO_REUSEADDR`, so a restarted broker can bind while old connections sit in TIME_WAIT
+    pub reuse_addr: bool,
+    /// Set `SO_REUSEPORT`, so several broker processes can share the port, with the kernel
+    /// spreading connections between them. Unix only.
+    pub reuse_port: bool,
+}
+
+/// Binds a listener on `addr` with `options` applied.
+pub fn bind(addr: SocketAddr, options: ListenOptions) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if options.reuse_addr {
+        socket.set_reuse_address(true)?;
+    }
+    if options.reuse_port {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SO_REUSEPORT is not supported on this platform",
+        ));
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
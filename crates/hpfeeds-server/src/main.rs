@@ -1,32 +1,22 @@
+use anyhow::{Context, Result};
 use clap::Parser;
-use dashmap::DashMap;
-use futures::StreamExt;
-use hpfeeds_core::{Frame, HpfeedsCodec};
-use std::fs::File;
-use std::io::Read;
+use hpfeeds_core::SecretPolicy;
+use hpfeeds_server::audit::{AuditLog, AuditTarget};
+use hpfeeds_server::auth::{Authenticator, MemoryAuthenticator};
+use hpfeeds_server::db::SqliteAuthenticator;
+use hpfeeds_server::listen::{self, ListenOptions};
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{
+    BATCH_LIMIT, Broker, BrokerOptions, CHANNEL_SIZE, DEFAULT_KEEPALIVE_INTERVAL,
+    DEFAULT_SHUTDOWN_GRACE, MAX_BATCH_LIMIT, MAX_CHANNEL_CAPACITY, run_keepalive, run_server,
+};
+use hpfeeds_server::stats;
+use hpfeeds_server::topchannels::DEFAULT_TOP_CHANNELS_WINDOW;
+use hpfeeds_server::{checkpoint, config};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpListener;
-use tokio::sync::broadcast;
-use tokio_util::codec::Framed;
-use tracing::info;
-
-use anyhow::Result;
-use prometheus::{Encoder, IntCounter, Opts, Registry};
-use tokio_stream::wrappers::BroadcastStream;
-
-mod auth;
-use auth::{Authenticator, MemoryAuthenticator};
-mod config;
-mod db;
-use bytes::{BufMut, Bytes, BytesMut};
-use db::SqliteAuthenticator;
-use http_body_util::Full;
-use hyper::server::conn::http1;
-use hyper::service::service_fn;
-use hyper::{Request, Response, StatusCode};
-use hyper_util::rt::TokioIo;
+use std::time::Duration;
+use tracing::{info, warn};
 
 #[derive(Parser, Debug)]
 #[clap(name = "hpfeeds-server", about = "hpfeeds broker (Rust)")]
@@ -35,119 +25,287 @@ struct CliOpts {
     host: String,
     #[clap(long, default_value_t = 10000)]
     port: u16,
+    #[cfg(feature = "metrics")]
     #[clap(long, default_value_t = 9431)]
     metrics_port: u16,
+    /// Do not start the Prometheus metrics server
+    #[cfg(feature = "metrics")]
+    #[clap(long)]
+    no_metrics: bool,
+    /// Times to retry binding the metrics port, a second apart, before giving up on it
+    #[cfg(feature = "metrics")]
+    #[clap(long, default_value_t = 0)]
+    metrics_bind_retries: u32,
+    /// Exit if the metrics port cannot be bound, rather than running without metrics
+    #[cfg(feature = "metrics")]
+    #[clap(long)]
+    strict_metrics: bool,
+    /// Set SO_REUSEADDR on the listeners, for restarts while old connections are in TIME_WAIT
+    #[clap(long)]
+    reuse_addr: bool,
+    /// Set SO_REUSEPORT on the listeners, so several brokers can share the ports (Unix only)
+    #[clap(long)]
+    reuse_port: bool,
+    /// ident:secret user; repeat for more. Also read from HPFEEDS_AUTH (comma-separated) and
+    /// from the file named by HPFEEDS_AUTH_FILE (one per line), which keep secrets out of ps
     #[clap(long = "auth")]
     auth: Vec<String>,
+    /// User config in JSON, TOML or YAML by extension; repeat to merge several, later files
+    /// winning for a repeated ident
     #[clap(long)]
-    config: Option<String>,
+    config: Vec<String>,
     #[clap(long)]
     db: Option<String>,
+    /// Warn at startup about ACL entries that can never apply, such as permissions for an
+    /// ident with no user
+    #[clap(long)]
+    validate_acls: bool,
+    /// Warn about user secrets shorter than this many characters
+    #[clap(long, default_value_t = 0)]
+    min_secret_len: usize,
+    /// Warn about user secrets with less than this many bits of estimated entropy
+    #[clap(long, default_value_t = 0.0)]
+    min_secret_entropy: f64,
+    /// Refuse to start if any secret from --config or --auth is below the minimums
+    #[clap(long)]
+    strict_secrets: bool,
     #[clap(long)]
     json: bool,
+    /// Most verbose level logged: error, warn, info, debug or trace
+    #[clap(long, default_value_t = tracing::Level::INFO)]
+    log_level: tracing::Level,
     #[clap(long)]
     tls_cert: Option<String>,
     #[clap(long)]
     tls_key: Option<String>,
-}
-
-type SubscriberMap = Arc<DashMap<String, broadcast::Sender<Bytes>>>;
-const CHANNEL_SIZE: usize = 65536;
-const BATCH_LIMIT: usize = 128;
-
-struct Metrics {
-    registry: Registry,
-    total_delivered: IntCounter,
-    total_lagged: IntCounter,
-    total_published: IntCounter,
-    total_auth_success: IntCounter,
-    total_auth_fail: IntCounter,
-}
-
-impl Metrics {
-    fn new() -> Self {
-        let registry = Registry::new();
-        let total_delivered = IntCounter::with_opts(Opts::new(
-            "hpfeeds_delivered_total",
-            "Total messages successfully sent",
-        ))
-        .unwrap();
-        let total_lagged = IntCounter::with_opts(Opts::new(
-            "hpfeeds_lagged_total",
-            "Total messages dropped due to lag",
-        ))
-        .unwrap();
-        let total_published = IntCounter::with_opts(Opts::new(
-            "hpfeeds_published_total",
-            "Total messages received from publishers",
-        ))
-        .unwrap();
-        let total_auth_success = IntCounter::with_opts(Opts::new(
-            "hpfeeds_auth_success_total",
-            "Total successful auths",
-        ))
-        .unwrap();
-        let total_auth_fail =
-            IntCounter::with_opts(Opts::new("hpfeeds_auth_fail_total", "Total failed auths"))
-                .unwrap();
-        registry
-            .register(Box::new(total_delivered.clone()))
-            .unwrap();
-        registry.register(Box::new(total_lagged.clone())).unwrap();
-        registry
-            .register(Box::new(total_published.clone()))
-            .unwrap();
-        registry
-            .register(Box::new(total_auth_success.clone()))
-            .unwrap();
-        registry
-            .register(Box::new(total_auth_fail.clone()))
-            .unwrap();
-        Metrics {
-            registry,
-            total_delivered,
-            total_lagged,
-            total_published,
-            total_auth_success,
-            total_auth_fail,
-        }
-    }
+    /// Require client certificates signed by the CAs in this file; clients are then known by
+    /// their certificate's CN rather than their secret
+    #[clap(long)]
+    tls_client_ca: Option<String>,
+    /// Length in bytes of the rand sent to clients in OP_INFO
+    #[clap(long, default_value_t = 16, value_parser = clap::value_parser!(u8).range(4..=32))]
+    rand_len: u8,
+    /// Keep the last N messages per channel and send them to new subscribers
+    #[clap(long, default_value_t = 0)]
+    retain: usize,
+    /// Drop retained messages older than this many seconds
+    #[clap(long)]
+    retain_ttl_secs: Option<u64>,
+    /// Log a warning when a publish takes longer than this to reach a subscriber
+    #[clap(long)]
+    slow_delivery_ms: Option<u64>,
+    /// Disconnect clients that neither send nor receive anything for this many seconds
+    /// (0 = never)
+    #[clap(long, default_value_t = 0)]
+    idle_timeout: u64,
+    /// Close, with OP_ERROR, connections beyond this many open at once for the same ident
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_conns_per_ident: Option<u64>,
+    /// Refuse subscribes, with OP_ERROR, that would give more than this many channels
+    /// subscribers at once
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_channels: Option<u64>,
+    /// Accept up to this many capability-selection frames before OP_AUTH (0 = strict)
+    #[clap(long, default_value_t = 0)]
+    preauth_frames: usize,
+    /// Close new connections on accept while this many others have yet to authenticate
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_unauthenticated: Option<u64>,
+    /// Close new connections on accept beyond this many a second, in bursts of up to as many
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_accepts_per_sec: Option<u32>,
+    /// Disconnect blocked subscribers when delivery buffers exceed this many bytes in total
+    #[clap(long)]
+    max_buffered_bytes: Option<usize>,
+    /// Warn when a subscriber falls this many messages behind on a channel
+    #[clap(long)]
+    backlog_high_water: Option<u64>,
+    /// Append a JSON line per accepted publish (timestamp, ident, channel, size) to this file
+    #[clap(long, conflicts_with = "audit_syslog")]
+    audit_file: Option<String>,
+    /// Send the same audit records to this syslog address over UDP
+    #[clap(long)]
+    audit_syslog: Option<String>,
+    /// Reject publishes and subscribes on channels not matching this regex, e.g.
+    /// '^[a-z0-9._-]+$'
+    #[clap(long)]
+    channel_name_regex: Option<String>,
+    /// Include this many leading payload bytes in audit records and publish log lines
+    #[clap(long, default_value_t = 0)]
+    log_payload_preview_bytes: usize,
+    /// Never log or audit payload bytes on channels matching this regex, only sizes
+    #[clap(long)]
+    redact_channels: Option<String>,
+    /// Disconnect a subscriber once it has dropped more than this many messages to lag
+    #[clap(long)]
+    max_lag_drops: Option<u64>,
+    /// Channels on which lagging subscribers are sent only the newest queued publish
+    #[clap(long, value_delimiter = ',')]
+    coalesce: Vec<String>,
+    /// Publish a heartbeat on this channel so idle subscribers' connections stay open through
+    /// NAT and firewall timeouts; clients cannot publish on it
+    #[clap(long)]
+    keepalive_channel: Option<String>,
+    /// Seconds between heartbeats on --keepalive-channel
+    #[clap(long, default_value_t = DEFAULT_KEEPALIVE_INTERVAL.as_secs(),
+           value_parser = clap::value_parser!(u64).range(1..))]
+    keepalive_interval_secs: u64,
+    /// Drop client frames, of any opcode, beyond this many a second per connection
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_frame_rate: Option<u32>,
+    /// Disconnect a client once its frames have been dropped by --max-frame-rate for this many
+    /// seconds without a quiet second
+    #[clap(long, requires = "max_frame_rate")]
+    frame_rate_disconnect_secs: Option<u64>,
+    /// Drop client publishes beyond this many a second per connection, without disconnecting
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_pub_rate: Option<u32>,
+    /// Answer a publish on __whoami__ with the client's own ident and channel permissions
+    #[clap(long)]
+    whoami: bool,
+    /// Message of the day, e.g. terms of use, published to each client on __motd__ right after
+    /// OP_INFO
+    #[clap(long)]
+    motd: Option<String>,
+    /// Answer publishes the ACL denies with OP_ERROR rather than dropping them silently
+    #[clap(long)]
+    report_denied: bool,
+    /// Seconds connections get to finish after SIGTERM or Ctrl-C before they are dropped
+    #[clap(long, default_value_t = DEFAULT_SHUTDOWN_GRACE.as_secs())]
+    shutdown_grace_secs: u64,
+    /// Track the busiest N channels by publish count, served as JSON at /admin/top-channels on
+    /// the metrics port
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    top_channels: Option<u64>,
+    /// Seconds of publishes counted by --top-channels
+    #[clap(long, default_value_t = DEFAULT_TOP_CHANNELS_WINDOW.as_secs(),
+           value_parser = clap::value_parser!(u64).range(1..))]
+    top_channels_window_secs: u64,
+    /// Log a summary of publishes, deliveries, lag, auths and open connections this often
+    #[clap(long)]
+    stats_interval_secs: Option<u64>,
+    /// Save counter totals to this file and resume from it on startup
+    #[clap(long)]
+    metrics_checkpoint: Option<std::path::PathBuf>,
+    /// How often to save the metrics checkpoint
+    #[clap(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    metrics_checkpoint_interval_secs: u64,
+    /// Most messages written to a subscriber per flush; lower favours latency
+    #[clap(long, default_value_t = BATCH_LIMIT as u64,
+           value_parser = clap::value_parser!(u64).range(1..=MAX_BATCH_LIMIT as u64))]
+    batch_limit: u64,
+    /// Messages buffered per channel before slow subscribers start to lag
+    #[clap(long, default_value_t = CHANNEL_SIZE as u64,
+           value_parser = clap::value_parser!(u64).range(1..=MAX_CHANNEL_CAPACITY as u64))]
+    channel_capacity: u64,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let opts = CliOpts::parse();
+    let logs = tracing_subscriber::fmt().with_max_level(opts.log_level);
     if opts.json {
-        tracing_subscriber::fmt().json().init();
+        logs.json().init();
     } else {
-        tracing_subscriber::fmt::init();
+        logs.init();
     }
 
     let addr: SocketAddr = format!("{}:{}", opts.host, opts.port).parse()?;
-    let listener = TcpListener::bind(addr).await?;
+    let listen_options = ListenOptions {
+        reuse_addr: opts.reuse_addr,
+        reuse_port: opts.reuse_port,
+    };
+    let listener = listen::bind(addr, listen_options)
+        .with_context(|| format!("binding hpfeeds listener on {}", addr))?;
     info!("hpfeeds-server listening on {}", addr);
 
-    let tls_acceptor = if let (Some(cert_path), Some(key_path)) = (&opts.tls_cert, &opts.tls_key) {
-        // validate user-supplied paths to avoid path traversal / absolute path use
-        if !is_safe_relative_path(cert_path) || !is_safe_relative_path(key_path) {
-            eprintln!("Refusing to use absolute or parent-directory TLS paths");
-            return Err(anyhow::anyhow!("unsafe TLS path"));
+    let metrics = Arc::new(Metrics::new());
+    if let Some(path) = &opts.metrics_checkpoint {
+        checkpoint::restore(&metrics, path)?;
+        info!("Resumed counters from {}", path.display());
+    }
+    let cfg = config::load_configs(&opts.config)?;
+    let tls = config::tls_with_flags(
+        cfg.as_ref().and_then(|c| c.tls.clone()),
+        opts.tls_cert.as_deref(),
+        opts.tls_key.as_deref(),
+        opts.tls_client_ca.as_deref(),
+    )?;
+    let tls_acceptor = match &tls {
+        Some(tls) => {
+            info!("TLS enabled with cert: {} and key: {}", tls.cert, tls.key);
+            Some(Arc::new(hpfeeds_server::tls::acceptor(tls)?))
         }
-        info!("TLS enabled with cert: {} and key: {}", cert_path, key_path);
-        Some(Arc::new(load_tls_config(cert_path, key_path)?))
-    } else {
-        None
+        None => None,
+    };
+    let secret_policy = SecretPolicy {
+        min_len: opts.min_secret_len,
+        min_entropy_bits: opts.min_secret_entropy,
+    };
+    let options = BrokerOptions {
+        batch_limit: opts.batch_limit as usize,
+        channel_capacity: opts.channel_capacity as usize,
+        rand_len: opts.rand_len.into(),
+        dedup: cfg.as_ref().and_then(|c| c.dedup.clone()),
+        retain_depth: opts.retain,
+        retain_ttl: opts.retain_ttl_secs.map(Duration::from_secs),
+        idle_timeout: Some(opts.idle_timeout)
+            .filter(|&s| s > 0)
+            .map(Duration::from_secs),
+        max_channels: opts.max_channels.map(|n| n as usize),
+        max_conns_per_ident: opts.max_conns_per_ident.map(|n| n as usize),
+        slow_delivery: opts.slow_delivery_ms.map(Duration::from_millis),
+        preauth_frames: opts.preauth_frames,
+        max_unauthenticated: opts.max_unauthenticated.map(|n| n as usize),
+        max_accepts_per_sec: opts.max_accepts_per_sec,
+        max_buffered_bytes: opts.max_buffered_bytes,
+        backlog_high_water: opts.backlog_high_water,
+        channel_name_regex: opts
+            .channel_name_regex
+            .as_deref()
+            .map(regex::Regex::new)
+            .transpose()
+            .context("invalid --channel-name-regex")?,
+        log_payload_preview_bytes: opts.log_payload_preview_bytes,
+        redact_channels: opts
+            .redact_channels
+            .as_deref()
+            .map(regex::Regex::new)
+            .transpose()
+            .context("invalid --redact-channels")?,
+        coalesce: opts.coalesce.iter().cloned().collect(),
+        max_lag_drops: opts.max_lag_drops,
+        keepalive_channel: opts.keepalive_channel.clone(),
+        keepalive_interval: Duration::from_secs(opts.keepalive_interval_secs),
+        max_frame_rate: opts.max_frame_rate,
+        frame_rate_disconnect: opts.frame_rate_disconnect_secs.map(Duration::from_secs),
+        max_pub_rate: opts.max_pub_rate,
+        whoami: opts.whoami,
+        report_denied: opts.report_denied,
+        motd: opts.motd.clone(),
+        shutdown_grace: Duration::from_secs(opts.shutdown_grace_secs),
+        top_channels: opts.top_channels.map(|n| n as usize),
+        top_channels_window: Duration::from_secs(opts.top_channels_window_secs),
+        ..Default::default()
     };
-
-    let subscribers: SubscriberMap = Arc::new(DashMap::new());
-    let metrics = Arc::new(Metrics::new());
 
     let authenticator: Arc<dyn Authenticator> = if let Some(db_path) = &opts.db {
-        Arc::new(SqliteAuthenticator::new(db_path).await?)
+        let db = SqliteAuthenticator::new(db_path).await?;
+        if opts.validate_acls {
+            for (ident, channel) in db.dangling_permissions().await? {
+                warn!("ACL on {} for unknown ident {}", channel, ident);
+            }
+        }
+        Arc::new(db)
     } else {
         let mem_auth = Arc::new(MemoryAuthenticator::new());
-        if let Some(config_path) = &opts.config {
-            let cfg = config::load_config(config_path)?;
+        if let Some(cfg) = cfg {
+            cfg.check_secrets(&secret_policy, opts.strict_secrets)?;
+            if opts.validate_acls {
+                for problem in cfg.dangling_permissions() {
+                    warn!("config: {}", problem);
+                }
+            }
             for user in cfg.users {
                 mem_auth
                     .add_user(
@@ -159,256 +317,111 @@ async fn main() -> Result<()> {
                     .await;
             }
         }
-        for a in opts.auth.iter() {
-            if let Some((ident, secret)) = a.split_once(':') {
-                mem_auth.add(ident, secret).await;
-            }
+        // later entries win, so the flags override the environment
+        let mut auth_entries = config::auth_from_env()?;
+        auth_entries.extend(opts.auth.iter().cloned());
+        let auth_users: Vec<(&str, &str)> = auth_entries
+            .iter()
+            .filter_map(|a| a.split_once(':'))
+            .collect();
+        config::check_secrets(
+            auth_users.iter().copied(),
+            &secret_policy,
+            opts.strict_secrets,
+        )?;
+        for (ident, secret) in auth_users {
+            mem_auth.add(ident, secret).await;
         }
         mem_auth
     };
 
-    let metrics_registry = metrics.registry.clone();
-    let metrics_addr = SocketAddr::from(([0, 0, 0, 0], opts.metrics_port));
-    tokio::spawn(async move {
-        let listener = TcpListener::bind(metrics_addr).await.unwrap();
-        loop {
-            let (stream, _) = match listener.accept().await {
-                Ok(s) => s,
-                Err(_) => continue,
-            };
-            let io = TokioIo::new(stream);
-            let reg = metrics_registry.clone();
-            tokio::task::spawn(async move {
-                let _ = http1::Builder::new()
-                    .serve_connection(
-                        io,
-                        service_fn(move |req: Request<hyper::body::Incoming>| {
-                            let reg = reg.clone();
-                            async move {
-                                if req.uri().path() == "/metrics" {
-                                    let mut buffer = vec![];
-                                    prometheus::TextEncoder::new()
-                                        .encode(&reg.gather(), &mut buffer)
-                                        .unwrap();
-                                    Ok::<_, anyhow::Error>(Response::new(Full::new(Bytes::from(
-                                        buffer,
-                                    ))))
-                                } else {
-                                    let mut res =
-                                        Response::new(Full::new(Bytes::from("Not Found")));
-                                    *res.status_mut() = StatusCode::NOT_FOUND;
-                                    Ok(res)
-                                }
-                            }
-                        }),
-                    )
-                    .await;
-            });
-        }
-    });
-
-    loop {
-        let (socket, peer) = listener.accept().await?;
-        let _ = socket.set_nodelay(true);
-        let (subs, mets, auth, tls) = (
-            subscribers.clone(),
-            metrics.clone(),
-            authenticator.clone(),
-            tls_acceptor.clone(),
-        );
+    if let Some(secs) = opts.stats_interval_secs.filter(|&s| s > 0) {
+        let metrics = metrics.clone();
         tokio::spawn(async move {
-            if let Some(acceptor) = tls {
-                if let Ok(stream) = acceptor.accept(socket).await {
-                    handle_connection(stream, peer, subs, mets, auth).await;
-                }
-            } else {
-                handle_connection(socket, peer, subs, mets, auth).await;
-            }
+            stats::report(&metrics, Duration::from_secs(secs), |line| {
+                info!("{}", line)
+            })
+            .await
         });
     }
-}
-
-fn load_tls_config(cert_path: &str, key_path: &str) -> Result<tokio_rustls::TlsAcceptor> {
-    // Extra safety: check for path traversal or absolute paths
-    if !is_safe_relative_path(cert_path) || !is_safe_relative_path(key_path) {
-        return Err(anyhow::anyhow!(
-            "Unsafe TLS file path: absolute or parent-directory component detected"
-        ));
-    }
 
-    // Read and parse PEM-encoded certs
-    // Prevent path traversal attacks by rejecting paths containing '..'
-    let cert_path = std::path::Path::new(cert_path);
-    if cert_path
-        .components()
-        .any(|c| c == std::path::Component::ParentDir)
-    {
-        return Err(anyhow::anyhow!("Invalid input: {}", cert_path.display()));
-    }
-    let cert_data = std::fs::read_to_string(cert_path)?;
-    let cert_pems = pem::parse_many(&cert_data)?;
-    let cert_chain = cert_pems
-        .into_iter()
-        .filter(|p| p.tag() == "CERTIFICATE")
-        .map(|p| rustls::pki_types::CertificateDer::from(p.contents().to_vec()))
-        .collect::<Vec<_>>();
-    if cert_chain.is_empty() {
-        return Err(anyhow::anyhow!(
-            "no certificates found in {}",
-            cert_path.display()
-        ));
+    if let Some(path) = opts.metrics_checkpoint.clone() {
+        let metrics = metrics.clone();
+        let interval = Duration::from_secs(opts.metrics_checkpoint_interval_secs);
+        tokio::spawn(async move { checkpoint::run(&metrics, &path, interval).await });
     }
 
-    // Read and parse PEM-encoded private key (support PKCS#8 / PKCS#1 / EC)
-    // Prevent path traversal attacks by rejecting paths containing '..'
-    let key_path = std::path::Path::new(key_path);
-    if key_path
-        .components()
-        .any(|c| c == std::path::Component::ParentDir)
-    {
-        return Err(anyhow::anyhow!("Invalid input: {}", key_path.display()));
+    let mut broker = Broker::with_options(authenticator, metrics, options);
+    let audit = match (opts.audit_file, opts.audit_syslog) {
+        (Some(path), _) => Some(AuditTarget::File(path)),
+        (None, Some(addr)) => Some(AuditTarget::Syslog(addr)),
+        (None, None) => None,
+    };
+    if let Some(target) = audit {
+        info!("Auditing publishes to {:?}", target);
+        broker = broker.with_audit(AuditLog::open(&target)?);
     }
-    let key_data = std::fs::read_to_string(key_path)?;
-    let key_pems = pem::parse_many(&key_data)?;
-    let key_pem = key_pems
-        .into_iter()
-        .find(|p| {
-            let t = p.tag();
-            t == "PRIVATE KEY" || t == "RSA PRIVATE KEY" || t == "EC PRIVATE KEY"
-        })
-        .ok_or_else(|| anyhow::anyhow!("no private key found"))?;
-    let key = rustls::pki_types::PrivateKeyDer::try_from(key_pem.contents().to_vec())
-        .map_err(|e| anyhow::anyhow!(e))?;
+    let broker = Arc::new(broker);
 
-    let config = rustls::ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(cert_chain, key)?;
-    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
-}
-
-/// Return false for absolute paths or any parent-directory (`..`) components.
-fn is_safe_relative_path(p: &str) -> bool {
-    let path = std::path::Path::new(p);
-    if path.is_absolute() {
-        return false;
-    }
-    for comp in path.components() {
-        if matches!(comp, std::path::Component::ParentDir) {
-            return false;
+    #[cfg(feature = "metrics")]
+    if !opts.no_metrics {
+        use hpfeeds_server::metrics::{
+            METRICS_BIND_RETRY_DELAY, METRICS_CONN_TIMEOUT, METRICS_MAX_CONNECTIONS, bind_metrics,
+            serve_metrics,
+        };
+        let metrics_addr = SocketAddr::from(([0, 0, 0, 0], opts.metrics_port));
+        match bind_metrics(
+            metrics_addr,
+            listen_options,
+            opts.metrics_bind_retries,
+            METRICS_BIND_RETRY_DELAY,
+        )
+        .await
+        {
+            Ok(listener) => {
+                tokio::spawn(serve_metrics(
+                    listener,
+                    broker.metrics.registry.clone(),
+                    broker.top_channels(),
+                    METRICS_MAX_CONNECTIONS,
+                    METRICS_CONN_TIMEOUT,
+                ));
+            }
+            Err(e) if opts.strict_metrics => return Err(e),
+            Err(e) => tracing::error!("{:#}; continuing without metrics", e),
         }
     }
-    true
-}
 
-async fn handle_connection<S>(
-    stream: S,
-    _peer: SocketAddr,
-    subscribers: SubscriberMap,
-    metrics: Arc<Metrics>,
-    authenticator: Arc<dyn Authenticator>,
-) where
-    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
-{
-    let (reader, mut writer) = tokio::io::split(stream);
-    let mut read_framed = Framed::new(reader, HpfeedsCodec::new());
-    let mut codec = HpfeedsCodec::new();
-
-    let mut randbuf = vec![0u8; 16];
-    if let Ok(mut f) = File::open("/dev/urandom") {
-        if f.read_exact(&mut randbuf).is_err() {
-            return;
-        }
-    } else {
-        return;
-    }
-    let info_bytes = codec
-        .encode_to_bytes(Frame::Info {
-            name: "hpfeeds-rs".to_string().into(),
-            rand: randbuf.clone().into(),
-        })
-        .unwrap();
-    if writer.write_all(&info_bytes).await.is_err() {
-        return;
+    if let Some(channel) = &opts.keepalive_channel {
+        info!(
+            "Heartbeat on {} every {}s",
+            channel, opts.keepalive_interval_secs
+        );
+        tokio::spawn(run_keepalive(broker.clone()));
     }
+    let stopping = broker.clone();
+    let signal = shutdown_signal();
+    tokio::spawn(async move {
+        signal.await;
+        info!("Shutting down");
+        stopping.shutdown();
+    });
+    run_server(listener, broker, tls_acceptor).await
+}
 
-    use auth::AccessContext;
-    let access_ctx: AccessContext =
-        if let Some(Ok(Frame::Auth { ident, secret_hash })) = read_framed.next().await {
-            let ident_str = String::from_utf8_lossy(&ident);
-            if let Some(ctx) = authenticator
-                .authenticate(&ident_str, &secret_hash, &randbuf)
-                .await
-            {
-                metrics.total_auth_success.inc();
-                ctx
-            } else {
-                metrics.total_auth_fail.inc();
-                return;
-            }
-        } else {
-            return;
-        };
-
-    let mut write_buf = BytesMut::with_capacity(CHANNEL_SIZE);
-    let mut stream_map = tokio_stream::StreamMap::new();
-
-    loop {
+/// Resolves on Ctrl-C, or SIGTERM where there is one. The SIGTERM handler is installed before
+/// this returns, so the signal is caught from then on even if the future is not yet polled.
+fn shutdown_signal() -> impl std::future::Future<Output = ()> {
+    #[cfg(unix)]
+    let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("installing SIGTERM handler");
+    async move {
+        #[cfg(unix)]
         tokio::select! {
-            Some((_chan, result)) = stream_map.next(), if !stream_map.is_empty() => {
-                match result {
-                    Ok(msg) => {
-                        write_buf.put(msg);
-                        metrics.total_delivered.inc();
-                        let mut count = 1;
-                        {
-                            let waker = futures::task::noop_waker();
-                            let mut cx = std::task::Context::from_waker(&waker);
-                            while count < BATCH_LIMIT {
-                                match stream_map.poll_next_unpin(&mut cx) {
-                                    std::task::Poll::Ready(Some((_, Ok(next_msg)))) => {
-                                        write_buf.put(next_msg);
-                                        metrics.total_delivered.inc();
-                                        count += 1;
-                                    }
-                                    _ => break,
-                                }
-                            }
-                        }
-                        if writer.write_all(&write_buf).await.is_err() { break; }
-                        write_buf.clear();
-                    }
-                    Err(tokio_stream::wrappers::errors::BroadcastStreamRecvError::Lagged(n)) => {
-                        metrics.total_lagged.inc_by(n);
-                    }
-                }
-            }
-            Some(Ok(frame)) = read_framed.next() => {
-                match frame {
-                    Frame::Subscribe { channel, .. } => {
-                        let chan_str = String::from_utf8_lossy(&channel).to_string();
-                        if access_ctx.can_subscribe(&chan_str) {
-                            if stream_map.contains_key(&chan_str) { continue; }
-                            let b_tx = subscribers.entry(chan_str.clone()).or_insert_with(|| broadcast::channel(CHANNEL_SIZE).0).value().clone();
-                            stream_map.insert(chan_str, BroadcastStream::new(b_tx.subscribe()));
-                        }
-                    }
-                    Frame::Unsubscribe { channel, .. } => {
-                        stream_map.remove(String::from_utf8_lossy(&channel).as_ref());
-                    }
-                    Frame::Publish { channel, payload, .. } => {
-                        let chan_str = String::from_utf8_lossy(&channel);
-                        if access_ctx.can_publish(&chan_str) {
-                            metrics.total_published.inc();
-                            if let Some(b_tx) = subscribers.get(chan_str.as_ref()) {
-                                let f = Frame::Publish { ident: access_ctx.ident.clone().into(), channel: channel.clone(), payload: payload.clone() };
-                                if let Ok(b) = codec.encode_to_bytes(f) { let _ = b_tx.send(b); }
-                            }
-                        }
-                    }
-                    _ => {}
-                }
-            }
-            else => { break; }
+            _ = tokio::signal::ctrl_c() => {}
+            _ = term.recv() => {}
         }
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
     }
 }
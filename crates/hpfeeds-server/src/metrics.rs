@@ -0,0 +1,466 @@
+#[cfg(feature = "metrics")]
+pub use prometheus::{Histogram, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Registry};
+#[cfg(feature = "metrics")]
+use prometheus::{HistogramOpts, Opts};
+use std::time::Duration;
+
+#[cfg(not(feature = "metrics"))]
+pub use noop::{Histogram, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Registry};
+
+/// Maximum number of metrics connections served concurrently.
+pub const METRICS_MAX_CONNECTIONS: usize = 16;
+/// Maximum lifetime of a single metrics connection.
+pub const METRICS_CONN_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait between attempts to bind the metrics port.
+pub const METRICS_BIND_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+pub struct Metrics {
+    pub registry: Registry,
+    pub total_delivered: IntCounter,
+    pub total_lagged: IntCounter,
+    pub total_published: IntCounter,
+    pub total_auth_success: IntCounter,
+    pub total_auth_fail: IntCounter,
+    pub total_deduped: IntCounter,
+    pub total_slow_deliveries: IntCounter,
+    pub total_shed: IntCounter,
+    pub total_backlog_high_water: IntCounter,
+    pub total_preauth_rejected: IntCounter,
+    /// Publishes skipped on coalescing channels because a newer one was queued
+    pub total_coalesced: IntCounter,
+    /// Client frames dropped by the per-connection frame-rate limit
+    pub total_rate_limited: IntCounter,
+    /// Connections closed on accept for exceeding the accept rate
+    pub total_accepts_throttled: IntCounter,
+    /// Publishes dropped by the per-connection publish-rate limit
+    pub total_pub_rate_limited: IntCounter,
+    /// Client connections currently open, authenticated or not
+    pub active_connections: IntGauge,
+    /// Subscriptions currently held, labelled by `channel`
+    pub channel_subscribers: IntGaugeVec,
+    /// Frames decoded from clients, labelled by `opcode`
+    pub frames_received: IntCounterVec,
+    /// Messages written to a subscriber per flush
+    pub flush_batch_size: Histogram,
+    /// Messages a subscriber was behind on a channel, sampled at each flush
+    pub subscriber_backlog: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    /// The plain counters, by exported name. These are what `--metrics-checkpoint` saves.
+    pub fn counters(&self) -> [(&'static str, &IntCounter); 14] {
+        [
+            ("hpfeeds_delivered_total", &self.total_delivered),
+            ("hpfeeds_lagged_total", &self.total_lagged),
+            ("hpfeeds_published_total", &self.total_published),
+            ("hpfeeds_auth_success_total", &self.total_auth_success),
+            ("hpfeeds_auth_fail_total", &self.total_auth_fail),
+            ("hpfeeds_deduped_total", &self.total_deduped),
+            ("hpfeeds_slow_deliveries_total", &self.total_slow_deliveries),
+            ("hpfeeds_shed_total", &self.total_shed),
+            (
+                "hpfeeds_backlog_high_water_total",
+                &self.total_backlog_high_water,
+            ),
+            (
+                "hpfeeds_preauth_rejected_total",
+                &self.total_preauth_rejected,
+            ),
+            ("hpfeeds_coalesced_total", &self.total_coalesced),
+            ("hpfeeds_rate_limited_total", &self.total_rate_limited),
+            (
+                "hpfeeds_accepts_throttled_total",
+                &self.total_accepts_throttled,
+            ),
+            (
+                "hpfeeds_pub_rate_limited_total",
+                &self.total_pub_rate_limited,
+            ),
+        ]
+    }
+
+    pub fn new() -> Self {
+        let registry = Registry::default();
+        Metrics {
+            total_delivered: counter(
+                &registry,
+                "hpfeeds_delivered_total",
+                "Total messages successfully sent",
+            ),
+            total_lagged: counter(
+                &registry,
+                "hpfeeds_lagged_total",
+                "Total messages dropped due to lag",
+            ),
+            total_published: counter(
+                &registry,
+                "hpfeeds_published_total",
+                "Total messages received from publishers",
+            ),
+            total_auth_success: counter(
+                &registry,
+                "hpfeeds_auth_success_total",
+                "Total successful auths",
+            ),
+            total_auth_fail: counter(&registry, "hpfeeds_auth_fail_total", "Total failed auths"),
+            total_deduped: counter(
+                &registry,
+                "hpfeeds_deduped_total",
+                "Total duplicate publishes suppressed",
+            ),
+            total_slow_deliveries: counter(
+                &registry,
+                "hpfeeds_slow_deliveries_total",
+                "Total flushes slower than the slow-delivery threshold",
+            ),
+            total_shed: counter(
+                &registry,
+                "hpfeeds_shed_total",
+                "Total subscribers disconnected to bring buffered bytes under the limit",
+            ),
+            total_backlog_high_water: counter(
+                &registry,
+                "hpfeeds_backlog_high_water_total",
+                "Total times a subscriber's backlog rose past the high-water mark",
+            ),
+            total_preauth_rejected: counter(
+                &registry,
+                "hpfeeds_preauth_rejected_total",
+                "Total connections closed on accept because too many were unauthenticated",
+            ),
+            total_coalesced: counter(
+                &registry,
+                "hpfeeds_coalesced_total",
+                "Total publishes skipped on coalescing channels in favour of a newer one",
+            ),
+            total_rate_limited: counter(
+                &registry,
+                "hpfeeds_rate_limited_total",
+                "Total client frames dropped for exceeding the frame-rate limit",
+            ),
+            total_accepts_throttled: counter(
+                &registry,
+                "hpfeeds_accepts_throttled_total",
+                "Total connections closed on accept for exceeding the accept rate",
+            ),
+            total_pub_rate_limited: counter(
+                &registry,
+                "hpfeeds_pub_rate_limited_total",
+                "Total publishes dropped for exceeding the per-connection publish rate",
+            ),
+            active_connections: gauge(
+                &registry,
+                "hpfeeds_active_connections",
+                "Client connections currently open",
+            ),
+            channel_subscribers: gauge_vec(
+                &registry,
+                "hpfeeds_channel_subscribers",
+                "Subscriptions currently held, by channel",
+                &["channel"],
+            ),
+            frames_received: counter_vec(
+                &registry,
+                "hpfeeds_frames_received_total",
+                "Total frames received from clients, by opcode",
+                &["opcode"],
+            ),
+            flush_batch_size: histogram(
+                &registry,
+                "hpfeeds_flush_batch_size",
+                "Messages written to a subscriber per flush",
+                vec![1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0, 1024.0],
+            ),
+            subscriber_backlog: histogram(
+                &registry,
+                "hpfeeds_subscriber_backlog",
+                "Messages a subscriber was behind on a channel when flushing",
+                vec![0.0, 16.0, 128.0, 1024.0, 4096.0, 16384.0, 65536.0],
+            ),
+            registry,
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn counter(registry: &Registry, name: &str, help: &str) -> IntCounter {
+    let c = IntCounter::with_opts(Opts::new(name, help)).unwrap();
+    registry.register(Box::new(c.clone())).unwrap();
+    c
+}
+
+#[cfg(feature = "metrics")]
+fn gauge(registry: &Registry, name: &str, help: &str) -> IntGauge {
+    let g = IntGauge::with_opts(Opts::new(name, help)).unwrap();
+    registry.register(Box::new(g.clone())).unwrap();
+    g
+}
+
+#[cfg(feature = "metrics")]
+fn gauge_vec(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> IntGaugeVec {
+    let g = IntGaugeVec::new(Opts::new(name, help), labels).unwrap();
+    registry.register(Box::new(g.clone())).unwrap();
+    g
+}
+
+#[cfg(feature = "metrics")]
+fn counter_vec(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    let c = IntCounterVec::new(Opts::new(name, help), labels).unwrap();
+    registry.register(Box::new(c.clone())).unwrap();
+    c
+}
+
+#[cfg(feature = "metrics")]
+fn histogram(registry: &Registry, name: &str, help: &str, buckets: Vec<f64>) -> Histogram {
+    let h = Histogram::with_opts(HistogramOpts::new(name, help).buckets(buckets)).unwrap();
+    registry.register(Box::new(h.clone())).unwrap();
+    h
+}
+
+#[cfg(not(feature = "metrics"))]
+fn counter(_registry: &Registry, _name: &str, _help: &str) -> IntCounter {
+    IntCounter::default()
+}
+
+#[cfg(not(feature = "metrics"))]
+fn gauge(_registry: &Registry, _name: &str, _help: &str) -> IntGauge {
+    IntGauge::default()
+}
+
+#[cfg(not(feature = "metrics"))]
+fn gauge_vec(_registry: &Registry, _name: &str, _help: &str, _labels: &[&str]) -> IntGaugeVec {
+    IntGaugeVec::default()
+}
+
+#[cfg(not(feature = "metrics"))]
+fn counter_vec(_registry: &Registry, _name: &str, _help: &str, _labels: &[&str]) -> IntCounterVec {
+    IntCounterVec::default()
+}
+
+#[cfg(not(feature = "metrics"))]
+fn histogram(_registry: &Registry, _name: &str, _help: &str, _buckets: Vec<f64>) -> Histogram {
+    Histogram::default()
+}
+
+/// Stand-ins for the Prometheus types when the broker is built without the `metrics` feature.
+/// Counters still count so callers can read them, but nothing is exported.
+#[cfg(not(feature = "metrics"))]
+mod noop {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Debug, Default)]
+    pub struct Registry {
+        _private: (),
+    }
+
+    #[derive(Clone, Debug, Default)]
+    pub struct IntCounter(Arc<AtomicU64>);
+
+    impl IntCounter {
+        pub fn inc(&self) {
+            self.inc_by(1);
+        }
+
+        pub fn inc_by(&self, v: u64) {
+            self.0.fetch_add(v, Ordering::Relaxed);
+        }
+
+        pub fn get(&self) -> u64 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    #[derive(Clone, Debug, Default)]
+    pub struct IntGauge(Arc<AtomicI64>);
+
+    impl IntGauge {
+        pub fn inc(&self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+
+        pub fn dec(&self) {
+            self.0.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        pub fn get(&self) -> i64 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    /// Keeps only the count and sum of observations.
+    #[derive(Clone, Debug, Default)]
+    pub struct Histogram(Arc<Mutex<(u64, f64)>>);
+
+    impl Histogram {
+        pub fn observe(&self, v: f64) {
+            let mut h = self.0.lock().unwrap();
+            h.0 += 1;
+            h.1 += v;
+        }
+
+        pub fn get_sample_count(&self) -> u64 {
+            self.0.lock().unwrap().0
+        }
+
+        pub fn get_sample_sum(&self) -> f64 {
+            self.0.lock().unwrap().1
+        }
+    }
+
+    #[derive(Clone, Debug, Default)]
+    pub struct IntGaugeVec(Arc<Mutex<HashMap<Vec<String>, IntGauge>>>);
+
+    impl IntGaugeVec {
+        pub fn with_label_values(&self, vals: &[&str]) -> IntGauge {
+            let key = vals.iter().map(|v| v.to_string()).collect();
+            self.0.lock().unwrap().entry(key).or_default().clone()
+        }
+
+        pub fn remove_label_values(&self, vals: &[&str]) -> Option<IntGauge> {
+            let key: Vec<String> = vals.iter().map(|v| v.to_string()).collect();
+            self.0.lock().unwrap().remove(&key)
+        }
+    }
+
+    #[derive(Clone, Debug, Default)]
+    pub struct IntCounterVec(Arc<Mutex<HashMap<Vec<String>, IntCounter>>>);
+
+    impl IntCounterVec {
+        pub fn with_label_values(&self, vals: &[&str]) -> IntCounter {
+            let key = vals.iter().map(|v| v.to_string()).collect();
+            self.0.lock().unwrap().entry(key).or_default().clone()
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub use http::{bind_metrics, serve_metrics};
+
+#[cfg(feature = "metrics")]
+mod http {
+    use crate::listen::ListenOptions;
+    use crate::topchannels::TopChannels;
+    use bytes::Bytes;
+    use http_body_util::Full;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::{Request, Response, StatusCode};
+    use hyper_util::rt::TokioIo;
+    use prometheus::{Encoder, Registry};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::net::TcpListener;
+    use tokio::sync::Semaphore;
+    use tracing::warn;
+
+    /// Binds the metrics listener on `addr` with `options`, trying again up to `retries` times
+    /// `delay` apart, e.g. while a previous broker on the same port shuts down.
+    pub async fn bind_metrics(
+        addr: std::net::SocketAddr,
+        options: ListenOptions,
+        retries: u32,
+        delay: Duration,
+    ) -> anyhow::Result<TcpListener> {
+        let mut attempt = 0;
+        loop {
+            match crate::listen::bind(addr, options) {
+                Ok(listener) => return Ok(listener),
+                Err(e) if attempt < retries => {
+                    attempt += 1;
+                    warn!(
+                        "metrics bind on {} failed ({}), retry {}/{}",
+                        addr, e, attempt, retries
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => {
+                    return Err(anyhow::Error::new(e)
+                        .context(format!("binding metrics listener on {}", addr)));
+                }
+            }
+        }
+    }
+
+    /// Serves `/metrics` from `registry` on `listener`, and `/admin/top-channels` from
+    /// `top_channels` when it is given.
+    ///
+    /// At most `max_conns` connections are served at once; connections accepted beyond that are
+    /// closed immediately. Each served connection is dropped after `conn_timeout`.
+    pub async fn serve_metrics(
+        listener: TcpListener,
+        registry: Registry,
+        top_channels: Option<Arc<TopChannels>>,
+        max_conns: usize,
+        conn_timeout: Duration,
+    ) {
+        let limit = Arc::new(Semaphore::new(max_conns));
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            let permit = match limit.clone().try_acquire_owned() {
+                Ok(p) => p,
+                Err(_) => {
+                    drop(stream);
+                    continue;
+                }
+            };
+            let io = TokioIo::new(stream);
+            let reg = registry.clone();
+            let top = top_channels.clone();
+            tokio::task::spawn(async move {
+                let conn = http1::Builder::new().serve_connection(
+                    io,
+                    service_fn(move |req: Request<hyper::body::Incoming>| {
+                        let reg = reg.clone();
+                        let top = top.clone();
+                        async move {
+                            if let (Some(top), "/admin/top-channels") = (&top, req.uri().path()) {
+                                let mut res =
+                                    Response::new(Full::new(Bytes::from(top_channels_json(top))));
+                                res.headers_mut().insert(
+                                    hyper::header::CONTENT_TYPE,
+                                    hyper::header::HeaderValue::from_static("application/json"),
+                                );
+                                Ok(res)
+                            } else if req.uri().path() == "/metrics" {
+                                let mut buffer = vec![];
+                                prometheus::TextEncoder::new()
+                                    .encode(&reg.gather(), &mut buffer)
+                                    .unwrap();
+                                Ok::<_, anyhow::Error>(Response::new(Full::new(Bytes::from(
+                                    buffer,
+                                ))))
+                            } else {
+                                let mut res = Response::new(Full::new(Bytes::from("Not Found")));
+                                *res.status_mut() = StatusCode::NOT_FOUND;
+                                Ok(res)
+                            }
+                        }
+                    }),
+                );
+                let _ = tokio::time::timeout(conn_timeout, conn).await;
+                drop(permit);
+            });
+        }
+    }
+
+    // `{"window_secs":60,"channels":[{"channel":"cowrie.sessions","publishes":1520},...]}`
+    fn top_channels_json(top: &TopChannels) -> String {
+        let channels: Vec<_> = top
+            .top(Instant::now())
+            .into_iter()
+            .map(|(channel, publishes)| serde_json::json!({"channel": channel, "publishes": publishes}))
+            .collect();
+        serde_json::json!({"window_secs": top.window().as_secs(), "channels": channels}).to_string()
+    }
+}
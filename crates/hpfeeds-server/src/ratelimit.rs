@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+
+/// Longest quiet spell that still counts as the same bout of rate limiting.
+const LIMITED_GAP: Duration = Duration::from_secs(1);
+
+/// Token bucket allowing `rate` events a second on average, in bursts of up to `rate`. Used for
+/// a connection's frames and for the accept loop.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+    // Start of the current bout of events over the rate, and the latest one in it
+    limited: Option<(Instant, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, now: Instant) -> Self {
+        let rate = f64::from(rate.max(1));
+        Self {
+            rate,
+            tokens: rate,
+            last_refill: now,
+            limited: None,
+        }
+    }
+
+    /// Takes a token for one event. Returns false if the event is over the rate.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return true;
+        }
+        self.limited = match self.limited {
+            Some((since, last)) if now.saturating_duration_since(last) <= LIMITED_GAP => {
+                Some((since, now))
+            }
+            _ => Some((now, now)),
+        };
+        false
+    }
+
+    /// How long events have been over the rate without a quiet second in between, as of the
+    /// latest. Zero if none ever have been.
+    pub fn limited_for(&self) -> Duration {
+        self.limited
+            .map_or(Duration::ZERO, |(since, last)| last.duration_since(since))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_a_burst_then_the_rate() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(10, start);
+        assert_eq!((0..20).filter(|_| limiter.allow(start)).count(), 10);
+        // a tenth of a second buys one more
+        let later = start + Duration::from_millis(100);
+        assert!(limiter.allow(later));
+        assert!(!limiter.allow(later));
+        // and the bucket never holds more than a second's worth
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!((0..20).filter(|_| limiter.allow(much_later)).count(), 10);
+    }
+
+    #[test]
+    fn measures_sustained_limiting() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(10, start);
+        assert_eq!(limiter.limited_for(), Duration::ZERO);
+        for _ in 0..11 {
+            limiter.allow(start);
+        }
+        for ms in (10..=3000).step_by(10) {
+            let now = start + Duration::from_millis(ms);
+            for _ in 0..5 {
+                limiter.allow(now);
+            }
+        }
+        assert_eq!(limiter.limited_for(), Duration::from_secs(3));
+
+        // a quiet spell ends the bout
+        let resumed = start + Duration::from_secs(10);
+        for _ in 0..11 {
+            limiter.allow(resumed);
+        }
+        assert_eq!(limiter.limited_for(), Duration::ZERO);
+    }
+}
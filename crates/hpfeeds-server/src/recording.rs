@@ -0,0 +1,45 @@
+//! A delivery tap for tests, built with the `recording` feature. A [`Recorder`] attached with
+//! `Broker::with_recorder` keeps every publish the broker fans out, so routing can be asserted
+//! without connecting subscribers.
+
+use bytes::{Bytes, BytesMut};
+use hpfeeds_core::{Frame, HpfeedsCodec};
+use std::sync::{Arc, Mutex};
+use tokio_util::codec::Decoder;
+
+/// A publish as the broker sent it to `channel`'s subscribers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delivery {
+    pub channel: String,
+    pub frame: Frame,
+}
+
+/// Shared buffer of [`Delivery`]s. Clones record into, and read from, the same buffer.
+#[derive(Debug, Clone, Default)]
+pub struct Recorder(Arc<Mutex<Vec<Delivery>>>);
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything recorded so far, oldest first.
+    pub fn deliveries(&self) -> Vec<Delivery> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Everything recorded so far, emptying the buffer.
+    pub fn take(&self) -> Vec<Delivery> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+
+    pub(crate) fn record(&self, channel: &str, msg: &Bytes) {
+        let mut buf = BytesMut::from(&msg[..]);
+        if let Ok(Some(frame)) = HpfeedsCodec::new().decode(&mut buf) {
+            self.0.lock().unwrap().push(Delivery {
+                channel: channel.to_string(),
+                frame,
+            });
+        }
+    }
+}
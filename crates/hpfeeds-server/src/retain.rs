@@ -0,0 +1,219 @@
+use bytes::Bytes;
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Keeps the most recent encoded publishes per channel so new subscribers can be sent them
+/// before live traffic.
+pub struct RetainStore {
+    depth: usize,
+    ttl: Option<Duration>,
+    max_channels: Option<usize>,
+    channels: DashMap<String, VecDeque<(Instant, Bytes)>>,
+    // When every channel was last checked for expired messages
+    last_sweep: Mutex<Option<Instant>>,
+}
+
+impl RetainStore {
+    /// Retains up to `depth` messages per channel, each for at most `ttl` if given, on at most
+    /// `max_channels` channels at once.
+    pub fn new(depth: usize, ttl: Option<Duration>, max_channels: Option<usize>) -> Self {
+        Self {
+            depth,
+            ttl,
+            max_channels,
+            channels: DashMap::new(),
+            last_sweep: Mutex::new(None),
+        }
+    }
+
+    /// Stores `msg` for `channel`, evicting whatever falls outside the depth or TTL. Once
+    /// `max_channels` have something retained, publishes to any other channel are delivered
+    /// but not retained. With a TTL, channels whose messages have all expired are dropped at
+    /// least once per TTL.
+    ///
+    /// `deliver` runs while the channel is locked, so a subscriber taking a snapshot at the
+    /// same time sees each message exactly once: either retained or live.
+    pub fn retain_with(&self, channel: &str, msg: Bytes, now: Instant, deliver: impl FnOnce()) {
+        // both lock shards of `channels`, so neither may run under an entry's lock
+        self.sweep_if_due(now);
+        if self
+            .max_channels
+            .is_some_and(|max| !self.channels.contains_key(channel) && self.channels.len() >= max)
+        {
+            deliver();
+            return;
+        }
+        let mut entry = self.channels.entry(channel.to_string()).or_default();
+        entry.push_back((now, msg));
+        while entry.len() > self.depth {
+            entry.pop_front();
+        }
+        self.evict_expired(&mut entry, now);
+        deliver();
+    }
+
+    /// Returns the unexpired messages retained for `channel`, oldest first, together with the
+    /// result of `subscribe`, which runs under the same lock as `retain_with`. That holds for a
+    /// channel nothing has been retained on yet too, so its first message can't slip in between.
+    pub fn snapshot_with<T>(
+        &self,
+        channel: &str,
+        now: Instant,
+        subscribe: impl FnOnce() -> T,
+    ) -> (Vec<Bytes>, T) {
+        match self.channels.entry(channel.to_string()) {
+            Entry::Occupied(mut entry) => {
+                self.evict_expired(entry.get_mut(), now);
+                let msgs: Vec<Bytes> = entry.get().iter().map(|(_, m)| m.clone()).collect();
+                let subscribed = subscribe();
+                if msgs.is_empty() {
+                    entry.remove();
+                }
+                (msgs, subscribed)
+            }
+            // left vacant, but locked until `subscribe` returns
+            Entry::Vacant(_locked) => (Vec::new(), subscribe()),
+        }
+    }
+
+    // Drops every channel whose messages have all expired, if a TTL has passed since the last
+    // time. Otherwise channels published to once would keep an entry until subscribed to.
+    fn sweep_if_due(&self, now: Instant) {
+        let Some(ttl) = self.ttl else {
+            return;
+        };
+        {
+            let mut last = self.last_sweep.lock().unwrap();
+            match *last {
+                Some(at) if now.saturating_duration_since(at) < ttl => return,
+                _ => *last = Some(now),
+            }
+        }
+        self.channels.retain(|_, entry| {
+            self.evict_expired(entry, now);
+            !entry.is_empty()
+        });
+    }
+
+    fn evict_expired(&self, entry: &mut VecDeque<(Instant, Bytes)>, now: Instant) {
+        let Some(ttl) = self.ttl else {
+            return;
+        };
+        while let Some((at, _)) = entry.front() {
+            if now.duration_since(*at) <= ttl {
+                break;
+            }
+            entry.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_the_last_depth_messages() {
+        let store = RetainStore::new(2, None, None);
+        let now = Instant::now();
+        for m in ["a", "b", "c"] {
+            store.retain_with("ch", Bytes::from(m), now, || {});
+        }
+        let (msgs, ()) = store.snapshot_with("ch", now, || {});
+        assert_eq!(msgs, vec![Bytes::from("b"), Bytes::from("c")]);
+        assert!(store.snapshot_with("other", now, || {}).0.is_empty());
+    }
+
+    #[test]
+    fn stale_messages_are_not_replayed() {
+        let store = RetainStore::new(10, Some(Duration::from_secs(60)), None);
+        let start = Instant::now();
+        store.retain_with("ch", Bytes::from("old"), start, || {});
+        store.retain_with(
+            "ch",
+            Bytes::from("new"),
+            start + Duration::from_secs(45),
+            || {},
+        );
+
+        let (msgs, ()) = store.snapshot_with("ch", start + Duration::from_secs(30), || {});
+        assert_eq!(msgs, vec![Bytes::from("old"), Bytes::from("new")]);
+
+        // past the TTL of the first message only
+        let (msgs, ()) = store.snapshot_with("ch", start + Duration::from_secs(61), || {});
+        assert_eq!(msgs, vec![Bytes::from("new")]);
+
+        // the stale message was evicted, not just filtered
+        let (msgs, ()) = store.snapshot_with("ch", start + Duration::from_secs(30), || {});
+        assert_eq!(msgs, vec![Bytes::from("new")]);
+    }
+
+    #[test]
+    fn expired_channels_are_dropped() {
+        let store = RetainStore::new(10, Some(Duration::from_secs(60)), None);
+        let start = Instant::now();
+        store.retain_with("once", Bytes::from("a"), start, || {});
+        store.retain_with(
+            "busy",
+            Bytes::from("b"),
+            start + Duration::from_secs(30),
+            || {},
+        );
+
+        // a TTL after the last sweep, everything on "once" has expired
+        store.retain_with(
+            "busy",
+            Bytes::from("c"),
+            start + Duration::from_secs(61),
+            || {},
+        );
+        assert!(!store.channels.contains_key("once"));
+        assert!(store.channels.contains_key("busy"));
+
+        // as is a channel a snapshot finds expired
+        let (msgs, ()) = store.snapshot_with("busy", start + Duration::from_secs(200), || {});
+        assert!(msgs.is_empty());
+        assert!(store.channels.is_empty());
+    }
+
+    #[test]
+    fn channels_past_the_cap_are_delivered_but_not_retained() {
+        let store = RetainStore::new(1, None, Some(1));
+        let now = Instant::now();
+        let mut delivered = 0;
+        store.retain_with("a", Bytes::from("1"), now, || delivered += 1);
+        store.retain_with("b", Bytes::from("2"), now, || delivered += 1);
+        store.retain_with("a", Bytes::from("3"), now, || delivered += 1);
+
+        assert_eq!(delivered, 3);
+        assert_eq!(
+            store.snapshot_with("a", now, || {}).0,
+            vec![Bytes::from("3")]
+        );
+        assert!(store.snapshot_with("b", now, || {}).0.is_empty());
+    }
+
+    #[test]
+    fn first_message_waits_for_a_snapshot_in_progress() {
+        let store = std::sync::Arc::new(RetainStore::new(1, None, None));
+        let now = Instant::now();
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let (msgs, ()) = store.snapshot_with("new", now, || {
+            let store = store.clone();
+            let done_tx = done_tx.clone();
+            std::thread::spawn(move || {
+                store.retain_with("new", Bytes::from("first"), now, || {});
+                done_tx.send(()).unwrap();
+            });
+            // blocked until the snapshot's subscriber is in place
+            let blocked = done_rx.recv_timeout(Duration::from_millis(50));
+            assert!(blocked.is_err());
+        });
+        assert!(msgs.is_empty());
+        done_rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert!(store.channels.contains_key("new"));
+    }
+}
@@ -0,0 +1,1641 @@
+use crate::audit::AuditLog;
+use crate::auth::{AccessContext, Authenticator, Authorizer, CachedAccess, Operation};
+use crate::buffers::BufferAccountant;
+use crate::clock::{Clock, TokioClock};
+use crate::config::DedupConfig;
+use crate::dedup::Deduplicator;
+use crate::metrics::{IntGauge, Metrics};
+use crate::ratelimit::RateLimiter;
+use crate::retain::RetainStore;
+use crate::topchannels::{DEFAULT_TOP_CHANNELS_WINDOW, TopChannels};
+use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use futures::{FutureExt, StreamExt};
+use hpfeeds_core::{
+    CAP_BACKLOG, CAP_PAUSE, CAP_SELECT, CAP_SHARE, CAP_SUBACK, Capabilities, Frame, HpfeedsCodec,
+    MOTD_CHANNEL, SUBACK_CHANNEL, SubscriptionControl, split_backlog, split_control,
+    split_share_group,
+};
+use rand::TryRng;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, broadcast, watch};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_util::codec::Framed;
+use tracing::{Instrument, Span, debug, field, info, info_span, warn};
+
+pub type SubscriberMap = Arc<DashMap<String, ChannelSender>>;
+pub const CHANNEL_SIZE: usize = 65536;
+pub const BATCH_LIMIT: usize = 128;
+pub const MAX_BATCH_LIMIT: usize = 65536;
+pub const MAX_CHANNEL_CAPACITY: usize = 1 << 20;
+pub const BROKER_NAME: &str = "hpfeeds-rs";
+/// Reserved channel a client publishes to, when `whoami` is enabled, to be sent its own ident
+/// and ACL
+pub const WHOAMI_CHANNEL: &str = "__whoami__";
+/// Default time between heartbeats on the keepalive channel
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+/// Time connections are given to finish once the broker starts shutting down.
+pub const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+/// OP_ERROR sent to every client when the broker shuts down.
+pub const SHUTDOWN_MESSAGE: &str = "server shutting down";
+/// OP_ERROR answering a publish or subscribe with an empty channel name.
+pub const EMPTY_CHANNEL_MESSAGE: &str = "invalid channel name: empty";
+/// Length of the OP_INFO rand unless `BrokerOptions::rand_len` says otherwise, as in the original
+/// broker.
+pub const DEFAULT_RAND_LEN: usize = 16;
+pub const DEFAULT_PREAUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An encoded publish on its way to subscribers, stamped with when the broker accepted it.
+#[derive(Debug, Clone)]
+pub struct Published {
+    pub msg: Bytes,
+    pub at: Instant,
+    /// Position in the channel, counting from 0 when the channel was created
+    pub seq: u64,
+}
+
+/// A channel's broadcast sender and the sequence number of its next publish.
+#[derive(Debug)]
+pub struct ChannelSender {
+    tx: broadcast::Sender<Published>,
+    next_seq: AtomicU64,
+    capacity: usize,
+    groups: Mutex<Vec<ShareGroup>>,
+}
+
+// Subscribers that joined a channel under one CAP_SHARE group name. Each has a channel of its
+// own, and every publish is sent on just one of them.
+#[derive(Debug)]
+struct ShareGroup {
+    name: String,
+    members: Vec<broadcast::Sender<Published>>,
+    next: usize,
+}
+
+impl ShareGroup {
+    // Sends `msg` to the next member in turn, dropping members that have gone away.
+    fn deliver(&mut self, msg: &Published) {
+        while !self.members.is_empty() {
+            let i = self.next % self.members.len();
+            if self.members[i].send(msg.clone()).is_ok() {
+                self.next = i + 1;
+                return;
+            }
+            // the member unsubscribed or disconnected
+            self.members.remove(i);
+        }
+    }
+}
+
+impl ChannelSender {
+    fn new(capacity: usize) -> Self {
+        Self {
+            tx: broadcast::channel(capacity).0,
+            next_seq: AtomicU64::new(0),
+            capacity,
+            groups: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn publish(&self, msg: Bytes, at: Instant) {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let published = Published { msg, at, seq };
+        let mut groups = self.groups.lock().unwrap();
+        for group in groups.iter_mut() {
+            group.deliver(&published);
+        }
+        groups.retain(|g| !g.members.is_empty());
+        drop(groups);
+        let _ = self.tx.send(published);
+    }
+
+    // Adds a member to share group `name`, returning its receiver.
+    fn join(&self, name: &str) -> broadcast::Receiver<Published> {
+        let (tx, rx) = broadcast::channel(self.capacity);
+        let mut groups = self.groups.lock().unwrap();
+        match groups.iter_mut().find(|g| g.name == name) {
+            Some(group) => group.members.push(tx),
+            None => groups.push(ShareGroup {
+                name: name.to_string(),
+                members: vec![tx],
+                next: 0,
+            }),
+        }
+        rx
+    }
+
+    // True once neither subscribers nor share-group members are left to receive publishes.
+    fn is_unused(&self) -> bool {
+        self.tx.receiver_count() == 0
+            && self
+                .groups
+                .lock()
+                .unwrap()
+                .iter()
+                .all(|g| g.members.iter().all(|m| m.receiver_count() == 0))
+    }
+
+    /// Publishes made after `seq`, i.e. how far behind a subscriber that just received `seq`
+    /// is. Concurrent publishers may send slightly out of sequence, so this is approximate.
+    pub fn behind(&self, seq: u64) -> u64 {
+        self.next_seq
+            .load(Ordering::Relaxed)
+            .saturating_sub(seq + 1)
+    }
+}
+
+impl Deref for ChannelSender {
+    type Target = broadcast::Sender<Published>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tx
+    }
+}
+
+/// Optional protocol behaviours advertised to clients in OP_INFO.
+pub fn broker_capabilities() -> Capabilities {
+    Capabilities::default()
+}
+
+/// Tunable broker behaviour.
+#[derive(Debug, Clone)]
+pub struct BrokerOptions {
+    /// Most messages written to a subscriber in one flush, 1 to `MAX_BATCH_LIMIT`
+    pub batch_limit: usize,
+    /// Messages buffered per channel before slow subscribers lag, 1 to `MAX_CHANNEL_CAPACITY`
+    pub channel_capacity: usize,
+    /// Length of the OP_INFO rand, 4 to `MAX_RAND_LEN` bytes
+    pub rand_len: usize,
+    /// Suppress repeated identical publishes on these channels
+    pub dedup: Option<DedupConfig>,
+    /// Messages kept per channel and sent to new subscribers; 0 disables retention
+    pub retain_depth: usize,
+    /// Retained messages older than this are neither sent nor kept
+    pub retain_ttl: Option<Duration>,
+    /// Disconnect clients that neither send nor receive anything for this long
+    pub idle_timeout: Option<Duration>,
+    /// Connections one ident may hold open at once; a further one is sent OP_ERROR and closed
+    /// once it has authenticated
+    pub max_conns_per_ident: Option<usize>,
+    /// Channels with subscribers allowed at once; a subscribe that would create another is
+    /// answered with OP_ERROR
+    pub max_channels: Option<usize>,
+    /// Warn when a publish takes longer than this to be flushed to a subscriber
+    pub slow_delivery: Option<Duration>,
+    /// Capability-selection frames accepted before OP_AUTH; 0 requires OP_AUTH first
+    pub preauth_frames: usize,
+    /// Time allowed for the TLS handshake, any selection frames and OP_AUTH, after which the
+    /// connection is closed and its `max_unauthenticated` slot given back
+    pub preauth_timeout: Duration,
+    /// Shed blocked subscribers once delivery buffers across all connections exceed this
+    pub max_buffered_bytes: Option<usize>,
+    /// Warn when a subscriber falls this many messages behind on a channel
+    pub backlog_high_water: Option<u64>,
+    /// Reject publishes and subscribes, with OP_ERROR, on channels whose name does not match
+    pub channel_name_regex: Option<Regex>,
+    /// Leading payload bytes included in audit records and publish log lines; 0 logs sizes only
+    pub log_payload_preview_bytes: usize,
+    /// Channels whose payloads never appear in logs or audit records, whatever
+    /// `log_payload_preview_bytes` says
+    pub redact_channels: Option<Regex>,
+    /// Channels where only the latest value matters: a subscriber that has fallen behind is
+    /// sent just the newest queued publish
+    pub coalesce: HashSet<String>,
+    /// Connections allowed to be open but not yet authenticated; further sockets are closed
+    /// as soon as they are accepted
+    pub max_unauthenticated: Option<usize>,
+    /// Disconnect a subscriber, with OP_ERROR, once it has lost more than this many messages
+    /// to lag in total
+    pub max_lag_drops: Option<u64>,
+    /// Reserved channel the broker publishes a heartbeat on every `keepalive_interval`; clients
+    /// may subscribe to it but not publish
+    pub keepalive_channel: Option<String>,
+    pub keepalive_interval: Duration,
+    /// Frames a second a client may send, of any opcode, in bursts of up to as many; frames
+    /// beyond that are dropped
+    pub max_frame_rate: Option<u32>,
+    /// Disconnect a client, with OP_ERROR, once its frames have been dropped for this long
+    /// without a quiet second
+    pub frame_rate_disconnect: Option<Duration>,
+    /// Publishes a second a client may make, in bursts of up to as many; publishes beyond that
+    /// are dropped, but the client stays connected
+    pub max_pub_rate: Option<u32>,
+    /// Connections accepted a second, in bursts of up to as many; further sockets are closed
+    /// as soon as they are accepted
+    pub max_accepts_per_sec: Option<u32>,
+    /// Answer a publish on `WHOAMI_CHANNEL` with the client's own permissions
+    pub whoami: bool,
+    /// Text published to each client on `MOTD_CHANNEL` right after OP_INFO
+    pub motd: Option<String>,
+    /// Answer a publish the ACL denies with OP_ERROR instead of dropping it silently
+    pub report_denied: bool,
+    /// Track this many of the busiest channels by publish count, see `Broker::top_channels`
+    pub top_channels: Option<usize>,
+    /// Span of time over which `top_channels` counts publishes
+    pub top_channels_window: Duration,
+    /// After `Broker::shutdown`, how long `run_server` waits for connections to close before
+    /// dropping them
+    pub shutdown_grace: Duration,
+}
+
+impl Default for BrokerOptions {
+    fn default() -> Self {
+        Self {
+            batch_limit: BATCH_LIMIT,
+            channel_capacity: CHANNEL_SIZE,
+            rand_len: DEFAULT_RAND_LEN,
+            dedup: None,
+            retain_depth: 0,
+            retain_ttl: None,
+            idle_timeout: None,
+            max_channels: None,
+            max_conns_per_ident: None,
+            slow_delivery: None,
+            preauth_frames: 0,
+            preauth_timeout: DEFAULT_PREAUTH_TIMEOUT,
+            max_buffered_bytes: None,
+            backlog_high_water: None,
+            channel_name_regex: None,
+            log_payload_preview_bytes: 0,
+            redact_channels: None,
+            coalesce: HashSet::new(),
+            max_lag_drops: None,
+            keepalive_channel: None,
+            keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
+            max_frame_rate: None,
+            frame_rate_disconnect: None,
+            max_pub_rate: None,
+            max_accepts_per_sec: None,
+            whoami: false,
+            motd: None,
+            report_denied: false,
+            top_channels: None,
+            top_channels_window: DEFAULT_TOP_CHANNELS_WINDOW,
+            shutdown_grace: DEFAULT_SHUTDOWN_GRACE,
+            max_unauthenticated: None,
+        }
+    }
+}
+
+/// One of the broker's `max_unauthenticated` slots, given back when dropped.
+pub struct PreauthSlot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// State shared by every connection of a running broker.
+pub struct Broker {
+    pub subscribers: SubscriberMap,
+    pub metrics: Arc<Metrics>,
+    pub authenticator: Arc<dyn Authenticator>,
+    pub options: BrokerOptions,
+    pub clock: Arc<dyn Clock>,
+    pub buffers: BufferAccountant,
+    // Entries in `subscribers`, against `max_channels`
+    live_channels: AtomicUsize,
+    // Open authenticated connections by ident, against `max_conns_per_ident`
+    ident_connections: DashMap<String, usize>,
+    dedup: Option<Deduplicator>,
+    retain: Option<RetainStore>,
+    audit: Option<AuditLog>,
+    authorizer: Option<Arc<dyn Authorizer>>,
+    #[cfg(feature = "recording")]
+    recorder: Option<crate::recording::Recorder>,
+    top_channels: Option<Arc<TopChannels>>,
+    shutdown: watch::Sender<bool>,
+    next_conn_id: AtomicU64,
+    unauthenticated: Option<Arc<Semaphore>>,
+}
+
+impl Broker {
+    pub fn new(authenticator: Arc<dyn Authenticator>, metrics: Arc<Metrics>) -> Self {
+        Self::with_options(authenticator, metrics, BrokerOptions::default())
+    }
+
+    pub fn with_options(
+        authenticator: Arc<dyn Authenticator>,
+        metrics: Arc<Metrics>,
+        options: BrokerOptions,
+    ) -> Self {
+        let dedup = options.dedup.as_ref().map(|d| {
+            Deduplicator::new(
+                d.channels.iter().cloned(),
+                Duration::from_millis(d.window_ms),
+            )
+        });
+        let retain = (options.retain_depth > 0).then(|| {
+            RetainStore::new(
+                options.retain_depth,
+                options.retain_ttl,
+                options.max_channels,
+            )
+        });
+        let top_channels = options.top_channels.map(|n| {
+            Arc::new(TopChannels::new(
+                n,
+                options.top_channels_window,
+                Instant::now(),
+            ))
+        });
+        let unauthenticated = options
+            .max_unauthenticated
+            .map(|n| Arc::new(Semaphore::new(n)));
+        Self {
+            subscribers: Arc::new(DashMap::new()),
+            metrics,
+            authenticator,
+            clock: Arc::new(TokioClock),
+            buffers: BufferAccountant::new(options.max_buffered_bytes),
+            live_channels: AtomicUsize::new(0),
+            ident_connections: DashMap::new(),
+            options,
+            dedup,
+            retain,
+            audit: None,
+            authorizer: None,
+            #[cfg(feature = "recording")]
+            recorder: None,
+            top_channels,
+            shutdown: watch::Sender::new(false),
+            next_conn_id: AtomicU64::new(1),
+            unauthenticated,
+        }
+    }
+
+    /// Capabilities advertised in OP_INFO, including any enabled by the options.
+    pub fn capabilities(&self) -> Capabilities {
+        let base = broker_capabilities();
+        let mut caps: Vec<&str> = base.iter().collect();
+        if self.options.preauth_frames > 0 {
+            // pausing, sharing and confirmations are only useful to clients that can select them
+            caps.push(CAP_SELECT);
+            caps.push(CAP_PAUSE);
+            caps.push(CAP_SHARE);
+            caps.push(CAP_SUBACK);
+        }
+        if self.retain.is_some() {
+            caps.push(CAP_BACKLOG);
+        }
+        Capabilities::new(caps)
+    }
+
+    /// Takes a slot for a new connection that has yet to authenticate. Returns None when
+    /// `max_unauthenticated` connections are already waiting, otherwise the slot, held until
+    /// the connection's OP_AUTH has been checked.
+    pub fn admit(&self) -> Option<PreauthSlot> {
+        match &self.unauthenticated {
+            None => Some(PreauthSlot { _permit: None }),
+            Some(limit) => limit
+                .clone()
+                .try_acquire_owned()
+                .ok()
+                .map(|p| PreauthSlot { _permit: Some(p) }),
+        }
+    }
+
+    /// Replaces the wall clock, e.g. with a `TestClock`.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Records every accepted publish to `audit`.
+    pub fn with_audit(mut self, audit: AuditLog) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Asks `authorizer` about every publish and subscribe, in place of the client's ACL.
+    pub fn with_authorizer(mut self, authorizer: Arc<dyn Authorizer>) -> Self {
+        self.authorizer = Some(authorizer);
+        self
+    }
+
+    /// Records every publish fanned out to subscribers into `recorder`, whether or not anyone
+    /// is subscribed.
+    #[cfg(feature = "recording")]
+    pub fn with_recorder(mut self, recorder: crate::recording::Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Starts shutting down: `run_server` stops accepting, and every authenticated client is
+    /// sent what is already queued for it, then OP_ERROR `SHUTDOWN_MESSAGE`, and is
+    /// disconnected.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    // Completes once `shutdown` has been called.
+    async fn shutting_down(&self) {
+        let mut rx = self.shutdown.subscribe();
+        let _ = rx.wait_for(|&down| down).await;
+    }
+
+    /// The busiest-channel tracker, when `top_channels` is set.
+    pub fn top_channels(&self) -> Option<Arc<TopChannels>> {
+        self.top_channels.clone()
+    }
+
+    // False if `channel` is empty or breaks the configured naming convention. Names that are
+    // not UTF-8 never match a pattern.
+    fn channel_name_allowed(&self, channel: &[u8]) -> bool {
+        !channel.is_empty()
+            && self
+                .options
+                .channel_name_regex
+                .as_ref()
+                .is_none_or(|re| std::str::from_utf8(channel).is_ok_and(|c| re.is_match(c)))
+    }
+
+    // The start of `payload` as it may be logged, or None if only its size may be.
+    fn payload_preview(&self, channel: &[u8], payload: &[u8]) -> Option<String> {
+        let n = self.options.log_payload_preview_bytes.min(payload.len());
+        if n == 0 {
+            return None;
+        }
+        let redacted = self
+            .options
+            .redact_channels
+            .as_ref()
+            .is_some_and(|re| re.is_match(&String::from_utf8_lossy(channel)));
+        (!redacted).then(|| String::from_utf8_lossy(&payload[..n]).into_owned())
+    }
+
+    // True if this publish repeats one already fanned out within the dedup window, whoever
+    // published it.
+    fn is_duplicate(&self, publish: &Frame) -> bool {
+        let (Some(dedup), Some((channel, payload))) = (&self.dedup, publish.content_key()) else {
+            return false;
+        };
+        if !dedup.applies_to(&String::from_utf8_lossy(channel)) {
+            return false;
+        }
+        if dedup.is_duplicate(channel, payload, self.clock.now()) {
+            self.metrics.total_deduped.inc();
+            return true;
+        }
+        false
+    }
+
+    // What `channel` is kept for, if only the broker may publish on it.
+    fn reserved_for(&self, channel: &[u8]) -> Option<&'static str> {
+        let keepalive = self.options.keepalive_channel.as_ref();
+        if keepalive.is_some_and(|c| c.as_bytes() == channel) {
+            Some("broker keepalives")
+        } else if self.options.motd.is_some() && channel == MOTD_CHANNEL.as_bytes() {
+            Some("the message of the day")
+        } else if self.options.preauth_frames > 0 && channel == SUBACK_CHANNEL.as_bytes() {
+            Some("subscribe confirmations")
+        } else {
+            None
+        }
+    }
+
+    // Whether the client may `op` on `channel`: the authorizer's decision when there is one,
+    // otherwise the connection's ACL, whose decisions are cached as they never change.
+    // Non-UTF-8 channels are denied.
+    fn authorized(&self, access: &mut CachedAccess, op: Operation, channel: &[u8]) -> bool {
+        match (&self.authorizer, op) {
+            (Some(authorizer), _) => std::str::from_utf8(channel)
+                .is_ok_and(|c| authorizer.authorize(access.context(), op, c)),
+            (None, Operation::Publish) => access.can_publish(channel),
+            (None, Operation::Subscribe) => access.can_subscribe(channel),
+        }
+    }
+
+    // True if publishes on `channel` have anywhere to go, so are worth encoding.
+    fn wants(&self, channel: &str) -> bool {
+        self.retain.is_some() || self.subscribers.contains_key(channel) || self.recording()
+    }
+
+    #[cfg(feature = "recording")]
+    fn recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    #[cfg(not(feature = "recording"))]
+    fn recording(&self) -> bool {
+        false
+    }
+
+    // Fans an encoded publish out to the channel's subscribers, retaining it when enabled.
+    fn publish(&self, channel: &str, msg: Bytes) {
+        let at = self.clock.now();
+        #[cfg(feature = "recording")]
+        if let Some(recorder) = &self.recorder {
+            recorder.record(channel, &msg);
+        }
+        let send = |msg| {
+            if let Some(b_tx) = self.subscribers.get(channel) {
+                b_tx.publish(msg, at);
+            }
+        };
+        match &self.retain {
+            Some(retain) => retain.retain_with(channel, msg.clone(), at, || send(msg)),
+            None => send(msg),
+        }
+    }
+
+    // Runs `f` on `channel`'s sender, creating it unless `max_channels` are already live, in
+    // which case None. The entry stays locked meanwhile, so `release` can't drop the channel
+    // between finding it and `f` subscribing to it.
+    fn with_channel<T>(&self, channel: &str, f: impl FnOnce(&ChannelSender) -> T) -> Option<T> {
+        match self.subscribers.entry(channel.to_string()) {
+            Entry::Occupied(entry) => Some(f(entry.get())),
+            Entry::Vacant(entry) => {
+                let max = self.options.max_channels.unwrap_or(usize::MAX);
+                self.live_channels
+                    .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                        (n < max).then_some(n + 1)
+                    })
+                    .ok()?;
+                let sender = entry.insert(ChannelSender::new(self.options.channel_capacity));
+                Some(f(&sender))
+            }
+        }
+    }
+
+    // Counts a connection in for `ident`, until the returned guard is dropped. None if `ident`
+    // already holds `max_conns_per_ident`.
+    fn connect_ident(&self, ident: &str) -> Option<IdentConnection<'_>> {
+        let max = self.options.max_conns_per_ident.unwrap_or(usize::MAX);
+        let mut open = self.ident_connections.entry(ident.to_string()).or_insert(0);
+        if *open >= max {
+            return None;
+        }
+        *open += 1;
+        Some(IdentConnection {
+            broker: self,
+            ident: ident.to_string(),
+        })
+    }
+
+    // Counts a subscriber out of `channel` and drops the channel once the last one has gone,
+    // so it no longer counts as live. Its `channel_subscribers` series goes with it, or every
+    // channel ever subscribed to would keep one. The entry stays locked meanwhile, so a
+    // subscriber arriving after the drop is counted in a fresh series.
+    fn release(&self, channel: &str) {
+        let gauge = self
+            .metrics
+            .channel_subscribers
+            .with_label_values(&[channel]);
+        gauge.dec();
+        // a subscriber whose receiver is gone may not have been counted out yet
+        if let Entry::Occupied(entry) = self.subscribers.entry(channel.to_string())
+            && entry.get().is_unused()
+            && gauge.get() <= 0
+        {
+            entry.remove();
+            let _ = self
+                .metrics
+                .channel_subscribers
+                .remove_label_values(&[channel]);
+            self.live_channels.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+
+    // Joins share group `group` on `channel`. Members are sent nothing retained, as each
+    // message is meant for only one of them. None if the channel would exceed `max_channels`.
+    fn subscribe_shared(
+        &self,
+        channel: &str,
+        group: &str,
+    ) -> Option<broadcast::Receiver<Published>> {
+        self.with_channel(channel, |tx| tx.join(group))
+    }
+
+    // Subscribes to `channel`, returning the retained messages the subscriber has not yet seen.
+    // None if the channel would exceed `max_channels`.
+    fn subscribe(&self, channel: &str) -> Option<(Vec<Bytes>, broadcast::Receiver<Published>)> {
+        let subscribe = || self.with_channel(channel, |tx| tx.subscribe());
+        let (retained, rx) = match &self.retain {
+            Some(retain) => retain.snapshot_with(channel, self.clock.now(), subscribe),
+            None => (Vec::new(), subscribe()),
+        };
+        Some((retained, rx?))
+    }
+
+    // Records how far behind a subscriber that just received `seq` on `channel` is, warning
+    // when it first rises past the high-water mark. `high` holds the subscriber's channels
+    // currently above it.
+    fn track_backlog(&self, channel: &str, seq: u64, high: &mut HashSet<String>) {
+        let behind = self.subscribers.get(channel).map_or(0, |tx| tx.behind(seq));
+        self.metrics.subscriber_backlog.observe(behind as f64);
+        let Some(mark) = self.options.backlog_high_water else {
+            return;
+        };
+        if behind < mark {
+            high.remove(channel);
+        } else if high.insert(channel.to_string()) {
+            warn!(channel, behind, "subscriber backlog above high-water mark");
+            self.metrics.total_backlog_high_water.inc();
+        }
+    }
+
+    // Flags a flush that completed too long after its oldest message was accepted.
+    fn check_delivery(&self, channel: &str, accepted: Instant) {
+        let Some(threshold) = self.options.slow_delivery else {
+            return;
+        };
+        let latency = self.clock.now().saturating_duration_since(accepted);
+        if latency > threshold {
+            let subscribers = self
+                .subscribers
+                .get(channel)
+                .map_or(0, |tx| tx.receiver_count());
+            warn!(
+                channel,
+                subscribers,
+                latency_ms = latency.as_millis() as u64,
+                "slow delivery"
+            );
+            self.metrics.total_slow_deliveries.inc();
+        }
+    }
+}
+
+// Value of the `opcode` label on `frames_received`.
+fn opcode_label(frame: &Frame) -> &'static str {
+    match frame {
+        Frame::Error(_) => "error",
+        Frame::Info { .. } => "info",
+        Frame::Auth { .. } => "auth",
+        Frame::Publish { .. } => "publish",
+        Frame::Subscribe { .. } => "subscribe",
+        Frame::Unsubscribe { .. } => "unsubscribe",
+    }
+}
+
+/// Accepts hpfeeds connections on `listener` until accepting fails, wrapping them in TLS when
+/// an acceptor is given. A client presenting a certificate, which the acceptor must then have
+/// verified, is known by its common name; see [`handle_connection`].
+pub async fn run_server(
+    listener: TcpListener,
+    broker: Arc<Broker>,
+    tls_acceptor: Option<Arc<TlsAcceptor>>,
+) -> Result<()> {
+    let mut accepts = broker
+        .options
+        .max_accepts_per_sec
+        .map(|rate| RateLimiter::new(rate, broker.clock.now()));
+    let mut connections = JoinSet::new();
+    loop {
+        let (socket, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            _ = broker.shutting_down() => break,
+        };
+        if let Some(limiter) = &mut accepts
+            && !limiter.allow(broker.clock.now())
+        {
+            broker.metrics.total_accepts_throttled.inc();
+            debug!(%peer, "over the accept rate, closing");
+            continue;
+        }
+        // checked before anything else, so stalled handshakes cost no more than the socket
+        let Some(slot) = broker.admit() else {
+            broker.metrics.total_preauth_rejected.inc();
+            debug!(%peer, "too many unauthenticated connections, closing");
+            continue;
+        };
+        let _ = socket.set_nodelay(true);
+        let (broker, tls) = (broker.clone(), tls_acceptor.clone());
+        connections.spawn(async move {
+            if let Some(acceptor) = tls {
+                let deadline = broker.clock.now() + broker.options.preauth_timeout;
+                // bounded like OP_AUTH, or a stalled handshake would keep its slot forever
+                let stream = tokio::select! {
+                    stream = acceptor.accept(socket) => stream,
+                    _ = broker.clock.sleep_until(deadline) => {
+                        debug!(%peer, "TLS handshake timed out");
+                        return;
+                    }
+                };
+                let Ok(stream) = stream else {
+                    return;
+                };
+                match crate::tls::client_ident(stream.get_ref().1) {
+                    Ok(cert_ident) => {
+                        handle_connection(stream, peer, broker, slot, cert_ident).await
+                    }
+                    Err(e) => debug!(%peer, "rejecting client certificate: {:#}", e),
+                }
+            } else {
+                handle_connection(socket, peer, broker, slot, None).await;
+            }
+        });
+    }
+    drop(listener);
+    let drained = async { while connections.join_next().await.is_some() {} };
+    if tokio::time::timeout(broker.options.shutdown_grace, drained)
+        .await
+        .is_err()
+    {
+        warn!(
+            remaining = connections.len(),
+            "shutdown grace period over, dropping connections"
+        );
+    }
+    Ok(())
+}
+
+/// Publishes a heartbeat on the keepalive channel every `keepalive_interval`, forever, so idle
+/// subscribers' connections see traffic and clients can tell the broker is alive. The payload
+/// is `{"ts_ms":<milliseconds since the epoch>}` from ident `BROKER_NAME`. Returns at once if
+/// no keepalive channel is configured.
+pub async fn run_keepalive(broker: Arc<Broker>) {
+    let Some(channel) = broker.options.keepalive_channel.clone() else {
+        return;
+    };
+    let mut ticker = tokio::time::interval(broker.options.keepalive_interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ticker.tick().await;
+    let mut codec = HpfeedsCodec::new();
+    loop {
+        ticker.tick().await;
+        // heartbeats are never retained: a stale one tells a new subscriber nothing
+        let Some(b_tx) = broker.subscribers.get(&channel) else {
+            continue;
+        };
+        let ts_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let frame = Frame::Publish {
+            ident: Bytes::from_static(BROKER_NAME.as_bytes()),
+            channel: Bytes::from(channel.clone()),
+            payload: Bytes::from(format!("{{\"ts_ms\":{}}}", ts_ms)),
+        };
+        if let Ok(msg) = codec.encode_to_bytes(frame) {
+            b_tx.publish(msg, broker.clock.now());
+        }
+    }
+}
+
+type Subscriptions = tokio_stream::StreamMap<String, BroadcastStream<Published>>;
+
+/// What went into one flush to a subscriber.
+#[derive(Debug)]
+struct Batch {
+    count: usize,
+    /// When the oldest message in the batch was accepted
+    oldest: Instant,
+    /// Messages skipped by coalescing
+    superseded: u64,
+    /// Messages lost to lag while filling the batch
+    lagged: u64,
+}
+
+/// Appends `first`, received on `chan`, to `buf`, then greedily drains messages that are
+/// already ready in `streams` without waiting, until `limit` messages are batched. On channels
+/// in `coalesce` only the newest queued message is taken.
+fn fill_batch(
+    chan: &str,
+    first: Published,
+    streams: &mut Subscriptions,
+    buf: &mut BytesMut,
+    limit: usize,
+    coalesce: &HashSet<String>,
+) -> Batch {
+    let mut superseded = 0;
+    let mut take = |chan: &str, msg: Published, streams: &mut Subscriptions| {
+        if !coalesce.contains(chan) {
+            return msg;
+        }
+        let (msg, skipped) = newest_queued(chan, msg, streams);
+        superseded += skipped;
+        msg
+    };
+    let first = take(chan, first, streams);
+    let mut oldest = first.at;
+    buf.put(first.msg);
+    let mut count = 1;
+    let mut lagged = 0;
+    let waker = futures::task::noop_waker();
+    let mut cx = std::task::Context::from_waker(&waker);
+    while count < limit {
+        match streams.poll_next_unpin(&mut cx) {
+            std::task::Poll::Ready(Some((chan, Ok(next)))) => {
+                let next = take(&chan, next, streams);
+                oldest = oldest.min(next.at);
+                buf.put(next.msg);
+                count += 1;
+            }
+            std::task::Poll::Ready(Some((_, Err(BroadcastStreamRecvError::Lagged(n))))) => {
+                lagged += n;
+            }
+            _ => break,
+        }
+    }
+    Batch {
+        count,
+        oldest,
+        superseded,
+        lagged,
+    }
+}
+
+// Skips past everything already queued on `chan` to its newest message. Returns that message
+// and how many older ones it supersedes, counting any lost to lag.
+fn newest_queued(chan: &str, mut msg: Published, streams: &mut Subscriptions) -> (Published, u64) {
+    let Some((_, stream)) = streams.iter_mut().find(|(k, _)| k == chan) else {
+        return (msg, 0);
+    };
+    let waker = futures::task::noop_waker();
+    let mut cx = std::task::Context::from_waker(&waker);
+    let mut superseded = 0;
+    loop {
+        match stream.poll_next_unpin(&mut cx) {
+            std::task::Poll::Ready(Some(Ok(next))) => {
+                msg = next;
+                superseded += 1;
+            }
+            std::task::Poll::Ready(Some(Err(BroadcastStreamRecvError::Lagged(n)))) => {
+                superseded += n;
+            }
+            _ => break,
+        }
+    }
+    (msg, superseded)
+}
+
+// Writes `buf` to a subscriber, accounting for it as buffered until the write completes.
+// Returns false if the connection should close, either on error or because it was still
+// blocked when buffered bytes went over the limit.
+async fn write_accounted<W>(writer: &mut W, buf: &[u8], broker: &Broker) -> bool
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let _held = broker.buffers.hold(buf.len());
+    tokio::select! {
+        res = writer.write_all(buf) => res.is_ok(),
+        _ = broker.buffers.shedding() => {
+            broker.metrics.total_shed.inc();
+            false
+        }
+    }
+}
+
+// Sends `msg` to the client as OP_ERROR. Returns false if the connection should close.
+async fn send_error<W>(writer: &mut W, msg: String, broker: &Broker) -> bool
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    match HpfeedsCodec::new().encode_to_bytes(Frame::Error(msg.into())) {
+        Ok(bytes) => write_accounted(writer, &bytes, broker).await,
+        Err(_) => true,
+    }
+}
+
+/// `len` bytes from the operating system's secure random source, for an OP_INFO rand.
+pub fn nonce(len: usize) -> Result<Vec<u8>, rand::rngs::SysError> {
+    let mut rand = vec![0u8; len];
+    rand::rngs::SysRng.try_fill_bytes(&mut rand)?;
+    Ok(rand)
+}
+
+// Writes whatever is already queued on `stream_map`, without waiting for more.
+async fn flush_queued<W>(
+    stream_map: &mut Subscriptions,
+    write_buf: &mut BytesMut,
+    writer: &mut W,
+    broker: &Broker,
+) where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    while let Some(Some((chan, result))) = stream_map.next().now_or_never() {
+        let Ok(msg) = result else { continue };
+        let batch = fill_batch(
+            &chan,
+            msg,
+            stream_map,
+            write_buf,
+            broker.options.batch_limit,
+            &broker.options.coalesce,
+        );
+        broker.metrics.total_delivered.inc_by(batch.count as u64);
+        let written = write_accounted(writer, write_buf, broker).await;
+        write_buf.clear();
+        if !written {
+            return;
+        }
+    }
+}
+
+// A publish on `WHOAMI_CHANNEL`, for this client only, describing `ctx` as JSON.
+fn whoami_reply(ctx: &AccessContext, codec: &mut HpfeedsCodec) -> Bytes {
+    let payload = serde_json::json!({
+        "ident": ctx.ident,
+        "pub_channels": ctx.pub_channels,
+        "sub_channels": ctx.sub_channels,
+    });
+    codec
+        .encode_to_bytes(Frame::Publish {
+            ident: Bytes::from_static(BROKER_NAME.as_bytes()),
+            channel: Bytes::from_static(WHOAMI_CHANNEL.as_bytes()),
+            payload: payload.to_string().into(),
+        })
+        .unwrap_or_default()
+}
+
+// A publish on `SUBACK_CHANNEL` confirming the subscribe to `channel`, as the client sent it.
+fn suback_reply(channel: &Bytes, codec: &mut HpfeedsCodec) -> Bytes {
+    codec
+        .encode_to_bytes(Frame::Publish {
+            ident: Bytes::from_static(BROKER_NAME.as_bytes()),
+            channel: Bytes::from_static(SUBACK_CHANNEL.as_bytes()),
+            payload: channel.clone(),
+        })
+        .unwrap_or_default()
+}
+
+fn channel_limit(channel: &str, broker: &Broker) -> String {
+    format!(
+        "channel limit reached: {} would exceed {} live channels",
+        channel,
+        broker.options.max_channels.unwrap_or_default()
+    )
+}
+
+// Splits a subscribe or unsubscribe channel into the channel itself, the share group it joins
+// and the backlog it asks for. Each suffix is only parsed on connections that selected it.
+fn split_subscription<'a>(
+    channel: &'a [u8],
+    selected: &Capabilities,
+) -> (&'a [u8], Option<&'a [u8]>, Option<usize>) {
+    let (channel, group) = if selected.contains(CAP_SHARE) {
+        split_share_group(channel)
+    } else {
+        (channel, None)
+    };
+    let (channel, backlog) = if selected.contains(CAP_BACKLOG) {
+        split_backlog(channel)
+    } else {
+        (channel, None)
+    };
+    (channel, group, backlog)
+}
+
+// How a channel is subscribed to, for errors: plainly, or in share group `group`.
+fn subscription_kind(group: Option<&str>) -> String {
+    match group {
+        Some(group) => format!("in share group {}", group),
+        None => "without a share group".to_string(),
+    }
+}
+
+fn invalid_channel(channel: &[u8]) -> String {
+    if channel.is_empty() {
+        return EMPTY_CHANNEL_MESSAGE.to_string();
+    }
+    format!("invalid channel name: {}", String::from_utf8_lossy(channel))
+}
+
+// Reads frames up to the first that is not a capability selection, which the caller expects to
+// be OP_AUTH, within the pre-auth timeout. Selections are only accepted when enabled, and then
+// only a bounded number. Returns the frame and the capabilities agreed on.
+async fn read_auth_frame<R>(
+    frames: &mut Framed<R, HpfeedsCodec>,
+    broker: &Broker,
+) -> Option<(Frame, Capabilities)>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let limit = broker.options.preauth_frames;
+    let deadline = broker.clock.now() + broker.options.preauth_timeout;
+    let mut selected = Capabilities::default();
+    for seen in 0.. {
+        let frame = tokio::select! {
+            frame = frames.next() => frame,
+            _ = broker.clock.sleep_until(deadline) => None,
+        };
+        let Some(Ok(frame)) = frame else {
+            return None;
+        };
+        broker
+            .metrics
+            .frames_received
+            .with_label_values(&[opcode_label(&frame)])
+            .inc();
+        match frame {
+            Frame::Info { name, .. } if seen < limit => {
+                let (_, wanted) = Capabilities::parse_info_name(&name);
+                selected = broker.capabilities().negotiate(&wanted);
+            }
+            frame => return Some((frame, selected)),
+        }
+    }
+    None
+}
+
+// Messages a subscriber has lost to lag over the life of its connection, against
+// `max_lag_drops`.
+struct LagAllowance {
+    dropped: u64,
+    limit: Option<u64>,
+}
+
+impl LagAllowance {
+    fn new(limit: Option<u64>) -> Self {
+        Self { dropped: 0, limit }
+    }
+
+    // Counts `n` more dropped messages. Returns false once the total is over the limit.
+    fn add(&mut self, n: u64, broker: &Broker) -> bool {
+        broker.metrics.total_lagged.inc_by(n);
+        self.dropped += n;
+        self.limit.is_none_or(|limit| self.dropped <= limit)
+    }
+
+    fn disconnect_message(&self) -> String {
+        format!(
+            "disconnected: {} messages dropped because this subscriber fell behind (limit {})",
+            self.dropped,
+            self.limit.unwrap_or_default()
+        )
+    }
+}
+
+// Counts a connection out of `active_connections` however its handler returns.
+struct OpenConnection<'a>(&'a IntGauge);
+
+impl Drop for OpenConnection<'_> {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+// A client's socket, counting the bytes written to it into `sent`.
+struct CountingStream<S> {
+    inner: S,
+    sent: Arc<AtomicU64>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.sent.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// One of an ident's connections, as counted by `Broker::connect_ident`. Counted out however the
+// handler returns.
+struct IdentConnection<'a> {
+    broker: &'a Broker,
+    ident: String,
+}
+
+impl Drop for IdentConnection<'_> {
+    fn drop(&mut self) {
+        let connections = &self.broker.ident_connections;
+        if let Some(mut open) = connections.get_mut(&self.ident) {
+            *open -= 1;
+        }
+        connections.remove_if(&self.ident, |_, &open| open == 0);
+    }
+}
+
+// This connection's subscriptions, as counted in `channel_subscribers`. Whatever is still held
+// is released when the handler returns, counting it out and dropping channels left without
+// subscribers. Channels are only dropped once the receivers are gone, so callers drop those
+// first.
+struct CountedSubscriptions<'a> {
+    broker: &'a Broker,
+    channels: HashSet<String>,
+}
+
+impl<'a> CountedSubscriptions<'a> {
+    fn new(broker: &'a Broker) -> Self {
+        Self {
+            broker,
+            channels: HashSet::new(),
+        }
+    }
+
+    fn add(&mut self, channel: &str) {
+        if self.channels.insert(channel.to_string()) {
+            self.broker
+                .metrics
+                .channel_subscribers
+                .with_label_values(&[channel])
+                .inc();
+        }
+    }
+
+    fn remove(&mut self, channel: &str) {
+        if self.channels.remove(channel) {
+            self.broker.release(channel);
+        }
+    }
+}
+
+impl Drop for CountedSubscriptions<'_> {
+    fn drop(&mut self) {
+        for channel in &self.channels {
+            self.broker.release(channel);
+        }
+    }
+}
+
+/// Serves one client connection until it closes. Everything logged on its behalf is inside a
+/// `conn` span carrying `conn_id`, `peer` and, once authenticated, `ident`. Opening, auth,
+/// subscribes, unsubscribes and closing are logged at info level, the close with how long the
+/// connection lasted and how many bytes it was sent. `slot`, from
+/// [`Broker::admit`], is released once OP_AUTH has been checked.
+///
+/// With `cert_ident`, the common name of a verified client certificate, the client is that
+/// ident: its OP_AUTH is still required but neither its ident nor its secret hash is checked,
+/// and its permissions come from [`Authenticator::access`].
+pub async fn handle_connection<S>(
+    stream: S,
+    peer: SocketAddr,
+    broker: Arc<Broker>,
+    slot: PreauthSlot,
+    cert_ident: Option<String>,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let conn_id = broker.next_conn_id.fetch_add(1, Ordering::Relaxed);
+    let span = info_span!("conn", conn_id, %peer, ident = field::Empty);
+    span.in_scope(|| info!("connection opened"));
+    let opened = broker.clock.now();
+    let sent = Arc::new(AtomicU64::new(0));
+    let stream = CountingStream {
+        inner: stream,
+        sent: sent.clone(),
+    };
+    serve_connection(stream, broker.clone(), slot, cert_ident)
+        .instrument(span.clone())
+        .await;
+    let duration_ms = broker
+        .clock
+        .now()
+        .saturating_duration_since(opened)
+        .as_millis() as u64;
+    let bytes_sent = sent.load(Ordering::Relaxed);
+    span.in_scope(|| info!(duration_ms, bytes_sent, "connection closed"));
+}
+
+async fn serve_connection<S>(
+    stream: S,
+    broker: Arc<Broker>,
+    slot: PreauthSlot,
+    cert_ident: Option<String>,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let Broker {
+        metrics,
+        authenticator,
+        ..
+    } = &*broker;
+    metrics.active_connections.inc();
+    let _open = OpenConnection(&metrics.active_connections);
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut read_framed = Framed::new(reader, HpfeedsCodec::new());
+    let mut codec = HpfeedsCodec::new();
+
+    let randbuf = match nonce(broker.options.rand_len) {
+        Ok(rand) => rand,
+        Err(e) => {
+            warn!("no randomness for OP_INFO: {}", e);
+            return;
+        }
+    };
+    let mut info_bytes = codec
+        .encode_to_bytes(Frame::Info {
+            name: broker.capabilities().info_name(BROKER_NAME).into(),
+            rand: randbuf.clone().into(),
+        })
+        .unwrap()
+        .to_vec();
+    // a publish rather than OP_ERROR, which legacy clients take as a failure
+    if let Some(motd) = &broker.options.motd
+        && let Ok(b) = codec.encode_to_bytes(Frame::Publish {
+            ident: Bytes::from_static(BROKER_NAME.as_bytes()),
+            channel: Bytes::from_static(MOTD_CHANNEL.as_bytes()),
+            payload: Bytes::from(motd.clone()),
+        })
+    {
+        info_bytes.extend_from_slice(&b);
+    }
+    if writer.write_all(&info_bytes).await.is_err() {
+        return;
+    }
+
+    let Some((first, selected)) = read_auth_frame(&mut read_framed, &broker).await else {
+        return;
+    };
+    let access_ctx: AccessContext = if let Frame::Auth { ident, secret_hash } = first {
+        let ident_str = String::from_utf8_lossy(&ident);
+        let ctx = match &cert_ident {
+            Some(cert_ident) => authenticator.access(cert_ident).await,
+            None => {
+                authenticator
+                    .authenticate(&ident_str, &secret_hash, &randbuf)
+                    .await
+            }
+        };
+        if let Some(ctx) = ctx {
+            metrics.total_auth_success.inc();
+            Span::current().record("ident", ctx.ident.as_str());
+            info!(ident = %ctx.ident, "authenticated");
+            if !selected.is_empty() {
+                debug!(caps = %selected, "capabilities selected");
+            }
+            ctx
+        } else {
+            metrics.total_auth_fail.inc();
+            let ident_str = cert_ident.as_deref().unwrap_or(&ident_str);
+            info!(ident = %ident_str, "authentication failed");
+            return;
+        }
+    } else {
+        return;
+    };
+    drop(slot);
+    let Some(_ident_connection) = broker.connect_ident(&access_ctx.ident) else {
+        debug!("too many connections for this ident");
+        let msg = format!(
+            "too many connections for {}: at most {} allowed",
+            access_ctx.ident,
+            broker.options.max_conns_per_ident.unwrap_or_default()
+        );
+        let _ = send_error(&mut writer, msg, &broker).await;
+        return;
+    };
+    let mut access = CachedAccess::new(access_ctx);
+
+    let mut write_buf = BytesMut::with_capacity(CHANNEL_SIZE);
+    // declared ahead of the receivers so that it is dropped after them
+    let mut counted = CountedSubscriptions::new(&broker);
+    let mut stream_map = Subscriptions::new();
+    // Subscriptions paused under CAP_PAUSE. Their receivers are not polled, so publishes queue
+    // in the channel until resumed, and the oldest are lost once it is full.
+    let mut paused: HashMap<String, BroadcastStream<Published>> = HashMap::new();
+    // The group of each subscription, paused or not, that joined a share group
+    let mut share_groups: HashMap<String, String> = HashMap::new();
+    let idle_timeout = broker.options.idle_timeout;
+    let mut last_active = broker.clock.now();
+    let mut high_backlog = HashSet::new();
+    let mut lag = LagAllowance::new(broker.options.max_lag_drops);
+    let mut frame_rate = broker
+        .options
+        .max_frame_rate
+        .map(|rate| RateLimiter::new(rate, broker.clock.now()));
+    let mut pub_rate = broker
+        .options
+        .max_pub_rate
+        .map(|rate| RateLimiter::new(rate, broker.clock.now()));
+
+    loop {
+        tokio::select! {
+            Some((chan, result)) = stream_map.next(), if !stream_map.is_empty() => {
+                last_active = broker.clock.now();
+                match result {
+                    Ok(msg) => {
+                        broker.track_backlog(&chan, msg.seq, &mut high_backlog);
+                        let batch = fill_batch(&chan, msg, &mut stream_map, &mut write_buf, broker.options.batch_limit, &broker.options.coalesce);
+                        metrics.total_coalesced.inc_by(batch.superseded);
+                        metrics.total_delivered.inc_by(batch.count as u64);
+                        metrics.flush_batch_size.observe(batch.count as f64);
+                        debug!(channel = %chan, count = batch.count, "delivering");
+                        if !write_accounted(&mut writer, &write_buf, &broker).await { break; }
+                        write_buf.clear();
+                        broker.check_delivery(&chan, batch.oldest);
+                        if !lag.add(batch.lagged, &broker) {
+                            let _ = send_error(&mut writer, lag.disconnect_message(), &broker).await;
+                            break;
+                        }
+                    }
+                    Err(BroadcastStreamRecvError::Lagged(n)) => {
+                        if !lag.add(n, &broker) {
+                            let _ = send_error(&mut writer, lag.disconnect_message(), &broker).await;
+                            break;
+                        }
+                    }
+                }
+            }
+            frame = read_framed.next() => {
+                // the client closed the connection or sent something undecodable
+                let Some(Ok(frame)) = frame else { break; };
+                last_active = broker.clock.now();
+                metrics.frames_received.with_label_values(&[opcode_label(&frame)]).inc();
+                if let Some(limiter) = &mut frame_rate && !limiter.allow(last_active) {
+                    metrics.total_rate_limited.inc();
+                    let limited_for = limiter.limited_for();
+                    if broker.options.frame_rate_disconnect.is_some_and(|d| limited_for >= d) {
+                        let msg = format!("disconnected: over the frame-rate limit for {}s", limited_for.as_secs());
+                        let _ = send_error(&mut writer, msg, &broker).await;
+                        break;
+                    }
+                    continue;
+                }
+                match frame {
+                    Frame::Subscribe { channel, .. } => {
+                        if selected.contains(CAP_PAUSE) && let (channel, Some(control)) = split_control(&channel) {
+                            let chan_str = String::from_utf8_lossy(channel).into_owned();
+                            match control {
+                                SubscriptionControl::Pause => {
+                                    if let Some(rx) = stream_map.remove(&chan_str) {
+                                        debug!(channel = %chan_str, "paused");
+                                        paused.insert(chan_str, rx);
+                                    }
+                                }
+                                SubscriptionControl::Resume => {
+                                    if let Some(rx) = paused.remove(&chan_str) {
+                                        debug!(channel = %chan_str, "resumed");
+                                        stream_map.insert(chan_str, rx);
+                                    }
+                                }
+                            }
+                            continue;
+                        }
+                        let suback = selected.contains(CAP_SUBACK).then(|| suback_reply(&channel, &mut codec));
+                        let (channel, group, backlog) = split_subscription(&channel, &selected);
+                        if !broker.channel_name_allowed(channel) {
+                            if !send_error(&mut writer, invalid_channel(channel), &broker).await { break; }
+                            continue;
+                        }
+                        if !broker.authorized(&mut access, Operation::Subscribe, channel) {
+                            if suback.is_some() {
+                                let msg = format!("not authorized to subscribe to {}", String::from_utf8_lossy(channel));
+                                if !send_error(&mut writer, msg, &broker).await { break; }
+                            }
+                            continue;
+                        }
+                        // Only valid UTF-8 passes the ACL check, so this never substitutes
+                        let chan_str = String::from_utf8_lossy(channel).into_owned();
+                        let group = group.map(|g| String::from_utf8_lossy(g).into_owned());
+                        let retained = if stream_map.contains_key(&chan_str) || paused.contains_key(&chan_str) {
+                            // switching between plain and shared, or between groups, would change
+                            // what the subscription is sent, so it takes an unsubscribe first
+                            if share_groups.get(&chan_str) != group.as_ref() {
+                                let msg = format!("already subscribed to {} {}; unsubscribe first", chan_str, subscription_kind(share_groups.get(&chan_str).map(String::as_str)));
+                                if !send_error(&mut writer, msg, &broker).await { break; }
+                                continue;
+                            }
+                            Vec::new()
+                        } else if let Some(group) = group {
+                            let Some(rx) = broker.subscribe_shared(&chan_str, &group) else {
+                                if !send_error(&mut writer, channel_limit(&chan_str, &broker), &broker).await { break; }
+                                continue;
+                            };
+                            info!(channel = %chan_str, group = %group, "joined share group");
+                            counted.add(&chan_str);
+                            share_groups.insert(chan_str.clone(), group);
+                            stream_map.insert(chan_str, BroadcastStream::new(rx));
+                            Vec::new()
+                        } else {
+                            let Some((mut retained, rx)) = broker.subscribe(&chan_str) else {
+                                if !send_error(&mut writer, channel_limit(&chan_str, &broker), &broker).await { break; }
+                                continue;
+                            };
+                            if let Some(k) = backlog {
+                                retained.drain(..retained.len().saturating_sub(k));
+                            }
+                            info!(channel = %chan_str, retained = retained.len(), "subscribed");
+                            counted.add(&chan_str);
+                            stream_map.insert(chan_str, BroadcastStream::new(rx));
+                            retained
+                        };
+                        // confirmed ahead of any retained messages
+                        if let Some(ack) = &suback && !write_accounted(&mut writer, ack, &broker).await { break; }
+                        if !retained.is_empty() {
+                            metrics.total_delivered.inc_by(retained.len() as u64);
+                            if !write_accounted(&mut writer, &retained.concat(), &broker).await { break; }
+                        }
+                    }
+                    Frame::Unsubscribe { channel, .. } => {
+                        // subscriptions are held under the bare channel, shared or not
+                        let (channel, _, _) = split_subscription(&channel, &selected);
+                        let chan_str = String::from_utf8_lossy(channel);
+                        let removed = stream_map.remove(chan_str.as_ref()).is_some();
+                        if paused.remove(chan_str.as_ref()).is_some() || removed {
+                            info!(channel = %chan_str, "unsubscribed");
+                            share_groups.remove(chan_str.as_ref());
+                            counted.remove(&chan_str);
+                        }
+                    }
+                    Frame::Publish { channel, .. } if !broker.channel_name_allowed(&channel) => {
+                        let sent = send_error(&mut writer, invalid_channel(&channel), &broker).await;
+                        if !sent { break; }
+                    }
+                    Frame::Publish { channel, .. } if broker.options.whoami && channel == WHOAMI_CHANNEL => {
+                        let reply = whoami_reply(access.context(), &mut codec);
+                        if !write_accounted(&mut writer, &reply, &broker).await { break; }
+                    }
+                    Frame::Publish { channel, .. } if broker.reserved_for(&channel).is_some() => {
+                        let purpose = broker.reserved_for(&channel).unwrap_or_default();
+                        let msg = format!("channel {} is reserved for {}", String::from_utf8_lossy(&channel), purpose);
+                        if !send_error(&mut writer, msg, &broker).await { break; }
+                    }
+                    Frame::Publish { channel, payload, .. } if broker.authorized(&mut access, Operation::Publish, &channel) => {
+                        if let Some(limiter) = &mut pub_rate && !limiter.allow(last_active) {
+                            metrics.total_pub_rate_limited.inc();
+                            continue;
+                        }
+                        let publish = Frame::Publish { ident: access.context().ident.clone().into(), channel: channel.clone(), payload: payload.clone() };
+                        if broker.is_duplicate(&publish) { continue; }
+                        metrics.total_published.inc();
+                        if let Some(top) = &broker.top_channels {
+                            top.record(&channel, broker.clock.now());
+                        }
+                        let preview = broker.payload_preview(&channel, &payload);
+                        debug!(channel = %String::from_utf8_lossy(&channel), size = payload.len(), preview = preview.as_deref(), "published");
+                        if let Some(audit) = &broker.audit {
+                            audit.record(&access.context().ident, &channel, payload.len(), preview);
+                        }
+                        let chan_str = String::from_utf8_lossy(&channel).into_owned();
+                        if broker.wants(&chan_str) && let Ok(b) = codec.encode_to_bytes(publish) {
+                            broker.publish(&chan_str, b);
+                        }
+                    }
+                    Frame::Publish { channel, .. } if broker.options.report_denied => {
+                        let msg = format!("not authorized to publish to {}", String::from_utf8_lossy(&channel));
+                        if !send_error(&mut writer, msg, &broker).await { break; }
+                    }
+                    _ => {}
+                }
+            }
+            _ = broker.clock.sleep_until(last_active + idle_timeout.unwrap_or_default()), if idle_timeout.is_some() => {
+                break;
+            }
+            _ = broker.shutting_down() => {
+                flush_queued(&mut stream_map, &mut write_buf, &mut writer, &broker).await;
+                let _ = send_error(&mut writer, SHUTDOWN_MESSAGE.to_string(), &broker).await;
+                let _ = writer.shutdown().await;
+                break;
+            }
+            else => { break; }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamMap;
+
+    #[test]
+    fn nonces_are_fresh_and_sized() {
+        let a = nonce(DEFAULT_RAND_LEN).unwrap();
+        let b = nonce(DEFAULT_RAND_LEN).unwrap();
+        assert_eq!(a.len(), 16);
+        assert_eq!(b.len(), 16);
+        assert_ne!(a, b);
+        let longest = hpfeeds_core::MAX_RAND_LEN;
+        assert_eq!(nonce(longest).unwrap().len(), longest);
+    }
+
+    // Publishes `n` messages to a fresh subscription and returns the size of each flush the
+    // delivery loop would make.
+    async fn flush_sizes(n: usize) -> Vec<usize> {
+        let (tx, _) = broadcast::channel(CHANNEL_SIZE);
+        let mut streams = StreamMap::new();
+        streams.insert("ch".to_string(), BroadcastStream::new(tx.subscribe()));
+        for i in 0..n {
+            let msg = Bytes::from(i.to_string());
+            tx.send(Published {
+                msg,
+                at: Instant::now(),
+                seq: i as u64,
+            })
+            .unwrap();
+        }
+        drop(tx);
+
+        let mut sizes = Vec::new();
+        let mut buf = BytesMut::new();
+        while let Some((_, Ok(msg))) = streams.next().await {
+            let batch = fill_batch(
+                "ch",
+                msg,
+                &mut streams,
+                &mut buf,
+                BATCH_LIMIT,
+                &HashSet::new(),
+            );
+            sizes.push(batch.count);
+            buf.clear();
+        }
+        sizes
+    }
+
+    #[tokio::test]
+    async fn coalescing_channel_batches_only_its_newest_message() {
+        let (status, _) = broadcast::channel(CHANNEL_SIZE);
+        let (plain, _) = broadcast::channel(CHANNEL_SIZE);
+        let mut streams = StreamMap::new();
+        streams.insert(
+            "status".to_string(),
+            BroadcastStream::new(status.subscribe()),
+        );
+        streams.insert("plain".to_string(), BroadcastStream::new(plain.subscribe()));
+        for i in 0..10u8 {
+            for (tx, tag) in [(&status, b's'), (&plain, b'p')] {
+                let msg = Bytes::copy_from_slice(&[tag, i]);
+                tx.send(Published {
+                    msg,
+                    at: Instant::now(),
+                    seq: i.into(),
+                })
+                .unwrap();
+            }
+        }
+
+        let coalesce = HashSet::from(["status".to_string()]);
+        let (chan, Ok(first)) = streams.next().await.unwrap() else {
+            panic!("lagged");
+        };
+        let mut buf = BytesMut::new();
+        let batch = fill_batch(&chan, first, &mut streams, &mut buf, BATCH_LIMIT, &coalesce);
+
+        assert_eq!((batch.count, batch.superseded), (11, 9));
+        let status_msgs: Vec<&[u8]> = buf.chunks(2).filter(|m| m[0] == b's').collect();
+        assert_eq!(status_msgs, [&[b's', 9]]);
+    }
+
+    #[tokio::test]
+    async fn batches_keep_per_channel_order() {
+        let (a, _) = broadcast::channel(CHANNEL_SIZE);
+        let (b, _) = broadcast::channel(CHANNEL_SIZE);
+        let mut streams = StreamMap::new();
+        streams.insert("a".to_string(), BroadcastStream::new(a.subscribe()));
+        streams.insert("b".to_string(), BroadcastStream::new(b.subscribe()));
+        for i in 0..100u8 {
+            for (tx, tag) in [(&a, b'a'), (&b, b'b')] {
+                let msg = Bytes::copy_from_slice(&[tag, i]);
+                tx.send(Published {
+                    msg,
+                    at: Instant::now(),
+                    seq: i.into(),
+                })
+                .unwrap();
+            }
+        }
+        drop((a, b));
+
+        let mut buf = BytesMut::new();
+        while let Some((chan, Ok(msg))) = streams.next().await {
+            fill_batch(&chan, msg, &mut streams, &mut buf, 16, &HashSet::new());
+        }
+        let mut next = [0u8; 2];
+        for pair in buf.chunks(2) {
+            let slot = &mut next[(pair[0] - b'a') as usize];
+            assert_eq!(pair[1], *slot);
+            *slot += 1;
+        }
+        assert_eq!(next, [100, 100]);
+    }
+
+    #[tokio::test]
+    async fn fewer_than_batch_limit_flush_once() {
+        assert_eq!(flush_sizes(10).await, vec![10]);
+    }
+
+    #[tokio::test]
+    async fn more_than_batch_limit_flush_in_chunks() {
+        let n = BATCH_LIMIT * 2 + 44;
+        assert_eq!(flush_sizes(n).await, vec![BATCH_LIMIT, BATCH_LIMIT, 44]);
+    }
+}
@@ -0,0 +1,54 @@
+use crate::metrics::Metrics;
+use std::time::Duration;
+
+/// The counters a stats line reports, read at one instant.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub published: u64,
+    pub delivered: u64,
+    pub lagged: u64,
+    pub auth_success: u64,
+    pub auth_fail: u64,
+    pub connections: i64,
+}
+
+impl Snapshot {
+    pub fn take(metrics: &Metrics) -> Self {
+        Self {
+            published: metrics.total_published.get(),
+            delivered: metrics.total_delivered.get(),
+            lagged: metrics.total_lagged.get(),
+            auth_success: metrics.total_auth_success.get(),
+            auth_fail: metrics.total_auth_fail.get(),
+            connections: metrics.active_connections.get(),
+        }
+    }
+
+    /// One line describing what happened since `earlier`, with the open connections as of now.
+    pub fn summary_since(&self, earlier: &Snapshot, interval: Duration) -> String {
+        format!(
+            "stats over {:?}: published={} delivered={} lagged={} auth_ok={} auth_fail={} connections={}",
+            interval,
+            self.published - earlier.published,
+            self.delivered - earlier.delivered,
+            self.lagged - earlier.lagged,
+            self.auth_success - earlier.auth_success,
+            self.auth_fail - earlier.auth_fail,
+            self.connections,
+        )
+    }
+}
+
+/// Passes a summary line to `emit` every `interval`, forever.
+pub async fn report<F: FnMut(String)>(metrics: &Metrics, interval: Duration, mut emit: F) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ticker.tick().await;
+    let mut last = Snapshot::take(metrics);
+    loop {
+        ticker.tick().await;
+        let now = Snapshot::take(metrics);
+        emit(now.summary_since(&last, interval));
+        last = now;
+    }
+}
@@ -0,0 +1,111 @@
+use crate::config::{TlsConfig, TlsVersion};
+use anyhow::{Context, Result, anyhow, bail};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ServerConnection, WebPkiClientVerifier};
+use rustls::{RootCertStore, ServerConfig};
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+
+/// Builds the listener's TLS acceptor from `config`. Every path must be relative and free of
+/// `..` components. With `client_ca` set, clients must present a certificate it signed.
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
+    let cert_chain = read_certs(&config.cert)?;
+    let key = read_key(&config.key)?;
+
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let versions: &[&rustls::SupportedProtocolVersion] = match config.min_version {
+        None | Some(TlsVersion::V1_2) => rustls::ALL_VERSIONS,
+        Some(TlsVersion::V1_3) => &[&rustls::version::TLS13],
+    };
+    let builder =
+        ServerConfig::builder_with_provider(provider.clone()).with_protocol_versions(versions)?;
+    let builder = match &config.client_ca {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(ca_path)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("invalid CA certificate in {}", ca_path))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .context("building client certificate verifier")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(cert_chain, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// The common name of the certificate a client presented during the handshake, which the
+/// `client_ca` verifier has already checked; None when the client presented none. A
+/// certificate without a CN is an error, as it names no ident.
+pub fn client_ident(conn: &ServerConnection) -> Result<Option<String>> {
+    let Some(cert) = conn.peer_certificates().and_then(|c| c.first()) else {
+        return Ok(None);
+    };
+    let (_, cert) = x509_parser::parse_x509_certificate(cert)
+        .map_err(|e| anyhow!("unreadable client certificate: {}", e))?;
+    let cn = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .context("client certificate has no common name")?;
+    let cn = cn
+        .as_str()
+        .map_err(|e| anyhow!("client certificate common name: {}", e))?;
+    Ok(Some(cn.to_string()))
+}
+
+// Reads every PEM-encoded certificate in `path`.
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let data = read_safe(path)?;
+    let certs = pem::parse_many(&data)?
+        .into_iter()
+        .filter(|p| p.tag() == "CERTIFICATE")
+        .map(|p| CertificateDer::from(p.contents().to_vec()))
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        bail!("no certificates found in {}", path);
+    }
+    Ok(certs)
+}
+
+// Reads the first PEM-encoded private key in `path` (PKCS#8, PKCS#1 or EC).
+fn read_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    let data = read_safe(path)?;
+    let key_pem = pem::parse_many(&data)?
+        .into_iter()
+        .find(|p| {
+            let t = p.tag();
+            t == "PRIVATE KEY" || t == "RSA PRIVATE KEY" || t == "EC PRIVATE KEY"
+        })
+        .ok_or_else(|| anyhow!("no private key found in {}", path))?;
+    PrivateKeyDer::try_from(key_pem.contents().to_vec()).map_err(|e| anyhow!(e))
+}
+
+fn read_safe(path: &str) -> Result<String> {
+    // Prevent path traversal attacks by rejecting absolute paths and '..' components
+    if !is_safe_relative_path(path) {
+        bail!(
+            "unsafe TLS file path {}: absolute or parent-directory component detected",
+            path
+        );
+    }
+    std::fs::read_to_string(path).with_context(|| format!("reading {}", path))
+}
+
+/// Return false for absolute paths or any parent-directory (`..`) components.
+pub fn is_safe_relative_path(p: &str) -> bool {
+    let path = std::path::Path::new(p);
+    if path.is_absolute() {
+        return false;
+    }
+    for comp in path.components() {
+        if matches!(comp, std::path::Component::ParentDir) {
+            return false;
+        }
+    }
+    true
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_TOP_CHANNELS_WINDOW: Duration = Duration::from_secs(60);
+
+const SKETCH_DEPTH: usize = 4;
+const SKETCH_WIDTH: usize = 2048;
+
+/// Approximate publish counts in a fixed amount of memory. Counts are never under-estimated;
+/// with many more channels than columns, rare channels may be over-estimated by a few collisions.
+struct CountMinSketch {
+    counts: Vec<u64>,
+}
+
+impl CountMinSketch {
+    fn new() -> Self {
+        Self {
+            counts: vec![0; SKETCH_DEPTH * SKETCH_WIDTH],
+        }
+    }
+
+    fn cells(key: &[u8]) -> impl Iterator<Item = usize> + '_ {
+        (0..SKETCH_DEPTH).map(move |row| {
+            let mut hasher = DefaultHasher::new();
+            row.hash(&mut hasher);
+            key.hash(&mut hasher);
+            row * SKETCH_WIDTH + (hasher.finish() as usize % SKETCH_WIDTH)
+        })
+    }
+
+    /// Counts one more for `key` and returns its new estimate.
+    fn add(&mut self, key: &[u8]) -> u64 {
+        let mut estimate = u64::MAX;
+        for cell in Self::cells(key) {
+            self.counts[cell] += 1;
+            estimate = estimate.min(self.counts[cell]);
+        }
+        estimate
+    }
+
+    fn estimate(&self, key: &[u8]) -> u64 {
+        Self::cells(key).map(|c| self.counts[c]).min().unwrap_or(0)
+    }
+}
+
+// Publishes counted over one window, with the channels seen to be busiest in it.
+struct Window {
+    sketch: CountMinSketch,
+    leaders: HashMap<String, u64>,
+}
+
+impl Window {
+    fn new() -> Self {
+        Self {
+            sketch: CountMinSketch::new(),
+            leaders: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, channel: &[u8], n: usize) {
+        let estimate = self.sketch.add(channel);
+        let name = String::from_utf8_lossy(channel);
+        if let Some(count) = self.leaders.get_mut(name.as_ref()) {
+            *count = estimate;
+            return;
+        }
+        if self.leaders.len() >= n {
+            let Some((quietest, &least)) = self.leaders.iter().min_by_key(|(_, c)| **c) else {
+                return;
+            };
+            if estimate <= least {
+                return;
+            }
+            let quietest = quietest.clone();
+            self.leaders.remove(&quietest);
+        }
+        self.leaders.insert(name.into_owned(), estimate);
+    }
+}
+
+struct State {
+    started: Instant,
+    current: Window,
+    previous: Window,
+}
+
+/// Tracks the `n` channels with the most publishes over a sliding window.
+///
+/// Counts for the current window and the one before it are kept in count-min sketches, so memory
+/// stays fixed however many channels are published to. A channel's count is its count so far in
+/// the current window plus the share of the previous window's that still falls within the last
+/// `window`.
+pub struct TopChannels {
+    n: usize,
+    window: Duration,
+    state: Mutex<State>,
+}
+
+impl TopChannels {
+    pub fn new(n: usize, window: Duration, now: Instant) -> Self {
+        Self {
+            n,
+            window,
+            state: Mutex::new(State {
+                started: now,
+                current: Window::new(),
+                previous: Window::new(),
+            }),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Counts a publish on `channel`.
+    pub fn record(&self, channel: &[u8], now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        self.rotate(&mut state, now);
+        state.current.add(channel, self.n);
+    }
+
+    /// The busiest channels with their approximate publish counts, busiest first.
+    pub fn top(&self, now: Instant) -> Vec<(String, u64)> {
+        let mut state = self.state.lock().unwrap();
+        self.rotate(&mut state, now);
+        let elapsed = now.saturating_duration_since(state.started);
+        let overlap = 1.0 - elapsed.as_secs_f64() / self.window.as_secs_f64();
+        let count = |channel: &str| {
+            let previous = state.previous.sketch.estimate(channel.as_bytes()) as f64 * overlap;
+            state.current.sketch.estimate(channel.as_bytes()) + previous.round() as u64
+        };
+        let mut top: Vec<(String, u64)> = state
+            .current
+            .leaders
+            .keys()
+            .chain(
+                state
+                    .previous
+                    .leaders
+                    .keys()
+                    .filter(|c| !state.current.leaders.contains_key(*c)),
+            )
+            .map(|c| (c.clone(), count(c)))
+            .filter(|(_, n)| *n > 0)
+            .collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(self.n);
+        top
+    }
+
+    // Starts a new window once the current one has run its length.
+    fn rotate(&self, state: &mut State, now: Instant) {
+        let elapsed = now.saturating_duration_since(state.started);
+        if elapsed < self.window {
+            return;
+        }
+        let windows = (elapsed.as_nanos() / self.window.as_nanos()) as u32;
+        let current = std::mem::replace(&mut state.current, Window::new());
+        state.previous = if windows == 1 { current } else { Window::new() };
+        state.started += self.window * windows;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn publish(top: &TopChannels, channel: &str, times: usize, now: Instant) {
+        for _ in 0..times {
+            top.record(channel.as_bytes(), now);
+        }
+    }
+
+    #[test]
+    fn keeps_the_busiest_channels() {
+        let start = Instant::now();
+        let top = TopChannels::new(2, Duration::from_secs(60), start);
+        publish(&top, "quiet", 1, start);
+        publish(&top, "busy", 10, start);
+        publish(&top, "medium", 5, start);
+        for i in 0..500 {
+            publish(&top, &format!("noise{}", i), 1, start);
+        }
+        assert_eq!(
+            top.top(start),
+            vec![("busy".to_string(), 10), ("medium".to_string(), 5)]
+        );
+    }
+
+    #[test]
+    fn old_publishes_slide_out_of_the_window() {
+        let start = Instant::now();
+        let window = Duration::from_secs(60);
+        let top = TopChannels::new(3, window, start);
+        publish(&top, "old", 10, start);
+
+        // Halfway through the next window, half of the previous one still counts
+        let later = start + window + window / 2;
+        publish(&top, "new", 8, later);
+        assert_eq!(
+            top.top(later),
+            vec![("new".to_string(), 8), ("old".to_string(), 5)]
+        );
+
+        assert_eq!(top.top(start + window * 3), vec![]);
+    }
+}
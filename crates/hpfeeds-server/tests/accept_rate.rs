@@ -0,0 +1,56 @@
+use futures::StreamExt;
+use hpfeeds_core::{Frame, HpfeedsCodec};
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::clock::TestClock;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::time::{Duration, timeout};
+use tokio_util::codec::Framed;
+
+// True if the broker greeted a new connection with OP_INFO, false if it closed it unanswered.
+async fn greeted(addr: &str) -> bool {
+    let mut conn = Framed::new(TcpStream::connect(addr).await.unwrap(), HpfeedsCodec::new());
+    match timeout(Duration::from_secs(1), conn.next())
+        .await
+        .expect("broker neither greeted nor closed the connection")
+    {
+        Some(Ok(Frame::Info { .. })) => true,
+        Some(Ok(other)) => panic!("unexpected frame {:?}", other),
+        Some(Err(_)) | None => false,
+    }
+}
+
+#[tokio::test]
+async fn connections_beyond_the_accept_rate_are_closed() {
+    let metrics = Arc::new(Metrics::new());
+    let clock = Arc::new(TestClock::new());
+    let broker = Broker::with_options(
+        Arc::new(MemoryAuthenticator::new()),
+        metrics.clone(),
+        BrokerOptions {
+            max_accepts_per_sec: Some(5),
+            ..Default::default()
+        },
+    )
+    .with_clock(clock.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, Arc::new(broker), None));
+
+    // a burst of the rate, then nothing until time passes
+    for _ in 0..5 {
+        assert!(greeted(&addr).await);
+    }
+    for _ in 0..3 {
+        assert!(!greeted(&addr).await);
+    }
+    assert_eq!(metrics.total_accepts_throttled.get(), 3);
+
+    clock.advance(Duration::from_millis(400));
+    assert!(greeted(&addr).await);
+    assert!(greeted(&addr).await);
+    assert!(!greeted(&addr).await);
+    assert_eq!(metrics.total_accepts_throttled.get(), 4);
+}
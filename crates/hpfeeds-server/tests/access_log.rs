@@ -0,0 +1,116 @@
+use bytes::Bytes;
+use futures::SinkExt;
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, run_server};
+use serde_json::Value;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, timeout};
+
+// Collects everything the subscriber writes.
+#[derive(Clone, Default)]
+struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Every captured line logging `message`.
+fn events(capture: &Capture, message: &str) -> Vec<Value> {
+    let logs = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+    logs.lines()
+        .map(|l| serde_json::from_str::<Value>(l).unwrap())
+        .filter(|l| l["fields"]["message"] == message)
+        .collect()
+}
+
+#[tokio::test]
+async fn connection_lifecycle_is_logged_at_info() -> Result<(), Box<dyn std::error::Error>> {
+    let capture = Capture::default();
+    let writer = capture.clone();
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::fmt()
+            .json()
+            .with_current_span(true)
+            .with_max_level(tracing::Level::INFO)
+            .with_writer(move || writer.clone())
+            .finish(),
+    )?;
+
+    let auth = MemoryAuthenticator::new();
+    auth.add("sensor", "s").await;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    let broker = Broker::new(Arc::new(auth), Arc::new(Metrics::new()));
+    tokio::spawn(run_server(listener, Arc::new(broker), None));
+
+    let mut client = connect_and_auth(&addr, "sensor", "s").await?;
+    client
+        .send(Frame::Subscribe {
+            ident: Bytes::from_static(b"sensor"),
+            channel: Bytes::from_static(b"ch"),
+        })
+        .await?;
+    client
+        .send(Frame::Unsubscribe {
+            ident: Bytes::from_static(b"sensor"),
+            channel: Bytes::from_static(b"ch"),
+        })
+        .await?;
+    drop(client);
+    // the broker hangs up on bad credentials without a reply
+    let _ = connect_and_auth(&addr, "sensor", "wrong").await;
+
+    timeout(Duration::from_secs(2), async {
+        while events(&capture, "connection closed").len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+
+    let opened = events(&capture, "connection opened");
+    assert_eq!(opened.len(), 2);
+    assert!(
+        opened[0]["span"]["peer"]
+            .as_str()
+            .unwrap()
+            .starts_with("127.0.0.1:")
+    );
+
+    let authenticated = events(&capture, "authenticated");
+    assert_eq!(authenticated.len(), 1);
+    assert_eq!(authenticated[0]["fields"]["ident"], "sensor");
+    let conn_id = &authenticated[0]["span"]["conn_id"];
+    assert!(conn_id.is_u64());
+
+    let failed = events(&capture, "authentication failed");
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0]["fields"]["ident"], "sensor");
+    assert_ne!(&failed[0]["span"]["conn_id"], conn_id);
+
+    for message in ["subscribed", "unsubscribed"] {
+        let event = &events(&capture, message)[0];
+        assert_eq!(event["fields"]["channel"], "ch");
+        assert_eq!(&event["span"]["conn_id"], conn_id);
+    }
+
+    let closed = events(&capture, "connection closed");
+    let closed = closed
+        .iter()
+        .find(|e| &e["span"]["conn_id"] == conn_id)
+        .unwrap();
+    // at least OP_INFO was sent
+    assert!(closed["fields"]["bytes_sent"].as_u64().unwrap() > 0);
+    assert!(closed["fields"]["duration_ms"].is_u64());
+    Ok(())
+}
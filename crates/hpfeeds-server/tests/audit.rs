@@ -0,0 +1,147 @@
+use bytes::Bytes;
+use futures::SinkExt;
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::audit::{AuditLog, AuditTarget};
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+async fn start(target: AuditTarget) -> Result<String, Box<dyn std::error::Error>> {
+    start_with(target, BrokerOptions::default()).await
+}
+
+async fn start_with(
+    target: AuditTarget,
+    options: BrokerOptions,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add_user(
+        "sensor",
+        "s3cret",
+        vec!["ch".into(), "creds".into()],
+        vec![],
+    )
+    .await;
+    let broker = Broker::with_options(Arc::new(auth), Arc::new(Metrics::new()), options)
+        .with_audit(AuditLog::open(&target)?);
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, Arc::new(broker), None));
+    Ok(addr)
+}
+
+async fn read_lines(
+    path: &std::path::Path,
+    n: usize,
+) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(timeout(Duration::from_secs(2), async {
+        loop {
+            let content = std::fs::read_to_string(path).unwrap_or_default();
+            if content.lines().count() >= n {
+                return content;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?)
+}
+
+async fn publish(addr: &str, channels: &[&'static str]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = connect_and_auth(addr, "sensor", "s3cret").await?;
+    for (i, channel) in channels.iter().enumerate() {
+        client
+            .send(Frame::Publish {
+                ident: Bytes::from_static(b"sensor"),
+                channel: Bytes::from_static(channel.as_bytes()),
+                payload: Bytes::from(vec![0u8; i + 1]),
+            })
+            .await?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn accepted_publishes_are_written_to_the_audit_file() -> Result<(), Box<dyn std::error::Error>>
+{
+    let path = std::env::temp_dir().join(format!("hpfeeds-audit-{}.log", std::process::id()));
+    let addr = start(AuditTarget::File(path.to_str().unwrap().into())).await?;
+
+    // the publish to "denied" fails the ACL and is not audited
+    publish(&addr, &["ch", "denied", "ch"]).await?;
+
+    let lines = read_lines(&path, 2).await?;
+    std::fs::remove_file(&path)?;
+
+    let records: Vec<serde_json::Value> = lines
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(records.len(), 2);
+    for (record, size) in records.iter().zip([1, 3]) {
+        assert_eq!(record["ident"], "sensor");
+        assert_eq!(record["channel"], "ch");
+        assert_eq!(record["size"], size);
+        assert!(record["ts_ms"].as_u64().unwrap() > 0);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn audit_records_go_to_syslog() -> Result<(), Box<dyn std::error::Error>> {
+    let syslog = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+    let addr = start(AuditTarget::Syslog(syslog.local_addr()?.to_string())).await?;
+
+    publish(&addr, &["ch"]).await?;
+
+    let mut buf = [0u8; 1024];
+    let n = timeout(Duration::from_secs(2), syslog.recv(&mut buf)).await??;
+    let msg = std::str::from_utf8(&buf[..n])?;
+    let json = msg
+        .strip_prefix("<134>1 - - hpfeeds-server - audit - ")
+        .unwrap();
+    let record: serde_json::Value = serde_json::from_str(json)?;
+    assert_eq!(record["channel"], "ch");
+    assert_eq!(record["size"], 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn redacted_channels_are_audited_without_payload_bytes()
+-> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join(format!("hpfeeds-redact-{}.log", std::process::id()));
+    let options = BrokerOptions {
+        log_payload_preview_bytes: 6,
+        redact_channels: Some(regex::Regex::new("^creds$")?),
+        ..Default::default()
+    };
+    let addr = start_with(AuditTarget::File(path.to_str().unwrap().into()), options).await?;
+
+    let mut client = connect_and_auth(&addr, "sensor", "s3cret").await?;
+    for channel in ["ch", "creds"] {
+        client
+            .send(Frame::Publish {
+                ident: Bytes::from_static(b"sensor"),
+                channel: Bytes::from_static(channel.as_bytes()),
+                payload: Bytes::from_static(b"hunter2-password"),
+            })
+            .await?;
+    }
+
+    let lines = read_lines(&path, 2).await?;
+    std::fs::remove_file(&path)?;
+
+    let mut lines = lines.lines();
+    let open: serde_json::Value = serde_json::from_str(lines.next().unwrap())?;
+    assert_eq!(open["preview"], "hunter");
+    let redacted = lines.next().unwrap();
+    assert!(!redacted.contains("hunter"), "{}", redacted);
+    let redacted: serde_json::Value = serde_json::from_str(redacted)?;
+    assert_eq!(redacted["channel"], "creds");
+    assert_eq!(redacted["size"], 16);
+    assert!(redacted.get("preview").is_none());
+    Ok(())
+}
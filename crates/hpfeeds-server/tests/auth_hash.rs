@@ -0,0 +1,58 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth_with;
+use hpfeeds_core::{Frame, hashsecret};
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn caller_supplied_hash_authenticates() -> Result<(), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let broker = Arc::new(Broker::new(Arc::new(auth), metrics.clone()));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut client =
+        connect_and_auth_with(&addr, "client1", |rand| hashsecret(rand, "s3cret")).await?;
+    client
+        .send(Frame::Subscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch"),
+        })
+        .await?;
+    // Frames on the same connection are handled in order, so the subscription is in place
+    client
+        .send(Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch"),
+            payload: Bytes::from_static(b"hello"),
+        })
+        .await?;
+
+    let frame = timeout(Duration::from_secs(2), client.next()).await?;
+    match frame {
+        Some(Ok(Frame::Publish { payload, .. })) => {
+            assert_eq!(payload, Bytes::from_static(b"hello"))
+        }
+        other => panic!("expected publish, got {:?}", other),
+    }
+    assert_eq!(metrics.total_auth_success.get(), 1);
+
+    let mut wrong =
+        connect_and_auth_with(&addr, "client1", |rand| hashsecret(rand, "nope")).await?;
+    assert!(
+        timeout(Duration::from_secs(2), wrong.next())
+            .await?
+            .is_none()
+    );
+    assert_eq!(metrics.total_auth_fail.get(), 1);
+
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::{Transport, connect_and_auth};
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::{
+    AccessContext, AclAuthorizer, Authorizer, MemoryAuthenticator, Operation,
+};
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::net::TcpStream;
+use tokio::time::{Duration, timeout};
+
+// Closes "ch" to publishers while a maintenance window is open, otherwise defers to the ACL.
+#[derive(Default)]
+struct MaintenanceWindow {
+    open: AtomicBool,
+}
+
+impl Authorizer for MaintenanceWindow {
+    fn authorize(&self, ctx: &AccessContext, op: Operation, channel: &str) -> bool {
+        let closed =
+            op == Operation::Publish && channel == "ch" && self.open.load(Ordering::SeqCst);
+        !closed && AclAuthorizer.authorize(ctx, op, channel)
+    }
+}
+
+fn publish(payload: &'static str) -> Frame {
+    Frame::Publish {
+        ident: Bytes::from_static(b"sensor"),
+        channel: Bytes::from_static(b"ch"),
+        payload: Bytes::from_static(payload.as_bytes()),
+    }
+}
+
+async fn next_payload(reader: &mut Transport<TcpStream>) -> Bytes {
+    match timeout(Duration::from_secs(2), reader.next())
+        .await
+        .unwrap()
+    {
+        Some(Ok(Frame::Publish { payload, .. })) => payload,
+        other => panic!("{:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn authorizer_denies_publishes_only_during_the_window() {
+    let auth = MemoryAuthenticator::new();
+    auth.add("reader", "s").await;
+    auth.add_user("sensor", "s", vec!["ch".into()], vec![])
+        .await;
+    let window = Arc::new(MaintenanceWindow::default());
+    let broker = Arc::new(
+        Broker::with_options(
+            Arc::new(auth),
+            Arc::new(Metrics::new()),
+            BrokerOptions {
+                report_denied: true,
+                ..Default::default()
+            },
+        )
+        .with_authorizer(window.clone()),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut reader = connect_and_auth(&addr, "reader", "s").await.unwrap();
+    reader
+        .send(Frame::Subscribe {
+            ident: Bytes::from_static(b"reader"),
+            channel: Bytes::from_static(b"ch"),
+        })
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(2), async {
+        while !broker.subscribers.contains_key("ch") {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .unwrap();
+    let mut sensor = connect_and_auth(&addr, "sensor", "s").await.unwrap();
+
+    sensor.send(publish("before")).await.unwrap();
+    assert_eq!(next_payload(&mut reader).await, "before");
+
+    window.open.store(true, Ordering::SeqCst);
+    // the ACL allowed "before" on this connection, so a cached decision would let this through
+    sensor.send(publish("during")).await.unwrap();
+    let denied = timeout(Duration::from_secs(2), sensor.next())
+        .await
+        .unwrap();
+    assert_eq!(
+        denied.unwrap().unwrap(),
+        Frame::Error("not authorized to publish to ch".into())
+    );
+
+    window.open.store(false, Ordering::SeqCst);
+    sensor.send(publish("after")).await.unwrap();
+    assert_eq!(next_payload(&mut reader).await, "after");
+    assert_eq!(broker.metrics.total_published.get(), 2);
+}
@@ -0,0 +1,74 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth_selecting;
+use hpfeeds_core::{CAP_BACKLOG, Capabilities, Frame, with_backlog};
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn subscribe_with_backlog_gets_last_k_then_live() -> Result<(), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let options = BrokerOptions {
+        retain_depth: 10,
+        preauth_frames: 1,
+        ..Default::default()
+    };
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        Arc::new(Metrics::new()),
+        options,
+    ));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker, None));
+
+    let (mut client, agreed) = connect_and_auth_selecting(
+        &addr,
+        "client1",
+        "s3cret",
+        &Capabilities::new([CAP_BACKLOG]),
+    )
+    .await?;
+    assert!(agreed.contains(CAP_BACKLOG));
+
+    let publish = |payload: String| Frame::Publish {
+        ident: Bytes::from_static(b"client1"),
+        channel: Bytes::from_static(b"ch"),
+        payload: payload.into(),
+    };
+    // Frames on one connection are handled in order, so all five are retained first
+    for i in 1..=5 {
+        client.send(publish(i.to_string())).await?;
+    }
+    client
+        .send(Frame::Subscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: with_backlog("ch", 2).into(),
+        })
+        .await?;
+    for i in 6..=7 {
+        client.send(publish(i.to_string())).await?;
+    }
+
+    let mut got = Vec::new();
+    timeout(Duration::from_secs(1), async {
+        while got.len() < 4 {
+            if let Some(Ok(Frame::Publish {
+                channel, payload, ..
+            })) = client.next().await
+            {
+                assert_eq!(channel, "ch");
+                got.push(String::from_utf8(payload.to_vec()).unwrap());
+            }
+        }
+    })
+    .await?;
+    assert_eq!(got, ["4", "5", "6", "7"]);
+
+    Ok(())
+}
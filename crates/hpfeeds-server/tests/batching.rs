@@ -0,0 +1,71 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, sleep, timeout};
+
+#[tokio::test]
+async fn batch_limit_of_one_flushes_each_message() -> Result<(), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let options = BrokerOptions {
+        batch_limit: 1,
+        channel_capacity: 1024,
+        ..Default::default()
+    };
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        metrics.clone(),
+        options,
+    ));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut sub = connect_and_auth(&addr, "client1", "s3cret").await?;
+    sub.send(Frame::Subscribe {
+        ident: Bytes::from_static(b"client1"),
+        channel: Bytes::from_static(b"ch"),
+    })
+    .await?;
+    timeout(Duration::from_secs(1), async {
+        while !broker.subscribers.contains_key("ch") {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await?;
+
+    // Publish a burst while the subscriber isn't reading, so batches would otherwise form
+    let count = 50;
+    let mut pubc = connect_and_auth(&addr, "client1", "s3cret").await?;
+    for i in 0..count {
+        pubc.send(Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch"),
+            payload: Bytes::from(i.to_string()),
+        })
+        .await?;
+    }
+    sleep(Duration::from_millis(50)).await;
+
+    let mut received = 0;
+    timeout(Duration::from_secs(2), async {
+        while received < count {
+            if let Some(Ok(Frame::Publish { .. })) = sub.next().await {
+                received += 1;
+            }
+        }
+    })
+    .await?;
+
+    let flushes = &metrics.flush_batch_size;
+    assert_eq!(flushes.get_sample_count(), count as u64);
+    assert_eq!(flushes.get_sample_sum(), count as f64);
+    Ok(())
+}
@@ -0,0 +1,118 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, EMPTY_CHANNEL_MESSAGE, run_server};
+use regex::Regex;
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn nonconforming_channels_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let options = BrokerOptions {
+        channel_name_regex: Some(Regex::new("^[a-z0-9._-]+$")?),
+        ..Default::default()
+    };
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        Arc::new(Metrics::new()),
+        options,
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut client = connect_and_auth(&addr, "client1", "s3cret").await?;
+    let frames = [
+        Frame::Subscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"Bad Channel"),
+        },
+        Frame::Subscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"good.ch"),
+        },
+        Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"\xff\xfe"),
+            payload: Bytes::from_static(b"garbage"),
+        },
+        Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"good.ch"),
+            payload: Bytes::from_static(b"hello"),
+        },
+    ];
+    // frames on one connection are handled in order, so the replies come back in order too
+    for frame in frames {
+        client.send(frame).await?;
+    }
+
+    let mut next = async || timeout(Duration::from_secs(2), client.next()).await;
+    match next().await? {
+        Some(Ok(Frame::Error(msg))) => {
+            assert_eq!(
+                msg,
+                Bytes::from_static(b"invalid channel name: Bad Channel")
+            )
+        }
+        other => panic!("expected error for the subscribe, got {:?}", other),
+    }
+    match next().await? {
+        Some(Ok(Frame::Error(msg))) => assert!(msg.starts_with(b"invalid channel name: ")),
+        other => panic!("expected error for the publish, got {:?}", other),
+    }
+    match next().await? {
+        Some(Ok(Frame::Publish {
+            channel, payload, ..
+        })) => {
+            assert_eq!(channel, Bytes::from_static(b"good.ch"));
+            assert_eq!(payload, Bytes::from_static(b"hello"));
+        }
+        other => panic!("expected the conforming publish, got {:?}", other),
+    }
+    assert!(!broker.subscribers.contains_key("Bad Channel"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn empty_channels_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let broker = Arc::new(Broker::new(Arc::new(auth), Arc::new(Metrics::new())));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    // sent as raw frames, since the client library refuses empty channels itself
+    let mut client = connect_and_auth(&addr, "client1", "s3cret").await?;
+    client
+        .send(Frame::Subscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::new(),
+        })
+        .await?;
+    client
+        .send(Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::new(),
+            payload: Bytes::from_static(b"hello"),
+        })
+        .await?;
+
+    for op in ["subscribe", "publish"] {
+        match timeout(Duration::from_secs(2), client.next()).await? {
+            Some(Ok(Frame::Error(msg))) => {
+                assert_eq!(msg, Bytes::from_static(EMPTY_CHANNEL_MESSAGE.as_bytes()))
+            }
+            other => panic!("expected error for the {}, got {:?}", op, other),
+        }
+    }
+    assert!(!broker.subscribers.contains_key(""));
+    assert!(broker.subscribers.is_empty());
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use bytes::Bytes;
+use futures::SinkExt;
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+async fn wait_for_count(metrics: &Metrics, channel: &str, count: i64) {
+    let gauge = metrics.channel_subscribers.with_label_values(&[channel]);
+    timeout(Duration::from_secs(2), async {
+        while gauge.get() != count {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("{} has {} subscribers, not {}", channel, gauge.get(), count));
+}
+
+fn subscribe(channel: &'static str) -> Frame {
+    Frame::Subscribe {
+        ident: Bytes::from_static(b"client1"),
+        channel: Bytes::from_static(channel.as_bytes()),
+    }
+}
+
+#[tokio::test]
+async fn gauge_follows_subscribes_unsubscribes_and_disconnects() {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let broker = Arc::new(Broker::new(Arc::new(auth), metrics.clone()));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker, None));
+
+    let mut first = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    let mut second = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    for channel in ["a", "b", "a"] {
+        first.send(subscribe(channel)).await.unwrap();
+    }
+    second.send(subscribe("a")).await.unwrap();
+    // a repeated subscribe on one connection is not counted twice
+    wait_for_count(&metrics, "a", 2).await;
+    wait_for_count(&metrics, "b", 1).await;
+
+    second
+        .send(Frame::Unsubscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"a"),
+        })
+        .await
+        .unwrap();
+    wait_for_count(&metrics, "a", 1).await;
+
+    // disconnecting counts out every channel the client held
+    drop(first);
+    wait_for_count(&metrics, "a", 0).await;
+    wait_for_count(&metrics, "b", 0).await;
+}
+
+// Channels with a `hpfeeds_channel_subscribers` series.
+#[cfg(feature = "metrics")]
+fn exported_channels(metrics: &Metrics) -> Vec<String> {
+    metrics
+        .registry
+        .gather()
+        .iter()
+        .filter(|f| f.name() == "hpfeeds_channel_subscribers")
+        .flat_map(|f| f.get_metric().iter())
+        .flat_map(|m| m.get_label().iter().map(|l| l.value().to_string()))
+        .collect()
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn series_is_removed_once_the_last_subscriber_leaves() {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let broker = Arc::new(Broker::new(Arc::new(auth), metrics.clone()));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker, None));
+
+    let mut first = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    let mut second = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    first.send(subscribe("a")).await.unwrap();
+    first.send(subscribe("b")).await.unwrap();
+    second.send(subscribe("a")).await.unwrap();
+    wait_for_count(&metrics, "a", 2).await;
+    wait_for_count(&metrics, "b", 1).await;
+
+    first
+        .send(Frame::Unsubscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"b"),
+        })
+        .await
+        .unwrap();
+    drop(first);
+    wait_for_count(&metrics, "a", 1).await;
+    assert_eq!(exported_channels(&metrics), ["a"]);
+
+    drop(second);
+    timeout(Duration::from_secs(2), async {
+        while !exported_channels(&metrics).is_empty() {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .expect("series left behind after the last subscriber disconnected");
+}
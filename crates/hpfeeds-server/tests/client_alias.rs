@@ -0,0 +1,49 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::{Subscriber, connect_and_auth};
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn aliased_channel_is_delivered_under_its_alias() -> Result<(), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("reader", "s").await;
+    auth.add("sensor", "s").await;
+    let broker = Arc::new(Broker::new(Arc::new(auth), Arc::new(Metrics::new())));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut subscriber = Subscriber::new(connect_and_auth(&addr, "reader", "s").await?, "reader")
+        .with_aliases([("a", "b")]);
+    subscriber.subscribe("a").await?;
+    // the broker only knows the upstream name
+    timeout(Duration::from_secs(2), async {
+        while !broker.subscribers.contains_key("a") {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await?;
+    assert!(!broker.subscribers.contains_key("b"));
+
+    let mut sensor = connect_and_auth(&addr, "sensor", "s").await?;
+    sensor
+        .send(Frame::Publish {
+            ident: Bytes::from_static(b"sensor"),
+            channel: Bytes::from_static(b"a"),
+            payload: Bytes::from_static(b"hello"),
+        })
+        .await?;
+
+    let msg = timeout(Duration::from_secs(2), subscriber.next())
+        .await?
+        .expect("connection open")?;
+    assert_eq!(msg.channel, Bytes::from_static(b"b"));
+    assert_eq!(msg.ident, Bytes::from_static(b"sensor"));
+    assert_eq!(msg.payload, Bytes::from_static(b"hello"));
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn lagging_subscriber_gets_latest_value_on_coalescing_channel() {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        metrics.clone(),
+        BrokerOptions {
+            coalesce: HashSet::from(["status".to_string()]),
+            ..Default::default()
+        },
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut slow = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    for channel in ["bulk", "status"] {
+        slow.send(Frame::Subscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from(channel),
+        })
+        .await
+        .unwrap();
+    }
+    timeout(Duration::from_secs(2), async {
+        while !broker.subscribers.contains_key("status") {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .expect("subscribes were not processed");
+
+    // Far more than the socket buffers hold, so the broker's write to the unread subscriber
+    // blocks and later publishes queue up behind it
+    let mut publisher = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    let bulk = 400;
+    let payload = Bytes::from(vec![0u8; 64 * 1024]);
+    for _ in 0..bulk {
+        publisher
+            .send(Frame::Publish {
+                ident: Bytes::from_static(b"client1"),
+                channel: Bytes::from_static(b"bulk"),
+                payload: payload.clone(),
+            })
+            .await
+            .unwrap();
+    }
+    let updates = 50;
+    for i in 1..=updates {
+        publisher
+            .send(Frame::Publish {
+                ident: Bytes::from_static(b"client1"),
+                channel: Bytes::from_static(b"status"),
+                payload: Bytes::from(i.to_string()),
+            })
+            .await
+            .unwrap();
+    }
+    timeout(Duration::from_secs(5), async {
+        while metrics.total_published.get() < bulk + updates {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .expect("publishes were not counted");
+    assert!(
+        broker.buffers.in_flight() > 0,
+        "subscriber write is not blocked"
+    );
+
+    let mut bulk_seen = 0;
+    let mut status_seen = Vec::new();
+    timeout(Duration::from_secs(10), async {
+        while bulk_seen < bulk || status_seen.is_empty() {
+            match slow.next().await {
+                Some(Ok(Frame::Publish {
+                    channel, payload, ..
+                })) => {
+                    if channel == "bulk" {
+                        bulk_seen += 1;
+                    } else {
+                        status_seen.push(String::from_utf8_lossy(&payload).into_owned());
+                    }
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    })
+    .await
+    .expect("deliveries stopped");
+
+    assert_eq!(status_seen, ["50"]);
+    assert_eq!(metrics.total_coalesced.get(), updates - 1);
+    // nothing stale follows
+    assert!(
+        timeout(Duration::from_millis(200), slow.next())
+            .await
+            .is_err()
+    );
+}
@@ -0,0 +1,72 @@
+use futures::StreamExt;
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn connection_past_the_ident_limit_is_refused() {
+    let auth = MemoryAuthenticator::new();
+    auth.add("sensor", "s3cret").await;
+    auth.add("other", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        metrics.clone(),
+        BrokerOptions {
+            max_conns_per_ident: Some(2),
+            ..Default::default()
+        },
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker, None));
+
+    let first = connect_and_auth(&addr, "sensor", "s3cret").await.unwrap();
+    let _second = connect_and_auth(&addr, "sensor", "s3cret").await.unwrap();
+    // both are counted once authenticated
+    timeout(Duration::from_secs(2), async {
+        while metrics.total_auth_success.get() < 2 {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .unwrap();
+
+    let mut third = connect_and_auth(&addr, "sensor", "s3cret").await.unwrap();
+    let reply = timeout(Duration::from_secs(2), third.next()).await.unwrap();
+    assert!(
+        matches!(&reply, Some(Ok(Frame::Error(msg)))
+            if msg == "too many connections for sensor: at most 2 allowed"),
+        "{:?}",
+        reply
+    );
+    let end = timeout(Duration::from_secs(2), third.next()).await.unwrap();
+    assert!(matches!(end, None | Some(Err(_))), "{:?}", end);
+
+    // other idents are unaffected
+    let mut other = connect_and_auth(&addr, "other", "s3cret").await.unwrap();
+    assert!(
+        timeout(Duration::from_millis(200), other.next())
+            .await
+            .is_err()
+    );
+
+    // closing a connection frees its place
+    drop(first);
+    timeout(Duration::from_secs(2), async {
+        loop {
+            let mut client = connect_and_auth(&addr, "sensor", "s3cret").await.unwrap();
+            match timeout(Duration::from_millis(200), client.next()).await {
+                // no OP_ERROR, so it was let in
+                Err(_) => return client,
+                Ok(_) => continue,
+            }
+        }
+    })
+    .await
+    .unwrap();
+}
@@ -0,0 +1,91 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::config::DedupConfig;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn duplicate_publishes_are_delivered_once() -> Result<(), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let options = BrokerOptions {
+        dedup: Some(DedupConfig {
+            channels: vec!["sensors".into()],
+            window_ms: 60_000,
+        }),
+        ..Default::default()
+    };
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        metrics.clone(),
+        options,
+    ));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut sub = connect_and_auth(&addr, "client1", "s3cret").await?;
+    for channel in ["sensors", "other"] {
+        sub.send(Frame::Subscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(channel.as_bytes()),
+        })
+        .await?;
+    }
+    timeout(Duration::from_secs(1), async {
+        while !(broker.subscribers.contains_key("sensors")
+            && broker.subscribers.contains_key("other"))
+        {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await?;
+
+    let mut pubc = connect_and_auth(&addr, "client1", "s3cret").await?;
+    for (channel, payload) in [
+        ("sensors", "a"),
+        ("sensors", "a"),
+        ("sensors", "b"),
+        ("other", "a"),
+        ("other", "a"),
+    ] {
+        pubc.send(Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(channel.as_bytes()),
+            payload: Bytes::from_static(payload.as_bytes()),
+        })
+        .await?;
+    }
+
+    let mut sensors = Vec::new();
+    let mut other = Vec::new();
+    timeout(Duration::from_secs(1), async {
+        while sensors.len() < 2 || other.len() < 2 {
+            if let Some(Ok(Frame::Publish {
+                channel, payload, ..
+            })) = sub.next().await
+            {
+                if channel == "sensors" {
+                    sensors.push(payload);
+                } else {
+                    other.push(payload);
+                }
+            }
+        }
+    })
+    .await?;
+
+    assert_eq!(sensors, vec![Bytes::from("a"), Bytes::from("b")]);
+    assert_eq!(other, vec![Bytes::from("a"), Bytes::from("a")]);
+    assert_eq!(metrics.total_deduped.get(), 1);
+    assert_eq!(metrics.total_published.get(), 4);
+
+    Ok(())
+}
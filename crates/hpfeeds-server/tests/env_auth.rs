@@ -0,0 +1,84 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use std::process::{Child, Command, Stdio};
+use tokio::time::{Duration, Instant, sleep};
+
+// Kills the broker when the test ends, even on a failed assertion.
+struct Broker(Child);
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+#[tokio::test]
+async fn users_from_environment_authenticate() -> Result<(), Box<dyn std::error::Error>> {
+    let users_file = std::env::temp_dir().join(format!("hpfeeds-env-auth-{}", std::process::id()));
+    std::fs::write(&users_file, "# fleet b\nsensor-b:file-secret\n")?;
+    let port = free_port();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_hpfeeds-server"));
+    command
+        .args(["--port", &port.to_string()])
+        .env("HPFEEDS_AUTH", "sensor-a:env-secret,other:x")
+        .env("HPFEEDS_AUTH_FILE", &users_file)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    #[cfg(feature = "metrics")]
+    command.arg("--no-metrics");
+    let _broker = Broker(command.spawn()?);
+
+    let addr = format!("127.0.0.1:{}", port);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while std::net::TcpStream::connect(&addr).is_err() {
+        assert!(Instant::now() < deadline, "broker did not start");
+        sleep(Duration::from_millis(20)).await;
+    }
+
+    // a rejected client is disconnected, so a delivery shows both were accepted
+    let mut sub = connect_and_auth(&addr, "sensor-a", "env-secret").await?;
+    sub.send(Frame::Subscribe {
+        ident: Bytes::from_static(b"sensor-a"),
+        channel: Bytes::from_static(b"ch"),
+    })
+    .await?;
+    let mut publisher = connect_and_auth(&addr, "sensor-b", "file-secret").await?;
+    let delivered = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            publisher
+                .send(Frame::Publish {
+                    ident: Bytes::from_static(b"sensor-b"),
+                    channel: Bytes::from_static(b"ch"),
+                    payload: Bytes::from_static(b"hi"),
+                })
+                .await?;
+            // the subscribe may not have been handled yet
+            if let Ok(frame) = tokio::time::timeout(Duration::from_millis(100), sub.next()).await {
+                return Ok::<_, Box<dyn std::error::Error>>(frame);
+            }
+        }
+    })
+    .await??;
+    assert!(
+        matches!(&delivered, Some(Ok(Frame::Publish { ident, .. })) if ident == "sensor-b"),
+        "{:?}",
+        delivered
+    );
+
+    let mut rejected = connect_and_auth(&addr, "sensor-a", "wrong").await?;
+    let end = tokio::time::timeout(Duration::from_secs(5), rejected.next()).await?;
+    assert!(end.is_none(), "{:?}", end);
+    std::fs::remove_file(&users_file)?;
+    Ok(())
+}
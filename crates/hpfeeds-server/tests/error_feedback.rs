@@ -0,0 +1,69 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::{ClientError, Subscriber, connect_and_auth};
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn refused_subscribe_reports_error_within_window() {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        Arc::new(Metrics::new()),
+        BrokerOptions {
+            channel_name_regex: Some(regex::Regex::new("^[a-z.]+$").unwrap()),
+            ..Default::default()
+        },
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let transport = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    let mut subscriber = Subscriber::new(transport, "client1");
+
+    subscriber.subscribe("Bad Name").await.unwrap();
+    let err = subscriber.wait_for_error(Duration::from_secs(2)).await;
+    assert!(
+        matches!(&err, Some(ClientError::Broker(msg)) if msg == "invalid channel name: Bad Name"),
+        "{:?}",
+        err
+    );
+
+    subscriber.subscribe("good.channel").await.unwrap();
+    timeout(Duration::from_secs(2), async {
+        while !broker.subscribers.contains_key("good.channel") {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .expect("subscribe was not processed");
+    let mut publisher = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    publisher
+        .send(Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"good.channel"),
+            payload: Bytes::from_static(b"hello"),
+        })
+        .await
+        .unwrap();
+    assert!(
+        subscriber
+            .wait_for_error(Duration::from_millis(200))
+            .await
+            .is_none()
+    );
+
+    // a delivery that arrived during the window is still there
+    let msg = timeout(Duration::from_secs(2), subscriber.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(msg.payload, Bytes::from_static(b"hello"));
+}
@@ -0,0 +1,50 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn frames_are_counted_by_opcode() -> Result<(), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let broker = Arc::new(Broker::new(Arc::new(auth), metrics.clone()));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker, None));
+
+    let frames = |opcode: &str| metrics.frames_received.with_label_values(&[opcode]).get();
+
+    let mut client = connect_and_auth(&addr, "client1", "s3cret").await?;
+    for channel in ["a", "b"] {
+        client
+            .send(Frame::Subscribe {
+                ident: Bytes::from_static(b"client1"),
+                channel: Bytes::from_static(channel.as_bytes()),
+            })
+            .await?;
+    }
+    client
+        .send(Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"a"),
+            payload: Bytes::from_static(b"x"),
+        })
+        .await?;
+    // Seeing our own publish come back means every frame before it was handled
+    let echo = timeout(Duration::from_secs(1), client.next()).await?;
+    assert!(matches!(echo, Some(Ok(Frame::Publish { .. }))));
+
+    assert_eq!(frames("auth"), 1);
+    assert_eq!(frames("subscribe"), 2);
+    assert_eq!(frames("publish"), 1);
+    assert_eq!(frames("unsubscribe"), 0);
+
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+async fn start(options: BrokerOptions) -> (Arc<Broker>, Arc<Metrics>, String) {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        metrics.clone(),
+        options,
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+    (broker, metrics, addr)
+}
+
+fn churn(i: usize) -> Frame {
+    let channel = Bytes::from(format!("churn{}", i / 2));
+    let ident = Bytes::from_static(b"client1");
+    if i.is_multiple_of(2) {
+        Frame::Subscribe { ident, channel }
+    } else {
+        Frame::Unsubscribe { ident, channel }
+    }
+}
+
+#[tokio::test]
+async fn subscribe_storm_is_rate_limited() {
+    let (broker, metrics, addr) = start(BrokerOptions {
+        max_frame_rate: Some(50),
+        ..Default::default()
+    })
+    .await;
+    let mut client = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    let storm = 2000;
+    for i in 0..storm {
+        client.feed(churn(i)).await.unwrap();
+    }
+    client.flush().await.unwrap();
+    let received = |op| metrics.frames_received.with_label_values(&[op]).get();
+    timeout(Duration::from_secs(5), async {
+        while received("subscribe") + received("unsubscribe") < storm as u64 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .expect("the storm was not received");
+    // only about a burst's worth got through to the broker's maps
+    let limited = metrics.total_rate_limited.get();
+    assert!(limited > storm as u64 / 2, "{} dropped", limited);
+    assert!(limited < storm as u64);
+
+    // without --frame-rate-disconnect the client stays connected, and is served again once
+    // the bucket refills
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    client
+        .send(Frame::Subscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"live"),
+        })
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(2), async {
+        while !broker.subscribers.contains_key("live") {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .expect("subscribe after the storm was not processed");
+}
+
+#[tokio::test]
+async fn sustained_abuse_is_disconnected() {
+    let (_broker, metrics, addr) = start(BrokerOptions {
+        max_frame_rate: Some(10),
+        frame_rate_disconnect: Some(Duration::from_millis(500)),
+        ..Default::default()
+    })
+    .await;
+    let client = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    let (mut tx, mut rx) = client.split();
+    let spam = tokio::spawn(async move {
+        for i in 0.. {
+            if tx.send(churn(i)).await.is_err() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    });
+
+    match timeout(Duration::from_secs(5), rx.next()).await {
+        Ok(Some(Ok(Frame::Error(msg)))) => {
+            assert!(
+                msg.starts_with(b"disconnected: over the frame-rate limit"),
+                "{:?}",
+                msg
+            )
+        }
+        other => panic!("expected OP_ERROR, got {:?}", other),
+    }
+    let rest = timeout(Duration::from_secs(2), rx.next())
+        .await
+        .expect("connection was not closed");
+    assert!(rest.as_ref().is_none_or(|r| r.is_err()), "{:?}", rest);
+    assert!(metrics.total_rate_limited.get() > 0);
+    spam.abort();
+}
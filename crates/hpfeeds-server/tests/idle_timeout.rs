@@ -0,0 +1,140 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::clock::TestClock;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn idle_client_is_disconnected_without_waiting() -> Result<(), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let clock = Arc::new(TestClock::new());
+    let options = BrokerOptions {
+        idle_timeout: Some(Duration::from_secs(30)),
+        ..Default::default()
+    };
+    let broker = Arc::new(
+        Broker::with_options(Arc::new(auth), Arc::new(Metrics::new()), options)
+            .with_clock(clock.clone()),
+    );
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut client = connect_and_auth(&addr, "client1", "s3cret").await?;
+    client
+        .send(Frame::Subscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch"),
+        })
+        .await?;
+    timeout(Duration::from_secs(1), async {
+        while !broker.subscribers.contains_key("ch") {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await?;
+
+    // Activity part-way through the timeout pushes the deadline out
+    clock.advance(Duration::from_secs(20));
+    client
+        .send(Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch"),
+            payload: Bytes::from_static(b"ping"),
+        })
+        .await?;
+    let echo = timeout(Duration::from_secs(1), client.next()).await?;
+    assert!(matches!(echo, Some(Ok(Frame::Publish { .. }))));
+
+    // 40s since connecting, but only 20s since the last activity
+    clock.advance(Duration::from_secs(20));
+    client
+        .send(Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch"),
+            payload: Bytes::from_static(b"ping"),
+        })
+        .await?;
+    let echo = timeout(Duration::from_secs(1), client.next()).await?;
+    assert!(matches!(echo, Some(Ok(Frame::Publish { .. }))));
+
+    clock.advance(Duration::from_secs(31));
+    let closed = timeout(Duration::from_secs(1), client.next()).await?;
+    assert!(closed.is_none() || matches!(closed, Some(Err(_))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn receiving_deliveries_keeps_a_subscriber_connected()
+-> Result<(), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let clock = Arc::new(TestClock::new());
+    let options = BrokerOptions {
+        idle_timeout: Some(Duration::from_secs(30)),
+        ..Default::default()
+    };
+    let broker = Arc::new(
+        Broker::with_options(Arc::new(auth), Arc::new(Metrics::new()), options)
+            .with_clock(clock.clone()),
+    );
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut busy = connect_and_auth(&addr, "client1", "s3cret").await?;
+    let mut quiet = connect_and_auth(&addr, "client1", "s3cret").await?;
+    let mut publisher = connect_and_auth(&addr, "client1", "s3cret").await?;
+    // every connection is known to be authenticated before the clock moves past the pre-auth
+    // timeout
+    for (client, channel) in [
+        (&mut busy, "busy"),
+        (&mut quiet, "quiet"),
+        (&mut publisher, "publisher"),
+    ] {
+        client
+            .send(Frame::Subscribe {
+                ident: Bytes::from_static(b"client1"),
+                channel: Bytes::copy_from_slice(channel.as_bytes()),
+            })
+            .await?;
+    }
+    timeout(Duration::from_secs(1), async {
+        while ["busy", "quiet", "publisher"]
+            .iter()
+            .any(|c| !broker.subscribers.contains_key(*c))
+        {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await?;
+
+    // publishes every 20s keep the publisher, and the subscriber they reach, active
+    for _ in 0..2 {
+        clock.advance(Duration::from_secs(20));
+        publisher
+            .send(Frame::Publish {
+                ident: Bytes::from_static(b"client1"),
+                channel: Bytes::from_static(b"busy"),
+                payload: Bytes::from_static(b"tick"),
+            })
+            .await?;
+        let delivered = timeout(Duration::from_secs(1), busy.next()).await?;
+        assert!(matches!(delivered, Some(Ok(Frame::Publish { .. }))));
+    }
+
+    // 40s without a frame either way
+    let closed = timeout(Duration::from_secs(1), quiet.next()).await?;
+    assert!(closed.is_none() || matches!(closed, Some(Err(_))));
+
+    Ok(())
+}
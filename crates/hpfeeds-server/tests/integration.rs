@@ -0,0 +1,172 @@
+//! End-to-end checks against the real broker loop (`run_server`) with users and ACLs configured
+//! the way `--config` sets them up. Waits are on broker state or on frame order within a
+//! connection, never on sleeps.
+
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::{Transport, connect_and_auth};
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, run_server};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::time::{Duration, timeout};
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+// Starts a broker on an ephemeral port with:
+// - sensor: publishes to "sensors.raw" only, subscribes to nothing
+// - reader: subscribes to "sensors.raw", publishes nowhere
+// - admin: "*" for both
+async fn start() -> Result<(Arc<Broker>, Arc<Metrics>, String), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add_user("sensor", "s1", vec!["sensors.raw".into()], vec![])
+        .await;
+    auth.add_user("reader", "s2", vec![], vec!["sensors.raw".into()])
+        .await;
+    auth.add_user("admin", "s3", vec!["*".into()], vec!["*".into()])
+        .await;
+    let metrics = Arc::new(Metrics::new());
+    let broker = Arc::new(Broker::new(Arc::new(auth), metrics.clone()));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+    Ok((broker, metrics, addr))
+}
+
+async fn send(client: &mut Transport<TcpStream>, frame: Frame) -> TestResult {
+    client.send(frame).await?;
+    Ok(())
+}
+
+fn subscribe(ident: &'static str, channel: &'static str) -> Frame {
+    Frame::Subscribe {
+        ident: Bytes::from_static(ident.as_bytes()),
+        channel: Bytes::from_static(channel.as_bytes()),
+    }
+}
+
+fn publish(ident: &'static str, channel: &'static str, payload: &'static str) -> Frame {
+    Frame::Publish {
+        ident: Bytes::from_static(ident.as_bytes()),
+        channel: Bytes::from_static(channel.as_bytes()),
+        payload: Bytes::from_static(payload.as_bytes()),
+    }
+}
+
+async fn wait_for_subscriber(broker: &Broker, channel: &str) -> TestResult {
+    timeout(Duration::from_secs(2), async {
+        while !broker.subscribers.contains_key(channel) {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await?;
+    Ok(())
+}
+
+// The next publish delivered to `client`, as (ident, channel, payload).
+async fn next_publish(
+    client: &mut Transport<TcpStream>,
+) -> Result<(Bytes, Bytes, Bytes), Box<dyn std::error::Error>> {
+    loop {
+        match timeout(Duration::from_secs(2), client.next()).await? {
+            Some(Ok(Frame::Publish {
+                ident,
+                channel,
+                payload,
+            })) => return Ok((ident, channel, payload)),
+            Some(Ok(_)) => continue,
+            other => return Err(format!("connection ended: {:?}", other).into()),
+        }
+    }
+}
+
+#[tokio::test]
+async fn permitted_publish_is_delivered_and_denied_one_is_not() -> TestResult {
+    let (broker, metrics, addr) = start().await?;
+    let mut reader = connect_and_auth(&addr, "reader", "s2").await?;
+    send(&mut reader, subscribe("reader", "sensors.raw")).await?;
+    wait_for_subscriber(&broker, "sensors.raw").await?;
+
+    // the reader may not publish; wait until the broker has read its attempt
+    send(&mut reader, publish("reader", "sensors.raw", "forged")).await?;
+    let publishes = metrics.frames_received.with_label_values(&["publish"]);
+    timeout(Duration::from_secs(2), async {
+        while publishes.get() < 1 {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await?;
+    // nor may the sensor publish outside its channel; its frames are handled in order
+    let mut sensor = connect_and_auth(&addr, "sensor", "s1").await?;
+    send(&mut sensor, publish("sensor", "other", "misrouted")).await?;
+    send(&mut sensor, publish("sensor", "sensors.raw", "hello")).await?;
+
+    let (ident, channel, payload) = next_publish(&mut reader).await?;
+    assert_eq!(ident, Bytes::from_static(b"sensor"));
+    assert_eq!(channel, Bytes::from_static(b"sensors.raw"));
+    assert_eq!(payload, Bytes::from_static(b"hello"));
+    assert_eq!(metrics.total_published.get(), 1);
+    assert_eq!(metrics.total_delivered.get(), 1);
+    assert_eq!(metrics.total_auth_success.get(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn denied_subscribe_is_not_registered() -> TestResult {
+    let (broker, _metrics, addr) = start().await?;
+    let mut reader = connect_and_auth(&addr, "reader", "s2").await?;
+    send(&mut reader, subscribe("reader", "sensors.raw")).await?;
+    wait_for_subscriber(&broker, "sensors.raw").await?;
+
+    // the sensor may only publish; once its publish arrives, its subscribe has been handled
+    let mut sensor = connect_and_auth(&addr, "sensor", "s1").await?;
+    send(&mut sensor, subscribe("sensor", "sensors.raw")).await?;
+    send(&mut sensor, publish("sensor", "sensors.raw", "x")).await?;
+    let (_, _, payload) = next_publish(&mut reader).await?;
+    assert_eq!(payload, Bytes::from_static(b"x"));
+
+    let receivers = broker
+        .subscribers
+        .get("sensors.raw")
+        .map(|tx| tx.receiver_count());
+    assert_eq!(receivers, Some(1));
+    Ok(())
+}
+
+#[tokio::test]
+async fn wildcard_acl_covers_every_channel() -> TestResult {
+    let (broker, metrics, addr) = start().await?;
+    let mut admin = connect_and_auth(&addr, "admin", "s3").await?;
+    for channel in ["alpha", "beta.gamma"] {
+        send(&mut admin, subscribe("admin", channel)).await?;
+    }
+    wait_for_subscriber(&broker, "beta.gamma").await?;
+    send(&mut admin, publish("admin", "alpha", "1")).await?;
+    send(&mut admin, publish("admin", "beta.gamma", "2")).await?;
+
+    let mut got = [
+        next_publish(&mut admin).await?,
+        next_publish(&mut admin).await?,
+    ];
+    got.sort();
+    let channels: Vec<&[u8]> = got.iter().map(|(_, c, _)| &c[..]).collect();
+    assert_eq!(channels, [&b"alpha"[..], b"beta.gamma"]);
+    assert_eq!(metrics.total_published.get(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn wrong_secret_is_refused_and_counted() -> TestResult {
+    let (_broker, metrics, addr) = start().await?;
+    let mut client = connect_and_auth(&addr, "sensor", "wrong").await?;
+    assert!(
+        timeout(Duration::from_secs(2), client.next())
+            .await?
+            .is_none()
+    );
+    assert_eq!(metrics.total_auth_fail.get(), 1);
+    assert_eq!(metrics.total_auth_success.get(), 0);
+    Ok(())
+}
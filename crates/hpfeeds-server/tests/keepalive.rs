@@ -0,0 +1,98 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{BROKER_NAME, Broker, BrokerOptions, run_keepalive, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, Instant, timeout};
+
+async fn start() -> (Arc<Broker>, String) {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        Arc::new(Metrics::new()),
+        BrokerOptions {
+            keepalive_channel: Some("hpfeeds.keepalive".into()),
+            keepalive_interval: Duration::from_millis(100),
+            ..Default::default()
+        },
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+    tokio::spawn(run_keepalive(broker.clone()));
+    (broker, addr)
+}
+
+#[tokio::test]
+async fn heartbeats_arrive_at_the_configured_interval() {
+    let (broker, addr) = start().await;
+    let mut client = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    client
+        .send(Frame::Subscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"hpfeeds.keepalive"),
+        })
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(2), async {
+        while !broker.subscribers.contains_key("hpfeeds.keepalive") {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .expect("subscribe was not processed");
+
+    let mut arrivals = Vec::new();
+    while arrivals.len() < 4 {
+        match timeout(Duration::from_secs(2), client.next()).await {
+            Ok(Some(Ok(Frame::Publish {
+                ident,
+                channel,
+                payload,
+            }))) => {
+                assert_eq!(ident, BROKER_NAME);
+                assert_eq!(channel, "hpfeeds.keepalive");
+                let body: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+                assert!(body["ts_ms"].as_u64().unwrap() > 0);
+                arrivals.push(Instant::now());
+            }
+            other => panic!("expected a heartbeat, got {:?}", other),
+        }
+    }
+    for pair in arrivals.windows(2) {
+        let gap = pair[1] - pair[0];
+        assert!(
+            gap >= Duration::from_millis(50) && gap <= Duration::from_millis(500),
+            "heartbeats {:?} apart",
+            gap
+        );
+    }
+}
+
+#[tokio::test]
+async fn clients_cannot_publish_on_the_keepalive_channel() {
+    let (_broker, addr) = start().await;
+    let mut client = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    client
+        .send(Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"hpfeeds.keepalive"),
+            payload: Bytes::from_static(b"{}"),
+        })
+        .await
+        .unwrap();
+    match timeout(Duration::from_secs(2), client.next()).await {
+        Ok(Some(Ok(Frame::Error(msg)))) => {
+            assert!(
+                msg.ends_with(b"reserved for broker keepalives"),
+                "{:?}",
+                msg
+            )
+        }
+        other => panic!("expected OP_ERROR, got {:?}", other),
+    }
+}
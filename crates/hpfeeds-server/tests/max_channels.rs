@@ -0,0 +1,98 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::{Transport, connect_and_auth};
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::time::{Duration, timeout};
+
+async fn send(client: &mut Transport<TcpStream>, frame: Frame) {
+    client.send(frame).await.unwrap();
+}
+
+fn subscribe(channel: &'static str) -> Frame {
+    Frame::Subscribe {
+        ident: Bytes::from_static(b"client1"),
+        channel: Bytes::from_static(channel.as_bytes()),
+    }
+}
+
+async fn wait_for_channels(broker: &Broker, n: usize) {
+    timeout(Duration::from_secs(2), async {
+        while broker.subscribers.len() != n {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .expect("subscriptions were not processed");
+}
+
+#[tokio::test]
+async fn subscribe_past_the_channel_cap_is_rejected() {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        Arc::new(Metrics::new()),
+        BrokerOptions {
+            max_channels: Some(2),
+            ..Default::default()
+        },
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut client = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    send(&mut client, subscribe("a")).await;
+    send(&mut client, subscribe("b")).await;
+    wait_for_channels(&broker, 2).await;
+
+    send(&mut client, subscribe("c")).await;
+    let reply = timeout(Duration::from_secs(2), client.next())
+        .await
+        .unwrap();
+    assert!(
+        matches!(&reply, Some(Ok(Frame::Error(msg)))
+            if msg == "channel limit reached: c would exceed 2 live channels"),
+        "{:?}",
+        reply
+    );
+    assert!(!broker.subscribers.contains_key("c"));
+
+    // the existing channels keep working
+    send(
+        &mut client,
+        Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"a"),
+            payload: Bytes::from_static(b"still here"),
+        },
+    )
+    .await;
+    let delivered = timeout(Duration::from_secs(2), client.next())
+        .await
+        .unwrap();
+    assert!(
+        matches!(&delivered, Some(Ok(Frame::Publish { payload, .. })) if payload == "still here"),
+        "{:?}",
+        delivered
+    );
+
+    // a channel whose last subscriber leaves no longer counts
+    send(
+        &mut client,
+        Frame::Unsubscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"b"),
+        },
+    )
+    .await;
+    wait_for_channels(&broker, 1).await;
+    send(&mut client, subscribe("c")).await;
+    wait_for_channels(&broker, 2).await;
+    assert!(broker.subscribers.contains_key("c"));
+}
@@ -0,0 +1,165 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn subscriber_is_disconnected_after_too_many_lag_drops() {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        metrics.clone(),
+        BrokerOptions {
+            channel_capacity: 4,
+            max_lag_drops: Some(10),
+            ..Default::default()
+        },
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut slow = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    slow.send(Frame::Subscribe {
+        ident: Bytes::from_static(b"client1"),
+        channel: Bytes::from_static(b"bulk"),
+    })
+    .await
+    .unwrap();
+    timeout(Duration::from_secs(2), async {
+        while broker
+            .subscribers
+            .get("bulk")
+            .map_or(0, |tx| tx.receiver_count())
+            == 0
+        {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .expect("subscribe was not processed");
+
+    // The unread subscriber's write blocks, so the tiny channel overflows over and over
+    let mut publisher = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    let bulk = 400;
+    let payload = Bytes::from(vec![0u8; 64 * 1024]);
+    for _ in 0..bulk {
+        publisher
+            .send(Frame::Publish {
+                ident: Bytes::from_static(b"client1"),
+                channel: Bytes::from_static(b"bulk"),
+                payload: payload.clone(),
+            })
+            .await
+            .unwrap();
+    }
+    timeout(Duration::from_secs(5), async {
+        while metrics.total_published.get() < bulk {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .expect("publishes were not counted");
+
+    let error = timeout(Duration::from_secs(10), async {
+        loop {
+            match slow.next().await {
+                Some(Ok(Frame::Publish { .. })) => {}
+                Some(Ok(Frame::Error(message))) => return message,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    })
+    .await
+    .expect("no OP_ERROR before the deadline");
+
+    let message = String::from_utf8_lossy(&error);
+    assert!(message.contains("fell behind"), "{}", message);
+    assert!(message.contains("limit 10"), "{}", message);
+    assert!(metrics.total_lagged.get() > 10);
+    // and then the connection is closed
+    let rest = timeout(Duration::from_secs(2), slow.next())
+        .await
+        .expect("connection was not closed");
+    assert!(rest.as_ref().is_none_or(|r| r.is_err()), "{:?}", rest);
+}
+
+#[tokio::test]
+async fn lag_is_tolerated_without_a_limit() {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        metrics.clone(),
+        BrokerOptions {
+            channel_capacity: 4,
+            ..Default::default()
+        },
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut slow = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    slow.send(Frame::Subscribe {
+        ident: Bytes::from_static(b"client1"),
+        channel: Bytes::from_static(b"bulk"),
+    })
+    .await
+    .unwrap();
+    timeout(Duration::from_secs(2), async {
+        while broker
+            .subscribers
+            .get("bulk")
+            .map_or(0, |tx| tx.receiver_count())
+            == 0
+        {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .expect("subscribe was not processed");
+
+    let mut publisher = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    let payload = Bytes::from(vec![0u8; 64 * 1024]);
+    for _ in 0..400 {
+        publisher
+            .send(Frame::Publish {
+                ident: Bytes::from_static(b"client1"),
+                channel: Bytes::from_static(b"bulk"),
+                payload: payload.clone(),
+            })
+            .await
+            .unwrap();
+    }
+    // a marker published last still arrives, after whatever survived the lag
+    publisher
+        .send(Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"bulk"),
+            payload: Bytes::from_static(b"last"),
+        })
+        .await
+        .unwrap();
+
+    timeout(Duration::from_secs(10), async {
+        loop {
+            match slow.next().await {
+                Some(Ok(Frame::Publish { payload, .. })) if payload == "last" => break,
+                Some(Ok(Frame::Publish { .. })) => {}
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    })
+    .await
+    .expect("marker was not delivered");
+    assert!(metrics.total_lagged.get() > 0);
+}
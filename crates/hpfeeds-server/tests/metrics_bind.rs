@@ -0,0 +1,30 @@
+#![cfg(feature = "metrics")]
+
+use hpfeeds_server::listen::ListenOptions;
+use hpfeeds_server::metrics::bind_metrics;
+use std::time::Instant;
+use tokio::net::TcpListener;
+use tokio::time::Duration;
+
+#[tokio::test]
+async fn taken_metrics_port_is_a_clear_error() -> Result<(), Box<dyn std::error::Error>> {
+    let taken = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = taken.local_addr()?;
+
+    let start = Instant::now();
+    let err = bind_metrics(addr, ListenOptions::default(), 2, Duration::from_millis(20))
+        .await
+        .expect_err("port is in use");
+    assert!(start.elapsed() >= Duration::from_millis(40));
+    let msg = format!("{:#}", err);
+    assert!(
+        msg.contains(&format!("binding metrics listener on {}", addr)),
+        "{}",
+        msg
+    );
+
+    // once the port frees up a retry succeeds
+    drop(taken);
+    bind_metrics(addr, ListenOptions::default(), 2, Duration::from_millis(20)).await?;
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use bytes::Bytes;
+use futures::SinkExt;
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::checkpoint;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+// Starts a broker counting into `metrics`, publishes `n` messages and waits for them to be
+// counted.
+async fn publish_n(metrics: &Arc<Metrics>, n: u64) {
+    let auth = MemoryAuthenticator::new();
+    auth.add("sensor", "s").await;
+    let broker = Arc::new(Broker::new(Arc::new(auth), metrics.clone()));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker, None));
+
+    let start = metrics.total_published.get();
+    let mut sensor = connect_and_auth(&addr, "sensor", "s").await.unwrap();
+    for _ in 0..n {
+        sensor
+            .send(Frame::Publish {
+                ident: Bytes::from_static(b"sensor"),
+                channel: Bytes::from_static(b"ch"),
+                payload: Bytes::from_static(b"x"),
+            })
+            .await
+            .unwrap();
+    }
+    timeout(Duration::from_secs(2), async {
+        while metrics.total_published.get() < start + n {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .expect("publishes were not counted");
+}
+
+#[tokio::test]
+async fn published_counter_resumes_from_checkpoint() {
+    let path = std::env::temp_dir().join(format!("hpfeeds-checkpoint-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let before = Arc::new(Metrics::new());
+    // no checkpoint yet is a fresh start
+    checkpoint::restore(&before, &path).unwrap();
+    publish_n(&before, 3).await;
+    checkpoint::save(&before, &path).unwrap();
+
+    // "restart" with fresh counters
+    let after = Arc::new(Metrics::new());
+    checkpoint::restore(&after, &path).unwrap();
+    assert_eq!(after.total_published.get(), 3);
+    assert_eq!(after.total_auth_success.get(), 1);
+    publish_n(&after, 2).await;
+    assert_eq!(after.total_published.get(), 5);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn checkpointed_names_are_exported() {
+    let metrics = Metrics::new();
+    let exported: Vec<String> = metrics
+        .registry
+        .gather()
+        .iter()
+        .map(|f| f.name().to_string())
+        .collect();
+    for (name, _) in metrics.counters() {
+        assert!(exported.iter().any(|e| e == name), "{} not exported", name);
+    }
+    // a checkpoint is keyed by name, so two counters sharing one would restore into each other
+    let names: std::collections::HashSet<_> = metrics
+        .counters()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(names.len(), metrics.counters().len());
+}
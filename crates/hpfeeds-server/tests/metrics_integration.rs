@@ -1,3 +1,5 @@
+#![cfg(feature = "metrics")]
+
 use std::process::{Command, Stdio};
 use std::thread;
 use std::time::Duration;
@@ -0,0 +1,58 @@
+#![cfg(feature = "metrics")]
+
+use hpfeeds_server::metrics::{Metrics, serve_metrics};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{Duration, timeout};
+
+async fn wait_closed(stream: &mut TcpStream) {
+    let mut buf = [0u8; 1024];
+    loop {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) => continue,
+        }
+    }
+}
+
+#[tokio::test]
+async fn metrics_connections_are_bounded_and_timed_out() -> Result<(), Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let metrics = Metrics::new();
+    tokio::spawn(serve_metrics(
+        listener,
+        metrics.registry.clone(),
+        None,
+        2,
+        Duration::from_millis(300),
+    ));
+
+    // Two idle connections occupy every slot.
+    let mut idle1 = TcpStream::connect(addr).await?;
+    let mut idle2 = TcpStream::connect(addr).await?;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // Anything beyond the limit is closed straight away.
+    let mut excess = TcpStream::connect(addr).await?;
+    timeout(Duration::from_millis(200), wait_closed(&mut excess))
+        .await
+        .expect("connection over the limit should be closed");
+
+    // Idle connections are dropped once the timeout elapses.
+    timeout(Duration::from_secs(2), wait_closed(&mut idle1)).await?;
+    timeout(Duration::from_secs(2), wait_closed(&mut idle2)).await?;
+
+    // With the slots released, the endpoint serves again.
+    let mut client = TcpStream::connect(addr).await?;
+    client
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await?;
+    let mut body = Vec::new();
+    timeout(Duration::from_secs(2), client.read_to_end(&mut body)).await??;
+    let body = String::from_utf8_lossy(&body);
+    assert!(body.starts_with("HTTP/1.1 200"));
+    assert!(body.contains("hpfeeds_published_total"));
+
+    Ok(())
+}
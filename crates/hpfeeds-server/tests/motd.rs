@@ -0,0 +1,82 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::{Frame, MOTD_CHANNEL};
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{BROKER_NAME, Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+const MOTD: &str = "Authorised sensors only. Contact soc@example.org";
+
+#[tokio::test]
+async fn clients_receive_the_motd_and_carry_on() {
+    let auth = MemoryAuthenticator::new();
+    auth.add("sensor1", "s3cret").await;
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        Arc::new(Metrics::new()),
+        BrokerOptions {
+            motd: Some(MOTD.into()),
+            ..Default::default()
+        },
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut client = connect_and_auth(&addr, "sensor1", "s3cret").await.unwrap();
+    let first = timeout(Duration::from_secs(2), client.next())
+        .await
+        .unwrap();
+    assert_eq!(
+        first.unwrap().unwrap(),
+        Frame::Publish {
+            ident: Bytes::from_static(BROKER_NAME.as_bytes()),
+            channel: Bytes::from_static(MOTD_CHANNEL.as_bytes()),
+            payload: Bytes::from_static(MOTD.as_bytes()),
+        }
+    );
+
+    // the session goes on as usual afterwards
+    client
+        .send(Frame::Subscribe {
+            ident: Bytes::from_static(b"sensor1"),
+            channel: Bytes::from_static(b"ch"),
+        })
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(2), async {
+        while !broker.subscribers.contains_key("ch") {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .unwrap();
+    for channel in [MOTD_CHANNEL, "ch"] {
+        client
+            .send(Frame::Publish {
+                ident: Bytes::from_static(b"sensor1"),
+                channel: Bytes::copy_from_slice(channel.as_bytes()),
+                payload: Bytes::from_static(b"hello"),
+            })
+            .await
+            .unwrap();
+    }
+    let reserved = timeout(Duration::from_secs(2), client.next())
+        .await
+        .unwrap();
+    assert_eq!(
+        reserved.unwrap().unwrap(),
+        Frame::Error("channel __motd__ is reserved for the message of the day".into())
+    );
+    let delivered = timeout(Duration::from_secs(2), client.next())
+        .await
+        .unwrap();
+    assert!(
+        matches!(&delivered, Some(Ok(Frame::Publish { channel, .. })) if channel == "ch"),
+        "{:?}",
+        delivered
+    );
+}
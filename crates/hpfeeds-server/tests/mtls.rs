@@ -0,0 +1,166 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::{Transport, auth_stream};
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::config::TlsConfig;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, run_server};
+use hpfeeds_server::tls;
+use rcgen::{
+    BasicConstraints, CertificateParams, CertifiedIssuer, DnType, IsCa, KeyPair,
+    generate_simple_self_signed,
+};
+use rustls::ClientConfig;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::time::{Duration, timeout};
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+fn ca(name: &str) -> CertifiedIssuer<'static, KeyPair> {
+    let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name.push(DnType::CommonName, name);
+    CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap()
+}
+
+// A client certificate for `cn` signed by `ca`, with its key.
+fn client_cert(
+    cn: &str,
+    ca: &CertifiedIssuer<'static, KeyPair>,
+) -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+    let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.distinguished_name.push(DnType::CommonName, cn);
+    let key = KeyPair::generate().unwrap();
+    let cert = params.signed_by(&key, ca).unwrap();
+    (
+        cert.der().clone(),
+        PrivateKeyDer::try_from(key.serialize_der()).unwrap(),
+    )
+}
+
+// A broker requiring client certificates signed by `client_ca`, where sensor1 may use "ch".
+// Returns its address and the server certificate to trust.
+async fn mtls_broker(
+    dir: &str,
+    client_ca: &CertifiedIssuer<'static, KeyPair>,
+) -> (String, CertificateDer<'static>, Arc<Broker>) {
+    let server = generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    // TLS paths must be relative, so work under the crate directory
+    std::fs::create_dir_all(dir).unwrap();
+    std::fs::write(format!("{}/cert.pem", dir), server.cert.pem()).unwrap();
+    std::fs::write(
+        format!("{}/key.pem", dir),
+        server.signing_key.serialize_pem(),
+    )
+    .unwrap();
+    std::fs::write(format!("{}/ca.pem", dir), client_ca.pem()).unwrap();
+    let acceptor = tls::acceptor(&TlsConfig {
+        cert: format!("{}/cert.pem", dir),
+        key: format!("{}/key.pem", dir),
+        client_ca: Some(format!("{}/ca.pem", dir)),
+        min_version: None,
+    })
+    .unwrap();
+
+    let auth = MemoryAuthenticator::new();
+    auth.add_user(
+        "sensor1",
+        "never-sent",
+        vec!["ch".into()],
+        vec!["ch".into()],
+    )
+    .await;
+    let broker = Arc::new(Broker::new(Arc::new(auth), Arc::new(Metrics::new())));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(
+        listener,
+        broker.clone(),
+        Some(Arc::new(acceptor)),
+    ));
+    (addr, server.cert.der().clone(), broker)
+}
+
+// Connects presenting `cert`, then authenticates with an ident and secret the broker does not
+// know.
+async fn connect_with_cert(
+    addr: &str,
+    server_cert: &CertificateDer<'static>,
+    (cert, key): (CertificateDer<'static>, PrivateKeyDer<'static>),
+) -> Result<Transport<TlsStream<TcpStream>>, Box<dyn std::error::Error>> {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(server_cert.clone())?;
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_client_auth_cert(vec![cert], key)?;
+    let socket = TcpStream::connect(addr).await?;
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost")?, socket)
+        .await?;
+    Ok(auth_stream(stream, "anyone", "wrong-secret").await?)
+}
+
+#[tokio::test]
+async fn client_certificate_names_the_ident() -> TestResult {
+    let dir = format!("mtls-test-{}", std::process::id());
+    let client_ca = ca("sensor CA");
+    let (addr, server_cert, broker) = mtls_broker(&dir, &client_ca).await;
+
+    let mut client =
+        connect_with_cert(&addr, &server_cert, client_cert("sensor1", &client_ca)).await?;
+    client
+        .send(Frame::Subscribe {
+            ident: Bytes::from_static(b"anyone"),
+            channel: Bytes::from_static(b"ch"),
+        })
+        .await?;
+    timeout(Duration::from_secs(2), async {
+        while !broker.subscribers.contains_key("ch") {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await?;
+    client
+        .send(Frame::Publish {
+            ident: Bytes::from_static(b"anyone"),
+            channel: Bytes::from_static(b"ch"),
+            payload: Bytes::from_static(b"hello"),
+        })
+        .await?;
+
+    // sensor1's permissions apply, and deliveries carry the certificate's name
+    let delivered = timeout(Duration::from_secs(2), client.next()).await?;
+    assert!(
+        matches!(&delivered, Some(Ok(Frame::Publish { ident, payload, .. }))
+            if ident == "sensor1" && payload == "hello"),
+        "{:?}",
+        delivered
+    );
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn certificate_from_another_ca_is_rejected() -> TestResult {
+    let dir = format!("mtls-untrusted-test-{}", std::process::id());
+    let client_ca = ca("sensor CA");
+    let (addr, server_cert, _broker) = mtls_broker(&dir, &client_ca).await;
+
+    let rogue = ca("rogue CA");
+    // under TLS 1.3 the client may only learn of the rejection on its first read
+    match connect_with_cert(&addr, &server_cert, client_cert("sensor1", &rogue)).await {
+        Err(_) => {}
+        Ok(mut client) => {
+            let end = timeout(Duration::from_secs(2), client.next()).await?;
+            assert!(matches!(end, None | Some(Err(_))), "{:?}", end);
+        }
+    }
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
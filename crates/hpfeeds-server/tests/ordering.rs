@@ -0,0 +1,78 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn channel_order_survives_a_busy_neighbour() -> Result<(), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let broker = Arc::new(Broker::new(Arc::new(auth), Arc::new(Metrics::new())));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut sub = connect_and_auth(&addr, "client1", "s3cret").await?;
+    for channel in ["ordered", "busy"] {
+        sub.send(Frame::Subscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(channel.as_bytes()),
+        })
+        .await?;
+    }
+    timeout(Duration::from_secs(1), async {
+        while !(broker.subscribers.contains_key("ordered")
+            && broker.subscribers.contains_key("busy"))
+        {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await?;
+
+    let count = 2000u32;
+    let publish = |addr: String, channel: &'static [u8]| async move {
+        let mut pubc = connect_and_auth(&addr, "client1", "s3cret").await?;
+        for i in 0..count {
+            pubc.send(Frame::Publish {
+                ident: Bytes::from_static(b"client1"),
+                channel: Bytes::from_static(channel),
+                payload: Bytes::copy_from_slice(&i.to_be_bytes()),
+            })
+            .await?;
+        }
+        anyhow::Ok(())
+    };
+    let ordered = tokio::spawn(publish(addr.clone(), b"ordered"));
+    let busy = tokio::spawn(publish(addr.clone(), b"busy"));
+
+    let (mut next_ordered, mut next_busy) = (0u32, 0u32);
+    timeout(Duration::from_secs(10), async {
+        while next_ordered < count || next_busy < count {
+            let Some(Ok(Frame::Publish {
+                channel, payload, ..
+            })) = sub.next().await
+            else {
+                continue;
+            };
+            let seq = u32::from_be_bytes(payload[..].try_into().unwrap());
+            let next = if channel == "ordered" {
+                &mut next_ordered
+            } else {
+                &mut next_busy
+            };
+            assert_eq!(seq, *next, "out of order on {:?}", channel);
+            *next += 1;
+        }
+    })
+    .await?;
+    ordered.await??;
+    busy.await??;
+
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::{Subscriber, Transport, connect_and_auth, connect_and_auth_selecting};
+use hpfeeds_core::{CAP_PAUSE, Capabilities, Frame};
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::time::{Duration, timeout};
+
+async fn wait_for_subscriber(broker: &Broker, channel: &str) {
+    timeout(Duration::from_secs(2), async {
+        while broker
+            .subscribers
+            .get(channel)
+            .map_or(0, |tx| tx.receiver_count())
+            == 0
+        {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .expect("subscribe was not processed");
+}
+
+async fn publish(sensor: &mut Transport<TcpStream>, channel: &'static str, payload: &'static str) {
+    sensor
+        .send(Frame::Publish {
+            ident: Bytes::from_static(b"sensor"),
+            channel: Bytes::from_static(channel.as_bytes()),
+            payload: Bytes::from_static(payload.as_bytes()),
+        })
+        .await
+        .unwrap();
+}
+
+async fn next_payload(subscriber: &mut Subscriber<TcpStream>) -> Bytes {
+    timeout(Duration::from_secs(2), subscriber.next())
+        .await
+        .expect("nothing delivered")
+        .expect("connection open")
+        .unwrap()
+        .payload
+}
+
+#[tokio::test]
+async fn paused_channel_holds_deliveries_until_resumed() {
+    let auth = MemoryAuthenticator::new();
+    auth.add("reader", "s").await;
+    auth.add("sensor", "s").await;
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        Arc::new(Metrics::new()),
+        BrokerOptions {
+            preauth_frames: 1,
+            ..Default::default()
+        },
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let (transport, agreed) =
+        connect_and_auth_selecting(&addr, "reader", "s", &Capabilities::new([CAP_PAUSE]))
+            .await
+            .unwrap();
+    assert!(agreed.contains(CAP_PAUSE));
+    let mut subscriber = Subscriber::new(transport, "reader");
+    subscriber.subscribe("ch").await.unwrap();
+    wait_for_subscriber(&broker, "ch").await;
+    let mut sensor = connect_and_auth(&addr, "sensor", "s").await.unwrap();
+
+    publish(&mut sensor, "ch", "before").await;
+    assert_eq!(next_payload(&mut subscriber).await, "before");
+
+    // frames on one connection are handled in order, so once "marker" is subscribed the pause
+    // has taken effect
+    subscriber.pause("ch").await.unwrap();
+    subscriber.subscribe("marker").await.unwrap();
+    wait_for_subscriber(&broker, "marker").await;
+
+    publish(&mut sensor, "ch", "held1").await;
+    publish(&mut sensor, "ch", "held2").await;
+    publish(&mut sensor, "marker", "m").await;
+    // the marker overtakes the held messages; the subscription itself is still in place
+    assert_eq!(next_payload(&mut subscriber).await, "m");
+    assert_eq!(broker.subscribers.get("ch").unwrap().receiver_count(), 1);
+
+    subscriber.resume("ch").await.unwrap();
+    assert_eq!(next_payload(&mut subscriber).await, "held1");
+    assert_eq!(next_payload(&mut subscriber).await, "held2");
+    publish(&mut sensor, "ch", "after").await;
+    assert_eq!(next_payload(&mut subscriber).await, "after");
+}
@@ -0,0 +1,121 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth_selecting;
+use hpfeeds_core::{CAP_SELECT, Capabilities, Frame, HpfeedsCodec, hashsecret};
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::time::{Duration, timeout};
+use tokio_util::codec::Framed;
+
+async fn start(options: BrokerOptions) -> Result<String, Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        Arc::new(Metrics::new()),
+        options,
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker, None));
+    Ok(addr)
+}
+
+// Sends `selections` capability frames then OP_AUTH, and reports whether the broker then serves
+// a publish back to us.
+async fn auth_after_selections(
+    addr: &str,
+    selections: usize,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut conn = Framed::new(TcpStream::connect(addr).await?, HpfeedsCodec::new());
+    let Some(Ok(Frame::Info { rand, .. })) = conn.next().await else {
+        panic!("expected OP_INFO");
+    };
+    // Once the broker gives up on us, writes may fail with a reset
+    let sent: Result<(), std::io::Error> = async {
+        for _ in 0..selections {
+            conn.send(Frame::Info {
+                name: Bytes::from_static(b"test caps=select"),
+                rand: Bytes::new(),
+            })
+            .await?;
+        }
+        conn.send(Frame::Auth {
+            ident: Bytes::from_static(b"client1"),
+            secret_hash: hashsecret(&rand, "s3cret").into(),
+        })
+        .await?;
+        conn.send(Frame::Subscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch"),
+        })
+        .await?;
+        conn.send(Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch"),
+            payload: Bytes::from_static(b"x"),
+        })
+        .await?;
+        Ok(())
+    }
+    .await;
+    if sent.is_err() {
+        return Ok(false);
+    }
+    let got = timeout(Duration::from_secs(1), conn.next()).await?;
+    Ok(matches!(got, Some(Ok(Frame::Publish { .. }))))
+}
+
+#[tokio::test]
+async fn capability_frame_before_auth() -> Result<(), Box<dyn std::error::Error>> {
+    let addr = start(BrokerOptions {
+        preauth_frames: 2,
+        ..Default::default()
+    })
+    .await?;
+
+    assert!(auth_after_selections(&addr, 1).await?);
+    assert!(auth_after_selections(&addr, 2).await?);
+    assert!(!auth_after_selections(&addr, 3).await?);
+
+    let wanted = Capabilities::new([CAP_SELECT, "unknown"]);
+    let (mut client, agreed) =
+        connect_and_auth_selecting(&addr, "client1", "s3cret", &wanted).await?;
+    assert_eq!(agreed, Capabilities::new([CAP_SELECT]));
+    client
+        .send(Frame::Subscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch"),
+        })
+        .await?;
+    client
+        .send(Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch"),
+            payload: Bytes::from_static(b"x"),
+        })
+        .await?;
+    let got = timeout(Duration::from_secs(1), client.next()).await?;
+    assert!(matches!(got, Some(Ok(Frame::Publish { .. }))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn strict_by_default() -> Result<(), Box<dyn std::error::Error>> {
+    let addr = start(BrokerOptions::default()).await?;
+
+    assert!(auth_after_selections(&addr, 0).await?);
+    assert!(!auth_after_selections(&addr, 1).await?);
+
+    // Nothing to select, so the helper goes straight to OP_AUTH
+    let (_, agreed) =
+        connect_and_auth_selecting(&addr, "client1", "s3cret", &Capabilities::new([CAP_SELECT]))
+            .await?;
+    assert!(agreed.is_empty());
+
+    Ok(())
+}
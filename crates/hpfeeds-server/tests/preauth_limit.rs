@@ -0,0 +1,161 @@
+use futures::StreamExt;
+use hpfeeds_client::{connect_and_auth, connect_tls_and_auth};
+use hpfeeds_core::{Frame, HpfeedsCodec};
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time::{Duration, timeout};
+use tokio_util::codec::Framed;
+
+// Connects without authenticating. Returns the connection if the broker sent OP_INFO, or None
+// if it closed the socket without a word.
+async fn stall(addr: &str) -> Option<Framed<TcpStream, HpfeedsCodec>> {
+    let mut conn = Framed::new(TcpStream::connect(addr).await.unwrap(), HpfeedsCodec::new());
+    match timeout(Duration::from_secs(1), conn.next())
+        .await
+        .expect("broker neither greeted nor closed the connection")
+    {
+        Some(Ok(Frame::Info { .. })) => Some(conn),
+        Some(Ok(other)) => panic!("unexpected frame {:?}", other),
+        Some(Err(_)) | None => None,
+    }
+}
+
+#[tokio::test]
+async fn excess_unauthenticated_connections_are_closed() {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        metrics.clone(),
+        BrokerOptions {
+            max_unauthenticated: Some(3),
+            ..Default::default()
+        },
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker, None));
+
+    let mut stalled = Vec::new();
+    for _ in 0..3 {
+        stalled.push(stall(&addr).await.expect("within the limit"));
+    }
+    for _ in 0..5 {
+        assert!(stall(&addr).await.is_none());
+    }
+    assert_eq!(metrics.total_preauth_rejected.get(), 5);
+
+    // hanging up frees a slot
+    drop(stalled.pop());
+    let mut admitted = None;
+    for _ in 0..50 {
+        admitted = stall(&addr).await;
+        if admitted.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    stalled.push(admitted.expect("slot was not released"));
+
+    // so does authenticating, which leaves the session itself unlimited
+    drop(stalled.pop());
+    let mut session = None;
+    for _ in 0..50 {
+        if let Ok(s) = connect_and_auth(&addr, "client1", "s3cret").await {
+            session = Some(s);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let _session = session.expect("could not authenticate");
+    for _ in 0..50 {
+        if let Some(conn) = stall(&addr).await {
+            stalled.push(conn);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(stalled.len(), 3);
+}
+
+#[tokio::test]
+async fn stalled_connections_give_their_slots_back_after_the_timeout() {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        Arc::new(Metrics::new()),
+        BrokerOptions {
+            max_unauthenticated: Some(2),
+            preauth_timeout: Duration::from_millis(200),
+            ..Default::default()
+        },
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker, None));
+
+    // never sending OP_AUTH, and never hanging up either
+    let mut stalled = Vec::new();
+    for _ in 0..2 {
+        stalled.push(stall(&addr).await.expect("within the limit"));
+    }
+    assert!(stall(&addr).await.is_none());
+
+    for conn in &mut stalled {
+        let closed = timeout(Duration::from_secs(2), conn.next()).await;
+        assert!(
+            matches!(closed, Ok(None | Some(Err(_)))),
+            "stalled connection was not closed"
+        );
+    }
+    connect_and_auth(&addr, "client1", "s3cret")
+        .await
+        .expect("slots were not given back");
+}
+
+#[tokio::test]
+async fn stalled_tls_handshakes_give_their_slots_back_after_the_timeout() {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![cert.cert.der().clone()],
+            rustls::pki_types::PrivateKeyDer::try_from(cert.signing_key.serialize_der()).unwrap(),
+        )
+        .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        Arc::new(Metrics::new()),
+        BrokerOptions {
+            max_unauthenticated: Some(1),
+            preauth_timeout: Duration::from_millis(200),
+            ..Default::default()
+        },
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker, Some(Arc::new(acceptor))));
+
+    // connects but never starts the handshake
+    let mut stalled = TcpStream::connect(&addr).await.unwrap();
+    let mut buf = [0u8; 1];
+    let closed = timeout(Duration::from_secs(2), stalled.read(&mut buf)).await;
+    assert!(
+        matches!(closed, Ok(Ok(0) | Err(_))),
+        "stalled handshake was not closed"
+    );
+
+    connect_tls_and_auth(&addr, "client1", "s3cret", cert.cert.der())
+        .await
+        .expect("slot was not given back");
+}
@@ -0,0 +1,105 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::{Transport, connect_and_auth};
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::clock::TestClock;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::time::{Duration, timeout};
+
+fn publish(i: usize) -> Frame {
+    Frame::Publish {
+        ident: Bytes::from_static(b"client1"),
+        channel: Bytes::from_static(b"ch"),
+        payload: Bytes::from(i.to_string()),
+    }
+}
+
+async fn publish_burst(client: &mut Transport<TcpStream>, n: usize) {
+    for i in 0..n {
+        client.feed(publish(i)).await.unwrap();
+    }
+    client.flush().await.unwrap();
+}
+
+// Waits until the broker has handled `n` publishes in all, accepted or dropped.
+async fn wait_for_publishes(metrics: &Metrics, n: u64) {
+    timeout(Duration::from_secs(5), async {
+        while metrics.total_published.get() + metrics.total_pub_rate_limited.get() < n {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .expect("the publishes were not handled");
+}
+
+// Publishes delivered to `subscriber` until nothing more arrives for a while.
+async fn delivered(subscriber: &mut Transport<TcpStream>) -> usize {
+    let mut count = 0;
+    while let Ok(Some(frame)) = timeout(Duration::from_millis(200), subscriber.next()).await {
+        assert!(matches!(frame, Ok(Frame::Publish { .. })), "{:?}", frame);
+        count += 1;
+    }
+    count
+}
+
+#[tokio::test]
+async fn publishes_over_the_rate_are_dropped() {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    // the clock stands still, so each connection has just its initial burst to spend
+    let clock = Arc::new(TestClock::new());
+    let broker = Arc::new(
+        Broker::with_options(
+            Arc::new(auth),
+            metrics.clone(),
+            BrokerOptions {
+                max_pub_rate: Some(10),
+                ..Default::default()
+            },
+        )
+        .with_clock(clock.clone()),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut subscriber = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    subscriber
+        .send(Frame::Subscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch"),
+        })
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(2), async {
+        while !broker.subscribers.contains_key("ch") {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .unwrap();
+
+    // a publisher within the limit has everything delivered
+    let mut polite = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    publish_burst(&mut polite, 10).await;
+    wait_for_publishes(&metrics, 10).await;
+    assert_eq!(delivered(&mut subscriber).await, 10);
+    assert_eq!(metrics.total_pub_rate_limited.get(), 0);
+
+    // one over it has the excess dropped, with a bucket of its own
+    let mut flooder = connect_and_auth(&addr, "client1", "s3cret").await.unwrap();
+    publish_burst(&mut flooder, 30).await;
+    wait_for_publishes(&metrics, 40).await;
+    assert_eq!(delivered(&mut subscriber).await, 10);
+    assert_eq!(metrics.total_pub_rate_limited.get(), 20);
+
+    // and stays connected, publishing again once the bucket refills
+    clock.advance(Duration::from_secs(1));
+    flooder.send(publish(0)).await.unwrap();
+    assert_eq!(delivered(&mut subscriber).await, 1);
+}
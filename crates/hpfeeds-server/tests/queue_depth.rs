@@ -0,0 +1,74 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn slow_subscriber_backlog_is_reported_before_lag() -> Result<(), Box<dyn std::error::Error>>
+{
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let options = BrokerOptions {
+        channel_capacity: 4096,
+        backlog_high_water: Some(100),
+        ..Default::default()
+    };
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        metrics.clone(),
+        options,
+    ));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut sub = connect_and_auth(&addr, "client1", "s3cret").await?;
+    sub.send(Frame::Subscribe {
+        ident: Bytes::from_static(b"client1"),
+        channel: Bytes::from_static(b"ch"),
+    })
+    .await?;
+    timeout(Duration::from_secs(1), async {
+        while !broker.subscribers.contains_key("ch") {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await?;
+
+    // Far more than the socket buffers hold, so the broker's writes to the unread subscriber
+    // block while publishes keep arriving, but fewer than the channel capacity.
+    let count = 1000;
+    let mut pubc = connect_and_auth(&addr, "client1", "s3cret").await?;
+    let payload = Bytes::from(vec![0u8; 32 * 1024]);
+    for _ in 0..count {
+        pubc.send(Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch"),
+            payload: payload.clone(),
+        })
+        .await?;
+    }
+
+    let mut received = 0;
+    timeout(Duration::from_secs(10), async {
+        while received < count {
+            if let Some(Ok(Frame::Publish { .. })) = sub.next().await {
+                received += 1;
+            }
+        }
+    })
+    .await?;
+
+    assert!(metrics.total_backlog_high_water.get() >= 1);
+    assert!(metrics.subscriber_backlog.get_sample_sum() >= 100.0);
+    assert_eq!(metrics.total_lagged.get(), 0);
+
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::{Frame, HpfeedsCodec, hashsecret};
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::time::{Duration, timeout};
+use tokio_util::codec::Framed;
+
+#[tokio::test]
+async fn handshake_with_20_byte_rand() -> Result<(), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let options = BrokerOptions {
+        rand_len: 20,
+        ..Default::default()
+    };
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        metrics.clone(),
+        options,
+    ));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    // The broker sends the configured length
+    let mut raw = Framed::new(TcpStream::connect(&addr).await?, HpfeedsCodec::new());
+    let Some(Ok(Frame::Info { rand, .. })) = raw.next().await else {
+        panic!("expected OP_INFO");
+    };
+    assert_eq!(rand.len(), 20);
+    raw.send(Frame::Auth {
+        ident: Bytes::from_static(b"client1"),
+        secret_hash: hashsecret(&rand, "s3cret").into(),
+    })
+    .await?;
+    raw.send(Frame::Subscribe {
+        ident: Bytes::from_static(b"client1"),
+        channel: Bytes::from_static(b"ch"),
+    })
+    .await?;
+
+    // And the stock client handshake works end to end
+    let mut client = connect_and_auth(&addr, "client1", "s3cret").await?;
+    client
+        .send(Frame::Subscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch"),
+        })
+        .await?;
+    timeout(Duration::from_secs(1), async {
+        while broker
+            .subscribers
+            .get("ch")
+            .map_or(0, |tx| tx.receiver_count())
+            < 2
+        {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await?;
+    client
+        .send(Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch"),
+            payload: Bytes::from_static(b"hello"),
+        })
+        .await?;
+    let got = timeout(Duration::from_secs(1), client.next()).await?;
+    assert!(matches!(got, Some(Ok(Frame::Publish { payload, .. })) if payload == "hello"));
+    let got = timeout(Duration::from_secs(1), raw.next()).await?;
+    assert!(matches!(got, Some(Ok(Frame::Publish { payload, .. })) if payload == "hello"));
+    assert_eq!(metrics.total_auth_success.get(), 2);
+
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use futures::StreamExt;
+use hpfeeds_client::ReconnectingClient;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, run_server};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use tokio::sync::oneshot;
+use tokio::time::{Duration, Instant, timeout};
+
+// A broker on a runtime of its own, so that stopping it closes every connection as a crashed
+// process would.
+struct TestBroker {
+    addr: SocketAddr,
+    stop: oneshot::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl TestBroker {
+    fn start(addr: SocketAddr) -> Self {
+        let (stop, stopped) = oneshot::channel();
+        let (bound, bound_rx) = std::sync::mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let auth = MemoryAuthenticator::new();
+                auth.add("client1", "s3cret").await;
+                let broker = Arc::new(Broker::new(Arc::new(auth), Arc::new(Metrics::new())));
+                let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+                bound.send(listener.local_addr().unwrap()).unwrap();
+                tokio::select! {
+                    _ = run_server(listener, broker, None) => {}
+                    _ = stopped => {}
+                }
+            });
+        });
+        Self {
+            addr: bound_rx.recv().unwrap(),
+            stop,
+            thread,
+        }
+    }
+
+    fn kill(self) {
+        self.stop.send(()).unwrap();
+        self.thread.join().unwrap();
+    }
+}
+
+// Publishes `payload` until `subscriber` receives it; anything sent while either side is
+// reconnecting is lost.
+async fn publish_until_delivered(
+    publisher: &mut ReconnectingClient,
+    subscriber: &mut ReconnectingClient,
+    payload: &'static str,
+) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        assert!(Instant::now() < deadline, "{} was never delivered", payload);
+        publisher.publish("ch", payload).await.unwrap();
+        let received = timeout(Duration::from_millis(200), async {
+            loop {
+                if let Some(Frame::Publish { payload: p, .. }) = subscriber.next().await
+                    && p == payload
+                {
+                    return;
+                }
+            }
+        })
+        .await;
+        if received.is_ok() {
+            return;
+        }
+    }
+}
+
+#[tokio::test]
+async fn delivery_resumes_after_broker_restart() {
+    let broker = TestBroker::start("127.0.0.1:0".parse().unwrap());
+    let addr = broker.addr;
+    let backoff = (Duration::from_millis(20), Duration::from_millis(200));
+    let mut subscriber = ReconnectingClient::connect(&addr.to_string(), "client1", "s3cret")
+        .await
+        .unwrap()
+        .with_backoff(backoff.0, backoff.1);
+    subscriber.subscribe("ch").await.unwrap();
+    let mut publisher = ReconnectingClient::connect(&addr.to_string(), "client1", "s3cret")
+        .await
+        .unwrap()
+        .with_backoff(backoff.0, backoff.1);
+    publish_until_delivered(&mut publisher, &mut subscriber, "before").await;
+    assert_eq!(subscriber.reconnects(), 0);
+
+    broker.kill();
+    let restarted = TestBroker::start(addr);
+    publish_until_delivered(&mut publisher, &mut subscriber, "after").await;
+    assert_eq!(subscriber.reconnects(), 1);
+    restarted.kill();
+}
@@ -0,0 +1,59 @@
+#![cfg(feature = "recording")]
+
+use bytes::Bytes;
+use futures::SinkExt;
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::recording::{Delivery, Recorder};
+use hpfeeds_server::server::{Broker, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn recorder_sees_publishes_without_subscribers() {
+    let auth = MemoryAuthenticator::new();
+    auth.add_user("sensor", "s", vec!["cowrie.sessions".into()], vec![])
+        .await;
+    let recorder = Recorder::new();
+    let broker = Arc::new(
+        Broker::new(Arc::new(auth), Arc::new(Metrics::new())).with_recorder(recorder.clone()),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker, None));
+
+    let mut sensor = connect_and_auth(&addr, "sensor", "s").await.unwrap();
+    for channel in ["denied", "cowrie.sessions"] {
+        sensor
+            .send(Frame::Publish {
+                ident: Bytes::from_static(b"sensor"),
+                channel: Bytes::from_static(channel.as_bytes()),
+                payload: Bytes::from_static(b"login"),
+            })
+            .await
+            .unwrap();
+    }
+    timeout(Duration::from_secs(2), async {
+        while recorder.deliveries().is_empty() {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .unwrap();
+
+    // the publish the ACL denied never reached the fan-out
+    assert_eq!(
+        recorder.take(),
+        [Delivery {
+            channel: "cowrie.sessions".into(),
+            frame: Frame::Publish {
+                ident: Bytes::from_static(b"sensor"),
+                channel: Bytes::from_static(b"cowrie.sessions"),
+                payload: Bytes::from_static(b"login"),
+            },
+        }]
+    );
+    assert!(recorder.deliveries().is_empty());
+}
@@ -0,0 +1,65 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn late_subscriber_receives_retained_then_live() -> Result<(), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let options = BrokerOptions {
+        retain_depth: 2,
+        retain_ttl: Some(Duration::from_secs(60)),
+        ..Default::default()
+    };
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        Arc::new(Metrics::new()),
+        options,
+    ));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let publish = |payload: &'static str| Frame::Publish {
+        ident: Bytes::from_static(b"client1"),
+        channel: Bytes::from_static(b"ch"),
+        payload: Bytes::from_static(payload.as_bytes()),
+    };
+
+    // Frames on one connection are handled in order, so the subscription sees all three
+    // publishes already retained, of which only the last two are kept
+    let mut client = connect_and_auth(&addr, "client1", "s3cret").await?;
+    for p in ["1", "2", "3"] {
+        client.send(publish(p)).await?;
+    }
+    client
+        .send(Frame::Subscribe {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch"),
+        })
+        .await?;
+    client.send(publish("4")).await?;
+
+    let mut got = Vec::new();
+    timeout(Duration::from_secs(1), async {
+        while got.len() < 3 {
+            if let Some(Ok(Frame::Publish { payload, .. })) = client.next().await {
+                got.push(payload);
+            }
+        }
+    })
+    .await?;
+    assert_eq!(
+        got,
+        vec![Bytes::from("2"), Bytes::from("3"), Bytes::from("4")]
+    );
+
+    Ok(())
+}
@@ -0,0 +1,52 @@
+// Linux balances connections across SO_REUSEPORT listeners; other Unixes may favour one.
+#![cfg(target_os = "linux")]
+
+use hpfeeds_server::listen::{ListenOptions, bind};
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{Duration, timeout};
+
+// Accepts forever, reporting `id` for each connection.
+fn count_accepts(listener: TcpListener, id: usize, tx: mpsc::UnboundedSender<usize>) {
+    tokio::spawn(async move {
+        while let Ok((_socket, _)) = listener.accept().await {
+            if tx.send(id).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+#[tokio::test]
+async fn two_listeners_share_a_port() -> Result<(), Box<dyn std::error::Error>> {
+    let options = ListenOptions {
+        reuse_addr: true,
+        reuse_port: true,
+    };
+    let first = bind("127.0.0.1:0".parse()?, options)?;
+    let addr: SocketAddr = first.local_addr()?;
+    let second = bind(addr, options)?;
+    assert!(
+        bind(addr, ListenOptions::default()).is_err(),
+        "a listener without SO_REUSEPORT must not join"
+    );
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    count_accepts(first, 0, tx.clone());
+    count_accepts(second, 1, tx);
+
+    // the kernel hashes each connection to one listener; with this many, both are all but
+    // certain to be picked
+    let mut clients = Vec::new();
+    let mut accepted = [0usize; 2];
+    for _ in 0..64 {
+        clients.push(TcpStream::connect(addr).await?);
+        let id = timeout(Duration::from_secs(2), rx.recv())
+            .await?
+            .expect("accept loop running");
+        accepted[id] += 1;
+    }
+    assert!(accepted[0] > 0 && accepted[1] > 0, "{:?}", accepted);
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn run_server_routes_publish_to_subscriber() -> Result<(), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let broker = Arc::new(Broker::new(Arc::new(auth), metrics.clone()));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut sub = connect_and_auth(&addr, "client1", "s3cret").await?;
+    sub.send(Frame::Subscribe {
+        ident: Bytes::from_static(b"client1"),
+        channel: Bytes::from_static(b"ch1"),
+    })
+    .await?;
+
+    // Wait until the broker has registered the subscription
+    timeout(Duration::from_secs(1), async {
+        while !broker.subscribers.contains_key("ch1") {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await?;
+
+    let mut pubc = connect_and_auth(&addr, "client1", "s3cret").await?;
+    pubc.send(Frame::Publish {
+        ident: Bytes::from_static(b"client1"),
+        channel: Bytes::from_static(b"ch1"),
+        payload: Bytes::from_static(b"hello"),
+    })
+    .await?;
+
+    match timeout(Duration::from_secs(1), sub.next()).await? {
+        Some(Ok(Frame::Publish {
+            ident,
+            channel,
+            payload,
+        })) => {
+            assert_eq!(ident, Bytes::from_static(b"client1"));
+            assert_eq!(channel, Bytes::from_static(b"ch1"));
+            assert_eq!(payload, Bytes::from_static(b"hello"));
+        }
+        other => panic!("expected publish, got {:?}", other),
+    }
+    assert_eq!(metrics.total_published.get(), 1);
+    assert_eq!(metrics.total_auth_success.get(), 2);
+
+    Ok(())
+}
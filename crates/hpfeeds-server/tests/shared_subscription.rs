@@ -0,0 +1,164 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::{Subscriber, connect_and_auth, connect_and_auth_selecting};
+use hpfeeds_core::{CAP_SHARE, Capabilities, Frame};
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::time::{Duration, timeout};
+
+async fn wait_for_subscribers(broker: &Broker, channel: &str, n: usize) {
+    timeout(Duration::from_secs(2), async {
+        while broker
+            .subscribers
+            .get(channel)
+            .map_or(0, |tx| tx.receiver_count())
+            < n
+        {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .expect("subscribes were not processed");
+}
+
+async fn worker(addr: &str) -> Subscriber<TcpStream> {
+    let (transport, agreed) =
+        connect_and_auth_selecting(addr, "reader", "s", &Capabilities::new([CAP_SHARE]))
+            .await
+            .unwrap();
+    assert!(agreed.contains(CAP_SHARE));
+    let mut subscriber = Subscriber::new(transport, "reader");
+    subscriber.subscribe_shared("ch", "workers").await.unwrap();
+    // frames on one connection are handled in order, so once "marker" is subscribed the
+    // worker has joined the group
+    subscriber.subscribe("marker").await.unwrap();
+    subscriber
+}
+
+// Payloads received on "ch" until nothing more arrives for a while.
+async fn received(subscriber: &mut Subscriber<TcpStream>) -> Vec<Bytes> {
+    let mut payloads = Vec::new();
+    while let Ok(msg) = timeout(Duration::from_millis(300), subscriber.next()).await {
+        let msg = msg.expect("connection open").unwrap();
+        if msg.channel == "ch" {
+            payloads.push(msg.payload);
+        }
+    }
+    payloads
+}
+
+#[tokio::test]
+async fn share_group_members_each_get_a_share() {
+    let auth = MemoryAuthenticator::new();
+    auth.add("reader", "s").await;
+    auth.add("sensor", "s").await;
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        Arc::new(Metrics::new()),
+        BrokerOptions {
+            preauth_frames: 1,
+            ..Default::default()
+        },
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut first = worker(&addr).await;
+    let mut second = worker(&addr).await;
+    let mut everything = Subscriber::new(
+        connect_and_auth(&addr, "reader", "s").await.unwrap(),
+        "reader",
+    );
+    everything.subscribe("ch").await.unwrap();
+    everything.subscribe("marker").await.unwrap();
+    wait_for_subscribers(&broker, "marker", 3).await;
+
+    let mut sensor = connect_and_auth(&addr, "sensor", "s").await.unwrap();
+    for i in 0..10 {
+        sensor
+            .send(Frame::Publish {
+                ident: Bytes::from_static(b"sensor"),
+                channel: Bytes::from_static(b"ch"),
+                payload: i.to_string().into(),
+            })
+            .await
+            .unwrap();
+    }
+
+    let first = received(&mut first).await;
+    let second = received(&mut second).await;
+    // round-robin: an even split, and no message delivered to both
+    assert_eq!(first.len(), 5, "{:?}", first);
+    assert_eq!(second.len(), 5, "{:?}", second);
+    let shared: HashSet<_> = first.iter().chain(&second).collect();
+    assert_eq!(shared.len(), 10);
+    // ordinary subscribers still get every copy
+    assert_eq!(received(&mut everything).await.len(), 10);
+}
+
+#[tokio::test]
+async fn members_switch_only_after_leaving_the_group() {
+    let auth = MemoryAuthenticator::new();
+    auth.add("reader", "s").await;
+    auth.add("sensor", "s").await;
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        Arc::new(Metrics::new()),
+        BrokerOptions {
+            preauth_frames: 1,
+            ..Default::default()
+        },
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut first = worker(&addr).await;
+    let mut second = worker(&addr).await;
+    wait_for_subscribers(&broker, "marker", 2).await;
+
+    // a plain subscribe would quietly keep delivering only the member's share
+    first.subscribe("ch").await.unwrap();
+    let err = first.wait_for_error(Duration::from_secs(2)).await.unwrap();
+    assert!(
+        err.to_string()
+            .contains("already subscribed to ch in share group workers"),
+        "{}",
+        err
+    );
+
+    first.unsubscribe_shared("ch", "workers").await.unwrap();
+    first.unsubscribe("marker").await.unwrap();
+    timeout(Duration::from_secs(2), async {
+        while broker
+            .subscribers
+            .get("marker")
+            .map_or(0, |tx| tx.receiver_count())
+            > 1
+        {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .expect("unsubscribes were not processed");
+
+    let mut sensor = connect_and_auth(&addr, "sensor", "s").await.unwrap();
+    for i in 0..4 {
+        sensor
+            .send(Frame::Publish {
+                ident: Bytes::from_static(b"sensor"),
+                channel: Bytes::from_static(b"ch"),
+                payload: i.to_string().into(),
+            })
+            .await
+            .unwrap();
+    }
+
+    assert!(received(&mut first).await.is_empty());
+    assert_eq!(received(&mut second).await.len(), 4);
+}
@@ -0,0 +1,90 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn stalled_subscriber_is_shed_over_buffer_limit() -> Result<(), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let options = BrokerOptions {
+        max_buffered_bytes: Some(256 * 1024),
+        ..Default::default()
+    };
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        metrics.clone(),
+        options,
+    ));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let subscribe = Frame::Subscribe {
+        ident: Bytes::from_static(b"client1"),
+        channel: Bytes::from_static(b"ch"),
+    };
+    let mut stalled = connect_and_auth(&addr, "client1", "s3cret").await?;
+    stalled.send(subscribe.clone()).await?;
+    let mut draining = connect_and_auth(&addr, "client1", "s3cret").await?;
+    draining.send(subscribe).await?;
+    timeout(Duration::from_secs(1), async {
+        while broker
+            .subscribers
+            .get("ch")
+            .map_or(0, |tx| tx.receiver_count())
+            < 2
+        {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await?;
+
+    // The stalled subscriber's flush blocks with far more than the limit buffered; the
+    // draining subscriber's flushes then push the total over and shed it.
+    let count = 300;
+    let reader = tokio::spawn(async move {
+        let mut n = 0;
+        while n < count {
+            match draining.next().await {
+                Some(Ok(Frame::Publish { .. })) => n += 1,
+                Some(_) => {}
+                None => break,
+            }
+        }
+        n
+    });
+    let mut pubc = connect_and_auth(&addr, "client1", "s3cret").await?;
+    let payload = Bytes::from(vec![0u8; 64 * 1024]);
+    for _ in 0..count {
+        pubc.send(Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch"),
+            payload: payload.clone(),
+        })
+        .await?;
+    }
+    timeout(Duration::from_secs(5), reader).await??;
+
+    // Whatever reached the stalled socket before it was shed can still be read, then EOF
+    timeout(Duration::from_secs(5), async {
+        while let Some(Ok(_)) = stalled.next().await {}
+    })
+    .await?;
+    assert!(metrics.total_shed.get() >= 1);
+    timeout(Duration::from_secs(1), async {
+        while broker.buffers.in_flight() != 0 {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await?;
+
+    Ok(())
+}
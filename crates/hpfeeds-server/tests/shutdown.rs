@@ -0,0 +1,141 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, SHUTDOWN_MESSAGE, WHOAMI_CHANNEL, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn clients_get_queued_messages_then_an_error_before_eof()
+-> Result<(), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let broker = Arc::new(Broker::new(Arc::new(auth), metrics.clone()));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    let server = tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut sub = connect_and_auth(&addr, "client1", "s3cret").await?;
+    sub.send(Frame::Subscribe {
+        ident: Bytes::from_static(b"client1"),
+        channel: Bytes::from_static(b"ch"),
+    })
+    .await?;
+    timeout(Duration::from_secs(2), async {
+        while !broker.subscribers.contains_key("ch") {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await?;
+    let mut publisher = connect_and_auth(&addr, "client1", "s3cret").await?;
+    for i in 0..10 {
+        publisher
+            .send(Frame::Publish {
+                ident: Bytes::from_static(b"client1"),
+                channel: Bytes::from_static(b"ch"),
+                payload: Bytes::from(i.to_string()),
+            })
+            .await?;
+    }
+    timeout(Duration::from_secs(2), async {
+        while metrics.total_published.get() < 10 {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await?;
+
+    broker.shutdown();
+    let mut frames = Vec::new();
+    timeout(Duration::from_secs(2), async {
+        while let Some(frame) = sub.next().await {
+            frames.push(frame.unwrap());
+        }
+    })
+    .await?;
+    assert_eq!(frames.len(), 11, "{:?}", frames);
+    assert!(
+        frames[..10]
+            .iter()
+            .all(|f| matches!(f, Frame::Publish { .. }))
+    );
+    assert_eq!(frames[10], Frame::Error(SHUTDOWN_MESSAGE.into()));
+
+    timeout(Duration::from_secs(2), server).await???;
+    assert!(tokio::net::TcpStream::connect(&addr).await.is_err());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn sigterm_disconnects_clients_and_exits_cleanly() -> Result<(), Box<dyn std::error::Error>> {
+    use std::process::{Command, Stdio};
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_hpfeeds-server"));
+    command
+        .args([
+            "--port",
+            &port.to_string(),
+            "--auth",
+            "client1:s3cret",
+            "--whoami",
+        ])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    #[cfg(feature = "metrics")]
+    command.arg("--no-metrics");
+    let mut child = command.spawn()?;
+
+    let addr = format!("127.0.0.1:{}", port);
+    let mut client = None;
+    for _ in 0..500 {
+        if let Ok(c) = connect_and_auth(&addr, "client1", "s3cret").await {
+            client = Some(c);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let Some(mut client) = client else {
+        child.kill()?;
+        panic!("broker did not start");
+    };
+    // once whoami is answered the session is authenticated, not still in the handshake
+    client
+        .send(Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(WHOAMI_CHANNEL.as_bytes()),
+            payload: Bytes::new(),
+        })
+        .await?;
+    let reply = timeout(Duration::from_secs(5), client.next()).await?;
+    assert!(
+        matches!(reply, Some(Ok(Frame::Publish { .. }))),
+        "{:?}",
+        reply
+    );
+
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()?;
+    let frame = timeout(Duration::from_secs(5), client.next()).await?;
+    assert!(
+        matches!(&frame, Some(Ok(Frame::Error(msg))) if msg == SHUTDOWN_MESSAGE),
+        "{:?}",
+        frame
+    );
+    assert!(
+        timeout(Duration::from_secs(5), client.next())
+            .await?
+            .is_none()
+    );
+
+    let status = tokio::task::spawn_blocking(move || child.wait()).await??;
+    assert!(status.success(), "{:?}", status);
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, sleep, timeout};
+
+#[tokio::test]
+async fn stalled_subscriber_trips_slow_delivery() -> Result<(), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let options = BrokerOptions {
+        slow_delivery: Some(Duration::from_millis(50)),
+        ..Default::default()
+    };
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        metrics.clone(),
+        options,
+    ));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut sub = connect_and_auth(&addr, "client1", "s3cret").await?;
+    sub.send(Frame::Subscribe {
+        ident: Bytes::from_static(b"client1"),
+        channel: Bytes::from_static(b"ch"),
+    })
+    .await?;
+    timeout(Duration::from_secs(1), async {
+        while !broker.subscribers.contains_key("ch") {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await?;
+
+    // Far more than the socket buffers hold, so the broker's flush blocks on the subscriber
+    let count = 200;
+    let mut pubc = connect_and_auth(&addr, "client1", "s3cret").await?;
+    let payload = Bytes::from(vec![0u8; 64 * 1024]);
+    for _ in 0..count {
+        pubc.send(Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch"),
+            payload: payload.clone(),
+        })
+        .await?;
+    }
+
+    sleep(Duration::from_millis(300)).await;
+    let mut received = 0;
+    timeout(Duration::from_secs(5), async {
+        while received < count {
+            if let Some(Ok(Frame::Publish { .. })) = sub.next().await {
+                received += 1;
+            }
+        }
+    })
+    .await?;
+
+    assert!(metrics.total_slow_deliveries.get() >= 1);
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, run_server};
+use serde_json::Value;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, timeout};
+
+// Collects everything the subscriber writes.
+#[derive(Clone, Default)]
+struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// The `conn` span of each captured line logging `message`.
+fn spans_of(capture: &Capture, message: &str) -> Vec<Value> {
+    let logs = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+    logs.lines()
+        .map(|l| serde_json::from_str::<Value>(l).unwrap())
+        .filter(|l| l["fields"]["message"] == message)
+        .map(|l| l["span"].clone())
+        .collect()
+}
+
+#[tokio::test]
+async fn connection_logs_carry_its_span_fields() -> Result<(), Box<dyn std::error::Error>> {
+    let capture = Capture::default();
+    let writer = capture.clone();
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::fmt()
+            .json()
+            .with_current_span(true)
+            .with_max_level(tracing::Level::DEBUG)
+            .with_writer(move || writer.clone())
+            .finish(),
+    )?;
+
+    let auth = MemoryAuthenticator::new();
+    auth.add("reader", "s").await;
+    auth.add("sensor", "s").await;
+    let broker = Arc::new(Broker::new(Arc::new(auth), Arc::new(Metrics::new())));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut reader = connect_and_auth(&addr, "reader", "s").await?;
+    for channel in ["a", "b"] {
+        reader
+            .send(Frame::Subscribe {
+                ident: Bytes::from_static(b"reader"),
+                channel: Bytes::from(channel),
+            })
+            .await?;
+    }
+    timeout(Duration::from_secs(2), async {
+        while !broker.subscribers.contains_key("b") {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await?;
+    let mut sensor = connect_and_auth(&addr, "sensor", "s").await?;
+    sensor
+        .send(Frame::Publish {
+            ident: Bytes::from_static(b"sensor"),
+            channel: Bytes::from_static(b"a"),
+            payload: Bytes::from_static(b"hello"),
+        })
+        .await?;
+    timeout(Duration::from_secs(2), reader.next()).await?;
+
+    let subscribed = spans_of(&capture, "subscribed");
+    let delivering = spans_of(&capture, "delivering");
+    assert_eq!(subscribed.len(), 2);
+    assert_eq!(delivering.len(), 1);
+    let span = &subscribed[0];
+    assert_eq!(span["name"], "conn");
+    assert_eq!(span["ident"], "reader");
+    assert!(span["conn_id"].is_u64());
+    assert!(span["peer"].as_str().unwrap().starts_with("127.0.0.1:"));
+    for other in subscribed.iter().chain(&delivering) {
+        assert_eq!(other, span);
+    }
+
+    // the publisher's connection has a span of its own
+    let authenticated = spans_of(&capture, "authenticated");
+    assert_eq!(authenticated.len(), 2);
+    assert_ne!(authenticated[0]["conn_id"], authenticated[1]["conn_id"]);
+    assert!(authenticated.iter().any(|s| s["ident"] == "sensor"));
+    Ok(())
+}
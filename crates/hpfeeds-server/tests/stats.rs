@@ -0,0 +1,66 @@
+use bytes::Bytes;
+use futures::SinkExt;
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, run_server};
+use hpfeeds_server::stats;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn stats_line_reports_publishes() -> Result<(), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let broker = Arc::new(Broker::new(Arc::new(auth), metrics.clone()));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker, None));
+
+    let (tx, mut lines) = mpsc::unbounded_channel();
+    let reporter = metrics.clone();
+    tokio::spawn(async move {
+        stats::report(&reporter, Duration::from_millis(50), |line| {
+            let _ = tx.send(line);
+        })
+        .await
+    });
+
+    // once a line is out, the reporter has its starting point
+    timeout(Duration::from_secs(5), lines.recv()).await?;
+    let mut client = connect_and_auth(&addr, "client1", "s3cret").await?;
+    client
+        .send(Frame::Publish {
+            ident: Bytes::from_static(b"client1"),
+            channel: Bytes::from_static(b"ch"),
+            payload: Bytes::from_static(b"hello"),
+        })
+        .await?;
+
+    // lines from intervals before the publish was handled report none
+    let line = timeout(Duration::from_secs(5), async {
+        loop {
+            let line = lines.recv().await.expect("reporter running");
+            if !line.contains("published=0") {
+                return line;
+            }
+        }
+    })
+    .await?;
+    assert!(
+        line.starts_with("stats over 50ms: published=1 "),
+        "{}",
+        line
+    );
+    assert!(line.ends_with("connections=1"), "{}", line);
+
+    // the next interval reports only what happened since
+    let next = timeout(Duration::from_secs(5), lines.recv())
+        .await?
+        .unwrap();
+    assert!(next.contains("published=0 "), "{}", next);
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use hpfeeds_client::{ClientError, Subscriber, connect_and_auth_selecting};
+use hpfeeds_core::{CAP_SUBACK, Capabilities};
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+
+const WAIT: Duration = Duration::from_secs(2);
+
+// A broker confirming subscribes, where "reader" may only subscribe to "allowed".
+async fn start_broker() -> String {
+    let auth = MemoryAuthenticator::new();
+    auth.add_user("reader", "s", vec![], vec!["allowed".into()])
+        .await;
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        Arc::new(Metrics::new()),
+        BrokerOptions {
+            preauth_frames: 1,
+            ..Default::default()
+        },
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker, None));
+    addr
+}
+
+async fn reader(addr: &str) -> Subscriber<TcpStream> {
+    let (transport, agreed) =
+        connect_and_auth_selecting(addr, "reader", "s", &Capabilities::new([CAP_SUBACK]))
+            .await
+            .unwrap();
+    assert!(agreed.contains(CAP_SUBACK));
+    Subscriber::new(transport, "reader")
+}
+
+#[tokio::test]
+async fn broker_confirms_allowed_subscribe() {
+    let addr = start_broker().await;
+    let mut subscriber = reader(&addr).await;
+
+    assert!(
+        subscriber
+            .subscribe_confirmed("allowed", WAIT)
+            .await
+            .unwrap()
+    );
+    // a repeated subscribe is confirmed too
+    assert!(
+        subscriber
+            .subscribe_confirmed("allowed", WAIT)
+            .await
+            .unwrap()
+    );
+}
+
+#[tokio::test]
+async fn broker_reports_denied_subscribe() {
+    let addr = start_broker().await;
+    let mut subscriber = reader(&addr).await;
+
+    let err = subscriber
+        .subscribe_confirmed("secret", WAIT)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(&err, ClientError::Broker(msg) if msg == "not authorized to subscribe to secret"),
+        "{:?}",
+        err
+    );
+    // the connection stays usable
+    assert!(
+        subscriber
+            .subscribe_confirmed("allowed", WAIT)
+            .await
+            .unwrap()
+    );
+}
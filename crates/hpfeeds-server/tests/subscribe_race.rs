@@ -0,0 +1,63 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+const ROUNDS: usize = 200;
+
+// With retain on, a publish racing the first subscribe to a channel reaches the subscriber
+// exactly once, either retained or live, whichever of the two the broker handles first.
+#[tokio::test]
+async fn racing_the_first_subscribe_delivers_exactly_once() -> Result<(), Box<dyn std::error::Error>>
+{
+    let auth = MemoryAuthenticator::new();
+    auth.add("reader", "s").await;
+    auth.add("sensor", "s").await;
+    let options = BrokerOptions {
+        retain_depth: 1,
+        ..Default::default()
+    };
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        Arc::new(Metrics::new()),
+        options,
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+
+    let mut reader = connect_and_auth(&addr, "reader", "s").await?;
+    let mut sensor = connect_and_auth(&addr, "sensor", "s").await?;
+    for i in 0..ROUNDS {
+        let channel = format!("race.{}", i);
+        let subscribe = reader.send(Frame::Subscribe {
+            ident: Bytes::from_static(b"reader"),
+            channel: Bytes::from(channel.clone()),
+        });
+        let publish = sensor.send(Frame::Publish {
+            ident: Bytes::from_static(b"sensor"),
+            channel: Bytes::from(channel.clone()),
+            payload: Bytes::from(i.to_string()),
+        });
+        let (subscribed, published) = tokio::join!(subscribe, publish);
+        subscribed?;
+        published?;
+
+        // a second copy of the previous round's message would show up here instead
+        match timeout(Duration::from_secs(2), reader.next()).await? {
+            Some(Ok(Frame::Publish { channel: got, .. })) => assert_eq!(got, channel.as_bytes()),
+            other => panic!("round {}: expected a publish, got {:?}", i, other),
+        }
+    }
+    assert!(
+        timeout(Duration::from_millis(100), reader.next())
+            .await
+            .is_err()
+    );
+    Ok(())
+}
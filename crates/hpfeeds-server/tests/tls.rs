@@ -1,5 +1,8 @@
 use futures::{SinkExt, StreamExt};
-use hpfeeds_client::connect_tls_and_auth;
+use hpfeeds_client::{
+    ClientBuilder, ClientError, MaybeTlsStream, connect_tls_and_auth, connect_tls_and_auth_named,
+    connect_tls_and_auth_webpki,
+};
 use hpfeeds_core::Frame;
 
 use bytes::Bytes;
@@ -7,6 +10,7 @@ use rcgen::generate_simple_self_signed;
 use rustls::ServerConfig;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio_rustls::TlsAcceptor;
 
 #[tokio::test]
@@ -64,3 +68,99 @@ async fn tls_handshake_and_auth() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+// A TLS endpoint whose certificate is valid for `name` alone, answering each client with
+// OP_INFO. Returns its address and the certificate to trust.
+async fn named_endpoint(name: &str) -> Result<(String, Vec<u8>), Box<dyn std::error::Error>> {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let cert = generate_simple_self_signed(vec![name.into()])?;
+    let cert_der = cert.cert.der().to_vec();
+    let server_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![CertificateDer::from(cert_der.clone())],
+            PrivateKeyDer::try_from(cert.signing_key.serialize_der())?,
+        )?;
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let Ok(tls_stream) = acceptor.accept(socket).await else {
+                continue;
+            };
+            let mut framed =
+                tokio_util::codec::Framed::new(tls_stream, hpfeeds_core::HpfeedsCodec::new());
+            let _ = framed
+                .send(Frame::Info {
+                    name: Bytes::from_static(b"tls-broker"),
+                    rand: vec![1u8, 2, 3, 4].into(),
+                })
+                .await;
+            tokio::spawn(async move { while framed.next().await.is_some() {} });
+        }
+    });
+    Ok((addr, cert_der))
+}
+
+#[tokio::test]
+async fn server_name_is_verified_against_the_certificate() -> Result<(), Box<dyn std::error::Error>>
+{
+    let (addr, cert_der) = named_endpoint("broker.example").await?;
+
+    connect_tls_and_auth_named(&addr, "broker.example", "client1", "s3cret", &cert_der).await?;
+
+    // the certificate is checked, not just the root: other names are refused
+    let wrong_name =
+        connect_tls_and_auth_named(&addr, "other.example", "client1", "s3cret", &cert_der).await;
+    assert!(matches!(wrong_name, Err(ClientError::Tls(_))));
+    let localhost = connect_tls_and_auth(&addr, "client1", "s3cret", &cert_der).await;
+    assert!(matches!(localhost, Err(ClientError::Tls(_))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn public_roots_do_not_trust_self_signed_brokers() -> Result<(), Box<dyn std::error::Error>> {
+    let (addr, _cert_der) = named_endpoint("broker.example").await?;
+    let result = connect_tls_and_auth_webpki(&addr, "broker.example", "client1", "s3cret").await;
+    assert!(
+        matches!(&result, Err(ClientError::Tls(e)) if e.contains("UnknownIssuer")),
+        "{:?}",
+        result.err()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn builder_connects_over_tls() -> Result<(), Box<dyn std::error::Error>> {
+    let (addr, cert_der) = named_endpoint("broker.example").await?;
+
+    let builder = ClientBuilder::new(&addr, "client1", "s3cret").tls(cert_der.clone());
+    let transport = builder
+        .clone()
+        .server_name("broker.example")
+        .connect()
+        .await?;
+    assert!(matches!(transport.get_ref(), MaybeTlsStream::Tls(_)));
+    // the server name defaults to the address's host, 127.0.0.1, which this certificate is
+    // not valid for
+    assert!(matches!(builder.connect().await, Err(ClientError::Tls(_))));
+
+    let (addr, cert_der) = named_endpoint("localhost").await?;
+    let port = addr.rsplit_once(':').unwrap().1;
+    let by_name = ClientBuilder::new(&format!("localhost:{}", port), "client1", "s3cret")
+        .tls(cert_der)
+        .connect()
+        .await?;
+    assert!(matches!(by_name.get_ref(), MaybeTlsStream::Tls(_)));
+
+    let (addr, cert_der) = named_endpoint("broker.example").await?;
+    let reconnecting = ClientBuilder::new(&addr, "client1", "s3cret")
+        .tls(cert_der)
+        .server_name("broker.example")
+        .reconnect(Duration::from_millis(10), Duration::from_millis(100))
+        .connect()
+        .await?;
+    assert_eq!(reconnecting.reconnects(), 0);
+    Ok(())
+}
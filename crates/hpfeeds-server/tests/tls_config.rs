@@ -0,0 +1,68 @@
+use futures::StreamExt;
+use hpfeeds_client::connect_tls_and_auth;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::config::{TlsVersion, load_config};
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{Broker, run_server};
+use hpfeeds_server::tls;
+use rcgen::generate_simple_self_signed;
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn config_tls_section_builds_a_working_acceptor() {
+    // for the client side
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let cert = generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    // TLS paths must be relative, so work under the crate directory
+    let dir = format!("tls-config-test-{}", std::process::id());
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(format!("{}/cert.pem", dir), cert.cert.pem()).unwrap();
+    std::fs::write(format!("{}/key.pem", dir), cert.signing_key.serialize_pem()).unwrap();
+    let config_path = format!("{}/server.json", dir);
+    std::fs::write(
+        &config_path,
+        format!(
+            r#"{{"users": [], "tls": {{"cert": "{dir}/cert.pem", "key": "{dir}/key.pem", "min_version": "1.2"}}}}"#
+        ),
+    )
+    .unwrap();
+
+    let config = load_config(&config_path).unwrap();
+    let tls_config = config.tls.expect("tls section");
+    assert_eq!(tls_config.min_version, Some(TlsVersion::V1_2));
+    let acceptor = tls::acceptor(&tls_config).unwrap();
+
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let broker = Arc::new(Broker::new(Arc::new(auth), Arc::new(Metrics::new())));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker, Some(Arc::new(acceptor))));
+
+    let mut transport = connect_tls_and_auth(&addr, "client1", "s3cret", cert.cert.der())
+        .await
+        .unwrap();
+    // authenticated sessions stay open
+    assert!(
+        timeout(Duration::from_millis(200), transport.next())
+            .await
+            .is_err()
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn tls_paths_must_be_relative() {
+    let config: hpfeeds_server::config::TlsConfig =
+        serde_json::from_str(r#"{"cert": "/etc/ssl/cert.pem", "key": "key.pem"}"#).unwrap();
+    let err = tls::acceptor(&config)
+        .err()
+        .expect("absolute path accepted");
+    assert!(err.to_string().contains("unsafe TLS file path"), "{}", err);
+
+    let config: hpfeeds_server::config::TlsConfig =
+        serde_json::from_str(r#"{"cert": "certs/../../cert.pem", "key": "key.pem"}"#).unwrap();
+    assert!(tls::acceptor(&config).is_err());
+}
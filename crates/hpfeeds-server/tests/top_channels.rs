@@ -0,0 +1,91 @@
+#![cfg(feature = "metrics")]
+
+use bytes::Bytes;
+use futures::SinkExt;
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::{Metrics, serve_metrics};
+use hpfeeds_server::server::{Broker, BrokerOptions, run_server};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{Duration, timeout};
+
+#[tokio::test]
+async fn busiest_channel_is_listed_first() -> Result<(), Box<dyn std::error::Error>> {
+    let auth = MemoryAuthenticator::new();
+    auth.add("client1", "s3cret").await;
+    let metrics = Arc::new(Metrics::new());
+    let options = BrokerOptions {
+        top_channels: Some(3),
+        ..Default::default()
+    };
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        metrics.clone(),
+        options,
+    ));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(run_server(listener, broker.clone(), None));
+    let http = TcpListener::bind("127.0.0.1:0").await?;
+    let http_addr = http.local_addr()?;
+    tokio::spawn(serve_metrics(
+        http,
+        metrics.registry.clone(),
+        broker.top_channels(),
+        4,
+        Duration::from_secs(5),
+    ));
+
+    let mut publisher = connect_and_auth(&addr, "client1", "s3cret").await?;
+    let mut sent = 0;
+    let mut plan: Vec<(String, usize)> = vec![
+        ("cowrie.sessions".into(), 60),
+        ("dionaea.capture".into(), 30),
+        ("glastopf.events".into(), 10),
+    ];
+    plan.extend((0..200).map(|i| (format!("sensor{}.status", i), 1)));
+    for (channel, count) in plan {
+        for _ in 0..count {
+            publisher
+                .send(Frame::Publish {
+                    ident: Bytes::from_static(b"client1"),
+                    channel: Bytes::from(channel.clone()),
+                    payload: Bytes::from_static(b"{}"),
+                })
+                .await?;
+            sent += 1;
+        }
+    }
+    timeout(Duration::from_secs(2), async {
+        while metrics.total_published.get() < sent {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await?;
+
+    let mut client = TcpStream::connect(http_addr).await?;
+    client
+        .write_all(
+            b"GET /admin/top-channels HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .await?;
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(2), client.read_to_end(&mut response)).await??;
+    let response = String::from_utf8(response)?;
+    assert!(response.starts_with("HTTP/1.1 200"));
+    let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+    let top: serde_json::Value = serde_json::from_str(body)?;
+
+    let channels = top["channels"].as_array().unwrap();
+    assert_eq!(channels.len(), 3);
+    assert_eq!(channels[0]["channel"], "cowrie.sessions");
+    assert_eq!(channels[0]["publishes"], 60);
+    assert_eq!(channels[1]["channel"], "dionaea.capture");
+    assert_eq!(channels[2]["channel"], "glastopf.events");
+    assert_eq!(top["window_secs"], 60);
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use hpfeeds_client::connect_and_auth;
+use hpfeeds_core::Frame;
+use hpfeeds_server::auth::MemoryAuthenticator;
+use hpfeeds_server::metrics::Metrics;
+use hpfeeds_server::server::{BROKER_NAME, Broker, BrokerOptions, WHOAMI_CHANNEL, run_server};
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+async fn start(whoami: bool) -> String {
+    let auth = MemoryAuthenticator::new();
+    auth.add_user(
+        "sensor1",
+        "s3cret",
+        vec!["cowrie.sessions".into(), "dionaea.capture".into()],
+        vec!["hpfeeds.keepalive".into()],
+    )
+    .await;
+    let broker = Arc::new(Broker::with_options(
+        Arc::new(auth),
+        Arc::new(Metrics::new()),
+        BrokerOptions {
+            whoami,
+            ..Default::default()
+        },
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(run_server(listener, broker, None));
+    addr
+}
+
+fn query() -> Frame {
+    Frame::Publish {
+        ident: Bytes::from_static(b"sensor1"),
+        channel: Bytes::from_static(WHOAMI_CHANNEL.as_bytes()),
+        payload: Bytes::new(),
+    }
+}
+
+#[tokio::test]
+async fn client_is_told_its_permissions() {
+    let addr = start(true).await;
+    let mut client = connect_and_auth(&addr, "sensor1", "s3cret").await.unwrap();
+    client.send(query()).await.unwrap();
+
+    match timeout(Duration::from_secs(2), client.next()).await {
+        Ok(Some(Ok(Frame::Publish {
+            ident,
+            channel,
+            payload,
+        }))) => {
+            assert_eq!(ident, BROKER_NAME);
+            assert_eq!(channel, WHOAMI_CHANNEL);
+            let body: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+            assert_eq!(
+                body,
+                serde_json::json!({
+                    "ident": "sensor1",
+                    "pub_channels": ["cowrie.sessions", "dionaea.capture"],
+                    "sub_channels": ["hpfeeds.keepalive"],
+                })
+            );
+        }
+        other => panic!("expected the whoami reply, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn whoami_is_off_by_default() {
+    let addr = start(false).await;
+    let mut client = connect_and_auth(&addr, "sensor1", "s3cret").await.unwrap();
+    client.send(query()).await.unwrap();
+    assert!(
+        timeout(Duration::from_millis(200), client.next())
+            .await
+            .is_err()
+    );
+}